@@ -1,20 +1,277 @@
 // Connection logger
-use iroh::Watcher;
 use iroh::endpoint::{ConnectionType, Endpoint};
+use iroh::{EndpointAddr, Watcher};
+use std::time::Duration;
 
-/// Log connection details (Direct/Relay/Mixed)
-pub fn log_connection_details(endpoint: &Endpoint, remote_node_id: iroh::PublicKey, prefix: &str) {
+use crate::tunnel::logger::SharedLogger;
+
+/// How long to let address discovery settle before printing
+const ADDR_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Connection details (Direct/Relay/Mixed) and the smoothed RTT if known
+pub fn format_connection_details(
+    endpoint: &Endpoint,
+    remote_node_id: iroh::PublicKey,
+    prefix: &str,
+    rtt: Option<Duration>,
+) -> String {
+    let rtt = rtt
+        .map(|rtt| format!(", RTT: {}", format_rtt(rtt)))
+        .unwrap_or_default();
     if let Some(mut watcher) = endpoint.conn_type(remote_node_id) {
         let type_ = watcher.get();
         match type_ {
-            ConnectionType::Direct(addr) => println!("{} (Mode: Direct, Addr: {})", prefix, addr),
-            ConnectionType::Relay(url) => println!("{} (Mode: Relay, Url: {})", prefix, url),
+            ConnectionType::Direct(addr) => {
+                format!("{} (Mode: Direct, Addr: {}{})", prefix, addr, rtt)
+            }
+            ConnectionType::Relay(url) => format!("{} (Mode: Relay, Url: {}{})", prefix, url, rtt),
             ConnectionType::Mixed(addr, url) => {
-                println!("{} (Mode: Mixed, Addr: {}, Url: {})", prefix, addr, url)
+                format!(
+                    "{} (Mode: Mixed, Addr: {}, Url: {}{})",
+                    prefix, addr, url, rtt
+                )
             }
-            ConnectionType::None => println!("{} (Mode: None)", prefix),
+            ConnectionType::None => format!("{} (Mode: None{})", prefix, rtt),
         }
     } else {
-        println!("{} (Mode: Unknown)", prefix);
+        format!("{} (Mode: Unknown{})", prefix, rtt)
+    }
+}
+
+/// Round-trip time in milliseconds with one decimal, e.g. "23.4 ms"
+pub fn format_rtt(rtt: Duration) -> String {
+    format!("{:.1} ms", rtt.as_secs_f64() * 1000.0)
+}
+
+/// Short label for a connection type (direct/relay/mixed/none)
+pub fn path_type_label(conn_type: &ConnectionType) -> &'static str {
+    match conn_type {
+        ConnectionType::Direct(_) => "direct",
+        ConnectionType::Relay(_) => "relay",
+        ConnectionType::Mixed(_, _) => "mixed",
+        ConnectionType::None => "none",
+    }
+}
+
+/// Current connection type to a peer, `None` if iroh doesn't know the peer
+pub fn current_connection_type(
+    endpoint: &Endpoint,
+    remote_node_id: iroh::PublicKey,
+) -> ConnectionType {
+    endpoint
+        .conn_type(remote_node_id)
+        .map(|mut watcher| watcher.get())
+        .unwrap_or_default()
+}
+
+/// Wait for the connection type to change away from `current`
+///
+/// Returns `None` once the watcher disconnects (the peer is gone).
+pub async fn next_connection_type<W>(
+    watcher: &mut W,
+    current: &ConnectionType,
+) -> Option<ConnectionType>
+where
+    W: Watcher<Value = ConnectionType>,
+{
+    loop {
+        let next = watcher.updated().await.ok()?;
+        if next != *current {
+            return Some(next);
+        }
+    }
+}
+
+/// Whether the path has a direct (hole-punched) leg: Direct or Mixed
+pub fn has_direct_path(conn_type: &ConnectionType) -> bool {
+    matches!(
+        conn_type,
+        ConnectionType::Direct(_) | ConnectionType::Mixed(_, _)
+    )
+}
+
+/// Wait up to `limit` for the path to get a direct leg (`--prefer-direct`)
+///
+/// Returns the connection type at the end: the first direct one, or the
+/// current one once `limit` passes or the watcher disconnects.
+pub async fn wait_for_direct_path<W>(watcher: &mut W, limit: Duration) -> ConnectionType
+where
+    W: Watcher<Value = ConnectionType>,
+{
+    let mut current = watcher.get();
+    let deadline = tokio::time::Instant::now() + limit;
+    while !has_direct_path(&current) {
+        let next = tokio::select! {
+            next = next_connection_type(watcher, &current) => next,
+            _ = tokio::time::sleep_until(deadline) => break,
+        };
+        match next {
+            Some(next) => current = next,
+            None => break,
+        }
+    }
+    current
+}
+
+/// A connection type transition, e.g. Relay → Direct after hole-punching
+pub fn format_connection_type_change(
+    remote_node_id: iroh::PublicKey,
+    previous: &ConnectionType,
+    next: &ConnectionType,
+) -> String {
+    let icon = match next {
+        ConnectionType::Direct(_) => "🎯",
+        _ => "🔀",
+    };
+    format!(
+        "{} Path to {}: {} → {} ({})",
+        icon,
+        remote_node_id.fmt_short(),
+        path_type_label(previous),
+        path_type_label(next),
+        next
+    )
+}
+
+/// Human-readable lines for this node's relay URL and direct addresses
+pub fn format_endpoint_addrs(addr: &EndpointAddr) -> Vec<String> {
+    let mut lines: Vec<String> = addr
+        .relay_urls()
+        .map(|url| format!("relay:  {}", url))
+        .collect();
+    lines.extend(addr.ip_addrs().map(|ip| format!("direct: {}", ip)));
+    if lines.is_empty() {
+        lines.push("(none discovered yet)".to_string());
+    }
+    lines
+}
+
+/// Log this node's reachable addresses once discovery settles, and again on change
+///
+/// Updates are debounced by `ADDR_DEBOUNCE` so a burst of discovery events
+/// logs once, and nothing is logged if the addresses end up unchanged.
+pub async fn watch_endpoint_addrs(
+    mut watcher: impl Watcher<Value = EndpointAddr>,
+    logger: SharedLogger,
+) {
+    let mut printed: Option<EndpointAddr> = None;
+    loop {
+        tokio::time::sleep(ADDR_DEBOUNCE).await;
+
+        let addr = watcher.get();
+        if printed.as_ref() != Some(&addr) {
+            let mut message = String::from("🔗 Endpoints:");
+            for line in format_endpoint_addrs(&addr) {
+                message.push_str("\n   ");
+                message.push_str(&line);
+            }
+            logger.info(&message);
+            printed = Some(addr);
+        }
+
+        if watcher.updated().await.is_err() {
+            break; // Endpoint closed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use n0_watcher::Watchable;
+
+    fn relay_url() -> iroh::RelayUrl {
+        "https://relay.example.com".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_next_connection_type_follows_transitions() {
+        let direct_addr = "203.0.113.7:4433".parse().unwrap();
+        let watchable = Watchable::new(ConnectionType::Relay(relay_url()));
+        let mut watcher = watchable.watch();
+        let mut current = watcher.get();
+
+        watchable
+            .set(ConnectionType::Mixed(direct_addr, relay_url()))
+            .ok();
+        let next = next_connection_type(&mut watcher, &current).await.unwrap();
+        assert_eq!(path_type_label(&next), "mixed");
+        current = next;
+
+        watchable.set(ConnectionType::Direct(direct_addr)).ok();
+        let next = next_connection_type(&mut watcher, &current).await.unwrap();
+        assert_eq!(next, ConnectionType::Direct(direct_addr));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_direct_path() {
+        let direct_addr = "203.0.113.7:4433".parse().unwrap();
+        let watchable = Watchable::new(ConnectionType::Relay(relay_url()));
+        let mut watcher = watchable.watch();
+
+        // Hole-punching succeeds while we wait
+        let punch = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            watchable
+                .set(ConnectionType::Mixed(direct_addr, relay_url()))
+                .ok();
+        };
+        let (found, _) = tokio::join!(
+            wait_for_direct_path(&mut watcher, Duration::from_secs(5)),
+            punch
+        );
+        assert!(has_direct_path(&found));
+
+        // Already direct: no waiting at all
+        let started = tokio::time::Instant::now();
+        let found = wait_for_direct_path(&mut watcher, Duration::from_secs(5)).await;
+        assert_eq!(found, ConnectionType::Mixed(direct_addr, relay_url()));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Stuck on the relay: give up after the limit
+        watchable.set(ConnectionType::Relay(relay_url())).ok();
+        let started = tokio::time::Instant::now();
+        let found = wait_for_direct_path(&mut watcher, Duration::from_millis(200)).await;
+        assert_eq!(found, ConnectionType::Relay(relay_url()));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_next_connection_type_ends_when_watchable_dropped() {
+        let watchable = Watchable::new(ConnectionType::None);
+        let mut watcher = watchable.watch();
+        drop(watchable);
+        assert_eq!(
+            next_connection_type(&mut watcher, &ConnectionType::None).await,
+            None
+        );
+    }
+
+    #[test]
+    fn test_format_endpoint_addrs() {
+        let id = iroh::SecretKey::generate(&mut rand::rng()).public();
+        let addr = EndpointAddr::new(id)
+            .with_relay_url(relay_url())
+            .with_ip_addr("192.168.1.10:4433".parse().unwrap());
+        assert_eq!(
+            format_endpoint_addrs(&addr),
+            vec![
+                "relay:  https://relay.example.com./".to_string(),
+                "direct: 192.168.1.10:4433".to_string(),
+            ]
+        );
+        assert_eq!(
+            format_endpoint_addrs(&EndpointAddr::new(id)),
+            vec!["(none discovered yet)".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_path_type_label() {
+        assert_eq!(path_type_label(&ConnectionType::None), "none");
+        assert_eq!(
+            path_type_label(&ConnectionType::Relay(relay_url())),
+            "relay"
+        );
     }
 }
@@ -1 +1,3 @@
+pub mod pac;
 pub mod parser;
+pub mod request;
@@ -0,0 +1,168 @@
+// Proxy auto-config (PAC) file endpoint
+use crate::http::request::{REQUEST_READ_TIMEOUT, read_request_path};
+use crate::tunnel::logger::SharedLogger;
+use anyhow::Result;
+use std::fmt::Write as _;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Loopback and RFC 1918 ranges sent `DIRECT` with `--pac-direct-private`
+const PRIVATE_NETS: [(&str, &str); 4] = [
+    ("127.0.0.0", "255.0.0.0"),
+    ("10.0.0.0", "255.0.0.0"),
+    ("172.16.0.0", "255.240.0.0"),
+    ("192.168.0.0", "255.255.0.0"),
+];
+
+/// What the PAC endpoint (`--pac-port`) serves
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PacOptions {
+    /// Localhost port the PAC file is served on
+    pub port: u16,
+    /// Send localhost, plain host names and private IPv4 literals direct
+    pub direct_private: bool,
+    /// `shExpMatch` patterns sent direct, e.g. `*.corp.example`
+    pub bypass: Vec<String>,
+}
+
+/// Validate a `--pac-bypass` pattern, so it can be embedded in the script
+pub fn parse_bypass(pattern: &str) -> Result<String, String> {
+    let pattern = pattern.trim().to_ascii_lowercase();
+    if pattern.is_empty()
+        || !pattern
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '*' | '?' | ':'))
+    {
+        return Err(format!(
+            "expected a host pattern like *.example.com, got {:?}",
+            pattern
+        ));
+    }
+    Ok(pattern)
+}
+
+/// The PAC script pointing browsers at the SOCKS proxy on `socks_addr`
+///
+/// Private ranges are only matched for IP literals, so checking a host
+/// never makes the browser resolve it outside the tunnel.
+pub fn render_pac(socks_addr: SocketAddr, options: &PacOptions) -> String {
+    let mut proxy = socks_addr;
+    if proxy.ip().is_unspecified() {
+        proxy.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+
+    let mut out = String::from("function FindProxyForURL(url, host) {\n");
+    if options.direct_private {
+        out.push_str(
+            "    if (isPlainHostName(host) || host === \"localhost\" ||\n        host === \"::1\" || host === \"[::1]\")\n        return \"DIRECT\";\n",
+        );
+        out.push_str("    if (/^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host) && (");
+        for (i, (net, mask)) in PRIVATE_NETS.iter().enumerate() {
+            if i > 0 {
+                out.push_str(" ||\n        ");
+            }
+            let _ = write!(out, "isInNet(host, \"{}\", \"{}\")", net, mask);
+        }
+        out.push_str("))\n        return \"DIRECT\";\n");
+    }
+    for pattern in &options.bypass {
+        let _ = writeln!(
+            out,
+            "    if (shExpMatch(host, \"{}\"))\n        return \"DIRECT\";",
+            pattern
+        );
+    }
+    let _ = writeln!(out, "    return \"SOCKS5 {}\";\n}}", proxy);
+    out
+}
+
+/// Serve `pac` at `/` and `/proxy.pac` on `listener` until the listener
+/// fails, reporting failed requests to `logger`
+pub async fn serve_pac(
+    listener: TcpListener,
+    pac: Arc<String>,
+    logger: SharedLogger,
+) -> Result<()> {
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        let pac = Arc::clone(&pac);
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_pac_request(socket, &pac).await {
+                logger.error(None, &format!("PAC request error from {}: {}", addr, e));
+            }
+        });
+    }
+}
+
+async fn handle_pac_request(mut socket: TcpStream, pac: &str) -> Result<()> {
+    let path = read_request_path(&mut socket, REQUEST_READ_TIMEOUT).await?;
+    let response = if path == "/" || path == "/proxy.pac" {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-ns-proxy-autoconfig\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            pac.len(),
+            pac
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_render_pac_bypass() {
+        let plain = render_pac("127.0.0.1:1080".parse().unwrap(), &PacOptions::default());
+        assert_eq!(
+            plain,
+            "function FindProxyForURL(url, host) {\n    return \"SOCKS5 127.0.0.1:1080\";\n}\n"
+        );
+
+        let options = PacOptions {
+            direct_private: true,
+            bypass: vec![parse_bypass("*.Corp.example").unwrap()],
+            ..Default::default()
+        };
+        let pac = render_pac("0.0.0.0:9050".parse().unwrap(), &options);
+        assert!(pac.contains("isInNet(host, \"192.168.0.0\", \"255.255.0.0\")"));
+        assert!(pac.contains("shExpMatch(host, \"*.corp.example\")"));
+        assert!(pac.ends_with("return \"SOCKS5 127.0.0.1:9050\";\n}\n"));
+    }
+
+    #[test]
+    fn test_parse_bypass_rejects_script() {
+        assert!(parse_bypass("intranet").is_ok());
+        assert!(parse_bypass("x\"); alert(1); (\"").is_err());
+        assert!(parse_bypass("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_pac_file() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pac = render_pac("127.0.0.1:4321".parse().unwrap(), &PacOptions::default());
+        tokio::spawn(serve_pac(listener, Arc::new(pac), SharedLogger::default()));
+
+        for (path, status) in [("/proxy.pac", "200 OK"), ("/other", "404")] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with(&format!("HTTP/1.1 {}", status)));
+            if status == "200 OK" {
+                assert!(response.contains("application/x-ns-proxy-autoconfig"));
+                assert!(response.contains("return \"SOCKS5 127.0.0.1:4321\";"));
+            }
+        }
+    }
+}
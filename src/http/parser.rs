@@ -8,25 +8,57 @@ pub struct HttpRequestInfo {
     pub method: String,
     pub path: String,
     pub host: Option<String>,
+    pub user_agent: Option<String>,
+    /// `Upgrade: websocket` with `Connection: Upgrade`: a long-lived WebSocket follows
+    pub websocket_upgrade: bool,
+}
+
+/// Request methods recognized at the start of a request line
+const HTTP_METHODS: [&str; 9] = [
+    "GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+/// Whether `data` starts like an HTTP request (`METHOD ` and more)
+///
+/// Needs only the first few bytes, unlike `extract_http_info`.
+pub fn starts_with_http_method(data: &[u8]) -> bool {
+    HTTP_METHODS.iter().any(|method| {
+        data.strip_prefix(method.as_bytes())
+            .is_some_and(|rest| rest.first() == Some(&b' '))
+    })
 }
 
 pub fn extract_http_info(data: &[u8]) -> Option<HttpRequestInfo> {
+    diagnose_http_request(data).ok()
+}
+
+/// Parse an HTTP request like `extract_http_info`, or say why it isn't one
+///
+/// For `--debug-parse`; the error names the check that failed.
+pub fn diagnose_http_request(data: &[u8]) -> Result<HttpRequestInfo, String> {
     // HTTP request must have at least "GET / HTTP/1.x\r\n" which is about 16 bytes minimum
     if data.len() < 16 {
-        return None;
+        return Err(format!(
+            "{} bytes is shorter than a request line (16)",
+            data.len()
+        ));
     }
 
     // Try to parse as UTF-8 string
-    let text = std::str::from_utf8(data).ok()?;
+    let text = std::str::from_utf8(data)
+        .map_err(|e| format!("not UTF-8 at offset {}", e.valid_up_to()))?;
 
     // Split into lines
     let mut lines = text.lines();
-    let first_line = lines.next()?;
+    let first_line = lines.next().unwrap_or_default();
 
     // Parse request line: "METHOD /path HTTP/1.x"
     let parts: Vec<&str> = first_line.split_whitespace().collect();
     if parts.len() < 3 {
-        return None;
+        return Err(format!(
+            "request line has {} of 3 parts (METHOD PATH VERSION)",
+            parts.len()
+        ));
     }
 
     let method = parts[0];
@@ -35,39 +67,76 @@ pub fn extract_http_info(data: &[u8]) -> Option<HttpRequestInfo> {
 
     // Check if this looks like an HTTP request
     if !version.starts_with("HTTP/1.") {
-        return None;
+        return Err(format!("version {:?} is not HTTP/1.x", version));
     }
 
-    // Valid HTTP methods
-    let valid_methods = [
-        "GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH", "CONNECT", "TRACE",
-    ];
-    if !valid_methods.contains(&method) {
-        return None;
+    if !HTTP_METHODS.contains(&method) {
+        return Err(format!("{:?} is not an HTTP method", method));
     }
 
-    // Extract Host header
+    // Extract Host, User-Agent and the upgrade headers
     let mut host = None;
+    let mut user_agent = None;
+    let mut upgrade_websocket = false;
+    let mut connection_upgrade = false;
     for line in lines {
         if line.is_empty() {
             break; // End of headers
         }
 
-        if let Some((header_name, header_value)) = line.split_once(':') {
-            if header_name.trim().eq_ignore_ascii_case("host") {
-                host = Some(header_value.trim().to_string());
-                break;
-            }
+        let Some((header_name, header_value)) = line.split_once(':') else {
+            continue;
+        };
+        let header_name = header_name.trim();
+        let header_value = header_value.trim();
+        if header_name.eq_ignore_ascii_case("host") {
+            host.get_or_insert_with(|| header_value.to_string());
+        } else if header_name.eq_ignore_ascii_case("user-agent") {
+            user_agent.get_or_insert_with(|| header_value.to_string());
+        } else if header_name.eq_ignore_ascii_case("upgrade") {
+            upgrade_websocket |= has_token(header_value, "websocket");
+        } else if header_name.eq_ignore_ascii_case("connection") {
+            connection_upgrade |= has_token(header_value, "upgrade");
         }
     }
 
-    Some(HttpRequestInfo {
+    Ok(HttpRequestInfo {
         method: method.to_string(),
         path: path.to_string(),
         host,
+        user_agent,
+        websocket_upgrade: upgrade_websocket && connection_upgrade,
     })
 }
 
+/// Status code from the first line of an HTTP/1.x response, e.g. 404 for
+/// `HTTP/1.1 404 Not Found`
+pub fn extract_http_response_status(data: &[u8]) -> Option<u16> {
+    let line_end = data
+        .iter()
+        .position(|&b| b == b'\r' || b == b'\n')
+        .unwrap_or(data.len());
+    let status_line = std::str::from_utf8(&data[..line_end]).ok()?;
+
+    let mut parts = status_line.split(' ');
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let code = parts.next()?;
+    if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u16 = code.parse().ok()?;
+    (100..=599).contains(&code).then_some(code)
+}
+
+/// Whether a comma-separated header value lists `token` (case-insensitive)
+fn has_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|item| item.trim().eq_ignore_ascii_case(token))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -99,6 +168,63 @@ mod tests {
         assert_eq!(info.host, None);
     }
 
+    #[test]
+    fn test_extract_http_info_user_agent() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\nuser-agent:  curl/8.5.0 \r\nAccept: */*\r\n\r\n";
+        let info = extract_http_info(request).unwrap();
+        assert_eq!(info.user_agent, Some("curl/8.5.0".to_string()));
+        assert_eq!(info.host, Some("example.com".to_string()));
+
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(extract_http_info(request).unwrap().user_agent, None);
+    }
+
+    #[test]
+    fn test_extract_http_info_websocket_upgrade() {
+        let request = b"GET /chat HTTP/1.1\r\nHost: ws.example.com\r\nUpgrade: WebSocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let info = extract_http_info(request).unwrap();
+        assert_eq!(info.path, "/chat");
+        assert_eq!(info.host, Some("ws.example.com".to_string()));
+        assert!(info.websocket_upgrade);
+
+        // Both headers are needed
+        let request = b"GET /chat HTTP/1.1\r\nHost: ws.example.com\r\nUpgrade: websocket\r\n\r\n";
+        assert!(!extract_http_info(request).unwrap().websocket_upgrade);
+        let request = b"GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+        assert!(!extract_http_info(request).unwrap().websocket_upgrade);
+    }
+
+    #[test]
+    fn test_extract_http_response_status_ok() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi";
+        assert_eq!(extract_http_response_status(response), Some(200));
+    }
+
+    #[test]
+    fn test_extract_http_response_status_not_found() {
+        let response = b"HTTP/1.0 404 Not Found\r\n\r\n";
+        assert_eq!(extract_http_response_status(response), Some(404));
+        // Only the status line needs to be in the first chunk
+        assert_eq!(extract_http_response_status(b"HTTP/1.1 404"), Some(404));
+    }
+
+    #[test]
+    fn test_extract_http_response_status_not_http() {
+        assert_eq!(
+            extract_http_response_status(b"SSH-2.0-OpenSSH_9.6\r\n"),
+            None
+        );
+        assert_eq!(
+            extract_http_response_status(b"GET / HTTP/1.1\r\n\r\n"),
+            None
+        );
+        assert_eq!(extract_http_response_status(b"HTTP/1.1 2000 OK\r\n"), None);
+        assert_eq!(
+            extract_http_response_status(&[0x16, 0x03, 0x03, 0x00]),
+            None
+        );
+    }
+
     #[test]
     fn test_extract_http_info_too_short() {
         let request = b"GET";
@@ -126,4 +252,14 @@ mod tests {
             assert_eq!(info.method, *method);
         }
     }
+
+    #[test]
+    fn test_starts_with_http_method() {
+        assert!(starts_with_http_method(b"GET / HTTP/1.1\r\n"));
+        assert!(starts_with_http_method(b"CONNECT example.com:443 HTTP/1.1"));
+        assert!(starts_with_http_method(b"POST "));
+        assert!(!starts_with_http_method(b"GETS / HTTP/1.1"));
+        assert!(!starts_with_http_method(b"GET"));
+        assert!(!starts_with_http_method(&[0x05, 0x01, 0x00]));
+    }
 }
@@ -0,0 +1,71 @@
+// Reading requests on the local HTTP endpoints (metrics, PAC file)
+use anyhow::Result;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest request header read; anything past it is ignored
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// How long a client may take to send its request header
+pub const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read a request header from `socket` and return the path of its request line
+///
+/// Reads up to the blank line ending the header, EOF or `MAX_REQUEST_SIZE`
+/// bytes; a body is ignored. Fails if the header takes longer than `limit`,
+/// so a client that never finishes can't hold its task forever.
+pub async fn read_request_path(
+    socket: &mut (impl AsyncRead + Unpin),
+    limit: Duration,
+) -> Result<String> {
+    let read = async {
+        let mut buf = Vec::with_capacity(1024);
+        let mut chunk = [0u8; 1024];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = socket.read(&mut chunk).await?;
+            if n == 0 || buf.len() + n > MAX_REQUEST_SIZE {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        anyhow::Ok(buf)
+    };
+    let buf = tokio::time::timeout(limit, read)
+        .await
+        .map_err(|_| anyhow::anyhow!("Request header not received within {:?}", limit))??;
+
+    let request = String::from_utf8_lossy(&buf);
+    Ok(request.split_whitespace().nth(1).unwrap_or("").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_read_request_path() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"GET /proxy.pac HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let path = read_request_path(&mut server, REQUEST_READ_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(path, "/proxy.pac");
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_request_times_out() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        let err = read_request_path(&mut server, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not received"), "{}", err);
+    }
+}
@@ -2,6 +2,7 @@
 
 pub mod connection;
 pub mod http;
+pub mod metrics;
 pub mod socks5;
 pub mod tls;
 pub mod tunnel;
@@ -1,204 +1,1214 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use iroh::endpoint::Endpoint;
-use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::Mutex;
-
-use iroh_socks5_proxy::tunnel::connection::{
-    generate_ticket, handle_peer_connection_with_monitoring, monitor_connection_health,
-};
+use clap::{Arg, ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::ffi::OsString;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use iroh_socks5_proxy::http::pac::{PacOptions, parse_bypass};
+#[cfg(unix)]
+use iroh_socks5_proxy::metrics::status::stats_dump;
+use iroh_socks5_proxy::tls::server::SocksTls;
+use iroh_socks5_proxy::tunnel::auth::AuthToken;
+use iroh_socks5_proxy::tunnel::config_file::config_file_args;
+use iroh_socks5_proxy::tunnel::connection::{parse_peer, read_peer_file};
+use iroh_socks5_proxy::tunnel::dns::DEFAULT_DNS_CACHE_SIZE;
+use iroh_socks5_proxy::tunnel::filter::{HostFilter, HostRule};
+use iroh_socks5_proxy::tunnel::forward::PortForward;
+use iroh_socks5_proxy::tunnel::logger::StdoutLogger;
+use iroh_socks5_proxy::tunnel::outbound::EgressBind;
 use iroh_socks5_proxy::tunnel::persistence::{
-    clear_remote_peer_id, get_or_create_secret_key, save_remote_peer_id,
+    Profile, create_secret_key, forget, list_profiles, parse_profile_name, read_secret_key,
+};
+use iroh_socks5_proxy::tunnel::quota::DEFAULT_QUOTA_WINDOW;
+use iroh_socks5_proxy::tunnel::relay::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE, RelayOptions};
+use iroh_socks5_proxy::tunnel::routing::{Route, RouteTable};
+use iroh_socks5_proxy::tunnel::runtime::{
+    DEFAULT_CONNECT_RETRIES, DEFAULT_DRAIN_TIMEOUT, DEFAULT_HEALTH_CHECK_INTERVAL,
+    DEFAULT_HEARTBEAT_TIMEOUT, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_QUEUED, DEFAULT_MAX_STREAMS,
+    DEFAULT_RECONNECT_WAIT, DEFAULT_SOCKS_PORT, PathMode, TunnelConfig, check_config,
+    parse_relay_url, run_client, run_server,
+};
+use iroh_socks5_proxy::tunnel::tcp::{
+    DEFAULT_KEEPALIVE_IDLE, DEFAULT_KEEPALIVE_INTERVAL, TcpOptions, parse_dscp,
 };
-use iroh_socks5_proxy::tunnel::socks::handle_socks_client;
-use iroh_socks5_proxy::tunnel::state::{ConnectionState, TUNNEL_ALPN, TunnelState};
+use iroh_socks5_proxy::tunnel::transport::{DEFAULT_IDLE_TIMEOUT, DEFAULT_QUIC_KEEPALIVE};
+use iroh_socks5_proxy::tunnel::upstream::UpstreamProxy;
+use iroh_socks5_proxy::utils::version::version_info;
+
+/// `--version` output; `-V` stays on one line
+static LONG_VERSION: LazyLock<String> = LazyLock::new(version_info);
 
 #[derive(Parser, Debug)]
-#[command(author, version, about = "Iroh-based SOCKS5 tunnel", long_about = None)]
+#[command(author, version, long_version = LONG_VERSION.as_str(), about = "Iroh-based SOCKS5 tunnel", long_about = None)]
+// A flag on the command line replaces the same one from --config
+#[command(args_override_self = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Read options from this TOML file (keys are long flag names); flags given here win
+    #[arg(long, value_name = "FILE", global = true)]
+    config: Option<std::path::PathBuf>,
+
     /// Local SOCKS5 proxy port
-    #[arg(short, long, default_value = "1080")]
+    #[arg(short, long, default_value_t = DEFAULT_SOCKS_PORT, global = true)]
     port: u16,
 
-    /// Peer node ticket to connect to (optional, for client mode)
-    #[arg(short = 'c', long)]
-    peer: Option<String>,
+    /// Also accept SOCKS5 clients on this unix socket (Unix only)
+    #[arg(long, value_name = "PATH", global = true)]
+    socks_unix: Option<std::path::PathBuf>,
+
+    /// Require TLS on the SOCKS5 port, presenting this PEM certificate chain
+    #[arg(long, value_name = "PATH", requires = "socks_tls_key", global = true)]
+    socks_tls_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key for --socks-tls-cert
+    #[arg(long, value_name = "PATH", requires = "socks_tls_cert", global = true)]
+    socks_tls_key: Option<std::path::PathBuf>,
+
+    /// Use the named identity: .tunnel_key.<NAME> and .tunnel_peer.<NAME>
+    #[arg(long, value_name = "NAME", value_parser = parse_profile_name, global = true)]
+    profile: Option<String>,
+
+    /// Deprecated: use `tunnel connect <TICKET>...` instead
+    #[arg(short = 'c', long, value_delimiter = ',', hide = true)]
+    peer: Vec<String>,
 
     /// Log file path for request logging (optional)
-    #[arg(short = 'l', long)]
+    #[arg(short = 'l', long, global = true)]
     log_file: Option<String>,
+
+    /// Append a combined-format line per completed request to this file
+    #[arg(long, value_name = "PATH", global = true)]
+    access_log: Option<std::path::PathBuf>,
+
+    /// Hide destination hosts, SNI and HTTP paths in log output
+    #[arg(long, global = true)]
+    privacy: bool,
+
+    /// Also show the connection ticket as a QR code (serve only)
+    #[arg(long, global = true)]
+    qr: bool,
+
+    /// Validate the configuration and identity, print what would run, and exit
+    #[arg(long, global = true)]
+    check: bool,
+
+    /// Give up reconnecting after this many failed attempts (0 = retry forever)
+    #[arg(long, default_value = "0", global = true)]
+    max_reconnect_attempts: u32,
+
+    /// Serve Prometheus metrics on this port (localhost only, optional)
+    #[arg(long, global = true)]
+    metrics_port: Option<u16>,
+
+    /// Serve a proxy auto-config file for this proxy on this port (localhost only)
+    #[arg(long, value_name = "PORT", global = true)]
+    pac_port: Option<u16>,
+
+    /// In the PAC file, send localhost and private IPv4 addresses direct
+    #[arg(long, requires = "pac_port", global = true)]
+    pac_direct_private: bool,
+
+    /// In the PAC file, send hosts matching this pattern direct (repeatable)
+    #[arg(long, value_name = "PATTERN", value_parser = parse_bypass, requires = "pac_port", global = true)]
+    pac_bypass: Vec<String>,
+
+    /// Node ID allowed to connect to this node (repeatable; empty allows any peer)
+    #[arg(long = "allow-peer", value_name = "NODE_ID", global = true)]
+    allow_peers: Vec<iroh::PublicKey>,
+
+    /// Serve one peer at a time, refusing others while it is connected
+    #[arg(long, global = true)]
+    exclusive: bool,
+
+    /// Pin the first peer to connect (saved in .tunnel_peer) and accept only it
+    #[arg(long, global = true)]
+    sticky_peer: bool,
+
+    /// Announce this node on the LAN and find peers there by node ID alone
+    #[arg(long, global = true)]
+    local_discovery: bool,
+
+    /// Shared secret sent with every request and required from peers' requests
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        env = "TUNNEL_AUTH_TOKEN",
+        hide_env_values = true,
+        global = true
+    )]
+    auth_token: Option<String>,
+
+    /// Bytes each peer may relay per quota window, e.g. 10G (0 = unlimited)
+    #[arg(long, value_name = "BYTES", default_value = "0", value_parser = parse_bytes, global = true)]
+    peer_quota: u64,
+
+    /// Length of the rolling window peer quotas apply to
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_QUOTA_WINDOW.as_secs(), global = true)]
+    quota_window: u64,
+
+    /// Seconds a SOCKS request waits for the peer to connect or reconnect before failing
+    #[arg(long, visible_alias = "reconnect-wait", value_name = "SECS", default_value_t = DEFAULT_RECONNECT_WAIT.as_secs(), global = true)]
+    connect_wait: u64,
+
+    /// Seconds between peer connection health checks
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_HEALTH_CHECK_INTERVAL.as_secs(), value_parser = clap::value_parser!(u64).range(1..), global = true)]
+    health_check_interval: u64,
+
+    /// Seconds without a heartbeat reply before the peer connection is dropped (0 = never)
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_HEARTBEAT_TIMEOUT.as_secs(), global = true)]
+    heartbeat_timeout: u64,
+
+    /// Seconds between QUIC keep-alives on the peer connection (0 = off)
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_QUIC_KEEPALIVE.as_secs(), global = true)]
+    keepalive_interval: u64,
+
+    /// Seconds without QUIC traffic before the peer connection is dropped
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_IDLE_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..), global = true)]
+    idle_timeout: u64,
+
+    /// Max SOCKS requests waiting for a reconnection at once (0 = unlimited)
+    #[arg(long, default_value_t = DEFAULT_MAX_QUEUED, global = true)]
+    max_queued: usize,
+
+    /// Seconds in-flight requests may finish after Ctrl-C before they are closed
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_DRAIN_TIMEOUT.as_secs(), global = true)]
+    drain_timeout: u64,
+
+    /// Max simultaneous local client connections, all listeners together (0 = unlimited)
+    #[arg(long, default_value_t = DEFAULT_MAX_CONNECTIONS, global = true)]
+    max_connections: usize,
+
+    /// Max concurrent tunnel requests per peer connection (0 = unlimited)
+    #[arg(long, default_value_t = DEFAULT_MAX_STREAMS, global = true)]
+    max_streams: usize,
+
+    /// Un-read bytes a peer may send per stream before it waits, e.g. 256K (0 = iroh default)
+    #[arg(long, value_name = "BYTES", default_value = "0", value_parser = parse_bytes, global = true)]
+    stream_window: u64,
+
+    /// Only proxy to destinations matching this rule (domain, *.domain or CIDR; repeatable)
+    #[arg(long = "allow-dest", value_name = "RULE", global = true)]
+    allow_dests: Vec<HostRule>,
+
+    /// Never proxy to destinations matching this rule (checked before --allow-dest; repeatable)
+    #[arg(long = "deny-dest", value_name = "RULE", global = true)]
+    deny_dests: Vec<HostRule>,
+
+    /// File with `allow <rule>` / `deny <rule>` lines, added to the CLI rules
+    #[arg(long, value_name = "FILE", global = true)]
+    dest_rules: Option<String>,
+
+    /// Refuse destinations resolving to loopback, private, link-local or unique-local addresses
+    #[arg(long, global = true)]
+    block_private: bool,
+
+    /// Dial destinations through this proxy (socks5://host:port or http://host:port)
+    #[arg(long, value_name = "URL", global = true)]
+    upstream_proxy: Option<UpstreamProxy>,
+
+    /// Retries of a destination lookup or dial that failed transiently (0 = fail at once)
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_CONNECT_RETRIES, global = true)]
+    connect_retries: u32,
+
+    /// Connect to destinations from this local address, one per IP family (repeatable)
+    #[arg(long = "egress-bind", value_name = "IP", global = true)]
+    egress_bind: Vec<std::net::IpAddr>,
+
+    /// Cache exit-side DNS lookups for this many seconds (0 = no cache)
+    #[arg(long, value_name = "SECS", default_value = "0", global = true)]
+    dns_cache_ttl: u64,
+
+    /// Most hosts kept in the exit-side DNS cache (least recently used are evicted)
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_DNS_CACHE_SIZE, global = true)]
+    dns_cache_size: usize,
+
+    /// Accept iptables-REDIRECTed connections on this address (Linux only)
+    #[arg(long, value_name = "ADDR", global = true)]
+    transparent: Option<std::net::SocketAddr>,
+
+    /// Tunnel a local port to a fixed destination, without SOCKS (repeatable)
+    #[arg(long = "forward", value_name = "[ADDR:]PORT:HOST:PORT", global = true)]
+    forwards: Vec<PortForward>,
+
+    /// Serve a read-only JSON status document on this unix socket
+    #[arg(long, value_name = "PATH", global = true)]
+    status_socket: Option<std::path::PathBuf>,
+
+    /// Take line-based commands (status, peers, reconnect, ...) on this unix socket
+    #[arg(long, value_name = "PATH", global = true)]
+    control_socket: Option<std::path::PathBuf>,
+
+    /// Send destinations matching PATTERN through another peer (repeatable)
+    #[arg(long = "route", value_name = "PATTERN=NODE_ID", global = true)]
+    routes: Vec<Route>,
+
+    /// Keep traffic on the iroh relay by binding loopback sockets only
+    ///
+    /// Remote peers can't reach a loopback address, so no direct path to them
+    /// forms; a peer on this same machine may still connect directly.
+    #[arg(long, global = true, conflicts_with = "no_relay")]
+    relay_only: bool,
+
+    /// Disable the iroh relay; peers must be reachable directly
+    #[arg(long, global = true)]
+    no_relay: bool,
+
+    /// Wait for hole-punching to give a direct path before serving (connect only)
+    #[arg(long, global = true, conflicts_with = "relay_only")]
+    prefer_direct: bool,
+
+    /// How long --prefer-direct waits before serving over the relay
+    #[arg(long, value_name = "SECS", default_value = "5", global = true)]
+    direct_wait: u64,
+
+    /// Use this iroh relay server instead of the default ones
+    #[arg(long, value_name = "URL", value_parser = parse_relay_url, global = true, conflicts_with = "no_relay")]
+    relay_url: Option<iroh::RelayUrl>,
+
+    /// Move a corrupt .tunnel_key to .tunnel_key.corrupt and generate a new identity
+    #[arg(long, global = true)]
+    regenerate_corrupt_key: bool,
+
+    /// Leave Nagle's algorithm on (TCP_NODELAY is set by default)
+    #[arg(long, global = true)]
+    no_nodelay: bool,
+
+    /// Seconds a relayed TCP connection is idle before keepalive probes (0 = off)
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_KEEPALIVE_IDLE.as_secs(), global = true)]
+    tcp_keepalive: u64,
+
+    /// Seconds between TCP keepalive probes
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_KEEPALIVE_INTERVAL.as_secs(), global = true)]
+    tcp_keepalive_interval: u64,
+
+    /// DSCP class for relayed TCP traffic, 0-63 or a name like EF, AF41, CS1
+    #[arg(long, value_name = "VALUE", value_parser = parse_dscp, global = true)]
+    dscp: Option<u8>,
+
+    /// Milliseconds to hold small socket reads so they share one tunnel message (0 = off)
+    #[arg(long, value_name = "MS", default_value_t = 0, global = true)]
+    coalesce_ms: u64,
+
+    /// Largest socket read relayed as one tunnel message, e.g. 64K (1K to 1M)
+    #[arg(long, value_name = "BYTES", default_value = "8K", value_parser = parse_chunk_size, global = true)]
+    relay_chunk_size: usize,
+
+    /// Close relayed connections this many seconds after they opened, even if active (0 = no limit)
+    #[arg(long, value_name = "SECS", default_value_t = 0, global = true)]
+    max_connection_lifetime: u64,
+
+    /// Report each stream's byte counts to the peer when it closes (if the peer does too)
+    #[arg(long, global = true)]
+    send_stats: bool,
+
+    /// Log a hex dump and the TLS/HTTP parse results of each stream's first packets
+    #[arg(long, global = true, conflicts_with = "privacy")]
+    debug_parse: bool,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+enum Command {
+    /// Run as a server: persistent identity, print ticket, wait for peers
+    Serve,
 
-    println!("🚇 Starting Iroh Tunnel...");
+    /// Connect to a peer with an ephemeral identity
+    Connect {
+        /// Peer ticket(s); repeat or comma-separate for failover, first is primary
+        #[arg(required_unless_present = "peer_file", value_delimiter = ',')]
+        tickets: Vec<String>,
 
-    // Determine if we should persist the secret key:
-    // - Server mode (no -c flag): persist key for stable Node ID
-    // - Client mode (with -c flag): ephemeral key for random Node ID each run
-    let persist_key = args.peer.is_none();
+        /// Read the peer ticket(s) from this file instead
+        #[arg(long, value_name = "PATH", conflicts_with = "tickets")]
+        peer_file: Option<std::path::PathBuf>,
+    },
 
-    let secret_key = get_or_create_secret_key(persist_key).await?;
+    /// Generate a persistent identity in .tunnel_key and print its Node ID
+    GenKey {
+        /// Overwrite an existing .tunnel_key
+        #[arg(long)]
+        force: bool,
+    },
 
-    // Setup Iroh Endpoint
-    let endpoint = Endpoint::builder()
-        .secret_key(secret_key)
-        .alpns(vec![TUNNEL_ALPN.to_vec()])
-        .bind()
-        .await
-        .context("Failed to bind Iroh endpoint")?;
+    /// Delete .tunnel_key and .tunnel_peer so the next run gets a new identity
+    Forget,
 
-    println!("📡 Node ID: {}", endpoint.id());
-    println!("🔗 Endpoints: (waiting for discovery...)");
-    println!();
+    /// List the persisted identities (profiles) in the current directory
+    Profiles,
+}
 
-    // Determine which peer to connect to based on command line args
-    let peer_to_connect = if let Some(peer_ticket) = &args.peer {
-        // Client mode: explicit peer provided via -c flag
-        // Clear any old persisted peer to ensure fresh connection
-        clear_remote_peer_id().await.ok();
+impl Args {
+    /// Resolve the subcommand, mapping the legacy `-c` / no-subcommand forms
+    fn command(&self) -> Command {
+        match &self.command {
+            Some(command) => command.clone(),
+            None if !self.peer.is_empty() => {
+                // Not deprecated as the `peers` key of a config file
+                if self.config.is_none() {
+                    eprintln!(
+                        "⚠️  `-c/--peer` is deprecated, use `tunnel connect <TICKET>` instead"
+                    );
+                }
+                Command::Connect {
+                    tickets: self.peer.clone(),
+                    peer_file: None,
+                }
+            }
+            None => Command::Serve,
+        }
+    }
+}
 
-        let peer_id: iroh::PublicKey =
-            peer_ticket.parse().context("Failed to parse peer ticket")?;
+/// Byte count with an optional K/M/G/T suffix (powers of 1024)
+fn parse_bytes(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, shift) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 30),
+        Some((i, 'T' | 't')) => (&value[..i], 40),
+        _ => (value, 0),
+    };
+    let number: u64 = digits
+        .parse()
+        .map_err(|_| format!("expected a byte count like 500M or 10G, got {:?}", value))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("{} is too large", value))
+}
 
-        Some(peer_id)
-    } else {
-        // Server mode: don't load persisted peer, just wait for incoming connections
-        // Clear any stale .tunnel_peer file to avoid confusion
-        clear_remote_peer_id().await.ok();
-        None
+/// `--relay-chunk-size`: a byte count within MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE
+fn parse_chunk_size(value: &str) -> Result<usize, String> {
+    let size = parse_bytes(value)?;
+    if !(MIN_CHUNK_SIZE as u64..=MAX_CHUNK_SIZE as u64).contains(&size) {
+        return Err(format!(
+            "chunk size must be between {} and {} bytes, got {}",
+            MIN_CHUNK_SIZE, MAX_CHUNK_SIZE, size
+        ));
+    }
+    Ok(size as usize)
+}
+
+/// The library configuration the parsed flags stand for, validated
+async fn tunnel_config(
+    args: &Args,
+    profile: Profile,
+    peers: Vec<iroh::EndpointAddr>,
+) -> Result<TunnelConfig> {
+    let mut host_filter = HostFilter::new(args.allow_dests.clone(), args.deny_dests.clone())
+        .block_private(args.block_private);
+    if let Some(path) = &args.dest_rules {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read destination rules: {}", path))?;
+        host_filter.extend(
+            HostFilter::parse(&contents)
+                .with_context(|| format!("Invalid destination rules in {}", path))?,
+        );
+    }
+
+    let mut config = TunnelConfig::default()
+        .socks_port(args.port)
+        .profile(profile)
+        .peers(peers)
+        .max_reconnect_attempts(args.max_reconnect_attempts)
+        .allow_peers(args.allow_peers.clone())
+        .exclusive(args.exclusive)
+        .sticky_peer(args.sticky_peer)
+        .local_discovery(args.local_discovery)
+        .host_filter(host_filter)
+        .connect_retries(args.connect_retries)
+        .egress_bind(EgressBind::new(&args.egress_bind)?)
+        .dns_cache_ttl(Duration::from_secs(args.dns_cache_ttl))
+        .dns_cache_size(args.dns_cache_size)
+        .max_streams(args.max_streams)
+        .stream_window(args.stream_window)
+        .max_connections(args.max_connections)
+        .reconnect_wait(Duration::from_secs(args.connect_wait))
+        .health_check_interval(Duration::from_secs(args.health_check_interval))
+        .heartbeat_timeout(Duration::from_secs(args.heartbeat_timeout))
+        .keepalive_interval(Duration::from_secs(args.keepalive_interval))
+        .idle_timeout(Duration::from_secs(args.idle_timeout))
+        .max_queued(args.max_queued)
+        .drain_timeout(Duration::from_secs(args.drain_timeout))
+        .peer_quota(args.peer_quota, Duration::from_secs(args.quota_window))
+        .privacy(args.privacy)
+        .show_qr(args.qr)
+        .regenerate_corrupt_key(args.regenerate_corrupt_key)
+        .routes(RouteTable::new(args.routes.clone()))
+        .path_mode(if args.relay_only {
+            PathMode::RelayOnly
+        } else if args.no_relay {
+            PathMode::DirectOnly
+        } else {
+            PathMode::Auto
+        })
+        .tcp_options(TcpOptions {
+            nodelay: !args.no_nodelay,
+            keepalive_idle: (args.tcp_keepalive > 0)
+                .then(|| Duration::from_secs(args.tcp_keepalive)),
+            keepalive_interval: Duration::from_secs(args.tcp_keepalive_interval),
+            dscp: args.dscp,
+        })
+        .relay_options(RelayOptions {
+            coalesce: Duration::from_millis(args.coalesce_ms),
+            chunk_size: args.relay_chunk_size,
+            send_stats: args.send_stats,
+            debug_parse: args.debug_parse,
+            max_lifetime: Duration::from_secs(args.max_connection_lifetime),
+            ..Default::default()
+        });
+    for forward in &args.forwards {
+        config = config.forward(forward.clone());
+    }
+    if let Some(path) = &args.log_file {
+        config = config.log_file(path.clone());
+    }
+    if let Some(path) = &args.access_log {
+        config = config.access_log(path.clone());
+    }
+    if let Some(port) = args.metrics_port {
+        config = config.metrics_port(port);
+    }
+    if let Some(port) = args.pac_port {
+        config = config.pac(PacOptions {
+            port,
+            direct_private: args.pac_direct_private,
+            bypass: args.pac_bypass.clone(),
+        });
+    }
+    if let Some(proxy) = &args.upstream_proxy {
+        config = config.upstream_proxy(proxy.clone());
+    }
+    if let Some(addr) = args.transparent {
+        config = config.transparent_addr(addr);
+    }
+    if let Some(path) = &args.status_socket {
+        config = config.status_socket(path.clone());
+    }
+    if let Some(path) = &args.control_socket {
+        config = config.control_socket(path.clone());
+    }
+    if let Some(path) = &args.socks_unix {
+        config = config.socks_unix(path.clone());
+    }
+    if let (Some(cert), Some(key)) = (&args.socks_tls_cert, &args.socks_tls_key) {
+        config = config.socks_tls(SocksTls {
+            cert: cert.clone(),
+            key: key.clone(),
+        });
+    }
+    if let Some(token) = args.auth_token.as_ref().filter(|token| !token.is_empty()) {
+        config = config.auth_token(AuthToken::new(token.clone()));
+    }
+    if let Some(url) = &args.relay_url {
+        println!("🛰️  Using custom relay {}", url);
+        config = config.relay_url(url.clone());
+    }
+    if args.prefer_direct {
+        config = config.prefer_direct(Duration::from_secs(args.direct_wait));
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+/// Parse the command line, with the flags from `--config` ahead of it
+async fn parse_args() -> Result<Args> {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let args = try_parse_args(&argv).unwrap_or_else(|e| e.exit());
+    let Some(path) = &args.config else {
+        return Ok(args);
     };
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+    let argv = with_config_file(&argv, &contents)
+        .with_context(|| format!("Invalid config file {}", path.display()))?;
+    Ok(try_parse_args(&argv).unwrap_or_else(|e| e.exit()))
+}
+
+/// The switch that turns `--<long>` off: `no-<long>`, or `<flag>` for `no-<flag>`
+fn negation(long: &str) -> String {
+    match long.strip_prefix("no-") {
+        Some(positive) => positive.to_string(),
+        None => format!("no-{}", long),
+    }
+}
+
+/// `Args::try_parse_from`, with the negations from `negatable_command`
+fn try_parse_args(argv: &[OsString]) -> Result<Args, clap::Error> {
+    let matches = negatable_command().try_get_matches_from(argv)?;
+    Args::from_arg_matches(&matches)
+}
 
-    let state = Arc::new(Mutex::new(TunnelState {
-        peer_connection: None,
-        connection_state: ConnectionState::Disconnected,
-        remote_peer_id: peer_to_connect,
-        reconnect_attempts: 0,
-        last_connection_attempt: None,
-        _log_file: args.log_file.clone(),
-    }));
-
-    // If we have a peer to connect to (either from -c flag or persisted), connect to it (client mode)
-    if let Some(peer_id) = peer_to_connect {
-        // Persist to disk (only if explicitly provided via -c flag)
-        if args.peer.is_some() {
-            save_remote_peer_id(peer_id).await.ok();
+/// The command line with a hidden negation for every switch
+///
+/// `--no-<flag>` turns `--<flag>` back off (`--nodelay` for `--no-nodelay`),
+/// so a switch set in the config file can be undone on the command line.
+/// Whichever of the two comes last wins.
+fn negatable_command() -> clap::Command {
+    let mut command = <Args as CommandFactory>::command();
+    let switches: Vec<(String, String)> = command
+        .get_arguments()
+        .filter(|arg| matches!(arg.get_action(), ArgAction::SetTrue))
+        .filter_map(|arg| Some((arg.get_id().to_string(), arg.get_long()?.to_string())))
+        .collect();
+    for (id, long) in switches {
+        let negation = negation(&long);
+        if command
+            .get_arguments()
+            .any(|arg| arg.get_long() == Some(negation.as_str()))
+        {
+            continue;
         }
+        let negation_id = format!("negate_{}", id);
+        command = command
+            .mut_arg(&id, |arg| arg.overrides_with(&negation_id))
+            .arg(
+                Arg::new(negation_id)
+                    .long(negation)
+                    .action(ArgAction::SetTrue)
+                    .global(true)
+                    .hide(true)
+                    .overrides_with(id),
+            );
+    }
+    command
+}
+
+/// `argv` with the flags of a config file inserted after the program name,
+/// so the ones given on the command line override them
+///
+/// A switch the command line negates is left out, as the negation may come
+/// after the subcommand, where it can't override a flag given before it.
+fn with_config_file(argv: &[OsString], contents: &str) -> Result<Vec<OsString>> {
+    let given: Vec<&str> = argv[1..]
+        .iter()
+        .filter_map(|arg| arg.to_str()?.strip_prefix("--"))
+        .collect();
+    let mut merged = argv[..1].to_vec();
+    merged.extend(
+        config_file_args(contents, config_flag)?
+            .into_iter()
+            .filter(|flag| {
+                let switch = flag.strip_prefix("--").filter(|name| !name.contains('='));
+                !switch.is_some_and(|name| given.contains(&negation(name).as_str()))
+            })
+            .map(OsString::from),
+    );
+    merged.extend_from_slice(&argv[1..]);
+    Ok(merged)
+}
 
-        println!("🔌 Connecting to peer...");
-        match endpoint.connect(peer_id, TUNNEL_ALPN).await {
-            Ok(conn) => {
-                println!("✅ Connected to peer: {}", conn.remote_id());
+/// The long flag a config file key sets
+///
+/// `peers` stands for the hidden `--peer` list, so a file can hold the
+/// tickets a run without a subcommand connects to. Visible aliases such as
+/// `reconnect-wait` work too.
+fn config_flag(key: &str) -> Option<String> {
+    match key {
+        "peers" => return Some("peer".to_string()),
+        "config" | "peer" => return None,
+        _ => {}
+    }
+    <Args as CommandFactory>::command()
+        .get_arguments()
+        .find(|arg| {
+            arg.get_long_and_visible_aliases()
+                .is_some_and(|names| names.contains(&key))
+        })
+        .and_then(|arg| arg.get_long().map(str::to_string))
+}
 
-                // Update state
-                {
-                    let mut state_guard = state.lock().await;
-                    state_guard.peer_connection = Some(conn.clone());
-                    state_guard.connection_state = ConnectionState::Connected;
-                }
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = parse_args().await?;
 
-                // Spawn handler with monitoring
-                let endpoint_clone = endpoint.clone();
-                let state_clone = Arc::clone(&state);
-                tokio::spawn(async move {
-                    handle_peer_connection_with_monitoring(conn, endpoint_clone, state_clone).await;
-                });
+    // Determine mode:
+    // - serve: persistent key for a stable Node ID, wait for peers
+    // - connect: ephemeral key, connect to the given peers
+    let profile = match &args.profile {
+        Some(name) => Profile::named(name)?,
+        None => Profile::default(),
+    };
+    let peer_tickets = match args.command() {
+        Command::GenKey { force } => {
+            let secret_key = create_secret_key(&profile, force, &StdoutLogger).await?;
+            println!("📡 Node ID: {}", secret_key.public());
+            return Ok(());
+        }
+        Command::Forget => {
+            let removed = forget(std::path::Path::new(""), &profile).await?;
+            if removed.is_empty() {
+                println!("💡 No persisted identity or peer to remove");
             }
-            Err(e) => {
-                eprintln!("❌ Failed to connect to peer: {}", e);
-                eprintln!("💡 Will keep retrying in background...");
-                let mut state_guard = state.lock().await;
-                state_guard.connection_state = ConnectionState::Failed;
+            for path in removed {
+                println!("🗑️  Removed {}", path.display());
             }
+            return Ok(());
         }
+        Command::Profiles => {
+            let profiles = list_profiles(std::path::Path::new("")).await?;
+            if profiles.is_empty() {
+                println!("💡 No persisted identities (run `tunnel gen-key` or `tunnel serve`)");
+            }
+            for profile in profiles {
+                let name = profile.name().unwrap_or("(default)");
+                match read_secret_key(&profile).await {
+                    Ok(Some(key)) => println!("🗂️  {:<12} {}", name, key.public()),
+                    Ok(None) => {}
+                    Err(e) => println!("🗂️  {:<12} ⚠️  {}", name, e),
+                }
+            }
+            return Ok(());
+        }
+        Command::Serve => Vec::new(),
+        Command::Connect {
+            peer_file: Some(path),
+            ..
+        } => read_peer_file(&path).await?,
+        Command::Connect { tickets, .. } => tickets,
+    };
+
+    let peers = peer_tickets
+        .iter()
+        .map(|ticket| parse_peer(ticket))
+        .collect::<Result<Vec<_>>>()?;
+
+    let config = tunnel_config(&args, profile, peers).await?;
+
+    if args.check {
+        println!("🔍 Checking configuration...");
+        for line in check_config(&config).await? {
+            println!("   {}", line);
+        }
+        println!("✅ Configuration OK");
+        return Ok(());
+    }
+
+    let handle = if config.peers.is_empty() {
+        run_server(config).await?
     } else {
-        println!("📋 Connection ticket (share this with peer):");
-        println!("   {}", generate_ticket(&endpoint).await?);
-        println!();
-        println!("💡 Waiting for peer to connect...");
+        run_client(config).await?
+    };
+
+    // SIGHUP resets a reconnection loop that gave up
+    #[cfg(unix)]
+    {
+        let state = handle.state();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{SignalKind, signal};
+            let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+                return;
+            };
+            while hangup.recv().await.is_some() {
+                println!("🔁 SIGHUP received, resetting reconnection attempts");
+                state.lock().await.reset_reconnect();
+            }
+        });
     }
 
-    // Start connection health monitor for BOTH client and server modes
+    // SIGUSR1 dumps connection state and counters to stderr
+    #[cfg(unix)]
     {
-        let state_clone = Arc::clone(&state);
-        let endpoint_clone = endpoint.clone();
+        let state = handle.state();
         tokio::spawn(async move {
-            monitor_connection_health(state_clone, endpoint_clone).await;
+            use tokio::signal::unix::{SignalKind, signal};
+            let Ok(mut usr1) = signal(SignalKind::user_defined1()) else {
+                return;
+            };
+            while usr1.recv().await.is_some() {
+                eprintln!("{}", stats_dump(&*state.lock().await));
+            }
         });
     }
 
-    // Start SOCKS5 proxy server
-    let socks_addr = format!("127.0.0.1:{}", args.port);
-    let listener = TcpListener::bind(&socks_addr)
-        .await
-        .context("Failed to bind SOCKS5 server")?;
+    // Ctrl-C triggers a graceful shutdown, a second one skips the drain
+    {
+        let draining = handle.shutdown_signal();
+        let shutdown = handle.state().lock().await.shutdown.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                println!("\n🛑 Shutting down (Ctrl-C again to force)...");
+                draining.trigger();
+            }
+            if tokio::signal::ctrl_c().await.is_ok() {
+                println!("\n🛑 Forcing shutdown...");
+                shutdown.trigger();
+            }
+        });
+    }
 
-    println!("🌐 SOCKS5 proxy listening on {}", socks_addr);
-    println!(
-        "📝 Configure your browser/app to use SOCKS5 proxy: localhost:{}",
-        args.port
-    );
-    println!();
-
-    let state_clone = state.clone();
-    let endpoint_clone = endpoint.clone();
-
-    // Accept incoming Iroh connections
-    tokio::spawn(async move {
-        while let Some(incoming) = endpoint_clone.accept().await {
-            let state_clone_inner = state_clone.clone();
-            let endpoint_clone_inner = endpoint_clone.clone();
-            match incoming.accept() {
-                Ok(connecting) => {
-                    tokio::spawn(async move {
-                        match connecting.await {
-                            Ok(connection) => {
-                                let remote_id = connection.remote_id();
-                                println!("✅ Peer connected: {}", remote_id);
-
-                                // Store remote peer ID in memory only (server doesn't persist)
-                                {
-                                    let mut state_guard = state_clone_inner.lock().await;
-                                    state_guard.peer_connection = Some(connection.clone());
-                                    state_guard.remote_peer_id = Some(remote_id);
-                                    state_guard.connection_state = ConnectionState::Connected;
-                                }
-                                // Note: Server mode does NOT persist peer ID to disk
-                                // This allows accepting connections from any peer
-
-                                // Spawn handler with monitoring
-                                handle_peer_connection_with_monitoring(
-                                    connection,
-                                    endpoint_clone_inner,
-                                    state_clone_inner,
-                                )
-                                .await;
-                            }
-                            Err(e) => eprintln!("❌ Connection error: {}", e),
-                        }
-                    });
-                }
-                Err(e) => eprintln!("❌ Failed to accept connection: {}", e),
+    handle.wait().await?;
+    println!("👋 Tunnel closed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh_socks5_proxy::tunnel::state::TUNNEL_PROTOCOL;
+
+    fn parse(argv: &[&str]) -> Args {
+        Args::try_parse_from(argv).unwrap()
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("0"), Ok(0));
+        assert_eq!(parse_bytes("1500"), Ok(1500));
+        assert_eq!(parse_bytes("500M"), Ok(500 << 20));
+        assert_eq!(parse_bytes("10g"), Ok(10 << 30));
+        assert!(parse_bytes("G").is_err());
+        assert!(parse_bytes("10GB").is_err());
+        assert!(parse_bytes("99999999T").is_err());
+
+        let args = parse(&["tunnel", "--peer-quota", "2K"]);
+        assert_eq!(args.peer_quota, 2048);
+        assert_eq!(args.quota_window, DEFAULT_QUOTA_WINDOW.as_secs());
+    }
+
+    #[test]
+    fn test_no_subcommand_is_serve() {
+        assert_eq!(parse(&["tunnel"]).command(), Command::Serve);
+    }
+
+    #[test]
+    fn test_connect_subcommand_with_global_flags() {
+        let args = parse(&[
+            "tunnel",
+            "--port",
+            "1081",
+            "connect",
+            "a,b",
+            "--max-reconnect-attempts",
+            "3",
+        ]);
+        assert_eq!(args.port, 1081);
+        assert_eq!(args.max_reconnect_attempts, 3);
+        assert_eq!(
+            args.command(),
+            Command::Connect {
+                tickets: vec!["a".to_string(), "b".to_string()],
+                peer_file: None,
             }
-        }
-    });
-
-    // Accept SOCKS5 connections
-    loop {
-        match listener.accept().await {
-            Ok((socket, addr)) => {
-                let state = state.clone();
-                let endpoint = endpoint.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = handle_socks_client(socket, addr, state, endpoint).await {
-                        eprintln!("❌ SOCKS error from {}: {}", addr, e);
-                    }
-                });
+        );
+    }
+
+    #[test]
+    fn test_health_check_interval_flag() {
+        let args = Args::parse_from(["tunnel"]);
+        assert_eq!(
+            args.health_check_interval,
+            DEFAULT_HEALTH_CHECK_INTERVAL.as_secs()
+        );
+        let args = Args::parse_from(["tunnel", "--health-check-interval", "2"]);
+        assert_eq!(args.health_check_interval, 2);
+        assert!(Args::try_parse_from(["tunnel", "--health-check-interval", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_connect_wait_flag() {
+        let args = Args::parse_from(["tunnel"]);
+        assert_eq!(args.connect_wait, DEFAULT_RECONNECT_WAIT.as_secs());
+        let args = Args::parse_from(["tunnel", "--connect-wait", "60"]);
+        assert_eq!(args.connect_wait, 60);
+        // The older name sets the same wait
+        let args = Args::parse_from(["tunnel", "--reconnect-wait", "5"]);
+        assert_eq!(args.connect_wait, 5);
+        assert!(Args::try_parse_from(["tunnel", "--connect-wait", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_quic_timeout_flags() {
+        let args = Args::parse_from(["tunnel"]);
+        assert_eq!(args.keepalive_interval, DEFAULT_QUIC_KEEPALIVE.as_secs());
+        assert_eq!(args.idle_timeout, DEFAULT_IDLE_TIMEOUT.as_secs());
+        let args = Args::parse_from([
+            "tunnel",
+            "--keepalive-interval",
+            "15",
+            "--idle-timeout",
+            "120",
+        ]);
+        assert_eq!((args.keepalive_interval, args.idle_timeout), (15, 120));
+        assert!(Args::try_parse_from(["tunnel", "--idle-timeout", "0"]).is_err());
+    }
+
+    fn parse_with_config(argv: &[&str], contents: &str) -> Result<Args> {
+        let argv: Vec<OsString> = argv.iter().map(OsString::from).collect();
+        Ok(try_parse_args(&with_config_file(&argv, contents)?)?)
+    }
+
+    #[test]
+    fn test_config_file_switches_can_be_turned_off() {
+        let contents = "send-stats = true\nprivacy = true\nno-nodelay = true";
+        let args = parse_with_config(&["tunnel"], contents).unwrap();
+        assert!(args.send_stats && args.privacy && args.no_nodelay);
+
+        let args = parse_with_config(
+            &[
+                "tunnel",
+                "--no-send-stats",
+                "serve",
+                "--no-privacy",
+                "--nodelay",
+            ],
+            contents,
+        )
+        .unwrap();
+        assert_eq!(
+            (args.send_stats, args.privacy, args.no_nodelay),
+            (false, false, false)
+        );
+
+        // Last one wins on the command line too
+        let args = parse_with_config(&["tunnel", "--no-send-stats", "--send-stats"], "").unwrap();
+        assert!(args.send_stats);
+
+        // A switch turned off no longer conflicts with the other one
+        let args = parse_with_config(
+            &["tunnel", "--no-relay-only", "--no-relay"],
+            "relay-only = true",
+        )
+        .unwrap();
+        assert!(!args.relay_only && args.no_relay);
+    }
+
+    #[test]
+    fn test_config_file_flags() {
+        let contents = r#"
+            port = 1090
+            connect-wait = 10
+            allow-dest = ["*.example.com"]
+            privacy = true
+        "#;
+        let args = parse_with_config(&["tunnel", "--config", "tunnel.toml"], contents).unwrap();
+        assert_eq!((args.port, args.connect_wait), (1090, 10));
+        assert_eq!(args.allow_dests.len(), 1);
+        assert!(args.privacy);
+
+        // The command line wins; repeatable flags add up
+        let args = parse_with_config(
+            &[
+                "tunnel",
+                "-p",
+                "2000",
+                "--allow-dest",
+                "10.0.0.0/8",
+                "serve",
+            ],
+            contents,
+        )
+        .unwrap();
+        assert_eq!((args.port, args.connect_wait), (2000, 10));
+        assert_eq!(args.allow_dests.len(), 2);
+        assert_eq!(args.command(), Command::Serve);
+
+        let args = parse_with_config(&["tunnel"], "reconnect-wait = 7").unwrap();
+        assert_eq!(args.connect_wait, 7);
+    }
+
+    #[test]
+    fn test_config_file_validation() {
+        // Values go through the same checks as flags
+        assert!(parse_with_config(&["tunnel"], "port = 70000").is_err());
+        assert!(parse_with_config(&["tunnel"], "port = \"socks\"").is_err());
+        assert!(parse_with_config(&["tunnel"], "relay-only = true\nno-relay = true").is_err());
+        assert!(parse_with_config(&["tunnel"], "pac-direct-private = true").is_err());
+
+        let err = parse_with_config(&["tunnel"], "config = \"other.toml\"").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown option `config` on line 1");
+        assert!(parse_with_config(&["tunnel"], "peer-file = \"peers\"").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_config_file_round_trip() {
+        let peer = iroh::SecretKey::generate(&mut rand::rng()).public();
+        let contents = format!(
+            r#"
+            peers = ["{peer}"]
+            port = 1090
+            metrics-port = 9100
+            log-file = "requests.log"
+            privacy = true
+            deny-dest = ["*.ads.example"]
+            block-private = true
+            max-streams = 64
+            stream-window = "256K"
+            connect-wait = 10
+            idle-timeout = 60
+            "#
+        );
+        let config_from = |argv: &'static [&'static str]| {
+            let contents = contents.clone();
+            async move {
+                let args = parse_with_config(argv, &contents).unwrap();
+                let Command::Connect { tickets, .. } = args.command() else {
+                    panic!("peers in the file should connect");
+                };
+                let peers = tickets.iter().map(|t| parse_peer(t).unwrap()).collect();
+                tunnel_config(&args, Profile::default(), peers)
+                    .await
+                    .unwrap()
             }
-            Err(e) => eprintln!("❌ Failed to accept SOCKS connection: {}", e),
-        }
+        };
+
+        let config = config_from(&["tunnel", "--config", "tunnel.toml"]).await;
+        assert_eq!(config.peers[0].id, peer);
+        assert_eq!(config.socks_addr.port(), 1090);
+        assert_eq!(config.metrics_port, Some(9100));
+        assert_eq!(config.log_file.as_deref(), Some("requests.log"));
+        assert!(config.privacy);
+        assert!(!config.host_filter.is_allowed("x.ads.example"));
+        assert!(config.host_filter.blocks_private());
+        assert_eq!((config.max_streams, config.stream_window), (64, 256 << 10));
+        assert_eq!(config.reconnect_wait, Duration::from_secs(10));
+        assert_eq!(config.idle_timeout, Duration::from_secs(60));
+
+        // Flags on the command line take precedence over the file
+        let config = config_from(&["tunnel", "-p", "1091", "--connect-wait", "0"]).await;
+        assert_eq!(config.socks_addr.port(), 1091);
+        assert_eq!(config.reconnect_wait, Duration::ZERO);
+        assert_eq!(config.metrics_port, Some(9100));
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_config_is_validated() {
+        let config = |argv: &[&str]| {
+            let args = parse(argv);
+            async move { tunnel_config(&args, Profile::default(), Vec::new()).await }
+        };
+        let ok = config(&["tunnel", "--metrics-port", "9100"]).await.unwrap();
+        assert_eq!(ok.metrics_port, Some(9100));
+
+        let timeouts = [
+            "tunnel",
+            "--keepalive-interval",
+            "60",
+            "--idle-timeout",
+            "30",
+        ];
+        assert!(config(&timeouts).await.is_err());
+        let err = config(&["tunnel", "--metrics-port", "1080"])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("port 1080"), "{}", err);
+    }
+
+    #[test]
+    fn test_long_version_names_protocol() {
+        let command = <Args as CommandFactory>::command();
+        let version = command.render_long_version();
+        assert!(version.contains(TUNNEL_PROTOCOL), "{}", version);
+        assert!(version.contains("rustc"), "{}", version);
+        assert!(!command.render_version().trim_end().contains('\n'));
+    }
+
+    #[test]
+    fn test_debug_parse_flag() {
+        assert!(!parse(&["tunnel"]).debug_parse);
+        assert!(parse(&["tunnel", "connect", "ticket", "--debug-parse"]).debug_parse);
+        assert!(Args::try_parse_from(["tunnel", "--debug-parse", "--privacy"]).is_err());
+    }
+
+    #[test]
+    fn test_max_connection_lifetime_flag() {
+        assert_eq!(parse(&["tunnel"]).max_connection_lifetime, 0);
+        let args = parse(&["tunnel", "--max-connection-lifetime", "3600"]);
+        assert_eq!(args.max_connection_lifetime, 3600);
+        assert!(Args::try_parse_from(["tunnel", "--max-connection-lifetime", "1h"]).is_err());
+    }
+
+    #[test]
+    fn test_relay_chunk_size_flag() {
+        let args = Args::parse_from(["tunnel"]);
+        assert_eq!(args.relay_chunk_size, 8192);
+        let args = Args::parse_from(["tunnel", "--relay-chunk-size", "64K"]);
+        assert_eq!(args.relay_chunk_size, 64 * 1024);
+        assert!(Args::try_parse_from(["tunnel", "--relay-chunk-size", "512"]).is_err());
+        assert!(Args::try_parse_from(["tunnel", "--relay-chunk-size", "2M"]).is_err());
+    }
+
+    #[test]
+    fn test_profile_flag() {
+        let args = Args::parse_from(["tunnel", "forget", "--profile", "work"]);
+        assert_eq!(args.command(), Command::Forget);
+        assert_eq!(args.profile.as_deref(), Some("work"));
+        assert_eq!(
+            Args::parse_from(["tunnel", "profiles"]).command(),
+            Command::Profiles
+        );
+        assert!(Args::try_parse_from(["tunnel", "--profile", "../work"]).is_err());
+    }
+
+    #[test]
+    fn test_forget_subcommand() {
+        let args = Args::parse_from(["tunnel", "forget"]);
+        assert_eq!(args.command(), Command::Forget);
+    }
+
+    #[test]
+    fn test_legacy_peer_flag_maps_to_connect() {
+        assert_eq!(
+            parse(&["tunnel", "-c", "a", "-c", "b"]).command(),
+            Command::Connect {
+                tickets: vec!["a".to_string(), "b".to_string()],
+                peer_file: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_dest_rule_flags() {
+        let args = parse(&[
+            "tunnel",
+            "serve",
+            "--allow-dest",
+            "*.example.com",
+            "--deny-dest",
+            "10.0.0.0/8",
+        ]);
+        assert_eq!(args.allow_dests, vec!["*.example.com".parse().unwrap()]);
+        assert_eq!(args.deny_dests, vec!["10.0.0.0/8".parse().unwrap()]);
+        assert!(Args::try_parse_from(["tunnel", "--deny-dest", "10.0.0.0/40"]).is_err());
+    }
+
+    #[test]
+    fn test_route_flags() {
+        let peer = iroh::SecretKey::generate(&mut rand::rng()).public();
+        let route = format!("*.corp.example.com={}", peer);
+        let args = parse(&["tunnel", "connect", "ticket", "--route", &route]);
+        assert_eq!(args.routes.len(), 1);
+        assert_eq!(args.routes[0].peer, peer);
+        assert!(Args::try_parse_from(["tunnel", "--route", "*.corp.example.com"]).is_err());
+    }
+
+    #[test]
+    fn test_forward_flags() {
+        let args = parse(&[
+            "tunnel",
+            "connect",
+            "ticket",
+            "--forward",
+            "127.0.0.1:5432:db.internal:5432",
+            "--forward",
+            "6379:cache.lan:6379",
+        ]);
+        assert_eq!(args.forwards.len(), 2);
+        assert_eq!(args.forwards[1].host, "cache.lan");
+        assert!(Args::try_parse_from(["tunnel", "--forward", "db.internal:5432"]).is_err());
+    }
+
+    #[test]
+    fn test_dscp_flag() {
+        assert_eq!(parse(&["tunnel", "--dscp", "46"]).dscp, Some(46));
+        assert_eq!(parse(&["tunnel", "--dscp", "af41"]).dscp, Some(34));
+        assert_eq!(parse(&["tunnel"]).dscp, None);
+        assert!(Args::try_parse_from(["tunnel", "--dscp", "64"]).is_err());
+    }
+
+    #[test]
+    fn test_socks_tls_flags() {
+        let args = parse(&[
+            "tunnel",
+            "--socks-tls-cert",
+            "cert.pem",
+            "--socks-tls-key",
+            "key.pem",
+        ]);
+        assert_eq!(args.socks_tls_cert.unwrap().to_str(), Some("cert.pem"));
+        assert_eq!(args.socks_tls_key.unwrap().to_str(), Some("key.pem"));
+        // One without the other is a mistake
+        assert!(Args::try_parse_from(["tunnel", "--socks-tls-cert", "cert.pem"]).is_err());
+        assert!(Args::try_parse_from(["tunnel", "--socks-tls-key", "key.pem"]).is_err());
+    }
+
+    #[test]
+    fn test_egress_bind_flag() {
+        let args = parse(&[
+            "tunnel",
+            "--egress-bind",
+            "192.0.2.10",
+            "--egress-bind",
+            "2001:db8::10",
+        ]);
+        assert_eq!(args.egress_bind.len(), 2);
+        assert!(EgressBind::new(&args.egress_bind).is_ok());
+        assert!(parse(&["tunnel"]).egress_bind.is_empty());
+        assert!(Args::try_parse_from(["tunnel", "--egress-bind", "eth0"]).is_err());
+    }
+
+    #[test]
+    fn test_pac_flags() {
+        let args = parse(&[
+            "tunnel",
+            "--pac-port",
+            "8088",
+            "--pac-direct-private",
+            "--pac-bypass",
+            "*.Corp.example",
+        ]);
+        assert_eq!(args.pac_port, Some(8088));
+        assert!(args.pac_direct_private);
+        assert_eq!(args.pac_bypass, vec!["*.corp.example".to_string()]);
+        assert!(Args::try_parse_from(["tunnel", "--pac-direct-private"]).is_err());
+        assert!(
+            Args::try_parse_from(["tunnel", "--pac-port", "1", "--pac-bypass", "a\"b"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_relay_flags_conflict() {
+        assert!(parse(&["tunnel", "--relay-only"]).relay_only);
+        assert!(parse(&["tunnel", "connect", "t", "--no-relay"]).no_relay);
+        assert!(Args::try_parse_from(["tunnel", "--relay-only", "--no-relay"]).is_err());
+        assert!(Args::try_parse_from(["tunnel", "--relay-only", "--prefer-direct"]).is_err());
+    }
+
+    #[test]
+    fn test_prefer_direct_flags() {
+        let args = parse(&["tunnel", "connect", "t", "--prefer-direct"]);
+        assert!(args.prefer_direct);
+        assert_eq!(args.direct_wait, 5);
+        let args = parse(&[
+            "tunnel",
+            "connect",
+            "t",
+            "--prefer-direct",
+            "--direct-wait",
+            "2",
+        ]);
+        assert_eq!(args.direct_wait, 2);
+    }
+
+    #[test]
+    fn test_relay_url_flag() {
+        let args = parse(&["tunnel", "--relay-url", "https://relay.example.com"]);
+        assert_eq!(
+            args.relay_url.unwrap().host_str(),
+            Some("relay.example.com.")
+        );
+        assert!(Args::try_parse_from(["tunnel", "--relay-url", "not a url"]).is_err());
+    }
+
+    #[test]
+    fn test_connect_requires_ticket() {
+        assert!(Args::try_parse_from(["tunnel", "connect"]).is_err());
+    }
+
+    #[test]
+    fn test_peer_file_flag() {
+        assert_eq!(
+            parse(&["tunnel", "connect", "--peer-file", "peer.txt"]).command(),
+            Command::Connect {
+                tickets: Vec::new(),
+                peer_file: Some("peer.txt".into()),
+            }
+        );
+        let err = Args::try_parse_from(["tunnel", "connect", "a", "--peer-file", "peer.txt"])
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
     }
 }
@@ -0,0 +1,3 @@
+// Metrics export
+pub mod prometheus;
+pub mod status;
@@ -0,0 +1,243 @@
+// Prometheus text exposition endpoint
+use anyhow::Result;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use crate::connection::logger::path_type_label;
+use crate::http::request::{REQUEST_READ_TIMEOUT, read_request_path};
+use crate::tunnel::state::{ConnectionState, TunnelState};
+
+const CONNECTION_STATES: [ConnectionState; 4] = [
+    ConnectionState::Disconnected,
+    ConnectionState::Connecting,
+    ConnectionState::Connected,
+    ConnectionState::Failed,
+];
+
+const PATH_TYPES: [&str; 4] = ["direct", "relay", "mixed", "none"];
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Render the tunnel state in Prometheus text exposition format
+pub fn render_metrics(state: &TunnelState) -> String {
+    let stats = state.stats_snapshot();
+    let path_type = path_type_label(&state.conn_type);
+    let mut out = String::new();
+
+    write_metric(
+        &mut out,
+        "iroh_tunnel_connections_total",
+        "counter",
+        "Peer connections established since start",
+        state.total_connections,
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_active_requests",
+        "gauge",
+        "Tunnel requests currently being relayed",
+        stats.active_requests,
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_peak_active_requests",
+        "gauge",
+        "Most tunnel requests relayed at once on the current peer connection",
+        stats.peak_active_requests,
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_open_streams",
+        "gauge",
+        "Tunnel streams currently open on the current peer connection",
+        stats.open_streams,
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_socks_clients",
+        "gauge",
+        "Clients currently connected to the local listeners",
+        state.active_socks_clients.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_requests_total",
+        "counter",
+        "Tunnel requests on the current peer connection",
+        stats.requests,
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_loops_rejected_total",
+        "counter",
+        "Tunnel requests refused by loop prevention on the current peer connection",
+        stats.loops_rejected,
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_bytes_sent_total",
+        "counter",
+        "Bytes sent into the tunnel on the current peer connection",
+        stats.bytes_sent,
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_bytes_received_total",
+        "counter",
+        "Bytes received from the tunnel on the current peer connection",
+        stats.bytes_received,
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_peer_reported_bytes_sent_total",
+        "counter",
+        "Bytes the peer reported sending in Stats messages on the current peer connection",
+        stats.peer_bytes_sent,
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_peer_reported_bytes_received_total",
+        "counter",
+        "Bytes the peer reported receiving in Stats messages on the current peer connection",
+        stats.peer_bytes_received,
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_rtt_microseconds",
+        "gauge",
+        "Smoothed round-trip time to the peer (0 until measured)",
+        stats.rtt.map_or(0, |rtt| rtt.as_micros() as u64),
+    );
+    write_metric(
+        &mut out,
+        "iroh_tunnel_reconnects_total",
+        "counter",
+        "Successful reconnections since start",
+        state.total_reconnects,
+    );
+
+    out.push_str("# HELP iroh_tunnel_connection_state Current peer connection state\n");
+    out.push_str("# TYPE iroh_tunnel_connection_state gauge\n");
+    for conn_state in &CONNECTION_STATES {
+        let value = u8::from(state.connection_state == *conn_state);
+        let _ = writeln!(
+            out,
+            "iroh_tunnel_connection_state{{state=\"{}\"}} {}",
+            conn_state.label(),
+            value
+        );
+    }
+
+    out.push_str("# HELP iroh_tunnel_path_type Iroh path to the peer (direct/relay/mixed)\n");
+    out.push_str("# TYPE iroh_tunnel_path_type gauge\n");
+    for label in &PATH_TYPES {
+        let value = u8::from(path_type == *label);
+        let _ = writeln!(out, "iroh_tunnel_path_type{{type=\"{}\"}} {}", label, value);
+    }
+
+    out
+}
+
+/// Serve `/metrics` on `listener` until the listener fails
+pub async fn serve_metrics(listener: TcpListener, state: Arc<Mutex<TunnelState>>) -> Result<()> {
+    let logger = state.lock().await.logger.clone();
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_metrics_request(socket, state).await {
+                logger.error(None, &format!("Metrics request error from {}: {}", addr, e));
+            }
+        });
+    }
+}
+
+async fn handle_metrics_request(
+    mut socket: TcpStream,
+    state: Arc<Mutex<TunnelState>>,
+) -> Result<()> {
+    let path = read_request_path(&mut socket, REQUEST_READ_TIMEOUT).await?;
+    let response = if path == "/metrics" {
+        let body = render_metrics(&*state.lock().await);
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::endpoint::ConnectionType;
+    use tokio::io::AsyncReadExt;
+
+    async fn scrape(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn test_render_metrics_state_labels() {
+        let state = TunnelState {
+            connection_state: ConnectionState::Connected,
+            conn_type: ConnectionType::Relay("https://relay.example.com".parse().unwrap()),
+            total_reconnects: 2,
+            ..Default::default()
+        };
+        let body = render_metrics(&state);
+        assert!(body.contains("iroh_tunnel_connection_state{state=\"connected\"} 1"));
+        assert!(body.contains("iroh_tunnel_connection_state{state=\"failed\"} 0"));
+        assert!(body.contains("iroh_tunnel_path_type{type=\"relay\"} 1"));
+        assert!(body.contains("iroh_tunnel_reconnects_total 2"));
+    }
+
+    #[tokio::test]
+    async fn test_scrape_metrics_endpoint() {
+        let state = Arc::new(Mutex::new(TunnelState::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(listener, state));
+
+        let response = scrape(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        for name in [
+            "iroh_tunnel_connections_total",
+            "iroh_tunnel_active_requests",
+            "iroh_tunnel_peak_active_requests",
+            "iroh_tunnel_open_streams",
+            "iroh_tunnel_socks_clients",
+            "iroh_tunnel_loops_rejected_total",
+            "iroh_tunnel_bytes_sent_total",
+            "iroh_tunnel_bytes_received_total",
+            "iroh_tunnel_reconnects_total",
+            "iroh_tunnel_connection_state",
+            "iroh_tunnel_path_type",
+        ] {
+            assert!(response.contains(name), "missing {}", name);
+        }
+
+        let response = scrape(addr, "/other").await;
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+}
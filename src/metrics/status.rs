@@ -0,0 +1,264 @@
+// Read-only JSON status document for scripts
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tokio::sync::Mutex;
+
+use crate::connection::logger::{format_rtt, path_type_label};
+use crate::tunnel::state::{ConnectionState, TunnelState};
+
+/// Traffic counters of the current peer connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub requests: u64,
+    pub active_requests: u64,
+    /// Most requests relayed at once on the current peer connection
+    pub peak_active_requests: u64,
+    /// Tunnel streams open right now, including pings and lookups
+    pub open_streams: u64,
+    /// Requests refused because they would loop back into this node's proxy
+    pub loops_rejected: u64,
+    /// Seconds since the current peer connection was established
+    pub connection_uptime_secs: Option<u64>,
+    /// Smoothed round-trip time to the peer in milliseconds
+    pub rtt_ms: Option<f64>,
+}
+
+/// Everything `--status-socket` reports
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub node_id: String,
+    /// disconnected / connecting / connected / failed
+    pub connection_state: String,
+    pub peer_id: Option<String>,
+    /// direct / relay / mixed / none
+    pub path_type: String,
+    /// Seconds since this node started
+    pub uptime_secs: u64,
+    pub stats: StatusStats,
+}
+
+impl StatusReport {
+    pub fn new(node_id: iroh::PublicKey, started_at: Instant, state: &TunnelState) -> Self {
+        let stats = state.stats_snapshot();
+        let connected = state.connection_state == ConnectionState::Connected;
+        let peer_id = match &state.peer_connection {
+            Some(conn) => Some(conn.remote_id()),
+            None => state.remote_peer_id,
+        };
+        Self {
+            node_id: node_id.to_string(),
+            connection_state: state.connection_state.label().to_string(),
+            peer_id: peer_id.map(|id| id.to_string()),
+            path_type: path_type_label(&state.conn_type).to_string(),
+            uptime_secs: started_at.elapsed().as_secs(),
+            stats: StatusStats {
+                bytes_sent: stats.bytes_sent,
+                bytes_received: stats.bytes_received,
+                requests: stats.requests,
+                active_requests: stats.active_requests,
+                peak_active_requests: stats.peak_active_requests,
+                open_streams: stats.open_streams,
+                loops_rejected: stats.loops_rejected,
+                connection_uptime_secs: connected.then(|| stats.uptime().as_secs()),
+                rtt_ms: stats.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            },
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Human-readable state dump, printed to stderr on SIGUSR1
+pub fn stats_dump(state: &TunnelState) -> String {
+    let stats = state.stats_snapshot();
+    let peer = match &state.peer_connection {
+        Some(conn) => Some(conn.remote_id()),
+        None => state.remote_peer_id,
+    };
+    let rtt = stats.rtt.map(format_rtt).unwrap_or_else(|| "-".to_string());
+    let lines = [
+        "📋 Tunnel status".to_string(),
+        format!("   State:           {:?}", state.connection_state),
+        format!(
+            "   Peer:            {}",
+            peer.map(|id| id.to_string())
+                .unwrap_or_else(|| "-".to_string())
+        ),
+        format!("   Path:            {}", path_type_label(&state.conn_type)),
+        format!("   RTT:             {}", rtt),
+        format!(
+            "   Active streams:  {} (peak {})",
+            stats.active_requests, stats.peak_active_requests
+        ),
+        format!("   Open streams:    {}", stats.open_streams),
+        format!(
+            "   SOCKS clients:   {}",
+            state.active_socks_clients.load(Ordering::Relaxed)
+        ),
+        format!("   Requests:        {}", stats.requests),
+        format!("   Loops rejected:  {}", stats.loops_rejected),
+        format!(
+            "   Bytes:           ↑ {} sent, ↓ {} received",
+            stats.bytes_sent, stats.bytes_received
+        ),
+        format!(
+            "   Connections:     {} ({} reconnects)",
+            state.total_connections, state.total_reconnects
+        ),
+    ];
+    lines.join("\n")
+}
+
+/// Answer every connection on the unix socket at `path` with the status JSON
+///
+/// The socket is read-only: clients just connect and read until EOF, e.g.
+/// `socat - UNIX-CONNECT:/run/tunnel.sock`. A stale socket file left by a
+/// previous run is replaced, see `bind_unix`.
+#[cfg(unix)]
+pub async fn serve_status(
+    path: std::path::PathBuf,
+    node_id: iroh::PublicKey,
+    started_at: Instant,
+    state: std::sync::Arc<Mutex<TunnelState>>,
+) -> Result<()> {
+    use crate::tunnel::unix_socket::bind_unix;
+    use tokio::io::AsyncWriteExt;
+
+    let listener = bind_unix(&path, "status")?;
+    let (shutdown, logger) = {
+        let state = state.lock().await;
+        (state.shutdown.clone(), state.logger.clone())
+    };
+    logger.info(&format!(
+        "🧾 Status available on unix socket {}",
+        path.display()
+    ));
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.wait() => break,
+        };
+        let mut socket = match accepted {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                logger.error(None, &format!("Failed to accept status client: {}", e));
+                continue;
+            }
+        };
+        let report = StatusReport::new(node_id, started_at, &*state.lock().await);
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let mut json = report.to_json()?;
+                json.push('\n');
+                socket.write_all(json.as_bytes()).await?;
+                socket.shutdown().await?;
+                anyhow::Ok(())
+            };
+            if let Err(e) = result.await {
+                logger.error(None, &format!("Status request error: {}", e));
+            }
+        });
+    }
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::endpoint::ConnectionType;
+    use std::time::Duration;
+
+    fn node_id() -> iroh::PublicKey {
+        iroh::SecretKey::generate(&mut rand::rng()).public()
+    }
+
+    #[test]
+    fn test_status_report_round_trips_through_json() {
+        let peer = node_id();
+        let state = TunnelState {
+            connection_state: ConnectionState::Connecting,
+            remote_peer_id: Some(peer),
+            conn_type: ConnectionType::None,
+            ..Default::default()
+        };
+        state.stats.record_sent(1200);
+        state.stats.record_rtt(Duration::from_millis(30));
+
+        let id = node_id();
+        let json = StatusReport::new(id, Instant::now(), &state)
+            .to_json()
+            .unwrap();
+        let report: StatusReport = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(report.node_id, id.to_string());
+        assert_eq!(report.connection_state, "connecting");
+        assert_eq!(report.peer_id, Some(peer.to_string()));
+        assert_eq!(report.path_type, "none");
+        assert_eq!(report.stats.bytes_sent, 1200);
+        assert_eq!(report.stats.connection_uptime_secs, None);
+        assert_eq!(report.stats.rtt_ms, Some(30.0));
+    }
+
+    #[test]
+    fn test_stats_dump_lists_state_and_counters() {
+        let peer = node_id();
+        let state = TunnelState {
+            connection_state: ConnectionState::Connected,
+            remote_peer_id: Some(peer),
+            conn_type: ConnectionType::Relay("https://relay.example.com".parse().unwrap()),
+            total_connections: 3,
+            total_reconnects: 2,
+            ..Default::default()
+        };
+        let _request = state.stats.start_request();
+        state.stats.record_sent(100);
+        state.stats.record_received(2048);
+
+        let dump = stats_dump(&state);
+        assert!(dump.contains("State:           Connected"));
+        assert!(dump.contains(&format!("Peer:            {}", peer)));
+        assert!(dump.contains("Path:            relay"));
+        assert!(dump.contains("RTT:             -"));
+        assert!(dump.contains("Active streams:  1 (peak 1)"));
+        assert!(dump.contains("↑ 100 sent, ↓ 2048 received"));
+        assert!(dump.contains("3 (2 reconnects)"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_status_socket_serves_json() {
+        use tokio::io::AsyncReadExt;
+
+        let dir = std::env::temp_dir().join(format!("tunnel-status-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("status.sock");
+        let state = std::sync::Arc::new(Mutex::new(TunnelState::default()));
+        let shutdown = state.lock().await.shutdown.clone();
+        let server = tokio::spawn(serve_status(path.clone(), node_id(), Instant::now(), state));
+
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let mut json = String::new();
+        stream.read_to_string(&mut json).await.unwrap();
+        let report: StatusReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(report.connection_state, "disconnected");
+
+        shutdown.trigger();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+        let _ = std::fs::remove_dir(&dir);
+    }
+}
@@ -1,10 +1,38 @@
-/// SOCKS5 protocol constants and types
+//! SOCKS5 protocol constants and types
 
 pub const SOCKS_VERSION: u8 = 5;
 pub const SOCKS_ADDR_TYPE_IPV4: u8 = 1;
 pub const SOCKS_ADDR_TYPE_DOMAIN: u8 = 3;
 pub const SOCKS_ADDR_TYPE_IPV6: u8 = 4;
 pub const SOCKS_CMD_CONNECT: u8 = 1;
+/// Method selection reply refusing every method the client offered
+pub const SOCKS_NO_ACCEPTABLE_METHODS: u8 = 0xff;
+pub const SOCKS_REPLY_NOT_ALLOWED: u8 = 2;
+/// Sent when no peer connection is available within `--connect-wait`
+pub const SOCKS_REPLY_TTL_EXPIRED: u8 = 6;
+
+/// Build a reply with a zero bound address of the given family
+///
+/// IPv6 requests get an IPv6 (22-byte) reply; everything else, including
+/// domain requests, gets the IPv4 (10-byte) form.
+pub fn socks_reply(code: u8, addr_type: u8) -> Vec<u8> {
+    let (atyp, addr_len) = match addr_type {
+        SOCKS_ADDR_TYPE_IPV6 => (SOCKS_ADDR_TYPE_IPV6, 16),
+        _ => (SOCKS_ADDR_TYPE_IPV4, 4),
+    };
+    let mut reply = vec![SOCKS_VERSION, code, 0, atyp];
+    reply.resize(4 + addr_len + 2, 0);
+    reply
+}
+
+/// Address type to answer with for a destination host as parsed from a request
+pub fn reply_addr_type(host: &str) -> u8 {
+    if host.starts_with('[') {
+        SOCKS_ADDR_TYPE_IPV6
+    } else {
+        SOCKS_ADDR_TYPE_IPV4
+    }
+}
 
 /// Check if the target is a loopback address on common SOCKS ports
 /// This prevents infinite loops when tunneling to localhost
@@ -34,6 +62,31 @@ mod tests {
         assert!(!is_loopback_address("127.0.0.1", 443));
     }
 
+    #[test]
+    fn test_socks_reply_ipv4() {
+        let reply = socks_reply(0, SOCKS_ADDR_TYPE_IPV4);
+        assert_eq!(reply.len(), 10);
+        assert_eq!(
+            reply,
+            [SOCKS_VERSION, 0, 0, SOCKS_ADDR_TYPE_IPV4, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_socks_reply_ipv6() {
+        let reply = socks_reply(5, SOCKS_ADDR_TYPE_IPV6);
+        assert_eq!(reply.len(), 22);
+        assert_eq!(&reply[..4], &[SOCKS_VERSION, 5, 0, SOCKS_ADDR_TYPE_IPV6]);
+        assert!(reply[4..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_socks_reply_domain_uses_ipv4_form() {
+        assert_eq!(socks_reply(0, SOCKS_ADDR_TYPE_DOMAIN).len(), 10);
+        assert_eq!(reply_addr_type("example.com"), SOCKS_ADDR_TYPE_IPV4);
+        assert_eq!(reply_addr_type("[2001:db8::1]"), SOCKS_ADDR_TYPE_IPV6);
+    }
+
     #[test]
     fn test_is_loopback_address_non_loopback() {
         assert!(!is_loopback_address("example.com", 1080));
@@ -0,0 +1,116 @@
+//! TLS on the local SOCKS listener (`--socks-tls-cert` / `--socks-tls-key`)
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::server::TlsStream;
+
+use crate::socks5::protocol::{SOCKS_NO_ACCEPTABLE_METHODS, SOCKS_VERSION};
+
+/// Certificate chain and private key the SOCKS listener presents
+#[derive(Clone, Debug, PartialEq)]
+pub struct SocksTls {
+    /// PEM file with the certificate chain, leaf first
+    pub cert: PathBuf,
+    /// PEM file with the private key (PKCS#8, PKCS#1 or SEC1)
+    pub key: PathBuf,
+}
+
+impl SocksTls {
+    /// Load the certificate and key and build the TLS acceptor
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("Failed to read TLS certificate {}", self.cert.display()))?;
+        if certs.is_empty() {
+            anyhow::bail!("No certificate found in {}", self.cert.display());
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .with_context(|| format!("Failed to read TLS key {}", self.key.display()))?;
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("TLS certificate and key don't match")?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Run the TLS handshake with a client of the SOCKS listener within `limit`
+///
+/// A client that starts talking SOCKS5 in plaintext is refused with "no
+/// acceptable methods", so it fails with a SOCKS error rather than hanging
+/// or reporting a garbled handshake.
+pub async fn accept_tls(
+    acceptor: &TlsAcceptor,
+    mut socket: TcpStream,
+    limit: Duration,
+) -> Result<TlsStream<TcpStream>> {
+    tokio::time::timeout(limit, async {
+        let mut first = [0u8; 1];
+        socket.peek(&mut first).await?;
+        if first[0] == SOCKS_VERSION {
+            // Take the greeting first: closing with unread data resets the
+            // connection, and the client would never see the reply
+            let mut head = [0u8; 2];
+            socket.read_exact(&mut head).await?;
+            socket.read_exact(&mut vec![0u8; head[1] as usize]).await?;
+            if socket
+                .write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHODS])
+                .await
+                .is_ok()
+            {
+                socket.shutdown().await.ok();
+            }
+            anyhow::bail!("Plaintext SOCKS5 client on the TLS listener, refused");
+        }
+        acceptor
+            .accept(socket)
+            .await
+            .context("TLS handshake failed")
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("TLS handshake timed out after {:?}", limit))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::test_support::self_signed_tls;
+
+    #[test]
+    fn test_acceptor_loads_certificate_and_key() {
+        let dir = std::env::temp_dir().join(format!("tunnel-tls-load-{}", std::process::id()));
+        let (tls, _) = self_signed_tls(&dir);
+        assert!(tls.acceptor().is_ok());
+
+        let missing = SocksTls {
+            cert: dir.join("missing.pem"),
+            key: tls.key.clone(),
+        };
+        let err = missing.acceptor().err().unwrap();
+        assert!(err.to_string().contains("missing.pem"));
+
+        // A key that isn't the certificate's
+        let (other, _) = self_signed_tls(&dir.join("other"));
+        let mismatched = SocksTls {
+            cert: tls.cert.clone(),
+            key: other.key,
+        };
+        assert!(mismatched.acceptor().is_err());
+
+        let no_certs = SocksTls {
+            cert: tls.key.clone(),
+            key: tls.key.clone(),
+        };
+        assert!(no_certs.acceptor().is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
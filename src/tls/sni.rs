@@ -1,26 +1,95 @@
+use std::fmt;
+
+/// TLS extension carrying the server name
+const EXT_SERVER_NAME: u16 = 0x0000;
+/// Encrypted Client Hello (outer ClientHello)
+const EXT_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+/// ECH inner extension compression marker
+const EXT_ECH_OUTER_EXTENSIONS: u16 = 0xfd00;
+
+/// What a ClientHello reveals about the server it is for
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerName {
+    /// Plain SNI hostname
+    Sni(String),
+    /// Encrypted Client Hello: the real name is hidden, `outer` is the public SNI
+    Ech { outer: Option<String> },
+    /// No server name at all, usually a connection to an IP literal
+    Missing,
+}
+
+impl fmt::Display for ServerName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerName::Sni(name) => write!(f, "{}", name),
+            ServerName::Ech { outer: Some(outer) } => write!(f, "ECH (outer: {})", outer),
+            ServerName::Ech { outer: None } => write!(f, "ECH"),
+            ServerName::Missing => write!(f, "no-SNI (IP literal)"),
+        }
+    }
+}
+
 /// Extract SNI (Server Name Indication) from TLS ClientHello
 ///
 /// Parses the TLS ClientHello message to extract the SNI extension.
 /// Returns the hostname if found, None otherwise.
 pub fn extract_sni(data: &[u8]) -> Option<String> {
+    let (sni, _) = walk_client_hello(data).ok()?;
+    sni
+}
+
+/// Classify the server name of a TLS ClientHello
+///
+/// Returns None if `data` isn't a ClientHello; otherwise distinguishes plain
+/// SNI, Encrypted Client Hello and a hello without any server name.
+pub fn inspect_client_hello(data: &[u8]) -> Option<ServerName> {
+    diagnose_client_hello(data).ok()
+}
+
+/// Classify a ClientHello like `inspect_client_hello`, or say where parsing stopped
+///
+/// For `--debug-parse`; the error names the field that didn't fit.
+pub fn diagnose_client_hello(data: &[u8]) -> Result<ServerName, String> {
+    let (sni, ech) = walk_client_hello(data)?;
+    Ok(match (sni, ech) {
+        (outer, true) => ServerName::Ech { outer },
+        (Some(name), false) => ServerName::Sni(name),
+        (None, false) => ServerName::Missing,
+    })
+}
+
+/// Walk the ClientHello extensions, returning (SNI hostname, ECH present)
+fn walk_client_hello(data: &[u8]) -> Result<(Option<String>, bool), String> {
     // TLS record must be at least 43 bytes
     if data.len() < 43 {
-        return None;
+        return Err(format!(
+            "{} bytes is shorter than a ClientHello header (43)",
+            data.len()
+        ));
     }
 
     // Check if this is a TLS handshake (0x16) with ClientHello (0x01)
     if data[0] != 0x16 {
-        return None;
+        return Err(format!(
+            "record type 0x{:02x} is not a handshake (0x16)",
+            data[0]
+        ));
     }
 
     // TLS version should be 3.x
     if data[1] != 0x03 {
-        return None;
+        return Err(format!(
+            "record version major 0x{:02x} is not 0x03",
+            data[1]
+        ));
     }
 
     // Skip to handshake type
     if data[5] != 0x01 {
-        return None; // Not a ClientHello
+        return Err(format!(
+            "handshake type 0x{:02x} is not a ClientHello (0x01)",
+            data[5]
+        ));
     }
 
     // Parse through the ClientHello to find extensions
@@ -28,28 +97,36 @@ pub fn extract_sni(data: &[u8]) -> Option<String> {
 
     // Skip session ID
     if pos >= data.len() {
-        return None;
+        return Err(format!("session ID length at offset {} is missing", pos));
     }
     let session_id_len = data[pos] as usize;
     pos += 1 + session_id_len;
 
     // Skip cipher suites
     if pos + 2 > data.len() {
-        return None;
+        return Err(format!(
+            "cipher suites length at offset {} is past the end",
+            pos
+        ));
     }
     let cipher_suites_len = u16::from_be_bytes([data[pos], data[pos + 1]]) as usize;
     pos += 2 + cipher_suites_len;
 
     // Skip compression methods
     if pos >= data.len() {
-        return None;
+        return Err(format!(
+            "compression methods at offset {} are past the end",
+            pos
+        ));
     }
     let compression_len = data[pos] as usize;
     pos += 1 + compression_len;
 
-    // Extensions length
+    // A ClientHello without extensions has no SNI
+    let mut sni = None;
+    let mut ech = false;
     if pos + 2 > data.len() {
-        return None;
+        return Ok((sni, ech));
     }
     let extensions_len = u16::from_be_bytes([data[pos], data[pos + 1]]) as usize;
     pos += 2;
@@ -62,33 +139,31 @@ pub fn extract_sni(data: &[u8]) -> Option<String> {
         let ext_len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
         pos += 4;
 
-        // SNI extension type is 0x0000
-        if ext_type == 0x0000 && pos + ext_len <= data.len() {
+        match ext_type {
             // SNI extension format:
             // - 2 bytes: list length
             // - 1 byte: name type (0 = hostname)
             // - 2 bytes: hostname length
             // - hostname bytes
-            if ext_len >= 5 {
-                let name_type = data[pos + 2];
-                if name_type == 0 {
-                    let hostname_len = u16::from_be_bytes([data[pos + 3], data[pos + 4]]) as usize;
-                    if pos + 5 + hostname_len <= data.len() {
-                        if let Ok(hostname) =
-                            std::str::from_utf8(&data[pos + 5..pos + 5 + hostname_len])
-                        {
-                            return Some(hostname.to_string());
-                        }
-                    }
+            EXT_SERVER_NAME
+                if pos + ext_len <= data.len() && ext_len >= 5 && data[pos + 2] == 0 =>
+            {
+                let hostname_len = u16::from_be_bytes([data[pos + 3], data[pos + 4]]) as usize;
+                if pos + 5 + hostname_len <= data.len()
+                    && let Ok(hostname) =
+                        std::str::from_utf8(&data[pos + 5..pos + 5 + hostname_len])
+                {
+                    sni = Some(hostname.to_string());
                 }
             }
-            return None;
+            EXT_ENCRYPTED_CLIENT_HELLO | EXT_ECH_OUTER_EXTENSIONS => ech = true,
+            _ => {}
         }
 
         pos += ext_len;
     }
 
-    None
+    Ok((sni, ech))
 }
 
 #[cfg(test)]
@@ -173,5 +248,75 @@ mod tests {
         }
 
         assert_eq!(extract_sni(&data), Some("example.com".to_string()));
+        assert_eq!(
+            inspect_client_hello(&data),
+            Some(ServerName::Sni("example.com".to_string()))
+        );
+    }
+
+    /// ClientHello with the given extensions (type, body) and no padding
+    fn client_hello(extensions: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut data = vec![0u8; 43];
+        data[0] = 0x16; // Handshake
+        data[1] = 0x03; // TLS 1.x
+        data[5] = 0x01; // ClientHello
+        data.push(0); // Session ID length
+        data.extend_from_slice(&[0, 2, 0x13, 0x01]); // One cipher suite
+        data.extend_from_slice(&[1, 0]); // No compression
+
+        let mut ext = Vec::new();
+        for (ext_type, body) in extensions {
+            ext.extend_from_slice(&ext_type.to_be_bytes());
+            ext.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            ext.extend_from_slice(body);
+        }
+        data.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        data.extend_from_slice(&ext);
+        data
+    }
+
+    fn sni_body(hostname: &str) -> Vec<u8> {
+        let mut body = ((hostname.len() + 3) as u16).to_be_bytes().to_vec();
+        body.push(0);
+        body.extend_from_slice(&(hostname.len() as u16).to_be_bytes());
+        body.extend_from_slice(hostname.as_bytes());
+        body
+    }
+
+    #[test]
+    fn test_inspect_client_hello_with_ech() {
+        let outer = sni_body("public.example.net");
+        let data = client_hello(&[
+            (EXT_SERVER_NAME, &outer),
+            (EXT_ENCRYPTED_CLIENT_HELLO, &[0u8; 32]),
+        ]);
+        let name = inspect_client_hello(&data).unwrap();
+        assert_eq!(
+            name,
+            ServerName::Ech {
+                outer: Some("public.example.net".to_string())
+            }
+        );
+        assert_eq!(name.to_string(), "ECH (outer: public.example.net)");
+        // The outer SNI is still what extract_sni reports
+        assert_eq!(extract_sni(&data), Some("public.example.net".to_string()));
+
+        let data = client_hello(&[(EXT_ENCRYPTED_CLIENT_HELLO, &[0u8; 32])]);
+        assert_eq!(inspect_client_hello(&data).unwrap().to_string(), "ECH");
+    }
+
+    #[test]
+    fn test_inspect_client_hello_without_sni() {
+        // e.g. supported_versions only, as sent when connecting to an IP
+        let data = client_hello(&[(0x002b, &[2, 0x03, 0x04])]);
+        assert_eq!(inspect_client_hello(&data), Some(ServerName::Missing));
+        assert_eq!(ServerName::Missing.to_string(), "no-SNI (IP literal)");
+        assert_eq!(extract_sni(&data), None);
+
+        assert_eq!(
+            inspect_client_hello(&client_hello(&[])),
+            Some(ServerName::Missing)
+        );
+        assert_eq!(inspect_client_hello(b"GET / HTTP/1.1\r\n\r\n"), None);
     }
 }
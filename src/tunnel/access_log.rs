@@ -0,0 +1,190 @@
+// Access log: one line per completed local request, for auditing
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+use crate::tunnel::logger::SharedLogger;
+use crate::utils::clock::Timestamp;
+
+/// Outcome codes, borrowed from HTTP so log tooling understands them
+pub const STATUS_OK: u16 = 200;
+pub const STATUS_NOT_ALLOWED: u16 = 403;
+pub const STATUS_TUNNEL_FAILED: u16 = 502;
+pub const STATUS_NO_PEER: u16 = 503;
+pub const STATUS_TIMEOUT: u16 = 504;
+
+/// Handle to the access log file; cheap to clone
+///
+/// Lines are queued to a writer task so relays never wait on disk I/O.
+#[derive(Clone, Debug)]
+pub struct AccessLog {
+    lines: mpsc::UnboundedSender<String>,
+}
+
+impl AccessLog {
+    /// Open `path` for appending and start its writer task, which reports
+    /// write failures to `logger`
+    pub async fn open(path: &Path, logger: SharedLogger) -> Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open access log {}", path.display()))?;
+        let (lines, mut queue) = mpsc::unbounded_channel::<String>();
+        tokio::spawn(async move {
+            let mut file = BufWriter::new(file);
+            while let Some(line) = queue.recv().await {
+                let mut result = file.write_all(line.as_bytes()).await;
+                // Write out whatever else is queued before flushing
+                while let Ok(line) = queue.try_recv() {
+                    result = result.and(file.write_all(line.as_bytes()).await);
+                }
+                if let Err(e) = result.and(file.flush().await) {
+                    logger.error(None, &format!("Failed to write access log: {}", e));
+                }
+            }
+        });
+        Ok(Self { lines })
+    }
+
+    fn write(&self, line: String) {
+        let _ = self.lines.send(line);
+    }
+}
+
+/// A request being served; written to the log when dropped
+pub struct AccessRecord {
+    log: Option<AccessLog>,
+    started: Instant,
+    pub timestamp: Timestamp,
+    pub client: SocketAddr,
+    /// `CONNECT` for SOCKS5, `TRANSPARENT` for redirected connections
+    pub method: &'static str,
+    pub host: String,
+    pub port: u16,
+    /// TLS server name or `METHOD /path` of plain HTTP, if recognized
+    pub sni: Option<String>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub status: u16,
+}
+
+impl AccessRecord {
+    /// Start timing a request; it counts as failed until `status` is set
+    pub fn start(
+        log: Option<AccessLog>,
+        client: SocketAddr,
+        method: &'static str,
+        host: &str,
+        port: u16,
+    ) -> Self {
+        Self {
+            log,
+            started: Instant::now(),
+            timestamp: Timestamp::now(),
+            client,
+            method,
+            host: host.to_string(),
+            port,
+            sni: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            status: STATUS_TUNNEL_FAILED,
+        }
+    }
+
+    /// Combined-Log-Format-style line
+    ///
+    /// `client - - [time] "METHOD host:port" status bytes_received bytes_sent "sni" duration_ms`
+    pub fn format(&self, duration: Duration) -> String {
+        format!(
+            "{} - - [{}] \"{} {}:{}\" {} {} {} \"{}\" {}\n",
+            self.client,
+            self.timestamp.log_date(),
+            self.method,
+            self.host,
+            self.port,
+            self.status,
+            self.bytes_received,
+            self.bytes_sent,
+            self.sni.as_deref().unwrap_or("-").replace('"', "'"),
+            duration.as_millis()
+        )
+    }
+}
+
+impl Drop for AccessRecord {
+    fn drop(&mut self) {
+        if let Some(log) = &self.log {
+            log.write(self.format(self.started.elapsed()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_combined_line() {
+        let mut record = AccessRecord::start(
+            None,
+            "127.0.0.1:51234".parse().unwrap(),
+            "CONNECT",
+            "example.com",
+            443,
+        );
+        record.timestamp =
+            Timestamp::from_system_time(std::time::UNIX_EPOCH + Duration::from_secs(1_792_227_903));
+        record.sni = Some("GET /\"q\"".to_string());
+        record.bytes_sent = 517;
+        record.bytes_received = 4096;
+        record.status = STATUS_OK;
+
+        let line = record.format(Duration::from_millis(1532));
+        assert_eq!(
+            line,
+            format!(
+                "127.0.0.1:51234 - - [{}] \"CONNECT example.com:443\" 200 4096 517 \"GET /'q'\" 1532\n",
+                record.timestamp.log_date()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_records_are_appended_to_file() {
+        let path = std::env::temp_dir().join(format!("tunnel-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = AccessLog::open(&path, SharedLogger::default())
+            .await
+            .unwrap();
+
+        let client = "127.0.0.1:40000".parse().unwrap();
+        drop(AccessRecord::start(
+            Some(log.clone()),
+            client,
+            "CONNECT",
+            "a.example",
+            80,
+        ));
+        let mut ok = AccessRecord::start(Some(log), client, "TRANSPARENT", "10.0.0.1", 22);
+        ok.status = STATUS_OK;
+        drop(ok);
+
+        let contents = loop {
+            let contents = std::fs::read_to_string(&path).unwrap_or_default();
+            if contents.lines().count() == 2 {
+                break contents;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let lines: Vec<&str> = contents.lines().collect();
+        assert!(lines[0].contains("\"CONNECT a.example:80\" 502 0 0 \"-\""));
+        assert!(lines[1].contains("\"TRANSPARENT 10.0.0.1:22\" 200"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
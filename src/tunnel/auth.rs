@@ -0,0 +1,111 @@
+// Peer authorization
+use iroh::endpoint::{Connection, VarInt};
+use std::fmt;
+
+/// QUIC application close code sent to peers rejected by the allowlist
+pub const PEER_NOT_ALLOWED_CODE: u32 = 1;
+
+/// QUIC application close code sent while another peer holds the `--exclusive` slot
+pub const PEER_BUSY_CODE: u32 = 2;
+
+/// Check whether a peer may use this node
+///
+/// An empty allowlist keeps the open behavior and accepts every peer.
+pub fn is_peer_allowed(allowlist: &[iroh::PublicKey], peer_id: &iroh::PublicKey) -> bool {
+    allowlist.is_empty() || allowlist.contains(peer_id)
+}
+
+/// Close a connection from a peer that is not on the allowlist
+pub fn reject_peer(connection: &Connection) {
+    connection.close(
+        VarInt::from_u32(PEER_NOT_ALLOWED_CODE),
+        b"peer not in allowlist",
+    );
+}
+
+/// Close a connection arriving while another peer holds the `--exclusive` slot
+pub fn reject_busy_peer(connection: &Connection) {
+    connection.close(
+        VarInt::from_u32(PEER_BUSY_CODE),
+        b"node is in use by another peer",
+    );
+}
+
+/// Shared secret a client presents in an `Auth` message before `Connect`
+///
+/// Independent of the node-id allowlist; `Debug` never prints the secret.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthToken(String);
+
+impl AuthToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(token.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether `presented` is this token, in time independent of where they differ
+    pub fn matches(&self, presented: &str) -> bool {
+        constant_time_eq(self.0.as_bytes(), presented.as_bytes())
+    }
+}
+
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthToken(<redacted>)")
+    }
+}
+
+/// Compare without an early exit, so timing doesn't reveal a matching prefix
+///
+/// Only the length can leak, which says little about a random token.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a
+        .iter()
+        .zip(b)
+        .fold(0u8, |diff, (x, y)| diff | std::hint::black_box(x ^ y));
+    diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    fn random_peer_id() -> iroh::PublicKey {
+        SecretKey::generate(&mut rand::rng()).public()
+    }
+
+    #[test]
+    fn test_is_peer_allowed_empty_allowlist() {
+        assert!(is_peer_allowed(&[], &random_peer_id()));
+    }
+
+    #[test]
+    fn test_is_peer_allowed_listed_peer() {
+        let peer = random_peer_id();
+        let allowlist = vec![random_peer_id(), peer];
+        assert!(is_peer_allowed(&allowlist, &peer));
+    }
+
+    #[test]
+    fn test_is_peer_allowed_rejects_unlisted_peer() {
+        let allowlist = vec![random_peer_id(), random_peer_id()];
+        assert!(!is_peer_allowed(&allowlist, &random_peer_id()));
+    }
+
+    #[test]
+    fn test_auth_token_matches() {
+        let token = AuthToken::new("s3cret-token");
+        assert!(token.matches("s3cret-token"));
+        assert!(!token.matches("s3cret-tokeN"));
+        assert!(!token.matches("s3cret"));
+        assert!(!token.matches(""));
+        assert!(!format!("{:?}", token).contains("s3cret"));
+    }
+}
@@ -0,0 +1,108 @@
+// Config file (`--config`): TOML whose keys are the long command-line flags
+//
+//     port = 1090
+//     connect-wait = 10
+//     allow-dest = ["*.example.com", "10.0.0.0/8"]
+//     privacy = true
+//
+// Each key becomes `--key=value` (an array repeats the flag, `true` sets a
+// switch, `false` leaves it out), so the file goes through the same parsing
+// and validation as the command line and there is a single set of names.
+use anyhow::{Context, Result};
+use toml_edit::{Document, Value};
+
+/// The flags a config file stands for, to be parsed ahead of the command line
+///
+/// `flag` gives the long flag a key sets, or `None` if the file may not use it.
+pub fn config_file_args(
+    contents: &str,
+    flag: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<String>> {
+    let document = Document::parse(contents).context("Not valid TOML")?;
+    let table = document.as_table();
+    let mut args = Vec::new();
+    for (key, item) in table.iter() {
+        let line = table
+            .key(key)
+            .and_then(|key| key.span())
+            .map_or(0, |span| contents[..span.start].matches('\n').count() + 1);
+        let Some(name) = flag(key) else {
+            anyhow::bail!("Unknown option `{}` on line {}", key, line);
+        };
+        let Some(value) = item.as_value() else {
+            anyhow::bail!(
+                "`{}` on line {} must be a value; sections are not supported",
+                key,
+                line
+            );
+        };
+        match value.as_array() {
+            Some(values) => {
+                for value in values.iter() {
+                    push_flag(&mut args, &name, value)?;
+                }
+            }
+            None => push_flag(&mut args, &name, value)?,
+        }
+    }
+    Ok(args)
+}
+
+fn push_flag(args: &mut Vec<String>, key: &str, value: &Value) -> Result<()> {
+    let value = match value {
+        Value::Boolean(switch) => {
+            if *switch.value() {
+                args.push(format!("--{}", key));
+            }
+            return Ok(());
+        }
+        Value::String(s) => s.value().clone(),
+        Value::Integer(n) => n.value().to_string(),
+        Value::Float(f) => f.value().to_string(),
+        other => anyhow::bail!("`{}` can't be a {}", key, other.type_name()),
+    };
+    args.push(format!("--{}={}", key, value));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn known(key: &str) -> Option<String> {
+        ["port", "allow-dest", "privacy", "qr", "relay-chunk-size"]
+            .contains(&key)
+            .then(|| key.to_string())
+    }
+
+    #[test]
+    fn test_config_file_args() {
+        let contents = r#"
+            # Local proxy
+            port = 1090
+            allow-dest = ["*.example.com", "10.0.0.0/8"]
+            privacy = true
+            qr = false
+            relay-chunk-size = "64K"
+        "#;
+        assert_eq!(
+            config_file_args(contents, known).unwrap(),
+            [
+                "--port=1090",
+                "--allow-dest=*.example.com",
+                "--allow-dest=10.0.0.0/8",
+                "--privacy",
+                "--relay-chunk-size=64K",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_file_rejects_unknown_and_nested() {
+        let err = config_file_args("qr = true\nprot = 1090", known).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown option `prot` on line 2");
+        assert!(config_file_args("[port]\nvalue = 1", known).is_err());
+        assert!(config_file_args("port = [[1]]", known).is_err());
+        assert!(config_file_args("port = ", known).is_err());
+    }
+}
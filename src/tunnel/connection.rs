@@ -1,103 +1,292 @@
 use anyhow::{Context, Result};
-use iroh::endpoint::{Connection, Endpoint};
+use iroh::endpoint::{Connection, ConnectionType, Endpoint, VarInt};
+use iroh::{EndpointAddr, PublicKey, RelayMap, TransportAddr, Watcher};
+use iroh_tickets::endpoint::EndpointTicket;
+use rand::Rng;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tokio::sync::{Mutex, Semaphore};
 
-use crate::connection::logger::log_connection_details;
+use crate::connection::logger::{
+    current_connection_type, format_connection_details, format_connection_type_change, format_rtt,
+    next_connection_type, path_type_label,
+};
 use crate::socks5::protocol::is_loopback_address;
-use crate::tunnel::protocol::TunnelMessage;
-use crate::tunnel::relay::{recv_message, relay_bidirectional, send_message};
+use crate::tunnel::auth::AuthToken;
+use crate::tunnel::dns::normalize_host;
+use crate::tunnel::events::TunnelEvent;
+use crate::tunnel::failover::select_peer;
+use crate::tunnel::logger::{Logger, SharedLogger};
+use crate::tunnel::outbound::{Outbound, OutboundError};
+use crate::tunnel::protocol::{
+    AUTH_FAILED_MESSAGE, CLOSE_PROTOCOL_ERROR, CLOSE_SHUTTING_DOWN, Features, NOT_ALLOWED_MESSAGE,
+    TunnelMessage,
+};
+use crate::tunnel::relay::{
+    recv_after_hello, recv_message, relay_bidirectional, send_hello, send_message,
+};
+use crate::tunnel::shutdown::Shutdown;
 use crate::tunnel::state::{ConnectionState, TUNNEL_ALPN, TunnelState};
-use crate::utils::logging::format_log;
+use crate::tunnel::stats::ConnectionStats;
+use crate::utils::logging::{RequestId, detail, format_log, target};
 
-pub async fn monitor_connection_health(state: Arc<Mutex<TunnelState>>, endpoint: Endpoint) {
-    const HEALTH_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
 
+/// Time given to relay tasks to send Close before the endpoint goes down
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+
+/// Backoff before the next reconnection attempt
+///
+/// Exponential backoff (1s, 2s, 4s, ... 60s max) with +/- 25% jitter so that
+/// clients losing a shared relay at the same time don't reconnect in lockstep.
+pub fn reconnect_delay(attempts: u32, rng: &mut impl Rng) -> Duration {
+    let delay = RECONNECT_BASE_DELAY * 2_u32.pow(attempts.min(6));
+    let delay = delay.min(RECONNECT_MAX_DELAY);
+    delay
+        .mul_f64(rng.random_range(0.75..=1.25))
+        .min(RECONNECT_MAX_DELAY)
+}
+
+/// Check the peer connection every `interval`, reconnecting when it is gone
+pub async fn monitor_connection_health(
+    state: Arc<Mutex<TunnelState>>,
+    endpoint: Endpoint,
+    interval: Duration,
+) {
+    /// How often the smoothed RTT is logged
+    const RTT_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+    let (shutdown, logger) = {
+        let state = state.lock().await;
+        (state.shutdown.clone(), state.logger.clone())
+    };
+    let mut last_rtt_log = Instant::now();
     loop {
-        tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = shutdown.wait() => return,
+        }
 
         let (should_reconnect, remote_peer_id) = {
+            let state_handle = &state;
             let mut state = state.lock().await;
+            let now = Instant::now();
+            if let Some(conn) = &state.peer_connection
+                && conn.close_reason().is_none()
+            {
+                if !state.heartbeat_timeout.is_zero() && !state.heartbeat_stale(now) {
+                    spawn_heartbeat(
+                        Arc::clone(state_handle),
+                        conn.clone(),
+                        state.connection_epoch,
+                        state.heartbeat_timeout,
+                    );
+                }
+                state.stats.record_rtt(conn.rtt());
+                if let Some(rtt) = state.stats.rtt() {
+                    state.events.publish(TunnelEvent::RttUpdated { rtt });
+                }
+                if last_rtt_log.elapsed() >= RTT_LOG_INTERVAL
+                    && let Some(rtt) = state.stats.rtt()
+                {
+                    last_rtt_log = Instant::now();
+                    logger.info(&format!(
+                        "📶 RTT to {}: {}",
+                        conn.remote_id().fmt_short(),
+                        format_rtt(rtt)
+                    ));
+                }
+            }
             match &state.peer_connection {
                 Some(conn) if conn.close_reason().is_some() => {
                     // Connection is closed
-                    eprintln!("⚠️  Connection lost, will attempt reconnection...");
-                    state.connection_state = ConnectionState::Disconnected;
+                    logger.warn("⚠️  Connection lost, will attempt reconnection...");
+                    state.set_connection_state(ConnectionState::Disconnected);
+                    state.peer_connection = None;
+                    (true, reconnect_target(&state))
+                }
+                Some(conn) if state.heartbeat_stale(now) => {
+                    // Looks open, but the peer stopped answering
+                    logger.warn(&format!(
+                        "⚠️  No heartbeat from peer for over {:?}, treating connection as dead...",
+                        state.heartbeat_timeout
+                    ));
+                    conn.close(VarInt::from_u32(0), b"heartbeat timeout");
+                    state.set_connection_state(ConnectionState::Disconnected);
                     state.peer_connection = None;
-                    (true, state.remote_peer_id)
+                    (true, reconnect_target(&state))
                 }
-                None if state.remote_peer_id.is_some() => {
-                    // No connection but we know the peer - try to reconnect
-                    (true, state.remote_peer_id)
+                // Gave up on the peer: stay quiet until reset
+                None if state.connection_state == ConnectionState::Failed => (false, None),
+                // Last attempt started too recently: wait for the next tick
+                None if !state.reconnect_allowed(Instant::now()) => (false, None),
+                None if state.remote_peer_id.is_some() || !state.candidate_peers.is_empty() => {
+                    // No connection but we know a peer - try to reconnect
+                    (true, reconnect_target(&state))
                 }
                 _ => (false, None),
             }
         };
 
-        if should_reconnect {
-            if let Some(peer_id) = remote_peer_id {
-                attempt_reconnection(&state, &endpoint, peer_id).await;
-            }
+        if should_reconnect && let Some(peer_id) = remote_peer_id {
+            attempt_reconnection(&state, &endpoint, peer_id).await;
         }
     }
 }
 
+/// Ping the peer in the background, recording the Pong if it arrives in time
+fn spawn_heartbeat(
+    state: Arc<Mutex<TunnelState>>,
+    conn: Connection,
+    epoch: u64,
+    timeout: Duration,
+) {
+    tokio::spawn(async move {
+        if let Ok(Ok(())) = tokio::time::timeout(timeout, ping_peer(&conn)).await {
+            state.lock().await.record_heartbeat(epoch, Instant::now());
+        }
+    });
+}
+
+/// Send a Ping on a fresh stream and wait for the Pong
+///
+/// An `Error` answer (e.g. from a peer at its stream limit) still shows the
+/// peer is alive, so it counts as a heartbeat too.
+pub async fn ping_peer(connection: &Connection) -> Result<()> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send_message(&mut send, &TunnelMessage::Ping).await?;
+    send.finish()?;
+
+    match recv_message(&mut recv).await? {
+        TunnelMessage::Pong | TunnelMessage::Error { .. } => Ok(()),
+        other => anyhow::bail!("Unexpected response to Ping: {:?}", other),
+    }
+}
+
+/// Peer to reconnect to, failing over through the candidate list
+fn reconnect_target(state: &TunnelState) -> Option<iroh::PublicKey> {
+    select_peer(
+        &state.candidate_peers,
+        state.remote_peer_id,
+        state.reconnect_attempts,
+    )
+}
+
 pub async fn attempt_reconnection(
     state: &Arc<Mutex<TunnelState>>,
     endpoint: &Endpoint,
     remote_peer_id: iroh::PublicKey,
 ) {
-    const BASE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
-    const MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
-
-    let attempts = {
-        let state = state.lock().await;
-        state.reconnect_attempts
+    let (attempts, fast_retry, logger) = {
+        let mut state = state.lock().await;
+        let now = Instant::now();
+        if !state.begin_reconnect(now) {
+            return; // Already connected, or another attempt is running or just ran
+        }
+        (
+            state.reconnect_attempts,
+            state.take_fast_retry(now),
+            state.logger.clone(),
+        )
     };
 
-    // Exponential backoff: 1s, 2s, 4s, 8s, 16s, 32s, 60s (max)
-    let delay = BASE_DELAY * 2_u32.pow(attempts.min(6));
-    let delay = delay.min(MAX_DELAY);
-
-    println!(
-        "🔄 Reconnection attempt #{} to {} in {:?}...",
-        attempts + 1,
-        remote_peer_id,
-        delay
-    );
-    tokio::time::sleep(delay).await;
+    if fast_retry {
+        logger.info(&format!(
+            "🔄 Reconnection attempt #{} to {} right away (network changed)...",
+            attempts + 1,
+            remote_peer_id
+        ));
+    } else {
+        let delay = reconnect_delay(attempts, &mut rand::rng());
+        logger.info(&format!(
+            "🔄 Reconnection attempt #{} to {} in {:?}...",
+            attempts + 1,
+            remote_peer_id,
+            delay
+        ));
+        tokio::time::sleep(delay).await;
+    }
 
     {
         let mut state = state.lock().await;
-        state.connection_state = ConnectionState::Connecting;
-        state.reconnect_attempts += 1;
+        state.set_connection_state(ConnectionState::Connecting);
     }
 
     // Connect directly using PublicKey (works for both client and server)
     match endpoint.connect(remote_peer_id, TUNNEL_ALPN).await {
         Ok(conn) => {
-            println!("✅ Reconnected to peer: {}", conn.remote_id());
+            logger.info(&format!("✅ Reconnected to peer: {}", conn.remote_id()));
 
             // Update state
-            {
+            let epoch = {
                 let mut state_guard = state.lock().await;
-                state_guard.peer_connection = Some(conn.clone());
-                state_guard.connection_state = ConnectionState::Connected;
+                state_guard.finish_reconnect();
+                state_guard.remote_peer_id = Some(conn.remote_id()); // Last-good peer
                 state_guard.reconnect_attempts = 0; // Reset on success
-            }
+                state_guard.total_reconnects += 1;
+                state_guard.install_connection(conn.clone())
+            };
 
             // Spawn new handler
             let endpoint_clone = endpoint.clone();
             let state_clone = Arc::clone(state);
             tokio::spawn(async move {
-                handle_peer_connection_with_monitoring(conn, endpoint_clone, state_clone).await;
+                handle_peer_connection_with_monitoring(conn, endpoint_clone, state_clone, epoch)
+                    .await;
             });
         }
         Err(e) => {
-            eprintln!("❌ Reconnection failed: {}", e);
+            logger.error(None, &format!("Reconnection failed: {}", e));
             let mut state = state.lock().await;
-            state.connection_state = ConnectionState::Failed;
+            state.finish_reconnect();
+            if state.record_failed_attempt() {
+                logger.warn(&format!(
+                    "🛑 Giving up after {} reconnection attempts (send SIGHUP or restart to retry)",
+                    state.reconnect_attempts
+                ));
+            }
+        }
+    }
+}
+
+/// Watch this node's direct addresses for a network change (e.g. Wi-Fi to
+/// cellular), so the next reconnection skips its backoff once
+///
+/// iroh migrates a live connection by itself; if the connection is already
+/// gone, reconnection starts right away instead of at the next health check.
+pub async fn watch_network_changes(
+    state: Arc<Mutex<TunnelState>>,
+    endpoint: Endpoint,
+    mut watcher: impl Watcher<Value = EndpointAddr>,
+) {
+    let logger = state.lock().await.logger.clone();
+    let direct_addrs = |addr: &EndpointAddr| addr.ip_addrs().copied().collect::<BTreeSet<_>>();
+    let mut known = direct_addrs(&watcher.get());
+    while watcher.updated().await.is_ok() {
+        // Relay changes alone don't move this node to another network
+        let addrs = direct_addrs(&watcher.get());
+        if addrs == known {
+            continue;
+        }
+        known = addrs;
+
+        let target = {
+            let mut state = state.lock().await;
+            state.record_network_change(Instant::now());
+            let lost = state.peer_connection.is_none()
+                && state.connection_state != ConnectionState::Failed;
+            if lost { reconnect_target(&state) } else { None }
+        };
+        if let Some(peer_id) = target {
+            logger.info("🌐 Network changed while disconnected, reconnecting now");
+            let state = Arc::clone(&state);
+            let endpoint = endpoint.clone();
+            tokio::spawn(async move { attempt_reconnection(&state, &endpoint, peer_id).await });
         }
     }
 }
@@ -106,27 +295,119 @@ pub async fn handle_peer_connection_with_monitoring(
     connection: Connection,
     endpoint: Endpoint,
     state: Arc<Mutex<TunnelState>>,
+    epoch: u64,
 ) {
-    handle_peer_connection(connection.clone(), endpoint).await;
+    if let Some(watcher) = endpoint.conn_type(connection.remote_id()) {
+        let connection = connection.clone();
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            track_connection_type(watcher, connection, state, epoch).await;
+        });
+    }
 
-    // When handler exits, clear the connection
+    let (stats, shutdown, outbound, max_streams, logger) = {
+        let state = state.lock().await;
+        (
+            Arc::clone(&state.stats),
+            state.shutdown.clone(),
+            Arc::clone(&state.outbound),
+            state.max_streams_per_peer,
+            state.logger.clone(),
+        )
+    };
+    handle_peer_connection(
+        connection,
+        endpoint,
+        stats,
+        shutdown,
+        outbound,
+        max_streams,
+        logger.clone(),
+    )
+    .await;
+
+    // When handler exits, clear the connection unless a newer one replaced it
     let mut state_lock = state.lock().await;
-    if let Some(conn) = &state_lock.peer_connection {
-        if conn.stable_id() == connection.stable_id() {
-            eprintln!("⚠️  Peer connection handler exited");
-            state_lock.peer_connection = None;
-            state_lock.connection_state = ConnectionState::Disconnected;
+    if state_lock.release_connection(epoch) {
+        logger.warn("⚠️  Peer connection handler exited");
+    }
+}
+
+/// Log every path change (Relay → Mixed → Direct) of a connection as it happens
+///
+/// Runs until the connection closes or is replaced by a newer one.
+async fn track_connection_type(
+    mut watcher: impl Watcher<Value = ConnectionType>,
+    connection: Connection,
+    state: Arc<Mutex<TunnelState>>,
+    epoch: u64,
+) {
+    let remote_node_id = connection.remote_id();
+    let mut current = watcher.get();
+    let logger = {
+        let mut state = state.lock().await;
+        if !state.set_conn_type(epoch, current.clone()) {
+            return;
+        }
+        state.logger.clone()
+    };
+
+    loop {
+        let next = tokio::select! {
+            next = next_connection_type(&mut watcher, &current) => next,
+            _ = connection.closed() => None,
+        };
+        let Some(next) = next else {
+            break;
+        };
+
+        logger.info(&format_connection_type_change(
+            remote_node_id,
+            &current,
+            &next,
+        ));
+        if !state.lock().await.set_conn_type(epoch, next.clone()) {
+            break;
         }
+        current = next;
+    }
+}
+
+/// Stop every task and close the peer connection and endpoint
+///
+/// Triggers the shared shutdown signal, gives relay tasks a moment to send
+/// `Close` on their streams, then closes the connection with a reason.
+pub async fn close_tunnel(state: &Arc<Mutex<TunnelState>>, endpoint: &Endpoint) {
+    let (draining, shutdown, peer_connection) = {
+        let state = state.lock().await;
+        (
+            state.draining.clone(),
+            state.shutdown.clone(),
+            state.peer_connection.clone(),
+        )
+    };
+
+    draining.trigger();
+    shutdown.trigger();
+    tokio::time::sleep(SHUTDOWN_GRACE).await;
+
+    if let Some(conn) = peer_connection {
+        conn.close(VarInt::from_u32(0), b"peer shutting down");
     }
+    endpoint.close().await;
 }
 
-pub async fn connect_to_peer(endpoint: &Endpoint, ticket: &str) -> Result<Connection> {
+pub async fn connect_to_peer(
+    endpoint: &Endpoint,
+    ticket: &str,
+    logger: &dyn Logger,
+) -> Result<Connection> {
     // Parse the ticket as a PublicKey (NodeId)
     let public_key: iroh::PublicKey = ticket
         .parse()
         .context("Failed to parse PublicKey from ticket")?;
 
-    println!("🔌 Attempting to connect to node: {}", public_key);
+    logger.info(&format!("🔌 Attempting to connect to node: {}", public_key));
 
     // Connect to the peer using the PublicKey
     // Iroh will use its discovery mechanisms to find the peer
@@ -135,63 +416,408 @@ pub async fn connect_to_peer(endpoint: &Endpoint, ticket: &str) -> Result<Connec
         .await
         .context("Failed to connect to peer")?;
 
-    println!("✅ Successfully connected to peer!");
+    logger.info("✅ Successfully connected to peer!");
 
     Ok(connection)
 }
 
-pub async fn generate_ticket(endpoint: &Endpoint) -> Result<String> {
-    // Generate a simple ticket with node ID
-    let node_id = endpoint.id();
-    Ok(format!("{}", node_id))
+/// Open a request stream: this node's `Hello`, then `token` as the `Auth`
+/// message that must precede Connect / Resolve
+pub async fn send_preamble(
+    send: &mut (impl AsyncWrite + Unpin),
+    features: Features,
+    token: Option<&AuthToken>,
+) -> Result<()> {
+    send_hello(send, features).await?;
+    if let Some(token) = token {
+        send_message(
+            send,
+            &TunnelMessage::Auth {
+                token: token.as_str().to_string(),
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Resolve `host` on the peer's side of the tunnel
+pub async fn resolve_via_peer(
+    connection: &Connection,
+    host: &str,
+    token: Option<&AuthToken>,
+) -> Result<Vec<IpAddr>> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send_preamble(&mut send, Features::supported(), token).await?;
+    send_message(
+        &mut send,
+        &TunnelMessage::Resolve {
+            host: host.to_string(),
+        },
+    )
+    .await?;
+    send.finish()?;
+
+    match recv_after_hello(&mut recv).await?.1 {
+        TunnelMessage::Resolved { addrs } => Ok(addrs),
+        TunnelMessage::Error { message } => anyhow::bail!("{}", message),
+        other => anyhow::bail!("Unexpected response to Resolve: {:?}", other),
+    }
+}
+
+/// Parse a peer given as an endpoint ticket or a bare node ID
+///
+/// Tickets carry direct addresses and a relay URL, which are used for the
+/// first connection; a bare node ID relies on discovery.
+pub fn parse_peer(s: &str) -> Result<EndpointAddr> {
+    let s = s.trim();
+    if let Ok(ticket) = s.parse::<EndpointTicket>() {
+        return Ok(ticket.into());
+    }
+    let node_id = s
+        .parse::<PublicKey>()
+        .with_context(|| format!("Failed to parse peer ticket: {}", s))?;
+    Ok(EndpointAddr::new(node_id))
+}
+
+/// Read peer tickets or node IDs from a file (`--peer-file`)
+///
+/// Surrounding whitespace is ignored; several peers may be given on separate
+/// lines or comma-separated, first is primary.
+pub async fn read_peer_file(path: &Path) -> Result<Vec<String>> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read peer file: {}", path.display()))?;
+    let tickets: Vec<String> = contents
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|ticket| !ticket.is_empty())
+        .map(str::to_string)
+        .collect();
+    if tickets.is_empty() {
+        anyhow::bail!("Peer file {} contains no ticket", path.display());
+    }
+    Ok(tickets)
+}
+
+/// Ticket for this node: its ID, current direct addresses and relay URLs
+///
+/// Besides the home relay the ticket lists every relay in `relays`, so a peer
+/// that can't reach one relay can fall back to another (see `dial_candidates`).
+pub fn generate_ticket(endpoint: &Endpoint, relays: &RelayMap) -> String {
+    let addr = endpoint.addr().with_addrs(
+        relays
+            .urls::<Vec<_>>()
+            .into_iter()
+            .map(TransportAddr::Relay),
+    );
+    EndpointTicket::new(addr).to_string()
+}
+
+/// Per-attempt limit when a peer has several candidate addresses to try
+pub const DIAL_CANDIDATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Addresses to dial `addr` with, in order
+///
+/// iroh only uses the first relay of an address, so a ticket listing several
+/// relays becomes one candidate per relay, each with all direct addresses.
+/// Addresses with at most one relay are dialled as they are.
+pub fn dial_candidates(addr: &EndpointAddr) -> Vec<EndpointAddr> {
+    let relays: Vec<_> = addr.relay_urls().cloned().collect();
+    if relays.len() <= 1 {
+        return vec![addr.clone()];
+    }
+    let direct: Vec<_> = addr.ip_addrs().copied().map(TransportAddr::Ip).collect();
+    relays
+        .into_iter()
+        .map(|relay| {
+            EndpointAddr::new(addr.id)
+                .with_relay_url(relay)
+                .with_addrs(direct.clone())
+        })
+        .collect()
+}
+
+/// Dial each candidate in turn until one connects
+///
+/// Every attempt but the last is limited to `per_attempt`, so an unreachable
+/// relay doesn't hold up the others; the last error is returned if all fail.
+pub async fn connect_candidates<T, F, Fut>(
+    candidates: Vec<EndpointAddr>,
+    per_attempt: Duration,
+    logger: &dyn Logger,
+    mut dial: F,
+) -> Result<T>
+where
+    F: FnMut(EndpointAddr) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let total = candidates.len();
+    let mut last_error = anyhow::anyhow!("No address to dial");
+    for (i, candidate) in candidates.into_iter().enumerate() {
+        if total > 1 {
+            let via = candidate
+                .relay_urls()
+                .next()
+                .map(|url| url.to_string())
+                .unwrap_or_else(|| "direct addresses".to_string());
+            logger.info(&format!("   ↪️  Attempt {}/{} via {}", i + 1, total, via));
+        }
+        let attempt = dial(candidate);
+        let result = if i + 1 < total {
+            tokio::time::timeout(per_attempt, attempt)
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out after {:?}", per_attempt)))
+        } else {
+            attempt.await
+        };
+        match result {
+            Ok(connected) => return Ok(connected),
+            Err(e) => {
+                if i + 1 < total {
+                    logger.warn(&format!("   ⚠️  Attempt {} failed: {}", i + 1, e));
+                }
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Connect to a peer from a ticket, trying each relay it lists in turn
+pub async fn connect_peer(
+    endpoint: &Endpoint,
+    addr: EndpointAddr,
+    logger: &dyn Logger,
+) -> Result<Connection> {
+    connect_candidates(
+        dial_candidates(&addr),
+        DIAL_CANDIDATE_TIMEOUT,
+        logger,
+        |candidate| async move { Ok(endpoint.connect(candidate, TUNNEL_ALPN).await?) },
+    )
+    .await
 }
 
-pub async fn handle_peer_connection(connection: Connection, endpoint: Endpoint) {
+/// Serve tunnel requests arriving on `connection` until it closes or shutdown
+///
+/// At most `max_streams` requests are relayed at once (0 = unlimited); streams
+/// opened beyond that are answered with an `Error` instead of being queued.
+///
+/// Every `accept_bi` error is a connection error, so it ends the handler. A
+/// failing request only ends its own stream's task.
+pub async fn handle_peer_connection(
+    connection: Connection,
+    endpoint: Endpoint,
+    stats: Arc<ConnectionStats>,
+    shutdown: Shutdown,
+    outbound: Arc<Outbound>,
+    max_streams: usize,
+    logger: SharedLogger,
+) {
     let remote_node_id = connection.remote_id();
-    let endpoint_clone = endpoint.clone();
+    let limiter = (max_streams > 0).then(|| Arc::new(Semaphore::new(max_streams)));
+    if let Some(quotas) = &outbound.quotas {
+        stats.count_towards(quotas.usage(remote_node_id));
+    }
+    let peer = Arc::new(PeerContext {
+        endpoint,
+        remote_node_id,
+        stats,
+        shutdown: shutdown.clone(),
+        outbound,
+        logger: logger.clone(),
+    });
 
     // Handle incoming tunnel requests from peer
     loop {
-        match connection.accept_bi().await {
+        let accepted = tokio::select! {
+            accepted = connection.accept_bi() => accepted,
+            _ = shutdown.wait() => break,
+        };
+        match accepted {
             Ok((mut send, mut recv)) => {
-                let endpoint = endpoint_clone.clone();
+                let permit = match &limiter {
+                    Some(limiter) => match Arc::clone(limiter).try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            let logger = logger.clone();
+                            tokio::spawn(async move {
+                                match reject_stream(&mut send, &mut recv).await {
+                                    Ok(false) => {}
+                                    Ok(true) => logger.warn(&format!(
+                                        "⚠️  Stream limit ({}) reached for peer {}, rejected request",
+                                        max_streams,
+                                        remote_node_id.fmt_short()
+                                    )),
+                                    Err(e) => logger.error(
+                                        None,
+                                        &format!("Failed to reject tunnel request: {}", e),
+                                    ),
+                                }
+                            });
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+
+                let peer = Arc::clone(&peer);
                 tokio::spawn(async move {
-                    if let Err(e) =
-                        handle_tunnel_request(&mut send, &mut recv, endpoint, remote_node_id).await
-                    {
-                        eprintln!("❌ Tunnel request error: {}", e);
+                    let _permit = permit;
+                    if let Err(e) = handle_tunnel_request(&mut send, &mut recv, &peer).await {
+                        peer.logger
+                            .error(None, &format!("Tunnel request error: {}", e));
                     }
                 });
             }
             Err(e) => {
-                eprintln!("❌ Failed to accept bi-stream: {}", e);
+                logger.error(None, &format!("Failed to accept bi-stream: {}", e));
                 break;
             }
         }
     }
 }
 
-pub async fn handle_tunnel_request(
+/// Answer a tunnel request over the stream limit with an `Error`
+///
+/// A health-check `Ping` doesn't count against the limit and still gets its
+/// `Pong`, so a busy peer isn't mistaken for a dead one. Returns whether the
+/// stream was a rejected request rather than a Ping.
+async fn reject_stream(
     send: &mut iroh::endpoint::SendStream,
     recv: &mut iroh::endpoint::RecvStream,
+) -> Result<bool> {
+    // Read the request first so the peer isn't reset while still writing it
+    let ping = matches!(recv_message(recv).await?, TunnelMessage::Ping);
+    let reply = match ping {
+        true => TunnelMessage::Pong,
+        false => TunnelMessage::Error {
+            message: "Too many concurrent streams".to_string(),
+        },
+    };
+    send_message(send, &reply).await?;
+    send.finish()?;
+    Ok(!ping)
+}
+
+/// `normalize_host`, answering the peer with an `Error` if the name is invalid
+async fn normalize_or_reject(
+    send: &mut iroh::endpoint::SendStream,
+    id: RequestId,
+    host: &str,
+    logger: &dyn Logger,
+) -> Result<Option<String>> {
+    match normalize_host(host) {
+        Ok(normalized) => {
+            if normalized != host {
+                logger.info(&format!(
+                    "   {} 🔤 Normalized to {}",
+                    id,
+                    target(&normalized)
+                ));
+            }
+            Ok(Some(normalized))
+        }
+        Err(e) => {
+            logger.error(Some(id), detail(&e.to_string()));
+            send_message(
+                send,
+                &TunnelMessage::Error {
+                    message: e.to_string(),
+                },
+            )
+            .await?;
+            Ok(None)
+        }
+    }
+}
+
+/// What a peer's tunnel requests are served with
+struct PeerContext {
     endpoint: Endpoint,
-    remote_node_id: iroh::PublicKey,
+    remote_node_id: PublicKey,
+    stats: Arc<ConnectionStats>,
+    shutdown: Shutdown,
+    outbound: Arc<Outbound>,
+    logger: SharedLogger,
+}
+
+async fn handle_tunnel_request(
+    send: &mut iroh::endpoint::SendStream,
+    recv: &mut iroh::endpoint::RecvStream,
+    peer: &PeerContext,
 ) -> Result<()> {
-    // Read the connect message
-    let msg = recv_message(recv).await?;
+    let PeerContext {
+        endpoint,
+        remote_node_id,
+        stats,
+        shutdown,
+        outbound,
+        logger,
+    } = peer;
+    let remote_node_id = *remote_node_id;
+    let _stream = stats.open_stream();
+
+    // Read the connect message, after the peer's Hello and auth token if it
+    // sends them; a Hello is answered with ours
+    let (hello, mut msg) = recv_after_hello(recv).await?;
+    if hello.is_some() {
+        send_hello(send, outbound.relay.features()).await?;
+    }
+    let relay = outbound.relay.negotiated(hello.unwrap_or_default());
+    let id = RequestId::next();
+    let mut token = None;
+    if let TunnelMessage::Auth { token: presented } = msg {
+        // Only a peer that advertised AUTH may authenticate
+        token = hello
+            .is_some_and(|features| features.contains(Features::AUTH))
+            .then_some(presented);
+        msg = recv_message(recv).await?;
+    }
+    let needs_auth = matches!(
+        msg,
+        TunnelMessage::Connect { .. } | TunnelMessage::Resolve { .. }
+    );
+    if needs_auth && !outbound.is_authorized(token.as_deref()) {
+        logger.warn(&format!(
+            "🔒 {} Rejected request from {} with {} auth token",
+            id,
+            remote_node_id.fmt_short(),
+            if token.is_some() { "a wrong" } else { "no" }
+        ));
+        send_message(
+            send,
+            &TunnelMessage::Error {
+                message: AUTH_FAILED_MESSAGE.to_string(),
+            },
+        )
+        .await?;
+        return Ok(());
+    }
 
     match msg {
         TunnelMessage::Connect { host, port } => {
-            let log_prefix = format!("\n📤 {}", format_log("OUTGOING", &host, port));
-            println!("{}", log_prefix);
-            log_connection_details(&endpoint, remote_node_id, "   ℹ️  Connection Info:");
+            let _request = stats.start_request();
+            logger.request_start(id, "OUTGOING", &host, port);
+            logger.info(&format_connection_details(
+                endpoint,
+                remote_node_id,
+                &format!("   {} ℹ️  Connection Info:", id),
+                stats.rtt(),
+            ));
+            let Some(host) = normalize_or_reject(send, id, &host, &**logger).await? else {
+                return Ok(());
+            };
 
             // LOOP PREVENTION: Check if the destination is localhost on our SOCKS port
             if is_loopback_address(&host, port) {
-                eprintln!(
-                    "⚠️  Loop detected! Rejecting connection to {}:{}",
-                    host, port
-                );
+                stats.record_loop_rejected();
+                logger.warn(&format!(
+                    "⚠️  {} Loop detected! Rejecting connection to {}:{}",
+                    id,
+                    target(&host),
+                    port
+                ));
                 send_message(
                     send,
                     &TunnelMessage::Error {
@@ -202,23 +828,100 @@ pub async fn handle_tunnel_request(
                 return Ok(());
             }
 
-            // Connect to the actual destination
-            match TcpStream::connect(format!("{}:{}", host, port)).await {
+            if let Some(quotas) = &outbound.quotas
+                && let Err(reason) = quotas.check(remote_node_id)
+            {
+                logger.warn(&format!(
+                    "🚫 {} Peer {} refused: {}",
+                    id,
+                    remote_node_id.fmt_short(),
+                    reason
+                ));
+                send_message(send, &TunnelMessage::Error { message: reason }).await?;
+                return Ok(());
+            }
+
+            // Connect to the actual destination, unless we're going away
+            let connected = tokio::select! {
+                result = outbound.connect(&host, port) => result,
+                _ = shutdown.wait() => {
+                    send_message(
+                        send,
+                        &relay.close_message(CLOSE_SHUTTING_DOWN, "Exit node is shutting down"),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+            match connected {
                 Ok(remote) => {
-                    println!("✅ {}", format_log("CONNECTED", &host, port));
+                    logger.info(&format!("✅ {}", format_log(id, "CONNECTED", &host, port)));
                     send_message(send, &TunnelMessage::Connected).await?;
 
                     // Relay data bidirectionally
-                    let (sent, received, sni) = relay_bidirectional(send, recv, remote).await;
-                    println!(
-                        "   📊 Stats: ↑ {} bytes sent, ↓ {} bytes received{}",
+                    let (sent, received, protocol, sni) = relay_bidirectional(
+                        send,
+                        recv,
+                        remote,
+                        stats,
+                        shutdown,
+                        (id, &**logger),
+                        &relay,
+                    )
+                    .await;
+                    logger.request_end(
+                        id,
                         sent,
                         received,
-                        sni.map(|s| format!(" (SNI: {})", s)).unwrap_or_default()
+                        path_type_label(&current_connection_type(endpoint, remote_node_id)),
+                        protocol,
+                        sni.as_deref(),
                     );
                 }
-                Err(e) => {
-                    eprintln!("❌ Failed to connect to {}:{}: {}", host, port, e);
+                Err(OutboundError::NotAllowed(reason)) => {
+                    logger.warn(&format!(
+                        "🚫 {} Destination {}:{} not allowed: {}",
+                        id,
+                        target(&host),
+                        port,
+                        detail(&reason)
+                    ));
+                    send_message(
+                        send,
+                        &TunnelMessage::Error {
+                            message: format!("{}: {}", NOT_ALLOWED_MESSAGE, reason),
+                        },
+                    )
+                    .await?;
+                }
+                Err(OutboundError::Loop(reason)) => {
+                    stats.record_loop_rejected();
+                    logger.warn(&format!(
+                        "⚠️  {} Loop detected! {}:{} leads back here: {}",
+                        id,
+                        target(&host),
+                        port,
+                        reason
+                    ));
+                    send_message(
+                        send,
+                        &TunnelMessage::Error {
+                            message: "Loop detected: destination is the exit node itself"
+                                .to_string(),
+                        },
+                    )
+                    .await?;
+                }
+                Err(OutboundError::Failed(e)) => {
+                    logger.error(
+                        Some(id),
+                        &format!(
+                            "Failed to connect to {}:{}: {}",
+                            target(&host),
+                            port,
+                            detail(&e.to_string())
+                        ),
+                    );
                     send_message(
                         send,
                         &TunnelMessage::Error {
@@ -229,10 +932,762 @@ pub async fn handle_tunnel_request(
                 }
             }
         }
-        _ => {
-            eprintln!("❌ Unexpected message type");
+        TunnelMessage::Resolve { host } => {
+            logger.request_start(id, "RESOLVE", &host, 0);
+            let Some(host) = normalize_or_reject(send, id, &host, &**logger).await? else {
+                return Ok(());
+            };
+            let reply = match outbound.resolve(&host).await {
+                Ok(addrs) => TunnelMessage::Resolved { addrs },
+                Err(OutboundError::NotAllowed(reason)) => TunnelMessage::Error {
+                    message: format!("{}: {}", NOT_ALLOWED_MESSAGE, reason),
+                },
+                Err(e) => TunnelMessage::Error {
+                    message: format!("Resolution failed: {}", e),
+                },
+            };
+            send_message(send, &reply).await?;
+        }
+        TunnelMessage::Ping => {
+            send_message(send, &TunnelMessage::Pong).await?;
+        }
+        other => {
+            logger.error(Some(id), &format!("Unexpected message type: {:?}", other));
+            send_message(
+                send,
+                &relay.close_message(CLOSE_PROTOCOL_ERROR, "Expected Connect, Resolve or Ping"),
+            )
+            .await?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::filter::HostFilter;
+    use crate::tunnel::logger::StdoutLogger;
+    use crate::tunnel::quota::{PeerQuotas, QUOTA_EXCEEDED_MESSAGE};
+    use crate::tunnel::relay::RelayOptions;
+    use crate::tunnel::test_support::{connected_pair, echo_server};
+    use iroh::SecretKey;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_peer_from_ticket() {
+        let id = SecretKey::generate(&mut rand::rng()).public();
+        let addr = EndpointAddr::new(id).with_ip_addr("192.0.2.7:4433".parse().unwrap());
+        let ticket = EndpointTicket::new(addr.clone()).to_string();
+
+        assert_eq!(parse_peer(&ticket).unwrap(), addr);
+    }
+
+    #[test]
+    fn test_multi_address_ticket_round_trips() {
+        let id = SecretKey::generate(&mut rand::rng()).public();
+        let relays: Vec<iroh::RelayUrl> =
+            ["https://relay-a.example.com", "https://relay-b.example.com"]
+                .iter()
+                .map(|url| url.parse().unwrap())
+                .collect();
+        let addr = EndpointAddr::new(id)
+            .with_relay_url(relays[0].clone())
+            .with_relay_url(relays[1].clone())
+            .with_ip_addr("192.0.2.7:4433".parse().unwrap())
+            .with_ip_addr("[2001:db8::7]:4433".parse().unwrap());
+        let parsed = parse_peer(&EndpointTicket::new(addr.clone()).to_string()).unwrap();
+        assert_eq!(parsed, addr);
+
+        // One candidate per relay, each keeping both direct addresses
+        let candidates = dial_candidates(&parsed);
+        assert_eq!(candidates.len(), 2);
+        for (candidate, relay) in candidates.iter().zip(&relays) {
+            assert_eq!(candidate.relay_urls().collect::<Vec<_>>(), vec![relay]);
+            assert_eq!(candidate.ip_addrs().count(), 2);
+        }
+        assert_eq!(
+            dial_candidates(&EndpointAddr::new(id)),
+            vec![EndpointAddr::new(id)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_candidates_tries_each_in_turn() {
+        let id = SecretKey::generate(&mut rand::rng()).public();
+        let candidates: Vec<_> = [
+            "https://a.example.com",
+            "https://b.example.com",
+            "https://c.example.com",
+        ]
+        .iter()
+        .map(|url| EndpointAddr::new(id).with_relay_url(url.parse().unwrap()))
+        .collect();
+
+        // a fails, b hangs past the per-attempt limit, c connects
+        let mut dialled = Vec::new();
+        let connected = connect_candidates(
+            candidates.clone(),
+            Duration::from_millis(100),
+            &StdoutLogger,
+            |candidate| {
+                let url = candidate.relay_urls().next().unwrap().to_string();
+                dialled.push(url.clone());
+                async move {
+                    if url.contains("//a.") {
+                        anyhow::bail!("relay unreachable");
+                    }
+                    if url.contains("//b.") {
+                        std::future::pending::<()>().await;
+                    }
+                    Ok(url)
+                }
+            },
+        )
+        .await
+        .unwrap();
+        assert!(connected.contains("//c."));
+        assert_eq!(dialled.len(), 3);
+
+        let err = connect_candidates(
+            candidates,
+            Duration::from_millis(100),
+            &StdoutLogger,
+            |_| async { Err::<(), _>(anyhow::anyhow!("relay unreachable")) },
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("relay unreachable"));
+    }
+
+    #[test]
+    fn test_parse_peer_from_node_id() {
+        let id = SecretKey::generate(&mut rand::rng()).public();
+        let addr = parse_peer(&format!(" {} ", id)).unwrap();
+        assert_eq!(addr, EndpointAddr::new(id));
+    }
+
+    #[tokio::test]
+    async fn test_read_peer_file() {
+        let path = std::env::temp_dir().join(format!("tunnel-peer-file-{}", std::process::id()));
+        let id = SecretKey::generate(&mut rand::rng()).public();
+        let ticket = EndpointTicket::new(EndpointAddr::new(id)).to_string();
+        tokio::fs::write(&path, format!("\n  {}\n", ticket))
+            .await
+            .unwrap();
+
+        let tickets = read_peer_file(&path).await.unwrap();
+        assert_eq!(tickets, vec![ticket.clone()]);
+        assert_eq!(parse_peer(&tickets[0]).unwrap(), EndpointAddr::new(id));
+
+        tokio::fs::write(&path, format!("{},{}\n", ticket, id))
+            .await
+            .unwrap();
+        assert_eq!(read_peer_file(&path).await.unwrap().len(), 2);
+
+        tokio::fs::write(&path, " \n").await.unwrap();
+        let err = read_peer_file(&path).await.unwrap_err();
+        assert!(err.to_string().contains("contains no ticket"));
+
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert!(read_peer_file(&path).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_peer_rejects_garbage() {
+        let err = parse_peer("not-a-peer").unwrap_err();
+        assert!(err.to_string().contains("Failed to parse peer ticket"));
+        assert!(parse_peer("").is_err());
+    }
+
+    /// Open a tunnel stream and send Connect; the streams are returned so they stay open
+    async fn request(
+        conn: &Connection,
+        port: u16,
+    ) -> (
+        TunnelMessage,
+        iroh::endpoint::SendStream,
+        iroh::endpoint::RecvStream,
+    ) {
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send_message(
+            &mut send,
+            &TunnelMessage::Connect {
+                host: "127.0.0.1".to_string(),
+                port,
+            },
+        )
+        .await
+        .unwrap();
+        let reply = recv_message(&mut recv).await.unwrap();
+        (reply, send, recv)
+    }
+
+    #[tokio::test]
+    async fn test_stream_limit_rejects_excess_requests() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = destination.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = destination.accept().await {
+                held.push(socket);
+            }
+        });
+
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        tokio::spawn(handle_peer_connection(
+            server_conn,
+            server.clone(),
+            Arc::new(ConnectionStats::new()),
+            Shutdown::new(),
+            Arc::new(Outbound::default()),
+            1,
+            SharedLogger::default(),
+        ));
+
+        let (reply, _send, _recv) = request(&client_conn, port).await;
+        assert!(matches!(reply, TunnelMessage::Connected));
+        match request(&client_conn, port).await.0 {
+            TunnelMessage::Error { message } => assert!(message.contains("Too many")),
+            other => panic!("expected Error, got {:?}", other),
+        }
+
+        // The heartbeat is still answered while the limit is reached
+        let (mut send, mut recv) = client_conn.open_bi().await.unwrap();
+        send_message(&mut send, &TunnelMessage::Ping).await.unwrap();
+        assert!(matches!(
+            recv_message(&mut recv).await.unwrap(),
+            TunnelMessage::Pong
+        ));
+        ping_peer(&client_conn).await.unwrap();
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_resolve_round_trip() {
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        tokio::spawn(handle_peer_connection(
+            server_conn,
+            server.clone(),
+            Arc::new(ConnectionStats::new()),
+            Shutdown::new(),
+            Arc::new(Outbound {
+                host_filter: HostFilter::new(vec![], vec!["blocked.example".parse().unwrap()]),
+                ..Default::default()
+            }),
+            0,
+            SharedLogger::default(),
+        ));
+
+        let addrs = resolve_via_peer(&client_conn, "127.0.0.1", None)
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec![IpAddr::from([127, 0, 0, 1])]);
+
+        let err = resolve_via_peer(&client_conn, "blocked.example", None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with(NOT_ALLOWED_MESSAGE));
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_hosts_normalized_before_outbound() {
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        tokio::spawn(handle_peer_connection(
+            server_conn,
+            server.clone(),
+            Arc::new(ConnectionStats::new()),
+            Shutdown::new(),
+            Arc::new(Outbound {
+                host_filter: HostFilter::new(
+                    vec![],
+                    vec!["xn--bcher-kva.example".parse().unwrap()],
+                ),
+                ..Default::default()
+            }),
+            0,
+            SharedLogger::default(),
+        ));
+
+        // A trailing dot names the same host
+        let addrs = resolve_via_peer(&client_conn, "127.0.0.1.", None)
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec![IpAddr::from([127, 0, 0, 1])]);
+
+        let echo = echo_server().await;
+        let (mut send, mut recv) = client_conn.open_bi().await.unwrap();
+        send_message(
+            &mut send,
+            &TunnelMessage::Connect {
+                host: "localhost.".to_string(),
+                port: echo.port(),
+            },
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            recv_message(&mut recv).await.unwrap(),
+            TunnelMessage::Connected
+        ));
+
+        // The filter sees the punycode form of a Unicode name
+        let err = resolve_via_peer(&client_conn, "BÜCHER.example.", None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with(NOT_ALLOWED_MESSAGE), "{}", err);
+
+        let err = resolve_via_peer(&client_conn, "bad host.example", None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid hostname"), "{}", err);
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_request_into_own_listener_is_rejected() {
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        // Stands in for the exit node's own SOCKS5 port
+        let own = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let own_addr = own.local_addr().unwrap();
+        let stats = Arc::new(ConnectionStats::new());
+        tokio::spawn(handle_peer_connection(
+            server_conn,
+            server.clone(),
+            stats.clone(),
+            Shutdown::new(),
+            Arc::new(Outbound {
+                local_listeners: vec![own_addr],
+                ..Default::default()
+            }),
+            0,
+            SharedLogger::default(),
+        ));
+
+        let (mut send, mut recv) = client_conn.open_bi().await.unwrap();
+        send_message(
+            &mut send,
+            &TunnelMessage::Connect {
+                host: "localhost".to_string(),
+                port: own_addr.port(),
+            },
+        )
+        .await
+        .unwrap();
+        match recv_message(&mut recv).await.unwrap() {
+            TunnelMessage::Error { message } => {
+                assert!(message.starts_with("Loop detected"), "{}", message)
+            }
+            other => panic!("expected a loop error, got {:?}", other),
+        }
+        assert_eq!(stats.snapshot().loops_rejected, 1);
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_hello_is_answered_with_features() {
+        let echo = echo_server().await;
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        tokio::spawn(handle_peer_connection(
+            server_conn,
+            server.clone(),
+            Arc::new(ConnectionStats::new()),
+            Shutdown::new(),
+            Arc::new(Outbound {
+                relay: RelayOptions {
+                    send_stats: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            0,
+            SharedLogger::default(),
+        ));
+
+        let (mut send, mut recv) = client_conn.open_bi().await.unwrap();
+        send_preamble(&mut send, Features::supported(), None)
+            .await
+            .unwrap();
+        send_message(
+            &mut send,
+            &TunnelMessage::Connect {
+                host: "127.0.0.1".to_string(),
+                port: echo.port(),
+            },
+        )
+        .await
+        .unwrap();
+        let (features, response) = recv_after_hello(&mut recv).await.unwrap();
+        assert_eq!(features, Some(Features::supported().with(Features::STATS)));
+        assert!(matches!(response, TunnelMessage::Connected));
+
+        // Without a Hello there is none in the answer either
+        let (response, _send, _recv) = request(&client_conn, echo.port()).await;
+        assert!(matches!(response, TunnelMessage::Connected));
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_auth_token_required_for_requests() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = destination.local_addr().unwrap().port();
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        tokio::spawn(handle_peer_connection(
+            server_conn,
+            server.clone(),
+            Arc::new(ConnectionStats::new()),
+            Shutdown::new(),
+            Arc::new(Outbound {
+                auth_token: Some(AuthToken::new("letmein")),
+                ..Default::default()
+            }),
+            0,
+            SharedLogger::default(),
+        ));
+
+        let connect = |features: Features, token: Option<AuthToken>| {
+            let client_conn = client_conn.clone();
+            async move {
+                let (mut send, mut recv) = client_conn.open_bi().await.unwrap();
+                send_preamble(&mut send, features, token.as_ref())
+                    .await
+                    .unwrap();
+                send_message(
+                    &mut send,
+                    &TunnelMessage::Connect {
+                        host: "127.0.0.1".to_string(),
+                        port,
+                    },
+                )
+                .await
+                .unwrap();
+                recv_after_hello(&mut recv).await.unwrap().1
+            }
+        };
+        let supported = Features::supported();
+        assert!(matches!(
+            connect(supported, Some(AuthToken::new("letmein"))).await,
+            TunnelMessage::Connected
+        ));
+        // A token is only taken from a peer that advertised AUTH
+        let rejected = [
+            (supported, None),
+            (supported, Some(AuthToken::new("letmeout"))),
+            (Features::CLOSE_REASON, Some(AuthToken::new("letmein"))),
+        ];
+        for (features, token) in rejected {
+            match connect(features, token).await {
+                TunnelMessage::Error { message } => assert_eq!(message, AUTH_FAILED_MESSAGE),
+                other => panic!("expected Error, got {:?}", other),
+            }
+        }
+
+        // Resolve is checked too; health pings are not
+        let good = AuthToken::new("letmein");
+        assert!(
+            resolve_via_peer(&client_conn, "127.0.0.1", Some(&good))
+                .await
+                .is_ok()
+        );
+        let err = resolve_via_peer(&client_conn, "127.0.0.1", None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), AUTH_FAILED_MESSAGE);
+        ping_peer(&client_conn).await.unwrap();
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_peer_over_quota_is_refused() {
+        let echo = echo_server().await;
+        let outbound = Arc::new(Outbound {
+            quotas: Some(PeerQuotas::new(1000, Duration::from_secs(60))),
+            ..Default::default()
+        });
+        let mut pairs = Vec::new();
+        for _ in 0..2 {
+            let (client, server, client_conn, server_conn) = connected_pair().await;
+            tokio::spawn(handle_peer_connection(
+                server_conn,
+                server.clone(),
+                Arc::new(ConnectionStats::new()),
+                Shutdown::new(),
+                Arc::clone(&outbound),
+                0,
+                SharedLogger::default(),
+            ));
+            pairs.push((client, server, client_conn));
+        }
+        let (heavy, light) = (&pairs[0].2, &pairs[1].2);
+
+        // Push 600 bytes each way through the echo destination
+        let (reply, mut send, mut recv) = request(heavy, echo.port()).await;
+        assert!(matches!(reply, TunnelMessage::Connected));
+        send_message(&mut send, &TunnelMessage::Data { data: vec![0; 600] })
+            .await
+            .unwrap();
+        let mut echoed = 0;
+        while echoed < 600 {
+            match recv_message(&mut recv).await.unwrap() {
+                TunnelMessage::Data { data } => echoed += data.len(),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+
+        match request(heavy, echo.port()).await.0 {
+            TunnelMessage::Error { message } => {
+                assert!(message.starts_with(QUOTA_EXCEEDED_MESSAGE), "{}", message)
+            }
+            other => panic!("expected Error, got {:?}", other),
+        }
+        assert!(matches!(
+            request(light, echo.port()).await.0,
+            TunnelMessage::Connected
+        ));
+
+        for (client, server, _) in pairs {
+            client.close().await;
+            server.close().await;
+        }
+    }
+
+    #[test]
+    fn test_reconnect_delay_jitter_within_bounds() {
+        let mut rng = rand::rng();
+        for attempts in 0..10 {
+            let nominal =
+                (RECONNECT_BASE_DELAY * 2_u32.pow(attempts.min(6))).min(RECONNECT_MAX_DELAY);
+            for _ in 0..100 {
+                let delay = reconnect_delay(attempts, &mut rng);
+                assert!(delay >= nominal.mul_f64(0.75), "{:?} too short", delay);
+                assert!(delay <= nominal.mul_f64(1.25), "{:?} too long", delay);
+                assert!(delay <= RECONNECT_MAX_DELAY);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_monitor_uses_configured_interval() {
+        let endpoint = Endpoint::empty_builder(iroh::RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let state = Arc::new(Mutex::new(TunnelState {
+            remote_peer_id: Some(SecretKey::generate(&mut rand::rng()).public()),
+            ..Default::default()
+        }));
+        let shutdown = state.lock().await.shutdown.clone();
+        tokio::spawn(monitor_connection_health(
+            Arc::clone(&state),
+            endpoint.clone(),
+            Duration::from_millis(20),
+        ));
+
+        // The default 5s interval would not have ticked yet
+        let started = Instant::now();
+        while !state.lock().await.reconnect_in_progress {
+            assert!(
+                started.elapsed() < Duration::from_secs(1),
+                "monitor did not attempt reconnection"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        shutdown.trigger();
+        endpoint.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_ping_answered_with_pong() {
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        tokio::spawn(handle_peer_connection(
+            server_conn,
+            server.clone(),
+            Arc::new(ConnectionStats::new()),
+            Shutdown::new(),
+            Arc::new(Outbound::default()),
+            0,
+            SharedLogger::default(),
+        ));
+
+        ping_peer(&client_conn).await.unwrap();
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_bad_streams_dont_end_peer_handler() {
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        let handler = tokio::spawn(handle_peer_connection(
+            server_conn,
+            server.clone(),
+            Arc::new(ConnectionStats::new()),
+            Shutdown::new(),
+            Arc::new(Outbound::default()),
+            0,
+            SharedLogger::default(),
+        ));
+
+        // A stream reset mid-message, and a frame that isn't a message
+        let (mut send, _recv) = client_conn.open_bi().await.unwrap();
+        send.write_all(&[0, 0]).await.unwrap();
+        send.reset(VarInt::from_u32(7)).unwrap();
+        let (mut send, _recv) = client_conn.open_bi().await.unwrap();
+        send.write_all(&[0, 0, 0, 4, 0xff, 0xff, 0xff, 0xff])
+            .await
+            .unwrap();
+        send.finish().unwrap();
+
+        // Requests on the same connection are still served
+        ping_peer(&client_conn).await.unwrap();
+        ping_peer(&client_conn).await.unwrap();
+        assert!(!handler.is_finished());
+
+        // while the connection failing ends the handler at once
+        client_conn.close(VarInt::from_u32(0), b"bye");
+        tokio::time::timeout(Duration::from_secs(2), handler)
+            .await
+            .expect("peer handler outlived its connection")
+            .unwrap();
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_stalled_peer_triggers_reconnection() {
+        // The server accepts the connection but never serves its streams
+        let (client, server, client_conn, _server_conn) = connected_pair().await;
+        let state = Arc::new(Mutex::new(TunnelState {
+            remote_peer_id: Some(server.id()),
+            heartbeat_timeout: Duration::from_millis(100),
+            ..Default::default()
+        }));
+        state.lock().await.install_connection(client_conn.clone());
+        let shutdown = state.lock().await.shutdown.clone();
+        tokio::spawn(monitor_connection_health(
+            Arc::clone(&state),
+            client.clone(),
+            Duration::from_millis(20),
+        ));
+
+        let started = Instant::now();
+        loop {
+            {
+                let state = state.lock().await;
+                if state.peer_connection.is_none() && state.reconnect_in_progress {
+                    break;
+                }
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(2),
+                "stalled peer was not detected"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(client_conn.close_reason().is_some());
+
+        shutdown.trigger();
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_network_change_skips_backoff() {
+        let (client, server, _client_conn, _server_conn) = connected_pair().await;
+        // Deep into the backoff: the next regular attempt would wait 48s or more
+        let state = Arc::new(Mutex::new(TunnelState {
+            remote_peer_id: Some(server.id()),
+            reconnect_attempts: 6,
+            ..Default::default()
+        }));
+        let mut events = state.lock().await.events.subscribe();
+        let addrs = n0_watcher::Watchable::new(
+            EndpointAddr::new(client.id()).with_ip_addr("192.168.1.20:41641".parse().unwrap()),
+        );
+        tokio::spawn(watch_network_changes(
+            Arc::clone(&state),
+            client.clone(),
+            addrs.watch(),
+        ));
+
+        // A relay change alone is not a network change
+        let relay: iroh::RelayUrl = "https://relay.example.com".parse().unwrap();
+        addrs.set(addrs.get().with_relay_url(relay)).ok();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(state.lock().await.network_changed, None);
+        assert!(!state.lock().await.reconnect_in_progress);
+
+        addrs
+            .set(EndpointAddr::new(client.id()).with_ip_addr("10.64.0.3:41641".parse().unwrap()))
+            .ok();
+        let event = tokio::time::timeout(Duration::from_secs(2), events.recv())
+            .await
+            .expect("no immediate reconnection attempt");
+        assert_eq!(event.unwrap(), TunnelEvent::Connecting);
+        // Used up by this attempt, so the next one backs off again
+        assert_eq!(state.lock().await.network_changed, None);
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_events_follow_connect_disconnect_cycle() {
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        let state = Arc::new(Mutex::new(TunnelState {
+            remote_peer_id: Some(server.id()),
+            heartbeat_timeout: Duration::ZERO,
+            ..Default::default()
+        }));
+        let mut events = state.lock().await.events.subscribe();
+        let shutdown = state.lock().await.shutdown.clone();
+
+        state.lock().await.install_connection(client_conn);
+        tokio::spawn(monitor_connection_health(
+            Arc::clone(&state),
+            client.clone(),
+            Duration::from_millis(20),
+        ));
+        server_conn.close(VarInt::from_u32(0), b"bye");
+
+        let mut lifecycle = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while lifecycle.len() < 3 {
+                match events.recv().await.unwrap() {
+                    TunnelEvent::RttUpdated { .. } => {}
+                    event => lifecycle.push(event),
+                }
+            }
+        })
+        .await
+        .expect("missing lifecycle events");
+        assert_eq!(
+            lifecycle,
+            vec![
+                TunnelEvent::Connected { peer: server.id() },
+                TunnelEvent::Disconnected,
+                TunnelEvent::Connecting,
+            ]
+        );
+
+        shutdown.trigger();
+        client.close().await;
+        server.close().await;
+    }
+}
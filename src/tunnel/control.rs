@@ -0,0 +1,373 @@
+// Line-based command interface on a unix socket (`--control-socket`)
+use iroh::endpoint::VarInt;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Mutex;
+
+use crate::metrics::status::{StatusReport, stats_dump};
+use crate::tunnel::persistence::{Profile, clear_remote_peer_id};
+use crate::tunnel::state::TunnelState;
+
+/// Longest command line accepted; longer lines end the session
+pub const MAX_COMMAND_LINE: usize = 256;
+
+/// Commands understood on the control socket, one per line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlCommand {
+    /// The `--status-socket` JSON document, on one line
+    Status,
+    /// Current, pinned and routed peers
+    Peers,
+    /// The SIGUSR1 stats dump
+    Stats,
+    /// Close the peer connection so it is re-established
+    Reconnect,
+    /// Unpin the `--sticky-peer` peer so the next one to connect is pinned
+    ForgetPeer,
+    Help,
+    Quit,
+}
+
+const HELP: &str = "Commands:
+  status       Status as JSON
+  peers        Current, pinned and routed peers
+  stats        Traffic counters
+  reconnect    Drop the peer connection and connect again
+  forget-peer  Unpin the sticky peer
+  quit         Close this session";
+
+/// Parse one command line; `None` for a blank line
+pub fn parse_command(line: &str) -> Option<Result<ControlCommand, String>> {
+    let mut words = line.split_whitespace();
+    let name = words.next()?;
+    if let Some(extra) = words.next() {
+        return Some(Err(format!("{} takes no arguments, got '{}'", name, extra)));
+    }
+    let command = match name.to_ascii_lowercase().as_str() {
+        "status" => ControlCommand::Status,
+        "peers" => ControlCommand::Peers,
+        "stats" => ControlCommand::Stats,
+        "reconnect" => ControlCommand::Reconnect,
+        "forget-peer" => ControlCommand::ForgetPeer,
+        "help" | "?" => ControlCommand::Help,
+        "quit" | "exit" => ControlCommand::Quit,
+        _ => return Some(Err(format!("Unknown command '{}', try 'help'", name))),
+    };
+    Some(Ok(command))
+}
+
+/// What the commands act on
+pub struct ControlContext {
+    pub node_id: iroh::PublicKey,
+    pub started_at: Instant,
+    pub state: Arc<Mutex<TunnelState>>,
+    /// Profile whose saved peer `forget-peer` removes, under `--sticky-peer`
+    pub sticky: Option<Profile>,
+}
+
+impl ControlContext {
+    /// Run `command` and return its reply, without a trailing newline
+    pub async fn execute(&self, command: ControlCommand) -> String {
+        match command {
+            ControlCommand::Status => {
+                let state = self.state.lock().await;
+                let report = StatusReport::new(self.node_id, self.started_at, &state);
+                serde_json::to_string(&report).unwrap_or_else(|e| format!("error: {}", e))
+            }
+            ControlCommand::Peers => peers(&*self.state.lock().await),
+            ControlCommand::Stats => stats_dump(&*self.state.lock().await),
+            ControlCommand::Reconnect => reconnect(&mut *self.state.lock().await),
+            ControlCommand::ForgetPeer => self.forget_peer().await,
+            ControlCommand::Help => HELP.to_string(),
+            ControlCommand::Quit => "bye".to_string(),
+        }
+    }
+
+    async fn forget_peer(&self) -> String {
+        let (peer, logger) = {
+            let mut state = self.state.lock().await;
+            let Some(peer) = state.pinned_peer.take() else {
+                return "No pinned peer".to_string();
+            };
+            (peer, state.logger.clone())
+        };
+        if let Some(profile) = &self.sticky
+            && let Err(e) = clear_remote_peer_id(profile, &*logger).await
+        {
+            return format!("Unpinned {}, but its saved copy remains: {}", peer, e);
+        }
+        logger.info(&format!("📌 Unpinned peer {} on request", peer));
+        format!("Unpinned {}; the next peer to connect is pinned", peer)
+    }
+}
+
+/// One line per known peer: `current`, `pinned` and `routed`
+fn peers(state: &TunnelState) -> String {
+    let mut lines = Vec::new();
+    let current = match &state.peer_connection {
+        Some(conn) => Some(conn.remote_id()),
+        None => state.remote_peer_id,
+    };
+    if let Some(peer) = current {
+        lines.push(format!(
+            "current {} {}",
+            peer,
+            state.connection_state.label()
+        ));
+    }
+    if let Some(peer) = state.pinned_peer {
+        lines.push(format!("pinned  {}", peer));
+    }
+    let mut routed: Vec<_> = state.routed_connections.keys().collect();
+    routed.sort();
+    lines.extend(routed.into_iter().map(|peer| format!("routed  {}", peer)));
+    match lines.is_empty() {
+        true => "No peers".to_string(),
+        false => lines.join("\n"),
+    }
+}
+
+/// Close the peer connection; the health monitor (or the client, on a server)
+/// connects again. Also lifts a `Failed` state so retries resume.
+fn reconnect(state: &mut TunnelState) -> String {
+    state.reset_reconnect();
+    match &state.peer_connection {
+        Some(conn) if conn.close_reason().is_none() => {
+            let peer = conn.remote_id();
+            conn.close(VarInt::from_u32(0), b"reconnect requested");
+            state
+                .logger
+                .info(&format!("🔄 Reconnect to {} requested", peer.fmt_short()));
+            format!("Closed the connection to {}", peer)
+        }
+        _ if state.reconnect_expected() => "Not connected, reconnection is pending".to_string(),
+        _ => "Not connected and no peer to reconnect to".to_string(),
+    }
+}
+
+/// Serve `ctx`'s commands on the unix socket at `path` until shutdown
+///
+/// Each client gets a session: one command per line, each answered with its
+/// reply followed by a blank line. A stale socket file is replaced.
+#[cfg(unix)]
+pub async fn serve_control(path: std::path::PathBuf, ctx: ControlContext) -> anyhow::Result<()> {
+    use crate::tunnel::unix_socket::bind_private_unix;
+
+    // Commands change the tunnel, so only this user may send them
+    let listener = bind_private_unix(&path, "control")?;
+    let (shutdown, logger) = {
+        let state = ctx.state.lock().await;
+        (state.shutdown.clone(), state.logger.clone())
+    };
+    logger.info(&format!(
+        "🎛️  Control commands on unix socket {}",
+        path.display()
+    ));
+
+    let ctx = Arc::new(ctx);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.wait() => break,
+        };
+        let socket = match accepted {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                logger.error(None, &format!("Failed to accept control client: {}", e));
+                continue;
+            }
+        };
+        let ctx = Arc::clone(&ctx);
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control_session(socket, &ctx).await {
+                logger.error(None, &format!("Control session error: {}", e));
+            }
+        });
+    }
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn control_session(
+    socket: tokio::net::UnixStream,
+    ctx: &ControlContext,
+) -> anyhow::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let (read, mut write) = socket.into_split();
+    let mut reader = BufReader::new(read);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let limit = MAX_COMMAND_LINE as u64 + 1;
+        if (&mut reader)
+            .take(limit)
+            .read_until(b'\n', &mut buf)
+            .await?
+            == 0
+        {
+            break;
+        }
+        if !buf.ends_with(b"\n") && buf.len() > MAX_COMMAND_LINE {
+            anyhow::bail!("Command line longer than {} bytes", MAX_COMMAND_LINE);
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let reply = match parse_command(&line) {
+            None => continue,
+            Some(Ok(command)) => {
+                let reply = ctx.execute(command).await;
+                if command == ControlCommand::Quit {
+                    write.write_all(format!("{}\n", reply).as_bytes()).await?;
+                    break;
+                }
+                reply
+            }
+            Some(Err(e)) => format!("error: {}", e),
+        };
+        write.write_all(format!("{}\n\n", reply).as_bytes()).await?;
+    }
+    write.shutdown().await.ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::state::ConnectionState;
+
+    fn node_id() -> iroh::PublicKey {
+        iroh::SecretKey::generate(&mut rand::rng()).public()
+    }
+
+    fn context(state: TunnelState) -> ControlContext {
+        ControlContext {
+            node_id: node_id(),
+            started_at: Instant::now(),
+            state: Arc::new(Mutex::new(state)),
+            sticky: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("status"), Some(Ok(ControlCommand::Status)));
+        assert_eq!(
+            parse_command("  PEERS \r\n"),
+            Some(Ok(ControlCommand::Peers))
+        );
+        assert_eq!(
+            parse_command("forget-peer"),
+            Some(Ok(ControlCommand::ForgetPeer))
+        );
+        assert_eq!(parse_command("?"), Some(Ok(ControlCommand::Help)));
+        assert_eq!(parse_command("exit"), Some(Ok(ControlCommand::Quit)));
+        assert_eq!(parse_command(""), None);
+        assert_eq!(parse_command("   \n"), None);
+
+        let err = parse_command("restart").unwrap().unwrap_err();
+        assert_eq!(err, "Unknown command 'restart', try 'help'");
+        let err = parse_command("reconnect now").unwrap().unwrap_err();
+        assert_eq!(err, "reconnect takes no arguments, got 'now'");
+    }
+
+    #[tokio::test]
+    async fn test_status_and_peers_commands() {
+        let peer = node_id();
+        let pinned = node_id();
+        let ctx = context(TunnelState {
+            connection_state: ConnectionState::Connecting,
+            remote_peer_id: Some(peer),
+            pinned_peer: Some(pinned),
+            ..Default::default()
+        });
+
+        let json = ctx.execute(ControlCommand::Status).await;
+        assert!(!json.contains('\n'));
+        let report: StatusReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(report.connection_state, "connecting");
+        assert_eq!(report.peer_id, Some(peer.to_string()));
+
+        assert_eq!(
+            ctx.execute(ControlCommand::Peers).await,
+            format!("current {} connecting\npinned  {}", peer, pinned)
+        );
+        assert_eq!(
+            context(TunnelState::default())
+                .execute(ControlCommand::Peers)
+                .await,
+            "No peers"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forget_peer_and_reconnect_commands() {
+        let pinned = node_id();
+        let ctx = context(TunnelState {
+            connection_state: ConnectionState::Failed,
+            remote_peer_id: Some(node_id()),
+            pinned_peer: Some(pinned),
+            ..Default::default()
+        });
+
+        let reply = ctx.execute(ControlCommand::ForgetPeer).await;
+        assert!(reply.starts_with(&format!("Unpinned {}", pinned)));
+        assert_eq!(ctx.state.lock().await.pinned_peer, None);
+        assert_eq!(
+            ctx.execute(ControlCommand::ForgetPeer).await,
+            "No pinned peer"
+        );
+
+        // A peer that was given up on is retried again
+        assert_eq!(
+            ctx.execute(ControlCommand::Reconnect).await,
+            "Not connected, reconnection is pending"
+        );
+        let state = ctx.state.lock().await;
+        assert_eq!(state.connection_state, ConnectionState::Disconnected);
+        assert_eq!(state.reconnect_attempts, 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_control_socket_session() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let dir = std::env::temp_dir().join(format!("tunnel-control-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+        let ctx = context(TunnelState::default());
+        let shutdown = ctx.state.lock().await.shutdown.clone();
+        let server = tokio::spawn(serve_control(path.clone(), ctx));
+
+        let stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        let (read, mut write) = stream.into_split();
+        write.write_all(b"peers\n\nbogus\nquit\n").await.unwrap();
+        let mut lines = BufReader::new(read).lines();
+        let mut replies = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            replies.push(line);
+        }
+        assert_eq!(
+            replies,
+            [
+                "No peers",
+                "",
+                "error: Unknown command 'bogus', try 'help'",
+                "",
+                "bye"
+            ]
+        );
+
+        shutdown.trigger();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+        let _ = std::fs::remove_dir(&dir);
+    }
+}
@@ -0,0 +1,259 @@
+// Exit-side DNS resolution with an optional TTL cache
+use anyhow::Result;
+use idna::AsciiDenyList;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+
+/// Default bound on cached hosts so a peer can't grow the cache without limit
+pub const DEFAULT_DNS_CACHE_SIZE: usize = 4096;
+
+#[derive(Debug)]
+struct CachedLookup {
+    addrs: Vec<IpAddr>,
+    expires_at: Instant,
+    /// Value of `Entries::clock` when the entry was last read or written
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    hosts: HashMap<String, CachedLookup>,
+    /// Ticks on every access; orders entries by recency for LRU eviction
+    clock: u64,
+}
+
+/// Resolves destination hosts, remembering results for `ttl`
+///
+/// At most `max_entries` hosts are kept; when full, expired entries are
+/// dropped first, then the least recently used one. A zero TTL or size (the
+/// default) disables caching and every lookup hits the system resolver. IP
+/// literals are never cached.
+#[derive(Debug, Default)]
+pub struct DnsCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<Entries>,
+}
+
+impl DnsCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Resolve `host` (domain or IP literal, IPv6 optionally bracketed)
+    pub async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        if let Some(addrs) = self.get(&host, Instant::now()) {
+            return Ok(addrs);
+        }
+
+        let addrs: Vec<IpAddr> = lookup_host((host.as_str(), 0))
+            .await?
+            .map(|addr| addr.ip())
+            .collect();
+        self.insert(&host, addrs.clone(), Instant::now());
+        Ok(addrs)
+    }
+
+    /// Answer lookups of `host` with `addrs`, standing in for the resolver
+    #[cfg(test)]
+    pub(crate) fn seed(&self, host: &str, addrs: Vec<IpAddr>) {
+        self.insert(host, addrs, Instant::now());
+    }
+
+    fn get(&self, host: &str, now: Instant) -> Option<Vec<IpAddr>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entry = entries
+            .hosts
+            .get_mut(host)
+            .filter(|entry| entry.expires_at > now)?;
+        entry.last_used = clock;
+        Some(entry.addrs.clone())
+    }
+
+    fn insert(&self, host: &str, addrs: Vec<IpAddr>, now: Instant) {
+        if self.ttl.is_zero() || self.max_entries == 0 || addrs.is_empty() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        if !entries.hosts.contains_key(host) && entries.hosts.len() >= self.max_entries {
+            entries.hosts.retain(|_, entry| entry.expires_at > now);
+            if entries.hosts.len() >= self.max_entries
+                && let Some(lru) = entries
+                    .hosts
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(host, _)| host.clone())
+            {
+                entries.hosts.remove(&lru);
+            }
+        }
+        entries.hosts.insert(
+            host.to_string(),
+            CachedLookup {
+                addrs,
+                expires_at: now + self.ttl,
+                last_used: clock,
+            },
+        );
+    }
+}
+
+/// Hostname as it should be resolved and matched against rules
+///
+/// Strips one trailing dot (`example.com.` is the same host) and converts
+/// internationalized names to punycode (`bücher.example` becomes
+/// `xn--bcher-kva.example`), lowercasing on the way. IP literals, including
+/// bracketed IPv6, are returned unchanged.
+pub fn normalize_host(host: &str) -> Result<String> {
+    if host.parse::<IpAddr>().is_ok() || host.starts_with('[') {
+        return Ok(host.to_string());
+    }
+    let name = host.strip_suffix('.').unwrap_or(host);
+    if name.is_empty() {
+        anyhow::bail!("Empty hostname");
+    }
+    match idna::domain_to_ascii_cow(name.as_bytes(), AsciiDenyList::URL) {
+        Ok(ascii) => Ok(ascii.into_owned()),
+        Err(_) => anyhow::bail!("Invalid hostname {:?}", host),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("example.com.").unwrap(), "example.com");
+        assert_eq!(normalize_host("Example.COM").unwrap(), "example.com");
+        assert_eq!(
+            normalize_host("bücher.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            normalize_host("例え.テスト.").unwrap(),
+            "xn--r8jz45g.xn--zckzah"
+        );
+        assert_eq!(normalize_host("10.0.0.1").unwrap(), "10.0.0.1");
+        assert_eq!(normalize_host("[2001:db8::1]").unwrap(), "[2001:db8::1]");
+
+        assert!(normalize_host(".").is_err());
+        assert!(normalize_host("bad host.example").is_err());
+    }
+
+    fn addr(ip: &str) -> Vec<IpAddr> {
+        vec![ip.parse().unwrap()]
+    }
+
+    #[test]
+    fn test_cache_hit_until_ttl_expires() {
+        let cache = DnsCache::new(Duration::from_secs(60), DEFAULT_DNS_CACHE_SIZE);
+        let now = Instant::now();
+        cache.insert("example.com", addr("93.184.216.34"), now);
+
+        assert_eq!(
+            cache.get("example.com", now + Duration::from_secs(59)),
+            Some(addr("93.184.216.34"))
+        );
+        assert_eq!(
+            cache.get("example.com", now + Duration::from_secs(60)),
+            None
+        );
+        assert_eq!(cache.get("other.com", now), None);
+    }
+
+    #[test]
+    fn test_zero_ttl_disables_cache() {
+        let cache = DnsCache::default();
+        let now = Instant::now();
+        cache.insert("example.com", addr("93.184.216.34"), now);
+        assert_eq!(cache.get("example.com", now), None);
+    }
+
+    #[test]
+    fn test_cache_is_bounded() {
+        let cache = DnsCache::new(Duration::from_secs(60), DEFAULT_DNS_CACHE_SIZE);
+        let now = Instant::now();
+        for i in 0..DEFAULT_DNS_CACHE_SIZE + 10 {
+            let at = now + Duration::from_millis(i as u64);
+            cache.insert(&format!("host{}.example", i), addr("192.0.2.1"), at);
+        }
+        assert_eq!(
+            cache.entries.lock().unwrap().hosts.len(),
+            DEFAULT_DNS_CACHE_SIZE
+        );
+        // The least recently used entries were evicted first
+        assert_eq!(cache.get("host0.example", now), None);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = DnsCache::new(Duration::from_secs(60), 2);
+        let now = Instant::now();
+        cache.insert("a.example", addr("192.0.2.1"), now);
+        cache.insert("b.example", addr("192.0.2.2"), now);
+        // Reading a makes b the least recently used
+        assert!(cache.get("a.example", now).is_some());
+        cache.insert("c.example", addr("192.0.2.3"), now);
+
+        assert_eq!(cache.get("a.example", now), Some(addr("192.0.2.1")));
+        assert_eq!(cache.get("b.example", now), None);
+        assert_eq!(cache.get("c.example", now), Some(addr("192.0.2.3")));
+    }
+
+    #[test]
+    fn test_expired_entries_evicted_before_live_ones() {
+        let cache = DnsCache::new(Duration::from_secs(60), 2);
+        let now = Instant::now();
+        cache.insert("old.example", addr("192.0.2.1"), now);
+        let later = now + Duration::from_secs(45);
+        cache.insert("live.example", addr("192.0.2.2"), later);
+        // old is the most recently used, but it has expired
+        assert!(cache.get("old.example", now).is_some());
+        cache.insert(
+            "new.example",
+            addr("192.0.2.3"),
+            later + Duration::from_secs(30),
+        );
+
+        let hosts = &cache.entries.lock().unwrap().hosts;
+        assert!(hosts.contains_key("live.example"));
+        assert!(hosts.contains_key("new.example"));
+        assert!(!hosts.contains_key("old.example"));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_populates_cache() {
+        let cache = DnsCache::new(Duration::from_secs(60), DEFAULT_DNS_CACHE_SIZE);
+        let addrs = cache.lookup("localhost").await.unwrap();
+        assert!(!addrs.is_empty());
+        assert_eq!(cache.get("localhost", Instant::now()), Some(addrs));
+    }
+
+    #[tokio::test]
+    async fn test_ip_literals_are_not_cached() {
+        let cache = DnsCache::new(Duration::from_secs(60), DEFAULT_DNS_CACHE_SIZE);
+        assert_eq!(cache.lookup("127.0.0.1").await.unwrap(), addr("127.0.0.1"));
+        assert_eq!(cache.lookup("[::1]").await.unwrap(), addr("::1"));
+        assert!(cache.entries.lock().unwrap().hosts.is_empty());
+    }
+}
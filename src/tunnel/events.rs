@@ -0,0 +1,70 @@
+// Connection lifecycle events for UIs and embedders
+use iroh::PublicKey;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Events buffered per subscriber before the oldest are dropped
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Something that happened to the tunnel
+#[derive(Clone, Debug, PartialEq)]
+pub enum TunnelEvent {
+    /// A reconnection attempt is dialling the peer
+    Connecting,
+    /// A peer connection was established
+    Connected { peer: PublicKey },
+    /// The peer connection was lost
+    Disconnected,
+    /// Reconnection gave up after `max_reconnect_attempts`
+    Failed,
+    /// New smoothed round-trip time to the peer
+    RttUpdated { rtt: Duration },
+    /// A local SOCKS / transparent client asked for a destination
+    Request { host: String, port: u16 },
+}
+
+/// Broadcast channel the tunnel publishes `TunnelEvent`s to
+///
+/// Publishing never blocks. A subscriber that falls more than
+/// `EVENT_CAPACITY` events behind loses the oldest ones and its next `recv`
+/// returns `RecvError::Lagged` with the number skipped.
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<TunnelEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Self { sender }
+    }
+}
+
+impl EventBus {
+    /// Send `event` to current subscribers (dropped if there are none)
+    pub fn publish(&self, event: TunnelEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Receive every event published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::error::RecvError;
+
+    #[tokio::test]
+    async fn test_slow_subscriber_lags() {
+        let events = EventBus::default();
+        let mut rx = events.subscribe();
+        for _ in 0..EVENT_CAPACITY + 5 {
+            events.publish(TunnelEvent::Disconnected);
+        }
+        assert!(matches!(rx.recv().await, Err(RecvError::Lagged(5))));
+        assert_eq!(rx.recv().await.unwrap(), TunnelEvent::Disconnected);
+    }
+}
@@ -0,0 +1,72 @@
+// Upstream peer failover
+
+/// Pick the peer to try on the next (re)connection attempt
+///
+/// Starts from the last peer we were successfully connected to and moves one
+/// candidate further along the list for every failed attempt, wrapping around.
+/// With no candidates configured (server mode) the last-good peer is reused.
+pub fn select_peer(
+    candidates: &[iroh::PublicKey],
+    last_good: Option<iroh::PublicKey>,
+    failed_attempts: u32,
+) -> Option<iroh::PublicKey> {
+    if candidates.is_empty() {
+        return last_good;
+    }
+
+    let start = last_good
+        .and_then(|peer| candidates.iter().position(|c| *c == peer))
+        .unwrap_or(0);
+    let index = (start + failed_attempts as usize) % candidates.len();
+    Some(candidates[index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    fn random_peer_id() -> iroh::PublicKey {
+        SecretKey::generate(&mut rand::rng()).public()
+    }
+
+    #[test]
+    fn test_select_peer_no_candidates_uses_last_good() {
+        let peer = random_peer_id();
+        assert_eq!(select_peer(&[], Some(peer), 3), Some(peer));
+        assert_eq!(select_peer(&[], None, 0), None);
+    }
+
+    #[test]
+    fn test_select_peer_starts_with_primary() {
+        let candidates = vec![random_peer_id(), random_peer_id()];
+        assert_eq!(select_peer(&candidates, None, 0), Some(candidates[0]));
+    }
+
+    #[test]
+    fn test_select_peer_prefers_last_good() {
+        let candidates = vec![random_peer_id(), random_peer_id(), random_peer_id()];
+        assert_eq!(
+            select_peer(&candidates, Some(candidates[2]), 0),
+            Some(candidates[2])
+        );
+    }
+
+    #[test]
+    fn test_select_peer_promotes_backup_after_failure() {
+        let candidates = vec![random_peer_id(), random_peer_id(), random_peer_id()];
+        let last_good = Some(candidates[1]);
+        assert_eq!(select_peer(&candidates, last_good, 1), Some(candidates[2]));
+        assert_eq!(select_peer(&candidates, last_good, 2), Some(candidates[0]));
+        assert_eq!(select_peer(&candidates, last_good, 3), Some(candidates[1]));
+    }
+
+    #[test]
+    fn test_select_peer_unknown_last_good_starts_at_primary() {
+        let candidates = vec![random_peer_id(), random_peer_id()];
+        assert_eq!(
+            select_peer(&candidates, Some(random_peer_id()), 1),
+            Some(candidates[1])
+        );
+    }
+}
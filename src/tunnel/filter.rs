@@ -0,0 +1,341 @@
+// Destination allow/deny rules for outgoing connections
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// One destination rule: `example.com`, `*.example.com`, `10.0.0.0/8` or a bare IP
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostRule {
+    /// Matches this domain only (case-insensitive)
+    Exact(String),
+    /// `*.example.com`: matches any subdomain, but not `example.com` itself
+    Wildcard(String),
+    /// Network address and prefix length; a bare IP is a /32 or /128
+    Cidr(IpAddr, u8),
+}
+
+/// Lowercase a domain and strip a trailing dot, or IPv6 brackets
+fn normalize_host(host: &str) -> String {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Keep only the first `prefix` bits of `bytes`
+fn mask_bits(bytes: &mut [u8], prefix: u8) {
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let bits = (prefix as usize).saturating_sub(i * 8).min(8);
+        *byte &= !(0xffu16 >> bits) as u8;
+    }
+}
+
+fn mask(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mut octets = ip.octets();
+            mask_bits(&mut octets, prefix);
+            IpAddr::from(octets)
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            mask_bits(&mut octets, prefix);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+impl FromStr for HostRule {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> Result<Self> {
+        let rule = rule.trim();
+        if let Some((addr, prefix)) = rule.split_once('/') {
+            let addr: IpAddr = addr
+                .parse()
+                .with_context(|| format!("Invalid CIDR address: {}", rule))?;
+            let prefix: u8 = prefix
+                .parse()
+                .with_context(|| format!("Invalid CIDR prefix: {}", rule))?;
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            if prefix > max {
+                anyhow::bail!("CIDR prefix out of range: {}", rule);
+            }
+            return Ok(HostRule::Cidr(mask(addr, prefix), prefix));
+        }
+
+        let host = normalize_host(rule);
+        if let Ok(addr) = host.parse::<IpAddr>() {
+            let prefix = if addr.is_ipv4() { 32 } else { 128 };
+            return Ok(HostRule::Cidr(addr, prefix));
+        }
+        if let Some(suffix) = host.strip_prefix("*.") {
+            if suffix.is_empty() || suffix.contains('*') {
+                anyhow::bail!("Invalid wildcard rule: {}", rule);
+            }
+            return Ok(HostRule::Wildcard(suffix.to_string()));
+        }
+        if host.is_empty() || host.contains('*') {
+            anyhow::bail!("Invalid host rule: {}", rule);
+        }
+        Ok(HostRule::Exact(host))
+    }
+}
+
+impl HostRule {
+    /// Whether this rule matches a requested host (domain or IP literal)
+    pub fn matches_host(&self, host: &str) -> bool {
+        let host = normalize_host(host);
+        let ip = host.parse::<IpAddr>().ok();
+        self.matches(&host, ip)
+    }
+
+    /// Whether this rule matches a normalized host (domain or IP literal)
+    fn matches(&self, host: &str, ip: Option<IpAddr>) -> bool {
+        match self {
+            HostRule::Exact(domain) => host == domain,
+            HostRule::Wildcard(suffix) => host
+                .strip_suffix(suffix.as_str())
+                .is_some_and(|rest| rest.len() > 1 && rest.ends_with('.')),
+            HostRule::Cidr(network, prefix) => ip.is_some_and(|ip| {
+                ip.is_ipv4() == network.is_ipv4() && mask(ip, *prefix) == *network
+            }),
+        }
+    }
+}
+
+fn is_private_ipv4(ip: Ipv4Addr) -> bool {
+    ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+}
+
+fn is_private_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(mapped) = ip.to_ipv4_mapped() {
+        return is_private_ipv4(mapped);
+    }
+    ip.is_loopback() || ip.is_unspecified() || ip.is_unique_local() || ip.is_unicast_link_local()
+}
+
+/// Whether `ip` is loopback, private (RFC 1918), link-local, unique-local or unspecified
+///
+/// IPv4-mapped IPv6 addresses are checked as the IPv4 address they carry.
+pub fn is_private_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_ipv4(ip),
+        IpAddr::V6(ip) => is_private_ipv6(ip),
+    }
+}
+
+/// Allow/deny rules checked before connecting to a requested destination
+///
+/// Deny rules are evaluated first. With no allow rules every destination that
+/// isn't denied is allowed; otherwise it must also match an allow rule. CIDR
+/// rules only match IP literals, a domain is not resolved here.
+///
+/// With `block_private` set, the addresses a destination resolves to are also
+/// checked with `is_resolved_allowed` before connecting.
+#[derive(Clone, Debug, Default)]
+pub struct HostFilter {
+    allow: Vec<HostRule>,
+    deny: Vec<HostRule>,
+    block_private: bool,
+}
+
+impl HostFilter {
+    pub fn new(allow: Vec<HostRule>, deny: Vec<HostRule>) -> Self {
+        Self {
+            allow,
+            deny,
+            block_private: false,
+        }
+    }
+
+    /// Refuse destinations that resolve to private or local addresses
+    pub fn block_private(mut self, block: bool) -> Self {
+        self.block_private = block;
+        self
+    }
+
+    /// Parse a rules file: one `allow <rule>` or `deny <rule>` per line, `#` comments
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut filter = Self::default();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (action, rule) = line
+                .split_once(char::is_whitespace)
+                .with_context(|| format!("line {}: expected `allow|deny <rule>`", number + 1))?;
+            let rule: HostRule = rule
+                .parse()
+                .with_context(|| format!("line {}", number + 1))?;
+            match action {
+                "allow" => filter.allow.push(rule),
+                "deny" => filter.deny.push(rule),
+                _ => anyhow::bail!("line {}: unknown action `{}`", number + 1, action),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Add the rules of `other` to this filter
+    pub fn extend(&mut self, other: HostFilter) {
+        self.allow.extend(other.allow);
+        self.deny.extend(other.deny);
+    }
+
+    pub fn blocks_private(&self) -> bool {
+        self.block_private
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty() && !self.block_private
+    }
+
+    /// Whether a tunnel request to `host` may be connected
+    pub fn is_allowed(&self, host: &str) -> bool {
+        let host = normalize_host(host);
+        let ip = host.parse::<IpAddr>().ok();
+        if self.deny.iter().any(|rule| rule.matches(&host, ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(&host, ip))
+    }
+
+    /// Whether a resolved destination address may be connected to
+    pub fn is_resolved_allowed(&self, addr: IpAddr) -> bool {
+        !(self.block_private && is_private_address(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<HostRule> {
+        rules.iter().map(|r| r.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_wildcard_matches_subdomains_only() {
+        let filter = HostFilter::new(rules(&["*.example.com"]), vec![]);
+        assert!(filter.is_allowed("www.example.com"));
+        assert!(filter.is_allowed("a.b.EXAMPLE.com."));
+        assert!(!filter.is_allowed("example.com"));
+        assert!(!filter.is_allowed("badexample.com"));
+        assert!(!filter.is_allowed("example.com.evil.net"));
+    }
+
+    #[test]
+    fn test_exact_domain_match() {
+        let filter = HostFilter::new(vec![], rules(&["tracker.example.com"]));
+        assert!(!filter.is_allowed("Tracker.Example.com"));
+        assert!(filter.is_allowed("www.example.com"));
+    }
+
+    #[test]
+    fn test_cidr_matching() {
+        let filter = HostFilter::new(vec![], rules(&["10.0.0.0/8", "192.168.1.7", "fd00::/8"]));
+        assert!(!filter.is_allowed("10.1.2.3"));
+        assert!(filter.is_allowed("11.0.0.1"));
+        assert!(!filter.is_allowed("192.168.1.7"));
+        assert!(filter.is_allowed("192.168.1.8"));
+        assert!(!filter.is_allowed("[fd12:3456:789a:0001:0000:0000:0000:0001]"));
+        assert!(filter.is_allowed("[2001:0db8:0000:0000:0000:0000:0000:0001]"));
+    }
+
+    #[test]
+    fn test_cidr_host_bits_are_masked() {
+        assert_eq!(
+            "172.16.5.4/12".parse::<HostRule>().unwrap(),
+            HostRule::Cidr("172.16.0.0".parse().unwrap(), 12)
+        );
+        assert!("10.0.0.0/33".parse::<HostRule>().is_err());
+    }
+
+    #[test]
+    fn test_deny_evaluated_before_allow() {
+        let filter = HostFilter::new(rules(&["*.example.com"]), rules(&["admin.example.com"]));
+        assert!(filter.is_allowed("www.example.com"));
+        assert!(!filter.is_allowed("admin.example.com"));
+        assert!(!filter.is_allowed("other.org"));
+    }
+
+    #[test]
+    fn test_empty_filter_allows_everything() {
+        let filter = HostFilter::default();
+        assert!(filter.is_empty());
+        assert!(filter.is_allowed("anything.example"));
+    }
+
+    fn assert_private(addrs: &[&str]) {
+        for addr in addrs {
+            let ip: IpAddr = addr.parse().unwrap();
+            assert!(is_private_address(ip), "{} should be blocked", addr);
+        }
+    }
+
+    #[test]
+    fn test_private_loopback() {
+        assert_private(&["127.0.0.1", "127.255.255.254", "::1"]);
+    }
+
+    #[test]
+    fn test_private_rfc1918() {
+        assert_private(&[
+            "10.0.0.1",
+            "10.255.255.255",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.0.1",
+            "192.168.255.255",
+        ]);
+    }
+
+    #[test]
+    fn test_private_link_local() {
+        assert_private(&["169.254.0.1", "169.254.169.254", "fe80::1", "febf::1"]);
+    }
+
+    #[test]
+    fn test_private_unique_local() {
+        assert_private(&["fc00::1", "fd12:3456:789a::1"]);
+    }
+
+    #[test]
+    fn test_private_unspecified_and_mapped() {
+        assert_private(&["0.0.0.0", "::", "::ffff:10.0.0.1", "::ffff:127.0.0.1"]);
+    }
+
+    #[test]
+    fn test_public_addresses_not_private() {
+        for addr in [
+            "1.1.1.1",
+            "172.32.0.1",
+            "192.169.0.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(!is_private_address(addr.parse().unwrap()), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn test_block_private_only_when_enabled() {
+        let loopback = "127.0.0.1".parse().unwrap();
+        assert!(HostFilter::default().is_resolved_allowed(loopback));
+        let filter = HostFilter::default().block_private(true);
+        assert!(!filter.is_resolved_allowed(loopback));
+        assert!(filter.is_resolved_allowed("93.184.216.34".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_rules_file() {
+        let filter = HostFilter::parse(
+            "# proxy policy\nallow *.example.com\n\ndeny 10.0.0.0/8  # internal\n",
+        )
+        .unwrap();
+        assert_eq!(filter.allow, rules(&["*.example.com"]));
+        assert_eq!(filter.deny, rules(&["10.0.0.0/8"]));
+        assert!(HostFilter::parse("block example.com").is_err());
+        assert!(HostFilter::parse("allow").is_err());
+    }
+}
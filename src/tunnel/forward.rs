@@ -0,0 +1,282 @@
+// Fixed port forwards (`--forward 127.0.0.1:5432:db.internal:5432`)
+//
+// Each rule listens locally and tunnels every accepted connection to one
+// destination on the peer, with no SOCKS negotiation, for clients such as
+// database tools that can't use a proxy.
+use anyhow::{Context, Result};
+use iroh::endpoint::Endpoint;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use crate::tunnel::socks::{LocalProtocol, accept_clients, forward_to_peer};
+use crate::tunnel::state::TunnelState;
+
+/// Listen on `listen` and tunnel each connection to `host:port`
+///
+/// Parsed from `[ADDR:]PORT:HOST:PORT`; without an address the listener is
+/// on localhost. IPv6 addresses go in brackets, e.g. `[::1]:5432:[fd00::5]:5432`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortForward {
+    pub listen: SocketAddr,
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for PortForward {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> Result<Self> {
+        let invalid = || format!("Forward must be [ADDR:]PORT:HOST:PORT: {}", rule);
+        let (rest, port) = rule.rsplit_once(':').with_context(invalid)?;
+        let port: u16 = port.parse().with_context(invalid)?;
+        let (listen, host) = match rest.rfind('[') {
+            Some(start) if rest.ends_with(']') => {
+                let listen = rest[..start].strip_suffix(':').with_context(invalid)?;
+                (listen, &rest[start..])
+            }
+            _ => rest.rsplit_once(':').with_context(invalid)?,
+        };
+        if host.is_empty() || port == 0 {
+            anyhow::bail!(invalid());
+        }
+        let listen = match listen.parse::<u16>() {
+            Ok(port) => SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            Err(_) => listen
+                .parse()
+                .with_context(|| format!("Invalid listen address in forward: {}", rule))?,
+        };
+        Ok(Self {
+            listen,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for PortForward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} → {}:{}", self.listen, self.host, self.port)
+    }
+}
+
+/// Accept connections on `listener` and tunnel each to the forward's destination
+pub async fn serve_forward(
+    listener: TcpListener,
+    forward: PortForward,
+    state: Arc<Mutex<TunnelState>>,
+    endpoint: Endpoint,
+) {
+    let (tcp_options, logger) = {
+        let state = state.lock().await;
+        (state.tcp_options.clone(), state.logger.clone())
+    };
+    let (listener, tcp_options, accept_logger) = (&listener, &tcp_options, &logger);
+    let accept = move || async move {
+        let (socket, addr) = listener.accept().await?;
+        tcp_options.apply_or_warn(&socket, &**accept_logger);
+        Ok((socket, addr))
+    };
+    let serve = |socket: TcpStream, addr: SocketAddr, admitted: bool| {
+        let state = Arc::clone(&state);
+        let endpoint = endpoint.clone();
+        let (host, port) = (forward.host.clone(), forward.port);
+        let logger = logger.clone();
+        async move {
+            // Over the limit the connection is just closed
+            if !admitted {
+                return;
+            }
+            if let Err(e) = forward_to_peer(
+                socket,
+                addr,
+                host,
+                port,
+                state,
+                endpoint,
+                LocalProtocol::Forward,
+            )
+            .await
+            {
+                logger.error(None, &format!("Port forward error from {}: {}", addr, e));
+            }
+        }
+    };
+    accept_clients("forwarded", accept, &state, serve).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::test_support::{TunnelHarness, echo_server};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_parse_forward_rules() {
+        let forward: PortForward = "127.0.0.1:5432:db.internal:5432".parse().unwrap();
+        assert_eq!(forward.listen, "127.0.0.1:5432".parse().unwrap());
+        assert_eq!((forward.host.as_str(), forward.port), ("db.internal", 5432));
+
+        let forward: PortForward = "6379:cache.lan:6379".parse().unwrap();
+        assert_eq!(forward.listen, "127.0.0.1:6379".parse().unwrap());
+
+        let forward: PortForward = "[::1]:8080:[fd00::5]:80".parse().unwrap();
+        assert_eq!(forward.listen, "[::1]:8080".parse().unwrap());
+        assert_eq!((forward.host.as_str(), forward.port), ("[fd00::5]", 80));
+        assert_eq!(forward.to_string(), "[::1]:8080 → [fd00::5]:80");
+
+        for bad in [
+            "db.internal:5432",
+            "5432:db.internal",
+            "5432::5432",
+            "5432:db.internal:0",
+            "nowhere:5432:db.internal:5432",
+            "127.0.0.1:5432:[fd00::5:80",
+        ] {
+            assert!(bad.parse::<PortForward>().is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_relays_to_fixed_destination() {
+        let harness = TunnelHarness::start().await;
+        let destination = echo_server().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let forward = PortForward {
+            listen: local,
+            host: destination.ip().to_string(),
+            port: destination.port(),
+        };
+        tokio::spawn(serve_forward(
+            listener,
+            forward,
+            Arc::clone(&harness.client_state),
+            harness.client_endpoint(),
+        ));
+
+        // Plain TCP: whatever the client sends goes straight to the destination
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(local).await.unwrap();
+            stream.write_all(b"SELECT 1;").await.unwrap();
+            let mut echoed = [0u8; 9];
+            stream.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(&echoed, b"SELECT 1;");
+        }
+
+        assert_eq!(harness.server_stats.snapshot().requests, 2);
+        harness.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_forward_logs_through_state_logger() {
+        use crate::tunnel::logger::SharedLogger;
+        use crate::tunnel::test_support::CapturingLogger;
+
+        let harness = TunnelHarness::start().await;
+        let client_logger = CapturingLogger::default();
+        harness.client_state.lock().await.logger =
+            SharedLogger::new(Box::new(client_logger.clone()));
+        let destination = echo_server().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let forward = PortForward {
+            listen: local,
+            host: destination.ip().to_string(),
+            port: destination.port(),
+        };
+        tokio::spawn(serve_forward(
+            listener,
+            forward,
+            Arc::clone(&harness.client_state),
+            harness.client_endpoint(),
+        ));
+
+        let request = b"GET /health HTTP/1.1\r\nHost: db.internal\r\n\r\n";
+        let mut stream = TcpStream::connect(local).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut echoed = vec![0u8; request.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        drop(stream);
+
+        // Both relays report the request line to their logger, not stdout
+        let logged = |logger: &CapturingLogger| {
+            logger
+                .lines()
+                .iter()
+                .any(|line| line.starts_with("info ") && line.contains("🌐 HTTP: GET /health"))
+        };
+        for _ in 0..100 {
+            if logged(&client_logger) && logged(&harness.server_logger) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(logged(&client_logger), "{:?}", client_logger.lines());
+        assert!(
+            logged(&harness.server_logger),
+            "{:?}",
+            harness.server_logger.lines()
+        );
+        harness.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_forward_shares_connection_limit_with_socks() {
+        use crate::tunnel::socks::serve_socks_with;
+        use std::time::Duration;
+
+        let harness = TunnelHarness::start().await;
+        harness.client_state.lock().await.max_socks_clients = 1;
+        let destination = echo_server().await;
+        let socks = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_addr = socks.local_addr().unwrap();
+        tokio::spawn(serve_socks_with(
+            socks,
+            Arc::clone(&harness.client_state),
+            harness.forwarder(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let forward = PortForward {
+            listen: local,
+            host: destination.ip().to_string(),
+            port: destination.port(),
+        };
+        tokio::spawn(serve_forward(
+            listener,
+            forward,
+            Arc::clone(&harness.client_state),
+            harness.client_endpoint(),
+        ));
+
+        // A SOCKS client that never negotiates holds the only slot
+        let held = TcpStream::connect(socks_addr).await.unwrap();
+        let active = Arc::clone(&harness.client_state.lock().await.active_socks_clients);
+        while active.load(std::sync::atomic::Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut refused = TcpStream::connect(local).await.unwrap();
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), refused.read(&mut buf))
+            .await
+            .expect("forward over the limit was not closed");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        // Once it leaves, the forward is served again
+        drop(held);
+        while active.load(std::sync::atomic::Ordering::Relaxed) != 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut stream = TcpStream::connect(local).await.unwrap();
+        stream.write_all(b"SELECT 1;").await.unwrap();
+        let mut echoed = [0u8; 9];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"SELECT 1;");
+        harness.close().await;
+    }
+}
@@ -1,2 +1,176 @@
-// Tunnel handler - stub for now
-// TODO: Extract handle_tunnel_request, relay_bidirectional, etc.
+// Pluggable handling of negotiated SOCKS requests
+use anyhow::Result;
+use iroh::endpoint::Endpoint;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::socks5::protocol::{reply_addr_type, socks_reply};
+use crate::tunnel::socks::{LocalProtocol, LocalStream, forward_to_peer};
+use crate::tunnel::state::TunnelState;
+
+/// Future returned by `SocksHandler::handle`
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// A SOCKS5 CONNECT whose negotiation is complete but not yet answered
+pub struct SocksRequest {
+    /// Client socket (TCP or unix), positioned after the CONNECT request
+    pub socket: Box<dyn LocalStream>,
+    pub client: SocketAddr,
+    pub host: String,
+    pub port: u16,
+}
+
+impl SocksRequest {
+    /// Answer with SOCKS reply `code` without connecting anywhere
+    pub async fn reject(mut self, code: u8) -> Result<()> {
+        self.socket
+            .write_all(&socks_reply(code, reply_addr_type(&self.host)))
+            .await?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for SocksRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SocksRequest")
+            .field("client", &self.client)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+/// Serves negotiated SOCKS requests, like a `tower::Service<SocksRequest>`
+///
+/// The handler owns the request from here on: it must send the SOCKS reply
+/// (`SocksRequest::reject` or a successful connect) and relay the socket.
+/// Middleware wraps another handler and decides whether to call it.
+/// Closures `Fn(SocksRequest) -> impl Future<Output = Result<()>>` are handlers too.
+pub trait SocksHandler: Send + Sync {
+    fn handle(&self, request: SocksRequest) -> HandlerFuture;
+}
+
+impl<F, Fut> SocksHandler for F
+where
+    F: Fn(SocksRequest) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    fn handle(&self, request: SocksRequest) -> HandlerFuture {
+        Box::pin(self(request))
+    }
+}
+
+/// The built-in handler: forward the request through the tunnel peer
+pub struct PeerForwarder {
+    state: Arc<Mutex<TunnelState>>,
+    endpoint: Endpoint,
+}
+
+impl PeerForwarder {
+    pub fn new(state: Arc<Mutex<TunnelState>>, endpoint: Endpoint) -> Self {
+        Self { state, endpoint }
+    }
+}
+
+impl SocksHandler for PeerForwarder {
+    fn handle(&self, request: SocksRequest) -> HandlerFuture {
+        Box::pin(forward_to_peer(
+            request.socket,
+            request.client,
+            request.host,
+            request.port,
+            Arc::clone(&self.state),
+            self.endpoint.clone(),
+            LocalProtocol::Socks5,
+        ))
+    }
+}
+
+/// Wraps the built-in handler with middleware, like a tower `Layer`
+///
+/// Set with `TunnelConfig::socks_layer`; the function gets the default
+/// `PeerForwarder` and returns the handler the SOCKS listener should use.
+#[derive(Clone)]
+pub struct SocksLayer(Arc<LayerFn>);
+
+type LayerFn = dyn Fn(Arc<dyn SocksHandler>) -> Arc<dyn SocksHandler> + Send + Sync;
+
+impl SocksLayer {
+    pub fn new(
+        layer: impl Fn(Arc<dyn SocksHandler>) -> Arc<dyn SocksHandler> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(layer))
+    }
+
+    pub fn layer(&self, inner: Arc<dyn SocksHandler>) -> Arc<dyn SocksHandler> {
+        (self.0)(inner)
+    }
+}
+
+impl fmt::Debug for SocksLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SocksLayer")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks5::protocol::{
+        SOCKS_ADDR_TYPE_DOMAIN, SOCKS_CMD_CONNECT, SOCKS_REPLY_NOT_ALLOWED, SOCKS_VERSION,
+    };
+    use crate::tunnel::test_support::{TunnelHarness, echo_server};
+    use std::sync::atomic::Ordering;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    /// Middleware refusing `*.blocked.example` before anything reaches the peer
+    fn deny_blocked() -> SocksLayer {
+        SocksLayer::new(|inner: Arc<dyn SocksHandler>| {
+            Arc::new(move |request: SocksRequest| {
+                let inner = Arc::clone(&inner);
+                async move {
+                    if request.host.ends_with(".blocked.example") {
+                        request.reject(SOCKS_REPLY_NOT_ALLOWED).await
+                    } else {
+                        inner.handle(request).await
+                    }
+                }
+            })
+        })
+    }
+
+    #[tokio::test]
+    async fn test_custom_handler_rejects_hosts() {
+        let harness = TunnelHarness::start_with(Some(deny_blocked())).await;
+
+        let mut stream = TcpStream::connect(harness.socks_addr).await.unwrap();
+        stream.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        let host = b"www.blocked.example";
+        let mut request = vec![SOCKS_VERSION, SOCKS_CMD_CONNECT, 0, SOCKS_ADDR_TYPE_DOMAIN];
+        request.push(host.len() as u8);
+        request.extend_from_slice(host);
+        request.extend_from_slice(&443u16.to_be_bytes());
+        stream.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS_REPLY_NOT_ALLOWED);
+
+        // Other hosts still go through the default handler
+        let mut echo = harness.socks_connect(echo_server().await).await;
+        echo.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        echo.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(harness.server_stats.requests.load(Ordering::Relaxed), 1);
+
+        harness.close().await;
+    }
+}
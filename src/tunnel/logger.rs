@@ -0,0 +1,135 @@
+// Pluggable output for requests, connection changes and the tunnel's messages
+//
+// Embedders pass their own `Logger` to `TunnelConfig::logger` to route all of
+// it into their logging system; the default `StdoutLogger` prints it. Hosts
+// are passed to `request_start` as requested, so a logger that should honour
+// `--privacy` runs them through `utils::logging::target`.
+use iroh::PublicKey;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::tunnel::relay::ProtocolKind;
+use crate::tunnel::state::ConnectionState;
+use crate::utils::logging::{RequestId, format_log, format_stats};
+
+/// Receives the tunnel's request and connection events
+pub trait Logger: Send + Sync {
+    /// A request for `host:port` started
+    ///
+    /// `kind` is e.g. `PROXY REQUEST` for a local client, or `OUTGOING` /
+    /// `RESOLVE` for a peer's request on the exit node.
+    fn request_start(&self, id: RequestId, kind: &str, host: &str, port: u16);
+
+    /// A relayed request finished after moving these many bytes over `path`
+    ///
+    /// `sni` is the TLS server name, or the HTTP request line for plain HTTP.
+    fn request_end(
+        &self,
+        id: RequestId,
+        bytes_sent: u64,
+        bytes_received: u64,
+        path: &str,
+        protocol: ProtocolKind,
+        sni: Option<&str>,
+    );
+
+    /// The default peer connection changed state; `peer` is set once connected
+    fn connection_state(&self, state: &ConnectionState, peer: Option<PublicKey>);
+
+    /// A request failed, or something went wrong outside of one
+    fn error(&self, id: Option<RequestId>, message: &str);
+
+    /// Progress and diagnostics such as listener addresses or reconnects,
+    /// formatted with their icon
+    fn info(&self, message: &str);
+
+    /// Something worth attention that isn't a failure, formatted like `info`
+    fn warn(&self, message: &str);
+}
+
+/// Prints to stdout, and warnings and errors to stderr
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdoutLogger;
+
+impl Logger for StdoutLogger {
+    fn request_start(&self, id: RequestId, kind: &str, host: &str, port: u16) {
+        let icon = match kind {
+            "OUTGOING" => "📤",
+            "RESOLVE" => "🔎",
+            _ => "📥",
+        };
+        println!("\n{} {}", icon, format_log(id, kind, host, port));
+    }
+
+    fn request_end(
+        &self,
+        id: RequestId,
+        bytes_sent: u64,
+        bytes_received: u64,
+        path: &str,
+        protocol: ProtocolKind,
+        sni: Option<&str>,
+    ) {
+        println!(
+            "{}",
+            format_stats(id, bytes_sent, bytes_received, path, protocol, sni)
+        );
+    }
+
+    fn connection_state(&self, state: &ConnectionState, peer: Option<PublicKey>) {
+        match peer {
+            Some(peer) => println!(
+                "🔌 Connection state: {} ({})",
+                state.label(),
+                peer.fmt_short()
+            ),
+            None => println!("🔌 Connection state: {}", state.label()),
+        }
+    }
+
+    fn error(&self, id: Option<RequestId>, message: &str) {
+        match id {
+            Some(id) => eprintln!("❌ {} {}", id, message),
+            None => eprintln!("❌ {}", message),
+        }
+    }
+
+    fn info(&self, message: &str) {
+        println!("{}", message);
+    }
+
+    fn warn(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+}
+
+/// The `Logger` a tunnel reports to, shared by its tasks
+#[derive(Clone)]
+pub struct SharedLogger(Arc<dyn Logger>);
+
+impl SharedLogger {
+    pub fn new(logger: Box<dyn Logger>) -> Self {
+        Self(Arc::from(logger))
+    }
+}
+
+impl Default for SharedLogger {
+    fn default() -> Self {
+        Self(Arc::new(StdoutLogger))
+    }
+}
+
+impl Deref for SharedLogger {
+    type Target = dyn Logger;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl fmt::Debug for SharedLogger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Logger")
+    }
+}
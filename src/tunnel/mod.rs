@@ -1,8 +1,35 @@
 // Tunnel protocol implementation
+pub mod access_log;
+pub mod auth;
+pub mod config_file;
 pub mod connection;
+pub mod control;
+pub mod dns;
+pub mod events;
+pub mod failover;
+pub mod filter;
+pub mod flow;
+pub mod forward;
 pub mod handler;
+pub mod logger;
+pub mod outbound;
 pub mod persistence;
 pub mod protocol;
+pub mod quota;
 pub mod relay;
+pub mod routing;
+pub mod runtime;
+pub mod shutdown;
 pub mod socks;
 pub mod state;
+pub mod stats;
+pub mod streams;
+pub mod tcp;
+#[cfg(test)]
+pub(crate) mod test_support;
+#[cfg(target_os = "linux")]
+pub mod transparent;
+pub mod transport;
+#[cfg(unix)]
+pub mod unix_socket;
+pub mod upstream;
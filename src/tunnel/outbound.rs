@@ -0,0 +1,816 @@
+// Outgoing connections from the exit node to requested destinations
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::task::JoinSet;
+
+use crate::tunnel::auth::AuthToken;
+use crate::tunnel::dns::DnsCache;
+use crate::tunnel::filter::HostFilter;
+use crate::tunnel::logger::{Logger, SharedLogger};
+use crate::tunnel::quota::PeerQuotas;
+use crate::tunnel::relay::RelayOptions;
+use crate::tunnel::tcp::TcpOptions;
+use crate::tunnel::upstream::UpstreamProxy;
+
+/// Head start each connection attempt gets before the next one is started
+///
+/// The "Connection Attempt Delay" of RFC 8305 (Happy Eyeballs v2).
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Pause before the first retry of a transient failure, doubled for each retry
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Why a destination could not be connected
+#[derive(Debug)]
+pub enum OutboundError {
+    /// Refused by the destination rules or `--block-private`
+    NotAllowed(String),
+    /// Resolution or the TCP connect itself failed
+    Failed(anyhow::Error),
+    /// The destination is this node itself (one of its listeners or its
+    /// endpoint ID), so the request would come straight back into the tunnel
+    Loop(String),
+}
+
+impl fmt::Display for OutboundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutboundError::NotAllowed(reason) => write!(f, "{}", reason),
+            OutboundError::Failed(e) => write!(f, "{}", e),
+            OutboundError::Loop(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// How the exit node reaches destinations: policy checks, then a direct or proxied dial
+#[derive(Debug, Default)]
+pub struct Outbound {
+    pub host_filter: HostFilter,
+    /// Dial through this proxy instead of connecting directly
+    pub upstream_proxy: Option<UpstreamProxy>,
+    pub dns: DnsCache,
+    /// Applied to every destination socket
+    pub tcp: TcpOptions,
+    /// How streams to destinations are relayed
+    pub relay: RelayOptions,
+    /// Secret peers must send in an `Auth` message before Connect or Resolve
+    pub auth_token: Option<AuthToken>,
+    /// Traffic allowance per peer; `None` is unlimited
+    pub quotas: Option<PeerQuotas>,
+    /// This node's own SOCKS5 / transparent proxy listeners, which a
+    /// destination must not lead back into
+    pub local_listeners: Vec<SocketAddr>,
+    /// This node's endpoint ID, which isn't a destination either
+    pub node_id: Option<iroh::PublicKey>,
+    /// Extra attempts at a lookup or dial that failed transiently (`--connect-retries`)
+    pub connect_retries: u32,
+    /// Local addresses direct connections are made from (`--egress-bind`)
+    pub egress: EgressBind,
+    /// Where retries and socket option failures are reported
+    pub logger: SharedLogger,
+}
+
+impl Outbound {
+    /// Whether a request carrying `presented` passes the `--auth-token` check
+    pub fn is_authorized(&self, presented: Option<&str>) -> bool {
+        match &self.auth_token {
+            None => true,
+            Some(token) => presented.is_some_and(|presented| token.matches(presented)),
+        }
+    }
+
+    fn check_host(&self, host: &str) -> Result<(), OutboundError> {
+        if let Some(node_id) = self.node_id
+            && host.parse::<iroh::PublicKey>().ok() == Some(node_id)
+        {
+            return Err(OutboundError::Loop(format!(
+                "{} is this node's own endpoint ID",
+                node_id.fmt_short()
+            )));
+        }
+        if self.host_filter.is_allowed(host) {
+            Ok(())
+        } else {
+            Err(OutboundError::NotAllowed(format!(
+                "{} denied by filter rules",
+                host
+            )))
+        }
+    }
+
+    /// Resolve `host` for a peer's `Resolve` request, subject to the destination rules
+    ///
+    /// With `--block-private`, private answers are left out, and a name with
+    /// only private addresses is refused.
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, OutboundError> {
+        self.check_host(host)?;
+        let ips = self
+            .dns
+            .lookup(host)
+            .await
+            .map_err(|e| OutboundError::Failed(e.into()))?;
+        let allowed: Vec<IpAddr> = ips
+            .iter()
+            .copied()
+            .filter(|ip| self.host_filter.is_resolved_allowed(*ip))
+            .collect();
+        if allowed.is_empty() && !ips.is_empty() {
+            return Err(OutboundError::NotAllowed(format!(
+                "{} resolves to private address {}",
+                host, ips[0]
+            )));
+        }
+        Ok(allowed)
+    }
+
+    /// Check `host` against the rules and open a connection to it
+    ///
+    /// Without an upstream proxy the destination is resolved here, checked
+    /// against `--block-private`, and the checked addresses are dialled. With a
+    /// proxy, resolution is left to the proxy unless private addresses must be
+    /// blocked; then the proxy is handed the checked address instead of the
+    /// name, so it can't resolve the name to a different one.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, OutboundError> {
+        self.check_host(host)?;
+
+        let addrs = if self.upstream_proxy.is_none() || self.host_filter.blocks_private() {
+            let ips = retry_transient(self.connect_retries, &*self.logger, || {
+                self.dns.lookup(host)
+            })
+            .await
+            .map_err(|e| OutboundError::Failed(e.into()))?;
+            if let Some(ip) = ips
+                .iter()
+                .find(|ip| !self.host_filter.is_resolved_allowed(**ip))
+            {
+                return Err(OutboundError::NotAllowed(format!(
+                    "{} resolves to private address {}",
+                    host, ip
+                )));
+            }
+            let addrs: Vec<SocketAddr> = ips
+                .into_iter()
+                .filter(|ip| self.upstream_proxy.is_some() || self.egress.reaches(*ip))
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect();
+            if addrs.is_empty() && self.upstream_proxy.is_none() {
+                return Err(OutboundError::Failed(anyhow::anyhow!(
+                    "{} has no address in the family of egress address {}",
+                    host,
+                    self.egress
+                )));
+            }
+            // Checked before dialling, so a loop never reaches our listeners
+            if let Some(addr) = addrs
+                .iter()
+                .find(|addr| is_own_listener(**addr, &self.local_listeners))
+            {
+                return Err(OutboundError::Loop(format!(
+                    "{} is this node's own listener",
+                    addr
+                )));
+            }
+            addrs
+        } else {
+            Vec::new()
+        };
+
+        let stream = match &self.upstream_proxy {
+            Some(proxy) if self.host_filter.blocks_private() => {
+                connect_proxied(proxy, host, &addrs).await?
+            }
+            Some(proxy) => proxy
+                .connect(host, port)
+                .await
+                .map_err(OutboundError::Failed)?,
+            None => retry_transient(self.connect_retries, &*self.logger, || {
+                connect_happy_eyeballs(&addrs, HAPPY_EYEBALLS_DELAY, self.egress)
+            })
+            .await
+            .map_err(|e| OutboundError::Failed(e.into()))?,
+        };
+        self.tcp.apply_or_warn(&stream, &*self.logger);
+        Ok(stream)
+    }
+}
+
+/// Connect through `proxy` to the first of `addrs` (the vetted addresses of `host`) that works
+async fn connect_proxied(
+    proxy: &UpstreamProxy,
+    host: &str,
+    addrs: &[SocketAddr],
+) -> Result<TcpStream, OutboundError> {
+    let mut last_error = anyhow::anyhow!("{} has no address", host);
+    for addr in addrs {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        };
+        match proxy.connect(&ip, addr.port()).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(OutboundError::Failed(last_error))
+}
+
+/// Source addresses for connections to destinations (`--egress-bind`)
+///
+/// At most one per address family. A destination is dialled from the address
+/// of its family; with only one family configured, destinations of the other
+/// family are not dialled at all, so traffic can't leave by a different route.
+/// Empty lets the OS pick the source as usual.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EgressBind {
+    v4: Option<Ipv4Addr>,
+    v6: Option<Ipv6Addr>,
+}
+
+impl EgressBind {
+    pub fn new(addrs: &[IpAddr]) -> anyhow::Result<Self> {
+        let mut egress = Self::default();
+        for &addr in addrs {
+            if addr.is_unspecified() || addr.is_multicast() {
+                anyhow::bail!("Egress address {} can't be a connection source", addr);
+            }
+            let taken = match addr {
+                IpAddr::V4(v4) => egress.v4.replace(v4).is_some(),
+                IpAddr::V6(v6) => egress.v6.replace(v6).is_some(),
+            };
+            if taken {
+                anyhow::bail!(
+                    "Only one egress address per family, got a second one: {}",
+                    addr
+                );
+            }
+        }
+        Ok(egress)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_none() && self.v6.is_none()
+    }
+
+    /// Source address for a connection to `dest`, if one is set for its family
+    pub fn source_for(&self, dest: IpAddr) -> Option<IpAddr> {
+        match dest {
+            IpAddr::V4(_) => self.v4.map(IpAddr::V4),
+            IpAddr::V6(_) => self.v6.map(IpAddr::V6),
+        }
+    }
+
+    /// Whether `dest` may be dialled: no egress is set, or one of its family is
+    pub fn reaches(&self, dest: IpAddr) -> bool {
+        self.is_empty() || self.source_for(dest).is_some()
+    }
+
+    /// Make sure every egress address belongs to this host, by binding to it
+    pub fn check_local(&self) -> anyhow::Result<()> {
+        for addr in self
+            .v4
+            .map(IpAddr::V4)
+            .into_iter()
+            .chain(self.v6.map(IpAddr::V6))
+        {
+            std::net::TcpListener::bind((addr, 0)).map_err(|e| {
+                anyhow::anyhow!("Egress address {} is not usable on this host: {}", addr, e)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Connect to `dest` from the egress address of its family
+    async fn connect(self, dest: SocketAddr) -> io::Result<TcpStream> {
+        let Some(source) = self.source_for(dest.ip()) else {
+            return TcpStream::connect(dest).await;
+        };
+        let socket = match dest {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.bind(SocketAddr::new(source, 0))?;
+        socket.connect(dest).await
+    }
+}
+
+impl fmt::Display for EgressBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addrs: Vec<String> = (self.v4.map(IpAddr::V4).into_iter())
+            .chain(self.v6.map(IpAddr::V6))
+            .map(|addr| addr.to_string())
+            .collect();
+        match addrs.is_empty() {
+            true => f.write_str("none"),
+            false => f.write_str(&addrs.join(", ")),
+        }
+    }
+}
+
+/// Whether `err` is worth another attempt shortly after
+///
+/// Local resource shortages (no free source port or address yet, an
+/// interrupted call) and temporary DNS failures clear up on their own.
+/// Answers from the network - refused, unreachable, timed out - don't, and
+/// fail the request straight away.
+pub fn is_transient(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::AddrNotAvailable
+        | io::ErrorKind::AddrInUse
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::ResourceBusy => true,
+        // getaddrinfo's EAI_AGAIN has no ErrorKind of its own
+        _ => {
+            let message = err.to_string().to_ascii_lowercase();
+            message.contains("temporary failure in name resolution")
+                || message.contains("try again")
+        }
+    }
+}
+
+/// Run `attempt`, retrying transient failures up to `retries` times with backoff
+async fn retry_transient<T, F, Fut>(
+    retries: u32,
+    logger: &dyn Logger,
+    mut attempt: F,
+) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut delay = RETRY_BACKOFF;
+    let mut retry = 0;
+    loop {
+        match attempt().await {
+            Err(e) if retry < retries && is_transient(&e) => {
+                retry += 1;
+                logger.warn(&format!(
+                    "🔁 Transient error ({}), retrying in {:?} ({}/{})",
+                    e, delay, retry, retries
+                ));
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether `dest` is one of `listeners`
+///
+/// A wildcard listener is reached through any address of this host, which
+/// is any address we can bind to.
+fn is_own_listener(dest: SocketAddr, listeners: &[SocketAddr]) -> bool {
+    listeners.iter().any(|listener| {
+        listener.port() == dest.port()
+            && (listener.ip() == dest.ip()
+                || (listener.ip().is_unspecified() && is_local_address(dest.ip())))
+    })
+}
+
+/// Whether `ip` belongs to this host
+fn is_local_address(ip: IpAddr) -> bool {
+    ip.is_loopback() || ip.is_unspecified() || std::net::UdpSocket::bind((ip, 0)).is_ok()
+}
+
+/// Order addresses IPv6 first, then alternating families (RFC 8305)
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6());
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect to whichever of `addrs` answers first, from the `egress` addresses
+///
+/// Attempts start in `interleave_families` order, each `delay` after the
+/// previous one or as soon as it fails. The first successful connection wins
+/// and the attempts still in flight are aborted, so one broken address family
+/// can't stall the request.
+pub async fn connect_happy_eyeballs(
+    addrs: &[SocketAddr],
+    delay: Duration,
+    egress: EgressBind,
+) -> io::Result<TcpStream> {
+    let mut queue = interleave_families(addrs).into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+
+    let Some(first) = queue.next() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no addresses to connect to",
+        ));
+    };
+    attempts.spawn(egress.connect(first));
+
+    loop {
+        let finished = tokio::select! {
+            Some(finished) = attempts.join_next() => Some(finished),
+            _ = tokio::time::sleep(delay), if queue.len() > 0 => None,
+        };
+        match finished {
+            // Dropping the JoinSet aborts the remaining attempts
+            Some(Ok(Ok(stream))) => return Ok(stream),
+            Some(Ok(Err(e))) => last_error = Some(e),
+            Some(Err(e)) => last_error = Some(io::Error::other(e)),
+            // Delay elapsed: start the next attempt alongside the running ones
+            None => {}
+        }
+
+        if let Some(next) = queue.next() {
+            attempts.spawn(egress.connect(next));
+        } else if attempts.is_empty() {
+            return Err(
+                last_error.unwrap_or_else(|| io::Error::other("all connection attempts failed"))
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::test_support::{CapturingLogger, echo_server};
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    /// An address nothing is listening on
+    async fn refused_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn test_transient_error_kinds() {
+        let error = |kind| io::Error::from(kind);
+        assert!(is_transient(&error(io::ErrorKind::AddrNotAvailable)));
+        assert!(is_transient(&error(io::ErrorKind::Interrupted)));
+        assert!(is_transient(&io::Error::other(
+            "failed to lookup address information: Temporary failure in name resolution"
+        )));
+
+        assert!(!is_transient(&error(io::ErrorKind::ConnectionRefused)));
+        assert!(!is_transient(&error(io::ErrorKind::HostUnreachable)));
+        assert!(!is_transient(&error(io::ErrorKind::TimedOut)));
+        assert!(!is_transient(&io::Error::other(
+            "failed to lookup address information: Name or service not known"
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_transient_failure_succeeds_on_retry() {
+        let echo = echo_server().await;
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        let stream = retry_transient(2, &CapturingLogger::default(), || async {
+            if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                return Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+            }
+            TcpStream::connect(echo).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), echo);
+        assert_eq!(attempts.into_inner(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_failure_is_not_retried() {
+        let refused = refused_addr().await;
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        let started = Instant::now();
+        let err = retry_transient(3, &CapturingLogger::default(), || {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            TcpStream::connect(refused)
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(attempts.into_inner(), 1);
+        assert!(started.elapsed() < RETRY_BACKOFF);
+
+        // Transient failures stop once the retries are used up
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        let logger = CapturingLogger::default();
+        let err = retry_transient(2, &logger, || async {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err::<(), _>(io::Error::from(io::ErrorKind::AddrNotAvailable))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        assert_eq!(attempts.into_inner(), 3);
+        assert_eq!(logger.lines().len(), 2);
+        assert!(logger.lines()[0].starts_with("warn 🔁 Transient error"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_own_listener_is_a_loop() {
+        let own = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let own_addr = own.local_addr().unwrap();
+        let outbound = Outbound {
+            local_listeners: vec![own_addr],
+            ..Default::default()
+        };
+
+        let err = outbound
+            .connect("127.0.0.1", own_addr.port())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, OutboundError::Loop(reason) if reason.contains(&own_addr.to_string()))
+        );
+        let err = outbound
+            .connect("localhost", own_addr.port())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OutboundError::Loop(_)));
+        // Rejected before dialling: the listener never saw a connection
+        let accepted = tokio::time::timeout(Duration::from_millis(100), own.accept()).await;
+        assert!(accepted.is_err(), "loop check dialled the listener");
+
+        // Other services on this host are fine
+        let echo = echo_server().await;
+        assert!(outbound.connect("127.0.0.1", echo.port()).await.is_ok());
+
+        // A wildcard listener is reached through any of our addresses
+        let wildcard = Outbound {
+            local_listeners: vec![(IpAddr::from([0, 0, 0, 0]), own_addr.port()).into()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            wildcard.connect("127.0.0.1", own_addr.port()).await,
+            Err(OutboundError::Loop(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_block_private_hands_vetted_address_to_proxy() {
+        use crate::tunnel::upstream::UpstreamKind;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // An HTTP proxy that reports the CONNECT target it was asked for
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let (targets, mut requested) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let mut header = Vec::new();
+                while !header.ends_with(b"\r\n\r\n") {
+                    header.push(client.read_u8().await.unwrap());
+                }
+                let line = String::from_utf8_lossy(&header)
+                    .lines()
+                    .next()
+                    .unwrap()
+                    .to_string();
+                let _ = targets.send(line);
+                client
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let outbound = Outbound {
+            host_filter: HostFilter::default().block_private(true),
+            upstream_proxy: Some(UpstreamProxy {
+                kind: UpstreamKind::Http,
+                addr: proxy_addr.to_string(),
+            }),
+            dns: DnsCache::new(Duration::from_secs(60), 16),
+            ..Default::default()
+        };
+        outbound
+            .dns
+            .seed("public.example", vec!["198.51.100.7".parse().unwrap()]);
+        outbound.connect("public.example", 443).await.unwrap();
+        assert_eq!(
+            requested.recv().await.unwrap(),
+            "CONNECT 198.51.100.7:443 HTTP/1.1"
+        );
+
+        // A name resolving to a private address never reaches the proxy
+        outbound
+            .dns
+            .seed("internal.example", vec!["10.0.0.5".parse().unwrap()]);
+        assert!(matches!(
+            outbound.connect("internal.example", 443).await,
+            Err(OutboundError::NotAllowed(_))
+        ));
+        assert!(requested.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_leaves_out_private_answers() {
+        let outbound = Outbound {
+            host_filter: HostFilter::default().block_private(true),
+            dns: DnsCache::new(Duration::from_secs(60), 16),
+            ..Default::default()
+        };
+        let public: IpAddr = "198.51.100.7".parse().unwrap();
+        outbound.dns.seed(
+            "mixed.example",
+            vec!["10.0.0.5".parse().unwrap(), public, "::1".parse().unwrap()],
+        );
+        assert_eq!(
+            outbound.resolve("mixed.example").await.unwrap(),
+            vec![public]
+        );
+
+        outbound
+            .dns
+            .seed("internal.example", vec!["192.168.1.10".parse().unwrap()]);
+        assert!(matches!(
+            outbound.resolve("internal.example").await,
+            Err(OutboundError::NotAllowed(_))
+        ));
+        assert!(matches!(
+            outbound.resolve("127.0.0.1").await,
+            Err(OutboundError::NotAllowed(_))
+        ));
+
+        // Without --block-private every answer is returned
+        let open = Outbound::default();
+        assert_eq!(
+            open.resolve("127.0.0.1").await.unwrap(),
+            vec![IpAddr::from([127, 0, 0, 1])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_own_endpoint_id_is_a_loop() {
+        let node_id = iroh::SecretKey::generate(&mut rand::rng()).public();
+        let outbound = Outbound {
+            node_id: Some(node_id),
+            ..Default::default()
+        };
+        assert!(matches!(
+            outbound.connect(&node_id.to_string(), 80).await,
+            Err(OutboundError::Loop(_))
+        ));
+        assert!(matches!(
+            outbound.resolve(&node_id.to_string()).await,
+            Err(OutboundError::Loop(_))
+        ));
+
+        // Another node's ID is just a name that doesn't resolve
+        let other = iroh::SecretKey::generate(&mut rand::rng()).public();
+        assert!(matches!(
+            outbound.connect(&other.to_string(), 80).await,
+            Err(OutboundError::Failed(_))
+        ));
+    }
+
+    #[test]
+    fn test_egress_bind_picks_source_by_family() {
+        let v4: IpAddr = "192.0.2.10".parse().unwrap();
+        let v6: IpAddr = "2001:db8::10".parse().unwrap();
+        let dest4: IpAddr = "198.51.100.1".parse().unwrap();
+        let dest6: IpAddr = "2001:db8:1::1".parse().unwrap();
+
+        let none = EgressBind::default();
+        assert!(none.is_empty());
+        assert_eq!(none.source_for(dest4), None);
+        assert!(none.reaches(dest4) && none.reaches(dest6));
+
+        let both = EgressBind::new(&[v6, v4]).unwrap();
+        assert_eq!(both.source_for(dest4), Some(v4));
+        assert_eq!(both.source_for(dest6), Some(v6));
+        assert_eq!(both.to_string(), "192.0.2.10, 2001:db8::10");
+
+        // Only one family set: the other one is not dialled at all
+        let only_v4 = EgressBind::new(&[v4]).unwrap();
+        assert_eq!(only_v4.source_for(dest6), None);
+        assert!(only_v4.reaches(dest4));
+        assert!(!only_v4.reaches(dest6));
+    }
+
+    #[test]
+    fn test_egress_bind_rejects_bad_addresses() {
+        let v4: IpAddr = "192.0.2.10".parse().unwrap();
+        assert!(EgressBind::new(&[v4, "192.0.2.11".parse().unwrap()]).is_err());
+        assert!(EgressBind::new(&["0.0.0.0".parse().unwrap()]).is_err());
+        assert!(EgressBind::new(&["::".parse().unwrap()]).is_err());
+        assert!(EgressBind::new(&["224.0.0.1".parse().unwrap()]).is_err());
+
+        // TEST-NET addresses are never assigned to this host
+        let foreign = EgressBind::new(&[v4]).unwrap();
+        assert!(foreign.check_local().is_err());
+        let loopback = EgressBind::new(&[IpAddr::from([127, 0, 0, 1])]).unwrap();
+        assert!(loopback.check_local().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connect_from_egress_address() {
+        let echo = echo_server().await;
+        let outbound = Outbound {
+            egress: EgressBind::new(&[IpAddr::from([127, 0, 0, 1])]).unwrap(),
+            ..Default::default()
+        };
+        let stream = outbound.connect("127.0.0.1", echo.port()).await.unwrap();
+        assert_eq!(
+            stream.local_addr().unwrap().ip(),
+            IpAddr::from([127, 0, 0, 1])
+        );
+
+        // No IPv4 address of the destination's family is configured
+        let v6_only = Outbound {
+            egress: EgressBind::new(&[IpAddr::from(Ipv6Addr::LOCALHOST)]).unwrap(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            v6_only.connect("127.0.0.1", echo.port()).await,
+            Err(OutboundError::Failed(_))
+        ));
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let ordered = interleave_families(&addrs(&[
+            "192.0.2.1:443",
+            "192.0.2.2:443",
+            "192.0.2.3:443",
+            "[2001:db8::1]:443",
+            "[2001:db8::2]:443",
+        ]));
+        assert_eq!(
+            ordered,
+            addrs(&[
+                "[2001:db8::1]:443",
+                "192.0.2.1:443",
+                "[2001:db8::2]:443",
+                "192.0.2.2:443",
+                "192.0.2.3:443",
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_skips_dead_address() {
+        let good = echo_server().await;
+        // Unroutable (TEST-NET) IPv6 goes first and either hangs or fails fast
+        let dead: SocketAddr = "[2001:db8::dead]:80".parse().unwrap();
+
+        let started = Instant::now();
+        let stream = tokio::time::timeout(
+            Duration::from_secs(5),
+            connect_happy_eyeballs(
+                &[good, dead],
+                Duration::from_millis(50),
+                EgressBind::default(),
+            ),
+        )
+        .await
+        .expect("dead address stalled the connect")
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_moves_on_after_refusal() {
+        let good = echo_server().await;
+        let refused = refused_addr().await;
+        // A long delay proves the refusal itself starts the next attempt
+        let stream = tokio::time::timeout(
+            Duration::from_secs(5),
+            connect_happy_eyeballs(
+                &[refused, good],
+                Duration::from_secs(60),
+                EgressBind::default(),
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_all_failed() {
+        let refused = refused_addr().await;
+        let err = connect_happy_eyeballs(&[refused], HAPPY_EYEBALLS_DELAY, EgressBind::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(
+            connect_happy_eyeballs(&[], HAPPY_EYEBALLS_DELAY, EgressBind::default())
+                .await
+                .is_err()
+        );
+    }
+}
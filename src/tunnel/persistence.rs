@@ -1,68 +1,453 @@
 use anyhow::{Context, Result};
 use iroh::SecretKey;
+use std::path::{Path, PathBuf};
 
-pub async fn get_or_create_secret_key(persist: bool) -> Result<SecretKey> {
-    let path = std::path::Path::new(".tunnel_key");
+use crate::tunnel::logger::Logger;
 
+/// This node's persisted identity
+pub const KEY_FILE: &str = ".tunnel_key";
+/// Last peer this node connected to
+pub const PEER_FILE: &str = ".tunnel_peer";
+
+/// Suffix of a corrupt key moved aside, so not a profile name
+const CORRUPT_SUFFIX: &str = "corrupt";
+
+/// A named identity (`--profile`), with its own key and peer files
+///
+/// The default profile uses `.tunnel_key` / `.tunnel_peer`; profile `work`
+/// uses `.tunnel_key.work` / `.tunnel_peer.work`, so each keeps a stable
+/// Node ID.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    name: Option<String>,
+    dir: Option<PathBuf>,
+}
+
+impl Profile {
+    /// The profile called `name`, see `parse_profile_name`
+    pub fn named(name: &str) -> Result<Self> {
+        let name = parse_profile_name(name).map_err(anyhow::Error::msg)?;
+        Ok(Self {
+            name: Some(name),
+            dir: None,
+        })
+    }
+
+    /// Keep this profile's files in `dir` instead of the working directory
+    pub fn in_dir(mut self, dir: &Path) -> Self {
+        self.dir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// `None` for the default profile
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn key_file(&self) -> PathBuf {
+        self.file(KEY_FILE)
+    }
+
+    pub fn peer_file(&self) -> PathBuf {
+        self.file(PEER_FILE)
+    }
+
+    fn file(&self, base: &str) -> PathBuf {
+        let file = match &self.name {
+            Some(name) => format!("{}.{}", base, name),
+            None => base.to_string(),
+        };
+        match &self.dir {
+            Some(dir) => dir.join(file),
+            None => PathBuf::from(file),
+        }
+    }
+}
+
+/// Validate a `--profile` name: letters, digits, `-` and `_`
+pub fn parse_profile_name(name: &str) -> Result<String, String> {
+    if name.is_empty()
+        || name == CORRUPT_SUFFIX
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+    {
+        return Err(format!(
+            "expected a profile name of letters, digits, - and _, got {:?}",
+            name
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// Profiles with a key file in `dir`, the default one (if any) first
+pub async fn list_profiles(dir: &Path) -> Result<Vec<Profile>> {
+    let mut named = Vec::new();
+    let mut has_default = false;
+    let mut entries = tokio::fs::read_dir(dir.join("."))
+        .await
+        .with_context(|| format!("Failed to list {}", dir.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        if file_name == KEY_FILE {
+            has_default = true;
+        } else if let Some(name) = file_name
+            .strip_prefix(KEY_FILE)
+            .and_then(|s| s.strip_prefix('.'))
+            && let Ok(name) = parse_profile_name(name)
+        {
+            named.push(name);
+        }
+    }
+    named.sort();
+
+    let mut profiles = Vec::new();
+    if has_default {
+        profiles.push(Profile::default());
+    }
+    profiles.extend(named.into_iter().map(|name| Profile {
+        name: Some(name),
+        dir: None,
+    }));
+    Ok(profiles)
+}
+
+/// Load the profile's persisted identity, creating it on first run
+///
+/// A key file of the wrong length is an error unless `regenerate_corrupt` is
+/// set, in which case it is moved aside to `.tunnel_key.corrupt` and a new
+/// identity is generated.
+pub async fn get_or_create_secret_key(
+    profile: &Profile,
+    persist: bool,
+    regenerate_corrupt: bool,
+    logger: &dyn Logger,
+) -> Result<SecretKey> {
+    secret_key_at(&profile.key_file(), persist, regenerate_corrupt, logger).await
+}
+
+async fn secret_key_at(
+    path: &Path,
+    persist: bool,
+    regenerate_corrupt: bool,
+    logger: &dyn Logger,
+) -> Result<SecretKey> {
     if persist && path.exists() {
         let bytes = tokio::fs::read(path)
             .await
-            .context("Failed to read .tunnel_key")?;
-        let bytes: [u8; 32] = bytes
-            .try_into()
-            .map_err(|_| anyhow::anyhow!("Invalid key length in .tunnel_key"))?;
-        let key = SecretKey::from(bytes);
-        println!("🔑 Loaded persistent secret key");
-        Ok(key)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        match <[u8; 32]>::try_from(bytes.as_slice()) {
+            Ok(bytes) => {
+                logger.info("🔑 Loaded persistent secret key");
+                return Ok(SecretKey::from(bytes));
+            }
+            Err(_) if regenerate_corrupt => {
+                let backup = corrupt_backup_path(path);
+                tokio::fs::rename(path, &backup).await.with_context(|| {
+                    format!("Failed to move corrupt key to {}", backup.display())
+                })?;
+                logger.warn(&format!(
+                    "⚠️  {} was corrupt ({} bytes, expected 32); moved it to {} and generating a new identity",
+                    path.display(),
+                    bytes.len(),
+                    backup.display()
+                ));
+            }
+            Err(_) => anyhow::bail!(
+                "Key file {} is corrupt ({} bytes, expected 32). Delete it or run `tunnel forget` \
+                 to generate a new identity, or pass --regenerate-corrupt-key to back it up and \
+                 replace it",
+                path.display(),
+                bytes.len()
+            ),
+        }
+    }
+
+    let key = SecretKey::generate(&mut rand::rng());
+    if persist {
+        tokio::fs::write(path, key.to_bytes())
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        logger.info("🔑 Generated and saved new secret key");
     } else {
-        let key = SecretKey::generate(&mut rand::rng());
+        logger.info("🔑 Generated ephemeral secret key (not persisted)");
+    }
+    Ok(key)
+}
 
-        if persist {
-            tokio::fs::write(path, key.to_bytes())
-                .await
-                .context("Failed to write .tunnel_key")?;
-            println!("🔑 Generated and saved new secret key");
-        } else {
-            println!("🔑 Generated ephemeral secret key (not persisted)");
-        }
+/// Read the persisted identity without creating or replacing it
+///
+/// `Ok(None)` means there is no key file yet; a corrupt one is an error.
+pub async fn read_secret_key(profile: &Profile) -> Result<Option<SecretKey>> {
+    read_secret_key_at(&profile.key_file()).await
+}
+
+async fn read_secret_key_at(path: &Path) -> Result<Option<SecretKey>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bytes = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let bytes = <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| {
+        anyhow::anyhow!(
+            "Key file {} is corrupt ({} bytes, expected 32)",
+            path.display(),
+            bytes.len()
+        )
+    })?;
+    Ok(Some(SecretKey::from(bytes)))
+}
+
+/// Where a corrupt key file is moved before it is replaced
+fn corrupt_backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".corrupt");
+    PathBuf::from(backup)
+}
+
+/// Generate a new identity and save it to the profile's key file
+///
+/// Refuses to replace an existing key unless `overwrite` is set, since that
+/// would change this node's ID and break every ticket handed out for it.
+pub async fn create_secret_key(
+    profile: &Profile,
+    overwrite: bool,
+    logger: &dyn Logger,
+) -> Result<SecretKey> {
+    let path = profile.key_file();
 
-        Ok(key)
+    if path.exists() && !overwrite {
+        anyhow::bail!(
+            "{} already exists (use --force to replace it)",
+            path.display()
+        );
     }
+
+    let key = SecretKey::generate(&mut rand::rng());
+    tokio::fs::write(&path, key.to_bytes())
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    logger.info("🔑 Generated and saved new secret key");
+    Ok(key)
 }
 
-pub async fn save_remote_peer_id(peer_id: iroh::PublicKey) -> Result<()> {
-    let path = std::path::Path::new(".tunnel_peer");
-    tokio::fs::write(path, peer_id.as_bytes())
+pub async fn save_remote_peer_id(profile: &Profile, peer_id: iroh::PublicKey) -> Result<()> {
+    let path = profile.peer_file();
+    tokio::fs::write(&path, peer_id.as_bytes())
         .await
-        .context("Failed to write .tunnel_peer")?;
+        .with_context(|| format!("Failed to write {}", path.display()))?;
     Ok(())
 }
 
-pub async fn load_remote_peer_id() -> Option<iroh::PublicKey> {
-    let path = std::path::Path::new(".tunnel_peer");
-    if path.exists() {
-        if let Ok(bytes) = tokio::fs::read(path).await {
-            if let Ok(bytes_array) = bytes.try_into() {
-                match iroh::PublicKey::from_bytes(&bytes_array) {
-                    Ok(peer_id) => {
-                        println!("🔗 Loaded persisted peer ID: {}", peer_id);
-                        return Some(peer_id);
-                    }
-                    Err(_) => return None,
-                }
+pub async fn load_remote_peer_id(
+    profile: &Profile,
+    logger: &dyn Logger,
+) -> Option<iroh::PublicKey> {
+    let path = profile.peer_file();
+    if path.exists()
+        && let Ok(bytes) = tokio::fs::read(&path).await
+        && let Ok(bytes_array) = bytes.try_into()
+    {
+        match iroh::PublicKey::from_bytes(&bytes_array) {
+            Ok(peer_id) => {
+                logger.info(&format!("🔗 Loaded persisted peer ID: {}", peer_id));
+                return Some(peer_id);
             }
+            Err(_) => return None,
         }
     }
     None
 }
 
-pub async fn clear_remote_peer_id() -> Result<()> {
-    let path = std::path::Path::new(".tunnel_peer");
+pub async fn clear_remote_peer_id(profile: &Profile, logger: &dyn Logger) -> Result<()> {
+    let path = profile.peer_file();
     if path.exists() {
-        tokio::fs::remove_file(path)
+        tokio::fs::remove_file(&path)
             .await
-            .context("Failed to remove .tunnel_peer")?;
-        println!("🗑️  Cleared persisted peer ID");
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+        logger.info("🗑️  Cleared persisted peer ID");
     }
     Ok(())
 }
+
+/// Delete the profile's persisted identity and peer in `dir`, returning the
+/// files removed
+///
+/// The next server start generates a fresh key, i.e. a new Node ID.
+pub async fn forget(dir: &Path, profile: &Profile) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for file in [profile.key_file(), profile.peer_file()] {
+        let path = dir.join(file);
+        if path.exists() {
+            tokio::fs::remove_file(&path)
+                .await
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::test_support::CapturingLogger;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tunnel-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_forget_removes_identity_and_peer() {
+        let dir = temp_dir("forget");
+        let key_path = dir.join(KEY_FILE);
+        let old_key = secret_key_at(&key_path, true, false, &CapturingLogger::default())
+            .await
+            .unwrap();
+        let peer = SecretKey::generate(&mut rand::rng()).public();
+        std::fs::write(dir.join(PEER_FILE), peer.as_bytes()).unwrap();
+
+        let removed = forget(&dir, &Profile::default()).await.unwrap();
+        assert_eq!(removed, vec![key_path.clone(), dir.join(PEER_FILE)]);
+        assert!(!key_path.exists());
+        assert!(!dir.join(PEER_FILE).exists());
+
+        // The next run starts over with a new identity
+        let new_key = secret_key_at(&key_path, true, false, &CapturingLogger::default())
+            .await
+            .unwrap();
+        assert_ne!(new_key.public(), old_key.public());
+        assert_eq!(
+            forget(&dir, &Profile::default()).await.unwrap(),
+            vec![key_path]
+        );
+        assert!(forget(&dir, &Profile::default()).await.unwrap().is_empty());
+        let _ = std::fs::remove_dir(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_key_is_reported_not_replaced() {
+        let dir = temp_dir("corrupt-key");
+        let key_path = dir.join(KEY_FILE);
+        std::fs::write(&key_path, [7u8; 12]).unwrap();
+
+        let err = secret_key_at(&key_path, true, false, &CapturingLogger::default())
+            .await
+            .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("corrupt (12 bytes, expected 32)"));
+        assert!(message.contains("tunnel forget"));
+        assert_eq!(std::fs::read(&key_path).unwrap(), [7u8; 12]);
+
+        let logger = CapturingLogger::default();
+        let key = secret_key_at(&key_path, true, true, &logger).await.unwrap();
+        assert_eq!(std::fs::read(&key_path).unwrap(), key.to_bytes());
+        let backup = dir.join(format!("{}.corrupt", KEY_FILE));
+        assert_eq!(std::fs::read(&backup).unwrap(), [7u8; 12]);
+        let lines = logger.lines();
+        assert!(lines[0].starts_with("warn ⚠️  ") && lines[0].contains("was corrupt (12 bytes"));
+        assert_eq!(lines[1], "info 🔑 Generated and saved new secret key");
+
+        std::fs::remove_file(backup).unwrap();
+        std::fs::remove_file(key_path).unwrap();
+        let _ = std::fs::remove_dir(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_read_secret_key_never_writes() {
+        let dir = temp_dir("read-key");
+        let key_path = dir.join(KEY_FILE);
+        assert!(read_secret_key_at(&key_path).await.unwrap().is_none());
+        assert!(!key_path.exists());
+
+        let key = secret_key_at(&key_path, true, false, &CapturingLogger::default())
+            .await
+            .unwrap();
+        let read = read_secret_key_at(&key_path).await.unwrap().unwrap();
+        assert_eq!(read.public(), key.public());
+
+        std::fs::write(&key_path, [7u8; 12]).unwrap();
+        let err = read_secret_key_at(&key_path).await.unwrap_err();
+        assert!(err.to_string().contains("corrupt (12 bytes, expected 32)"));
+        assert_eq!(std::fs::read(&key_path).unwrap(), [7u8; 12]);
+
+        std::fs::remove_file(key_path).unwrap();
+        let _ = std::fs::remove_dir(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_profiles_keep_separate_stable_keys() {
+        let dir = temp_dir("profiles");
+        let work = Profile::named("work").unwrap();
+        let home = Profile::named("home").unwrap();
+        assert_eq!(work.key_file(), PathBuf::from(".tunnel_key.work"));
+        assert_eq!(home.peer_file(), PathBuf::from(".tunnel_peer.home"));
+
+        let work_key = secret_key_at(
+            &dir.join(work.key_file()),
+            true,
+            false,
+            &CapturingLogger::default(),
+        )
+        .await
+        .unwrap();
+        let home_key = secret_key_at(
+            &dir.join(home.key_file()),
+            true,
+            false,
+            &CapturingLogger::default(),
+        )
+        .await
+        .unwrap();
+        assert_ne!(work_key.public(), home_key.public());
+
+        // Each profile loads the same identity again on the next run
+        for (profile, key) in [(&work, &work_key), (&home, &home_key)] {
+            let again = secret_key_at(
+                &dir.join(profile.key_file()),
+                true,
+                false,
+                &CapturingLogger::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(again.public(), key.public());
+        }
+
+        // Corrupt-key backups aren't profiles
+        std::fs::write(dir.join(".tunnel_key.corrupt"), [0u8; 3]).unwrap();
+        secret_key_at(
+            &dir.join(KEY_FILE),
+            true,
+            false,
+            &CapturingLogger::default(),
+        )
+        .await
+        .unwrap();
+        let listed = list_profiles(&dir).await.unwrap();
+        let names: Vec<_> = listed.iter().map(|p| p.name()).collect();
+        assert_eq!(names, vec![None, Some("home"), Some("work")]);
+
+        // Forgetting one profile leaves the others alone
+        let removed = forget(&dir, &work).await.unwrap();
+        assert_eq!(removed, vec![dir.join(".tunnel_key.work")]);
+        assert!(dir.join(home.key_file()).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_profile_name() {
+        assert_eq!(parse_profile_name("work-2_b").unwrap(), "work-2_b");
+        assert!(parse_profile_name("").is_err());
+        assert!(parse_profile_name("a.b").is_err());
+        assert!(parse_profile_name("../x").is_err());
+        assert!(parse_profile_name("corrupt").is_err());
+    }
+}
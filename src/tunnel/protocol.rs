@@ -1,11 +1,228 @@
 // Tunnel protocol - TunnelMessage
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 
+/// Version sent in `Hello`; matches the `iroh-tunnel/N` ALPN
+///
+/// Incompatible wire changes bump the ALPN. Additions an older peer can't
+/// handle go behind a `Features` flag instead, so the version is informational.
+pub const PROTOCOL_VERSION: u16 = 3;
+
+/// Prefix of the `Error` message sent when a destination is refused by policy
+pub const NOT_ALLOWED_MESSAGE: &str = "Destination not allowed";
+
+/// `Error` message sent when `--auth-token` is required and missing or wrong
+pub const AUTH_FAILED_MESSAGE: &str = "Authentication failed";
+
+// `CloseWithReason` codes
+/// The sender's TCP socket failed (for the exit node, the destination reset)
+pub const CLOSE_SOCKET_ERROR: u16 = 1;
+/// The sender is shutting down
+pub const CLOSE_SHUTTING_DOWN: u16 = 2;
+/// The sender received a message it couldn't handle
+pub const CLOSE_PROTOCOL_ERROR: u16 = 3;
+/// The stream was open for `--max-connection-lifetime`
+pub const CLOSE_LIFETIME_EXCEEDED: u16 = 4;
+
+/// Optional protocol features, advertised in `Hello`
+///
+/// A message behind a flag is only sent to a peer that advertised it:
+/// `CloseWithReason` needs the receiver's `CLOSE_REASON` (otherwise a bare
+/// `Close` is sent), `Stats` needs `STATS` on both ends, and `Auth` is only
+/// accepted after a `Hello` with `AUTH`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    /// No optional features, as for a stream without a `Hello`
+    pub const NONE: Features = Features(0);
+    /// Understands `CloseWithReason`
+    pub const CLOSE_REASON: Features = Features(1);
+    /// Exchanges `Stats` before closing (`--send-stats`)
+    pub const STATS: Features = Features(1 << 1);
+    /// Understands `Auth`
+    pub const AUTH: Features = Features(1 << 2);
+
+    /// What this build supports on every stream; `STATS` is opt-in
+    pub fn supported() -> Features {
+        Features::CLOSE_REASON.with(Features::AUTH)
+    }
+
+    pub fn with(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Messages exchanged on a tunnel stream
+///
+/// Each frame is a 4-byte big-endian length followed by the message encoded
+/// with postcard. Postcard tags enum variants with their declaration index
+/// (a varint), so new variants must only ever be appended, and existing
+/// variants must not be reordered or have their fields changed; the golden
+/// tests below lock the encoding.
+///
+/// A requester may open a stream with `Hello`; the responder then answers
+/// with its own `Hello` before anything else. A stream without one has no
+/// optional `Features`.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum TunnelMessage {
-    Connect { host: String, port: u16 },
+    Connect {
+        host: String,
+        port: u16,
+    },
     Connected,
-    Error { message: String },
-    Data { data: Vec<u8> },
+    Error {
+        message: String,
+    },
+    Data {
+        data: Vec<u8>,
+    },
     Close,
+    /// Ask the exit node to resolve `host` without connecting
+    Resolve {
+        host: String,
+    },
+    /// Answer to `Resolve`
+    Resolved {
+        addrs: Vec<IpAddr>,
+    },
+    /// Liveness probe sent by the health monitor
+    Ping,
+    /// Answer to `Ping`
+    Pong,
+    /// Abnormal end of a stream; bare `Close` still means a clean end
+    CloseWithReason {
+        code: u16,
+        message: String,
+    },
+    /// Sender's totals for a stream, sent just before closing it
+    /// (only with `--send-stats`)
+    Stats {
+        bytes_sent: u64,
+        bytes_received: u64,
+        duration_ms: u64,
+    },
+    /// Shared secret sent ahead of `Connect` / `Resolve` (`--auth-token`)
+    Auth {
+        token: String,
+    },
+    /// Sender's protocol version and features, first on the stream
+    Hello {
+        version: u16,
+        features: Features,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::relay::{decode_message, encode_message};
+
+    fn assert_golden(msg: TunnelMessage, bytes: &[u8]) {
+        assert_eq!(encode_message(&msg).unwrap(), bytes, "{:?}", msg);
+        let decoded = decode_message(bytes).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", msg));
+    }
+
+    #[test]
+    fn test_wire_format_is_stable() {
+        assert_golden(
+            TunnelMessage::Connect {
+                host: "a.io".to_string(),
+                port: 443,
+            },
+            &[0, 4, b'a', b'.', b'i', b'o', 0xbb, 0x03],
+        );
+        assert_golden(TunnelMessage::Connected, &[1]);
+        assert_golden(
+            TunnelMessage::Error {
+                message: "no".to_string(),
+            },
+            &[2, 2, b'n', b'o'],
+        );
+        assert_golden(
+            TunnelMessage::Data {
+                data: vec![0xde, 0xad],
+            },
+            &[3, 2, 0xde, 0xad],
+        );
+        assert_golden(TunnelMessage::Close, &[4]);
+        assert_golden(
+            TunnelMessage::Resolve {
+                host: "a.io".to_string(),
+            },
+            &[5, 4, b'a', b'.', b'i', b'o'],
+        );
+        let mut resolved = vec![6, 2, 0, 10, 0, 0, 1, 1];
+        resolved.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        assert_golden(
+            TunnelMessage::Resolved {
+                addrs: vec!["10.0.0.1".parse().unwrap(), "2001:db8::1".parse().unwrap()],
+            },
+            &resolved,
+        );
+        assert_golden(TunnelMessage::Ping, &[7]);
+        assert_golden(TunnelMessage::Pong, &[8]);
+        assert_golden(
+            TunnelMessage::CloseWithReason {
+                code: CLOSE_SHUTTING_DOWN,
+                message: "x".to_string(),
+            },
+            &[9, 2, 1, b'x'],
+        );
+        assert_golden(
+            TunnelMessage::Stats {
+                bytes_sent: 300,
+                bytes_received: 2,
+                duration_ms: 1,
+            },
+            &[10, 0xac, 0x02, 2, 1],
+        );
+        assert_golden(
+            TunnelMessage::Auth {
+                token: "k".to_string(),
+            },
+            &[11, 1, b'k'],
+        );
+        assert_golden(
+            TunnelMessage::Hello {
+                version: PROTOCOL_VERSION,
+                features: Features::supported().with(Features::STATS),
+            },
+            &[12, 3, 7],
+        );
+    }
+
+    #[test]
+    fn test_features() {
+        let supported = Features::supported();
+        assert!(supported.contains(Features::CLOSE_REASON));
+        assert!(supported.contains(Features::AUTH));
+        assert!(!supported.contains(Features::STATS));
+        assert!(supported.with(Features::STATS).contains(Features::STATS));
+        assert!(supported.contains(Features::NONE));
+        assert!(!Features::NONE.contains(Features::CLOSE_REASON));
+    }
+
+    #[test]
+    fn test_protocol_version_matches_alpn() {
+        assert_eq!(
+            crate::tunnel::state::TUNNEL_PROTOCOL,
+            format!("iroh-tunnel/{}", PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn test_unknown_variant_is_rejected() {
+        assert!(decode_message(&[200]).is_err());
+    }
 }
@@ -0,0 +1,147 @@
+// Per-peer traffic quotas for the exit node
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_QUOTA_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Slices the window is tracked in; usage falls off one slice at a time
+const QUOTA_BUCKETS: u32 = 24;
+
+/// Prefix of the `Error` message sent to a peer over its quota
+pub const QUOTA_EXCEEDED_MESSAGE: &str = "Traffic quota exceeded";
+
+/// Bytes one peer relayed during the last `window`
+///
+/// Kept as a few timestamped buckets, so the window rolls forward instead of
+/// resetting all at once.
+#[derive(Debug)]
+pub struct PeerUsage {
+    window: Duration,
+    buckets: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl PeerUsage {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Count `bytes` relayed now
+    pub fn record(&self, bytes: u64) {
+        self.record_at(bytes, Instant::now());
+    }
+
+    fn record_at(&self, bytes: u64, now: Instant) {
+        let bucket_len = self.window / QUOTA_BUCKETS;
+        let mut buckets = self.buckets.lock().unwrap();
+        match buckets.back_mut() {
+            Some((start, total)) if now.duration_since(*start) < bucket_len => *total += bytes,
+            _ => buckets.push_back((now, bytes)),
+        }
+    }
+
+    /// Bytes relayed within the window
+    pub fn used(&self) -> u64 {
+        self.used_at(Instant::now())
+    }
+
+    fn used_at(&self, now: Instant) -> u64 {
+        let mut buckets = self.buckets.lock().unwrap();
+        while let Some((start, _)) = buckets.front()
+            && now.duration_since(*start) >= self.window
+        {
+            buckets.pop_front();
+        }
+        buckets.iter().map(|(_, total)| total).sum()
+    }
+}
+
+/// Traffic allowance per remote node id (`--peer-quota`)
+///
+/// Counts bytes in both directions on each peer's connections. A peer over
+/// its quota has new requests refused; streams already open may finish.
+#[derive(Debug)]
+pub struct PeerQuotas {
+    limit: u64,
+    window: Duration,
+    peers: Mutex<HashMap<iroh::PublicKey, Arc<PeerUsage>>>,
+}
+
+impl PeerQuotas {
+    pub fn new(limit: u64, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Usage counter of `peer`, shared by all of its connections
+    pub fn usage(&self, peer: iroh::PublicKey) -> Arc<PeerUsage> {
+        let mut peers = self.peers.lock().unwrap();
+        // Forget peers that are gone and whose traffic has aged out
+        peers.retain(|_, usage| Arc::strong_count(usage) > 1 || usage.used() > 0);
+        Arc::clone(
+            peers
+                .entry(peer)
+                .or_insert_with(|| Arc::new(PeerUsage::new(self.window))),
+        )
+    }
+
+    /// Refuse `peer` if it used up its quota, with the reason
+    pub fn check(&self, peer: iroh::PublicKey) -> Result<(), String> {
+        let used = self.usage(peer).used();
+        if used >= self.limit {
+            Err(format!(
+                "{}: {} of {} bytes used in the last {}s",
+                QUOTA_EXCEEDED_MESSAGE,
+                used,
+                self.limit,
+                self.window.as_secs()
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    #[test]
+    fn test_usage_rolls_off_after_window() {
+        let window = Duration::from_secs(QUOTA_BUCKETS as u64 * 10);
+        let usage = PeerUsage::new(window);
+        let start = Instant::now();
+        usage.record_at(100, start);
+        usage.record_at(50, start + Duration::from_secs(5));
+        usage.record_at(30, start + window / 2);
+        assert_eq!(usage.used_at(start + window / 2), 180);
+
+        // The first bucket (150 bytes) ages out on its own
+        assert_eq!(usage.used_at(start + window), 30);
+        assert_eq!(usage.used_at(start + window + window / 2), 0);
+    }
+
+    #[test]
+    fn test_check_is_per_peer() {
+        let quotas = PeerQuotas::new(1000, DEFAULT_QUOTA_WINDOW);
+        let heavy = SecretKey::generate(&mut rand::rng()).public();
+        let light = SecretKey::generate(&mut rand::rng()).public();
+
+        let usage = quotas.usage(heavy);
+        usage.record(999);
+        assert!(quotas.check(heavy).is_ok());
+        usage.record(1);
+        let err = quotas.check(heavy).unwrap_err();
+        assert!(err.starts_with(QUOTA_EXCEEDED_MESSAGE), "{}", err);
+
+        quotas.usage(light).record(10);
+        assert!(quotas.check(light).is_ok());
+    }
+}
@@ -1,126 +1,1516 @@
 use anyhow::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::{Duration, Instant, sleep_until};
 
-use crate::http::parser::extract_http_info;
-use crate::tls::sni::extract_sni;
-use crate::tunnel::protocol::TunnelMessage;
+use crate::http::parser::{HttpRequestInfo, extract_http_info, extract_http_response_status};
+use crate::tls::sni::{ServerName, inspect_client_hello};
+use crate::tunnel::logger::Logger;
+use crate::tunnel::protocol::{
+    CLOSE_LIFETIME_EXCEEDED, CLOSE_PROTOCOL_ERROR, CLOSE_SHUTTING_DOWN, CLOSE_SOCKET_ERROR,
+    Features, PROTOCOL_VERSION, TunnelMessage,
+};
+use crate::tunnel::shutdown::Shutdown;
+use crate::tunnel::stats::ConnectionStats;
+use crate::utils::logging::{RequestId, detail, privacy_enabled, target, truncate};
+use crate::utils::parse_debug::parse_dump;
+
+/// Wire encoding of one message (postcard), without the length prefix
+pub fn encode_message(msg: &TunnelMessage) -> Result<Vec<u8>> {
+    Ok(postcard::to_stdvec(msg)?)
+}
+
+pub fn decode_message(data: &[u8]) -> Result<TunnelMessage> {
+    Ok(postcard::from_bytes(data)?)
+}
 
 pub async fn send_message(
-    stream: &mut iroh::endpoint::SendStream,
+    stream: &mut (impl AsyncWrite + Unpin),
     msg: &TunnelMessage,
 ) -> Result<()> {
-    let data = bincode::serialize(msg)?;
-    let len = (data.len() as u32).to_be_bytes();
-    stream.write_all(&len).await?;
-    stream.write_all(&data).await?;
+    send_message_with(stream, msg, &mut Vec::new()).await
+}
+
+/// `send_message` encoding into a caller-owned frame buffer
+///
+/// The buffer keeps its capacity between calls, so a loop sending many
+/// messages stops allocating once it has seen its largest frame.
+pub async fn send_message_with(
+    stream: &mut (impl AsyncWrite + Unpin),
+    msg: &TunnelMessage,
+    frame: &mut Vec<u8>,
+) -> Result<()> {
+    encode_frame(msg, frame)?;
+    stream.write_all(frame).await?;
+    Ok(())
+}
+
+/// Length prefix and message, written over the previous contents of `frame`
+fn encode_frame(msg: &TunnelMessage, frame: &mut Vec<u8>) -> Result<()> {
+    frame.clear();
+    frame.extend_from_slice(&[0; 4]);
+    postcard::to_io(msg, &mut *frame)?;
+    let len = (frame.len() - 4) as u32;
+    frame[..4].copy_from_slice(&len.to_be_bytes());
     Ok(())
 }
 
-pub async fn recv_message(stream: &mut iroh::endpoint::RecvStream) -> Result<TunnelMessage> {
+/// Read one message; for control streams that exchange a few messages
+pub async fn recv_message(stream: &mut (impl AsyncRead + Unpin)) -> Result<TunnelMessage> {
+    recv_into(stream, &mut Vec::new()).await
+}
+
+/// `recv_message` reading the frame into a caller-owned buffer
+///
+/// Not cancel-safe; inside `select!` use `MessageReader`.
+pub async fn recv_into(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+) -> Result<TunnelMessage> {
     let mut len_buf = [0u8; 4];
     stream.read_exact(&mut len_buf).await?;
     let len = u32::from_be_bytes(len_buf) as usize;
 
-    let mut buf = vec![0u8; len];
-    stream.read_exact(&mut buf).await?;
+    buf.clear();
+    buf.resize(len, 0);
+    stream.read_exact(buf).await?;
 
-    let msg = bincode::deserialize(&buf)?;
-    Ok(msg)
+    decode_message(buf)
 }
 
-// Relay data bidirectionally between tunnel streams and TCP socket
-// Returns (bytes_sent, bytes_received, sni)
-pub async fn relay_bidirectional(
-    send: &mut iroh::endpoint::SendStream,
-    recv: &mut iroh::endpoint::RecvStream,
-    mut socket: TcpStream,
-) -> (u64, u64, Option<String>) {
-    // We can't use tokio::spawn with borrowed data, so we do manual bidirectional relay
-    let (mut socket_read, mut socket_write) = socket.split();
-
-    let mut send_buf = vec![0u8; 8192];
-    let mut first_packet_socket = true;
-    let mut first_packet_tunnel = true;
-    let mut sni = None;
-    let mut bytes_sent = 0u64;
-    let mut bytes_received = 0u64;
+/// Open a stream with this node's `Hello`
+pub async fn send_hello(stream: &mut (impl AsyncWrite + Unpin), features: Features) -> Result<()> {
+    send_message(
+        stream,
+        &TunnelMessage::Hello {
+            version: PROTOCOL_VERSION,
+            features,
+        },
+    )
+    .await
+}
+
+/// Read the peer's `Hello`, if the stream starts with one, and the message after it
+///
+/// Without a `Hello` the peer has no optional features.
+pub async fn recv_after_hello(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<(Option<Features>, TunnelMessage)> {
+    match recv_message(stream).await? {
+        TunnelMessage::Hello { features, .. } => Ok((Some(features), recv_message(stream).await?)),
+        other => Ok((None, other)),
+    }
+}
+
+/// Cancel-safe `TunnelMessage` reader for use inside `select!`
+///
+/// `recv_message` loses a partially read frame if its future is dropped, which
+/// happens whenever another `select!` branch wins. This reader keeps partial
+/// frames in its buffer between calls instead.
+#[derive(Default)]
+pub struct MessageReader {
+    buf: Vec<u8>,
+}
+
+impl MessageReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Next complete message, reading more from `stream` as needed
+    pub async fn next(&mut self, stream: &mut iroh::endpoint::RecvStream) -> Result<TunnelMessage> {
+        let mut chunk = [0u8; 16 * 1024];
+        loop {
+            if let Some(msg) = self.take_message()? {
+                return Ok(msg);
+            }
+            match stream.read(&mut chunk).await? {
+                Some(n) => self.buf.extend_from_slice(&chunk[..n]),
+                None => anyhow::bail!("Tunnel stream closed"),
+            }
+        }
+    }
+
+    fn take_message(&mut self) -> Result<Option<TunnelMessage>> {
+        take_frame(&mut self.buf)
+    }
+}
+
+/// Decode and remove the first message of `buf`, if it holds a whole frame
+fn take_frame(buf: &mut Vec<u8>) -> Result<Option<TunnelMessage>> {
+    let Some(len_bytes) = buf.first_chunk::<4>() else {
+        return Ok(None);
+    };
+    let len = u32::from_be_bytes(*len_bytes) as usize;
+    if buf.len() < 4 + len {
+        return Ok(None);
+    }
+    let msg = decode_message(&buf[4..4 + len])?;
+    buf.drain(..4 + len);
+    Ok(Some(msg))
+}
 
-    loop {
+/// Largest socket read relayed as one `Data` message, unless configured
+pub const DEFAULT_CHUNK_SIZE: usize = 8192;
+
+/// Bounds of `--relay-chunk-size`
+pub const MIN_CHUNK_SIZE: usize = 1024;
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Reads at least this big are sent at once even when coalescing
+const COALESCE_MAX_READ: usize = 1024;
+
+/// How `relay_bidirectional` treats a stream, on either end of the tunnel
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayOptions {
+    /// Hold small reads this long to send them as one tunnel message
+    /// (`Duration::ZERO` sends every read at once)
+    pub coalesce: Duration,
+    /// Largest socket read, and so `Data` message, relayed at once
+    ///
+    /// QUIC packs stream data into packets of the path MTU whatever the
+    /// message size, so this trades per-message overhead (framing, one read
+    /// and write each) against how much is read ahead of the tunnel.
+    pub chunk_size: usize,
+    /// Send the stream's byte counts to the peer as `Stats` before closing
+    ///
+    /// Advertised as `Features::STATS`; `negotiated` turns it off for a peer
+    /// that doesn't advertise it too.
+    pub send_stats: bool,
+    /// Report an abnormal end with `CloseWithReason` rather than a bare `Close`
+    ///
+    /// On by default; `negotiated` turns it off for a peer without
+    /// `Features::CLOSE_REASON`.
+    pub close_reasons: bool,
+    /// Log a hex dump and the TLS/HTTP parse results of each direction's first chunk
+    pub debug_parse: bool,
+    /// Close the stream this long after it started, however busy it is
+    /// (`Duration::ZERO` lets it live as long as both ends do)
+    pub max_lifetime: Duration,
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        Self {
+            coalesce: Duration::ZERO,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            send_stats: false,
+            close_reasons: true,
+            debug_parse: false,
+            max_lifetime: Duration::ZERO,
+        }
+    }
+}
+
+impl RelayOptions {
+    /// Features to advertise in this node's `Hello`
+    pub fn features(&self) -> Features {
+        match self.send_stats {
+            true => Features::supported().with(Features::STATS),
+            false => Features::supported(),
+        }
+    }
+
+    /// These options narrowed to what a peer advertising `peer` understands
+    pub fn negotiated(&self, peer: Features) -> RelayOptions {
+        RelayOptions {
+            send_stats: self.send_stats && peer.contains(Features::STATS),
+            close_reasons: self.close_reasons && peer.contains(Features::CLOSE_REASON),
+            ..self.clone()
+        }
+    }
+
+    /// `CloseWithReason`, or a bare `Close` without `close_reasons`
+    pub fn close_message(&self, code: u16, message: impl Into<String>) -> TunnelMessage {
+        match self.close_reasons {
+            true => close_with_reason(code, message),
+            false => TunnelMessage::Close,
+        }
+    }
+}
+
+/// Which way a chunk travels in `copy_bidirectional_counted`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Read from `a`, written to `b`
+    Sent,
+    /// Read from `b`, written to `a`
+    Received,
+}
+
+/// How a `copy_bidirectional_counted` ended
+#[derive(Debug, Default)]
+pub struct CopyOutcome {
+    /// Bytes read from `a`
+    pub sent: u64,
+    /// Bytes read from `b`
+    pub received: u64,
+    /// First read or write error, in either direction
+    pub error: Option<io::Error>,
+    /// `stop` completed before either side finished
+    pub stopped: bool,
+    /// `options.max_lifetime` ran out before either side finished
+    pub expired: bool,
+}
+
+/// Copy between two owned read/write pairs until either side is done
+///
+/// Stops at the first EOF or error in either direction (there is no
+/// half-close), when `stop` completes, or once `options.max_lifetime` has
+/// passed. Bytes are counted as they are read, both in the outcome and in
+/// `stats`; `inspect` sees the first chunk of each direction, for SNI/HTTP
+/// sniffing.
+///
+/// Reads are at most `options.chunk_size` bytes. With a non-zero
+/// `options.coalesce` window, small reads from `a` are held for up to that
+/// long (or until a chunk fills) and written to `b` together. The first read
+/// and reads of COALESCE_MAX_READ bytes or more go out immediately.
+pub async fn copy_bidirectional_counted<AR, AW, BR, BW>(
+    (mut a_read, mut a_write): (AR, AW),
+    (mut b_read, mut b_write): (BR, BW),
+    stats: &ConnectionStats,
+    options: &RelayOptions,
+    mut inspect: impl FnMut(Direction, &[u8]),
+    stop: impl Future<Output = ()>,
+) -> CopyOutcome
+where
+    AR: AsyncRead + Unpin,
+    AW: AsyncWrite + Unpin,
+    BR: AsyncRead + Unpin,
+    BW: AsyncWrite + Unpin,
+{
+    let (coalesce, chunk_size) = (options.coalesce, options.chunk_size);
+    let mut outcome = CopyOutcome::default();
+    let mut up = vec![0u8; chunk_size];
+    let mut down = vec![0u8; chunk_size];
+    // Bytes held in `up` while coalescing, and when they must go out
+    let mut pending = 0;
+    let mut flush_at: Option<Instant> = None;
+    let mut first_up = true;
+    let mut first_down = true;
+    let expires_at =
+        (!options.max_lifetime.is_zero()).then(|| Instant::now() + options.max_lifetime);
+    tokio::pin!(stop);
+
+    let result = loop {
         tokio::select! {
-            // Read from socket, write to tunnel
-            result = socket_read.read(&mut send_buf) => {
-                match result {
-                    Ok(0) | Err(_) => break,
-                    Ok(n) => {
-                        // Try to extract protocol info from first packet FROM socket
-                        if first_packet_socket {
-                            first_packet_socket = false;
-
-                            // Try TLS SNI first
-                            if let Some(extracted_sni) = extract_sni(&send_buf[..n]) {
-                                sni = Some(extracted_sni.clone());
-                                println!("   🔒 SNI: {}", extracted_sni);
-                            }
-                            // If not TLS, try HTTP
-                            else if let Some(http_info) = extract_http_info(&send_buf[..n]) {
-                                let host_display = http_info.host.as_deref().unwrap_or("unknown");
-                                sni = Some(format!("{} {}", http_info.method, http_info.path));
-                                println!("   🌐 HTTP: {} {} (Host: {})",
-                                    http_info.method,
-                                    http_info.path,
-                                    host_display
-                                );
-                            }
-                        }
-
-                        bytes_sent += n as u64;
-                        let msg = TunnelMessage::Data {
-                            data: send_buf[..n].to_vec(),
-                        };
-                        if send_message(send, &msg).await.is_err() {
-                            break;
-                        }
+            result = a_read.read(&mut up[pending..]) => {
+                let n = match result {
+                    Ok(0) => break Ok(()),
+                    Ok(n) => n,
+                    Err(e) => break Err(e),
+                };
+                let first = std::mem::take(&mut first_up);
+                if first {
+                    inspect(Direction::Sent, &up[pending..pending + n]);
+                }
+                outcome.sent += n as u64;
+                stats.record_sent(n as u64);
+                pending += n;
+                let hold = !coalesce.is_zero()
+                    && !first
+                    && n < COALESCE_MAX_READ
+                    && pending < chunk_size;
+                if hold {
+                    flush_at.get_or_insert_with(|| Instant::now() + coalesce);
+                    continue;
+                }
+                flush_at = None;
+                let written = write_flush(&mut b_write, &up[..pending]).await;
+                pending = 0;
+                if let Err(e) = written {
+                    break Err(e);
+                }
+            }
+            result = b_read.read(&mut down) => {
+                let n = match result {
+                    Ok(0) => break Ok(()),
+                    Ok(n) => n,
+                    Err(e) => break Err(e),
+                };
+                if std::mem::take(&mut first_down) {
+                    inspect(Direction::Received, &down[..n]);
+                }
+                outcome.received += n as u64;
+                stats.record_received(n as u64);
+                if let Err(e) = write_flush(&mut a_write, &down[..n]).await {
+                    break Err(e);
+                }
+            }
+            // Coalescing window is over
+            _ = sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                flush_at = None;
+                let written = write_flush(&mut b_write, &up[..pending]).await;
+                pending = 0;
+                if let Err(e) = written {
+                    break Err(e);
+                }
+            }
+            _ = &mut stop => {
+                outcome.stopped = true;
+                break Ok(());
+            }
+            _ = sleep_until(expires_at.unwrap_or_else(Instant::now)), if expires_at.is_some() => {
+                outcome.expired = true;
+                break Ok(());
+            }
+        }
+    };
+
+    // Whatever was still being coalesced goes out before returning
+    if pending > 0 {
+        write_flush(&mut b_write, &up[..pending]).await.ok();
+    }
+    outcome.error = result.err();
+    outcome
+}
+
+async fn write_flush(writer: &mut (impl AsyncWrite + Unpin), data: &[u8]) -> io::Result<()> {
+    writer.write_all(data).await?;
+    writer.flush().await
+}
+
+/// The `Data` payloads of a tunnel stream as an `AsyncRead`
+///
+/// Reads end at the peer's `Close` or `CloseWithReason`. `Stats` and the close
+/// reason are kept for the caller; a malformed frame, or the stream ending
+/// without a close, is an error.
+pub struct DataReader<R> {
+    inner: R,
+    /// Received bytes not yet decoded
+    buf: Vec<u8>,
+    payload: Vec<u8>,
+    offset: usize,
+    done: bool,
+    /// `(bytes_sent, bytes_received, duration_ms)` the peer reported
+    pub peer_stats: Option<(u64, u64, u64)>,
+    /// Code and message of the peer's `CloseWithReason`
+    pub close_reason: Option<(u16, String)>,
+    /// Why the stream could not be read as tunnel messages
+    pub protocol_error: Option<String>,
+}
+
+impl<R: AsyncRead + Unpin> DataReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            payload: Vec::new(),
+            offset: 0,
+            done: false,
+            peer_stats: None,
+            close_reason: None,
+            protocol_error: None,
+        }
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind, message: String) -> io::Error {
+        self.done = true;
+        self.protocol_error = Some(message.clone());
+        io::Error::new(kind, message)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DataReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.offset < this.payload.len() {
+                let n = out.remaining().min(this.payload.len() - this.offset);
+                out.put_slice(&this.payload[this.offset..this.offset + n]);
+                this.offset += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+            match take_frame(&mut this.buf) {
+                Ok(Some(TunnelMessage::Data { data })) => {
+                    this.payload = data;
+                    this.offset = 0;
+                }
+                Ok(Some(TunnelMessage::Stats {
+                    bytes_sent,
+                    bytes_received,
+                    duration_ms,
+                })) => this.peer_stats = Some((bytes_sent, bytes_received, duration_ms)),
+                Ok(Some(TunnelMessage::Close)) => this.done = true,
+                Ok(Some(TunnelMessage::CloseWithReason { code, message })) => {
+                    this.close_reason = Some((code, message));
+                    this.done = true;
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    let mut chunk = [0u8; 16 * 1024];
+                    let mut read = ReadBuf::new(&mut chunk);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+                    if read.filled().is_empty() {
+                        let e =
+                            this.fail(io::ErrorKind::UnexpectedEof, "Tunnel stream closed".into());
+                        return Poll::Ready(Err(e));
                     }
+                    this.buf.extend_from_slice(read.filled());
                 }
+                Err(e) => {
+                    let e = this.fail(io::ErrorKind::InvalidData, e.to_string());
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// An `AsyncWrite` sending each write as one `Data` message on a tunnel stream
+///
+/// A write takes up to `chunk_size` bytes (DEFAULT_CHUNK_SIZE unless set with
+/// `with_chunk_size`). The payload and frame buffers are reused, so
+/// steady-state writes don't allocate.
+pub struct DataWriter<W> {
+    inner: W,
+    chunk_size: usize,
+    payload: Vec<u8>,
+    frame: Vec<u8>,
+    /// How much of `frame` is already written
+    written: usize,
+}
+
+impl<W: AsyncWrite + Unpin> DataWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self::with_chunk_size(inner, DEFAULT_CHUNK_SIZE)
+    }
+
+    pub fn with_chunk_size(inner: W, chunk_size: usize) -> Self {
+        Self {
+            inner,
+            chunk_size,
+            payload: Vec::new(),
+            frame: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// Send a control message such as `Close` after any data still buffered
+    pub async fn send(&mut self, msg: &TunnelMessage) -> Result<()> {
+        self.flush().await?;
+        encode_frame(msg, &mut self.frame)?;
+        self.written = 0;
+        self.flush().await?;
+        Ok(())
+    }
+
+    fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.frame.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.frame[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for DataWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_frame(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(this.chunk_size);
+        // The payload moves into the message for encoding and back out afterwards
+        this.payload.clear();
+        this.payload.extend_from_slice(&buf[..n]);
+        let msg = TunnelMessage::Data {
+            data: std::mem::take(&mut this.payload),
+        };
+        let encoded = encode_frame(&msg, &mut this.frame);
+        if let TunnelMessage::Data { data } = msg {
+            this.payload = data;
+        }
+        encoded.map_err(io::Error::other)?;
+        this.written = 0;
+        // Start sending now; the rest goes out on the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_frame(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_frame(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_frame(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub fn close_with_reason(code: u16, message: impl Into<String>) -> TunnelMessage {
+    TunnelMessage::CloseWithReason {
+        code,
+        message: message.into(),
+    }
+}
+
+/// Longest User-Agent printed in the HTTP log line, in characters
+const MAX_USER_AGENT_LOG: usize = 80;
+
+/// What a stream carries, judged by the first packet of each direction
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProtocolKind {
+    /// A TLS handshake record, usually a ClientHello
+    Tls,
+    /// A plaintext HTTP request or response
+    Http,
+    /// Anything else, e.g. SSH or a protocol where the server speaks first
+    #[default]
+    Unknown,
+}
+
+impl ProtocolKind {
+    /// Label in the stats line
+    pub fn label(self) -> &'static str {
+        match self {
+            ProtocolKind::Tls => "TLS",
+            ProtocolKind::Http => "HTTP",
+            ProtocolKind::Unknown => "unknown protocol",
+        }
+    }
+}
+
+/// What the first packet of a direction reveals about the stream
+#[derive(Clone, Debug, PartialEq)]
+pub enum FirstPacket {
+    /// A TLS handshake record; the server name if it is a readable ClientHello
+    Tls(Option<ServerName>),
+    HttpRequest(HttpRequestInfo),
+    /// The status code of a plaintext HTTP response
+    HttpResponse(u16),
+}
+
+impl FirstPacket {
+    /// Protocol and detail: the server name for TLS (empty if unreadable),
+    /// `METHOD /path` (or `WebSocket /path`) for an HTTP request, the status
+    /// code for an HTTP response
+    pub fn summary(&self) -> (ProtocolKind, String) {
+        match self {
+            FirstPacket::Tls(server_name) => (
+                ProtocolKind::Tls,
+                server_name
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_default(),
+            ),
+            FirstPacket::HttpRequest(info) if info.websocket_upgrade => {
+                (ProtocolKind::Http, format!("WebSocket {}", info.path))
+            }
+            FirstPacket::HttpRequest(info) => {
+                (ProtocolKind::Http, format!("{} {}", info.method, info.path))
+            }
+            FirstPacket::HttpResponse(code) => (ProtocolKind::Http, code.to_string()),
+        }
+    }
+
+    /// Log the SNI, request line or response status of request `id`
+    fn log(&self, id: RequestId, logger: &dyn Logger) {
+        match self {
+            FirstPacket::Tls(Some(server_name)) => {
+                logger.info(&format!(
+                    "   {} 🔒 SNI: {}",
+                    id,
+                    target(&server_name.to_string())
+                ));
             }
-            // Read from tunnel, write to socket
-            result = recv_message(recv) => {
-                match result {
-                    Ok(TunnelMessage::Data { data }) => {
-                        // Try to extract protocol info from first packet FROM tunnel
-                        if first_packet_tunnel {
-                            first_packet_tunnel = false;
-
-                            // Try TLS SNI first
-                            if let Some(extracted_sni) = extract_sni(&data) {
-                                sni = Some(extracted_sni.clone());
-                                println!("   🔒 SNI: {}", extracted_sni);
-                            }
-                            // If not TLS, try HTTP
-                            else if let Some(http_info) = extract_http_info(&data) {
-                                let host_display = http_info.host.as_deref().unwrap_or("unknown");
-                                sni = Some(format!("{} {}", http_info.method, http_info.path));
-                                println!("   🌐 HTTP: {} {} (Host: {})",
-                                    http_info.method,
-                                    http_info.path,
-                                    host_display
-                                );
-                            }
-                        }
-
-                        bytes_received += data.len() as u64;
-                        if socket_write.write_all(&data).await.is_err() {
-                            break;
-                        }
+            FirstPacket::Tls(None) => {}
+            FirstPacket::HttpRequest(info) => {
+                let host_display = info.host.as_deref().unwrap_or("unknown");
+                // The User-Agent can single out a client, so privacy mode drops it
+                let user_agent = match &info.user_agent {
+                    Some(user_agent) if !privacy_enabled() => {
+                        format!(", UA: {}", truncate(user_agent, MAX_USER_AGENT_LOG))
                     }
-                    Ok(TunnelMessage::Close) | Err(_) => break,
-                    _ => {}
+                    _ => String::new(),
+                };
+                logger.info(&format!(
+                    "   {} 🌐 HTTP: {} {} (Host: {}{})",
+                    id,
+                    info.method,
+                    target(&info.path),
+                    target(host_display),
+                    user_agent
+                ));
+                if info.websocket_upgrade {
+                    // The final stats then describe a WebSocket session, not one request
+                    logger.info(&format!("   {} 🔌 WebSocket upgrade", id));
+                }
+            }
+            FirstPacket::HttpResponse(code) => {
+                logger.info(&format!("   {} 🌐 HTTP response: {}", id, code))
+            }
+        }
+    }
+}
+
+/// Classify the first packet of either direction of a stream
+///
+/// Returns the protocol and the detail described in [`FirstPacket::summary`].
+/// `None` for anything else, e.g. SSH or a protocol where the server speaks
+/// first.
+pub fn sniff_first_packet(data: &[u8]) -> Option<(ProtocolKind, String)> {
+    parse_first_packet(data).map(|packet| packet.summary())
+}
+
+/// Parse the first packet of a stream, keeping what the log lines need
+///
+/// Checked in order: a ClientHello, any other TLS handshake record, an HTTP
+/// request, an HTTP response.
+fn parse_first_packet(data: &[u8]) -> Option<FirstPacket> {
+    if let Some(server_name) = inspect_client_hello(data) {
+        return Some(FirstPacket::Tls(Some(server_name)));
+    }
+    // A handshake record (content type 22, version 3.x) we can't read further
+    if let [0x16, 0x03, ..] = data {
+        return Some(FirstPacket::Tls(None));
+    }
+    if let Some(info) = extract_http_info(data) {
+        return Some(FirstPacket::HttpRequest(info));
+    }
+    extract_http_response_status(data).map(FirstPacket::HttpResponse)
+}
+
+/// `--debug-parse` output for the first chunk `relay_bidirectional` sees in `direction`
+fn log_parse_dump(id: RequestId, direction: Direction, data: &[u8], logger: &dyn Logger) {
+    let from = match direction {
+        Direction::Sent => "socket",
+        Direction::Received => "tunnel",
+    };
+    let dump = parse_dump(data);
+    logger.info(&format!("   {} 🔬 First chunk from the {}:", id, from));
+    for line in dump.lines() {
+        logger.info(&format!("      {}", line));
+    }
+}
+
+// Relay data bidirectionally between tunnel streams and a local socket (TCP,
+// unix or any in-memory stream)
+// Returns (bytes_sent, bytes_received, sni)
+//
+// The byte copying is `copy_bidirectional_counted`; this adds the tunnel
+// framing (`DataReader` / `DataWriter`) and tells the peer how the stream
+// ended. `options.coalesce` applies to reads from the socket. What it finds
+// in the stream is logged through `logger`, tagged with request `id`.
+pub async fn relay_bidirectional(
+    send: &mut (impl AsyncWrite + Unpin),
+    recv: &mut (impl AsyncRead + Unpin),
+    socket: impl AsyncRead + AsyncWrite + Unpin,
+    stats: &ConnectionStats,
+    shutdown: &Shutdown,
+    (id, logger): (RequestId, &dyn Logger),
+    options: &RelayOptions,
+) -> (u64, u64, ProtocolKind, Option<String>) {
+    let started = Instant::now();
+    let mut reader = DataReader::new(&mut *recv);
+    let mut writer = DataWriter::with_chunk_size(&mut *send, options.chunk_size);
+    let mut protocol = ProtocolKind::Unknown;
+    let mut sni = None;
+    let mut status = None;
+
+    // Both ends relay with this, so requests may arrive from either side; the
+    // first chunk of each direction is checked for a request and a response
+    let outcome = copy_bidirectional_counted(
+        tokio::io::split(socket),
+        (&mut reader, &mut writer),
+        stats,
+        options,
+        |direction, data| {
+            if options.debug_parse {
+                log_parse_dump(id, direction, data, logger);
+            }
+            let Some(packet) = parse_first_packet(data) else {
+                return;
+            };
+            packet.log(id, logger);
+            let (kind, detail) = packet.summary();
+            protocol = kind;
+            match packet {
+                FirstPacket::HttpResponse(code) => status = Some(code),
+                _ if !detail.is_empty() => sni = Some(detail),
+                _ => {}
+            }
+        },
+        shutdown.wait(),
+    )
+    .await;
+    // The stats line shows the request with its response, e.g. "GET / → 404"
+    if let (Some(request), Some(code)) = (&mut sni, status) {
+        request.push_str(&format!(" → {}", code));
+    }
+
+    if let Some((code, message)) = &reader.close_reason {
+        logger.warn(&format!(
+            "   {} ⚠️  Peer closed the stream: {} (code {})",
+            id,
+            detail(message),
+            code
+        ));
+    }
+    if let Some((bytes_sent, bytes_received, duration_ms)) = reader.peer_stats {
+        logger.info(&format!(
+            "   {} 📊 Peer stats: ↑ {} bytes sent, ↓ {} bytes received in {} ms",
+            id, bytes_sent, bytes_received, duration_ms
+        ));
+        stats.record_peer_report(bytes_sent, bytes_received);
+    }
+
+    // What to tell the peer; anything but a clean EOF gets a reason
+    let close = if outcome.stopped {
+        options.close_message(CLOSE_SHUTTING_DOWN, "Peer is shutting down")
+    } else if outcome.expired {
+        logger.info(&format!(
+            "   {} ⏰ Open for the maximum lifetime of {:?}, closing",
+            id, options.max_lifetime
+        ));
+        options.close_message(
+            CLOSE_LIFETIME_EXCEEDED,
+            format!("Connection lifetime of {:?} reached", options.max_lifetime),
+        )
+    } else if let Some(e) = &reader.protocol_error {
+        options.close_message(CLOSE_PROTOCOL_ERROR, e.clone())
+    } else if let Some(e) = &outcome.error {
+        options.close_message(CLOSE_SOCKET_ERROR, e.to_string())
+    } else {
+        TunnelMessage::Close
+    };
+    if options.send_stats {
+        let totals = TunnelMessage::Stats {
+            bytes_sent: outcome.sent,
+            bytes_received: outcome.received,
+            duration_ms: started.elapsed().as_millis() as u64,
+        };
+        writer.send(&totals).await.ok();
+    }
+    writer.send(&close).await.ok();
+    (outcome.sent, outcome.received, protocol, sni)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::test_support::{CapturingLogger, connected_pair};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Counts allocations made by the current thread, to measure hot paths
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    fn allocations_during(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    fn frame_of(msg: &TunnelMessage) -> Vec<u8> {
+        let data = encode_message(msg).unwrap();
+        let mut framed = (data.len() as u32).to_be_bytes().to_vec();
+        framed.extend_from_slice(&data);
+        framed
+    }
+
+    #[test]
+    fn test_sniff_marks_websocket_upgrade() {
+        let plain = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(
+            sniff_first_packet(plain),
+            Some((ProtocolKind::Http, "GET /index.html".to_string()))
+        );
+        let upgrade = b"GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        assert_eq!(
+            sniff_first_packet(upgrade),
+            Some((ProtocolKind::Http, "WebSocket /chat".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_sniff_first_packet_tls() {
+        // ClientHello without extensions, so without a server name
+        let mut hello = vec![0u8; 43];
+        hello[..6].copy_from_slice(&[0x16, 0x03, 0x01, 0x00, 0x00, 0x01]);
+        hello.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0, 0, 0]);
+        assert_eq!(
+            parse_first_packet(&hello),
+            Some(FirstPacket::Tls(Some(ServerName::Missing)))
+        );
+        assert_eq!(
+            sniff_first_packet(&hello),
+            Some((ProtocolKind::Tls, "no-SNI (IP literal)".to_string()))
+        );
+        // A handshake record too short to read is still TLS
+        assert_eq!(
+            parse_first_packet(&hello[..10]),
+            Some(FirstPacket::Tls(None))
+        );
+        assert_eq!(
+            sniff_first_packet(&hello[..10]),
+            Some((ProtocolKind::Tls, String::new()))
+        );
+    }
+
+    #[test]
+    fn test_sniff_first_packet_http() {
+        let request = b"POST /api HTTP/1.1\r\nHost: example.com\r\nUser-Agent: curl/8.5\r\n\r\n";
+        let Some(FirstPacket::HttpRequest(info)) = parse_first_packet(request) else {
+            panic!("request not recognized");
+        };
+        assert_eq!(info.host.as_deref(), Some("example.com"));
+        assert_eq!(info.user_agent.as_deref(), Some("curl/8.5"));
+        assert_eq!(
+            sniff_first_packet(request),
+            Some((ProtocolKind::Http, "POST /api".to_string()))
+        );
+
+        let response = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(
+            parse_first_packet(response),
+            Some(FirstPacket::HttpResponse(404))
+        );
+        assert_eq!(
+            sniff_first_packet(response),
+            Some((ProtocolKind::Http, "404".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_sniff_first_packet_unknown() {
+        for unknown in [
+            &b"SSH-2.0-OpenSSH_9.6\r\n"[..],
+            b"\x00\x01\x02",
+            b"\x17\x03\x03\x00\x10",
+            b"",
+        ] {
+            assert_eq!(sniff_first_packet(unknown), None);
+        }
+    }
+
+    #[test]
+    fn test_message_reader_keeps_partial_frames() {
+        let mut framed = frame_of(&TunnelMessage::Data {
+            data: b"hello".to_vec(),
+        });
+        framed.extend(frame_of(&TunnelMessage::Close));
+
+        let mut reader = MessageReader::new();
+        reader.buf.extend_from_slice(&framed[..6]);
+        assert!(reader.take_message().unwrap().is_none());
+
+        reader.buf.extend_from_slice(&framed[6..]);
+        assert!(matches!(
+            reader.take_message().unwrap(),
+            Some(TunnelMessage::Data { data }) if data == b"hello"
+        ));
+        assert!(matches!(
+            reader.take_message().unwrap(),
+            Some(TunnelMessage::Close)
+        ));
+        assert!(reader.take_message().unwrap().is_none());
+    }
+
+    /// Yields `data` once, then fails as if the connection was reset
+    struct ResetAfter {
+        data: Option<Vec<u8>>,
+    }
+
+    impl AsyncRead for ResetAfter {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            out: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            match self.data.take() {
+                Some(data) => {
+                    out.put_slice(&data);
+                    Poll::Ready(Ok(()))
                 }
+                None => Poll::Ready(Err(io::ErrorKind::ConnectionReset.into())),
             }
         }
     }
 
-    send_message(send, &TunnelMessage::Close).await.ok();
-    (bytes_sent, bytes_received, sni)
+    #[tokio::test]
+    async fn test_copy_counts_both_directions() {
+        let (a, mut a_far) = tokio::io::duplex(64);
+        let (b, mut b_far) = tokio::io::duplex(64);
+        let stats = ConnectionStats::new();
+        let copy = tokio::spawn(async move {
+            let mut firsts = Vec::new();
+            let outcome = copy_bidirectional_counted(
+                tokio::io::split(a),
+                tokio::io::split(b),
+                &stats,
+                &RelayOptions::default(),
+                |direction, data| firsts.push((direction, data.to_vec())),
+                std::future::pending(),
+            )
+            .await;
+            (outcome, firsts, stats.snapshot())
+        });
+
+        a_far.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        b_far.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        b_far.write_all(b"hi").await.unwrap();
+        let mut buf = [0u8; 2];
+        a_far.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+        a_far.write_all(b" again").await.unwrap();
+        let mut buf = [0u8; 6];
+        b_far.read_exact(&mut buf).await.unwrap();
+        drop(a_far);
+
+        let (outcome, firsts, snapshot) = copy.await.unwrap();
+        assert_eq!((outcome.sent, outcome.received), (11, 2));
+        assert!(outcome.error.is_none() && !outcome.stopped);
+        assert_eq!(
+            firsts,
+            vec![
+                (Direction::Sent, b"hello".to_vec()),
+                (Direction::Received, b"hi".to_vec())
+            ]
+        );
+        assert_eq!((snapshot.bytes_sent, snapshot.bytes_received), (11, 2));
+    }
+
+    #[tokio::test]
+    async fn test_copy_reports_error_mid_transfer() {
+        let (a, mut a_far) = tokio::io::duplex(64);
+        let b = ResetAfter {
+            data: Some(b"partial".to_vec()),
+        };
+        let outcome = copy_bidirectional_counted(
+            tokio::io::split(a),
+            (b, tokio::io::sink()),
+            &ConnectionStats::new(),
+            &RelayOptions::default(),
+            |_, _| {},
+            std::future::pending(),
+        )
+        .await;
+
+        // What arrived before the error was delivered and counted
+        let mut buf = [0u8; 7];
+        a_far.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"partial");
+        assert_eq!(outcome.received, 7);
+        assert_eq!(
+            outcome.error.unwrap().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+
+        // Failing the other way round: the destination stops taking data
+        let (a, mut a_far) = tokio::io::duplex(64);
+        let (b_read, _b_read_far) = tokio::io::duplex(64);
+        let (b_write, b_write_far) = tokio::io::duplex(64);
+        drop(b_write_far);
+        a_far.write_all(b"lost").await.unwrap();
+        let outcome = copy_bidirectional_counted(
+            tokio::io::split(a),
+            (b_read, b_write),
+            &ConnectionStats::new(),
+            &RelayOptions::default(),
+            |_, _| {},
+            std::future::pending(),
+        )
+        .await;
+        assert_eq!(outcome.sent, 4);
+        assert_eq!(outcome.error.unwrap().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn test_data_adapters_frame_and_unframe() {
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        let mut writer = DataWriter::new(near);
+        let mut reader = DataReader::new(far);
+
+        writer
+            .write_all(&[9; DEFAULT_CHUNK_SIZE + 10])
+            .await
+            .unwrap();
+        writer
+            .send(&TunnelMessage::Stats {
+                bytes_sent: 1,
+                bytes_received: 2,
+                duration_ms: 3,
+            })
+            .await
+            .unwrap();
+        writer.send(&close_with_reason(7, "done")).await.unwrap();
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, vec![9; DEFAULT_CHUNK_SIZE + 10]);
+        assert_eq!(reader.peer_stats, Some((1, 2, 3)));
+        assert_eq!(reader.close_reason, Some((7, "done".to_string())));
+        assert!(reader.protocol_error.is_none());
+
+        // Garbage instead of a frame
+        let (mut near, far) = tokio::io::duplex(64);
+        near.write_all(&[0, 0, 0, 1, 0xff]).await.unwrap();
+        let mut reader = DataReader::new(far);
+        let err = reader.read(&mut [0u8; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(reader.protocol_error.is_some());
+    }
+
+    /// One stream relayed on the "server" side to a local destination socket
+    struct RelayUnderTest {
+        _endpoints: (iroh::Endpoint, iroh::Endpoint),
+        send: iroh::endpoint::SendStream,
+        recv: iroh::endpoint::RecvStream,
+        shutdown: Shutdown,
+        /// Stats of the connection the relay runs on
+        stats: std::sync::Arc<ConnectionStats>,
+        /// What the relay logged
+        logger: CapturingLogger,
+        relay: tokio::task::JoinHandle<()>,
+    }
+
+    impl RelayUnderTest {
+        /// Start relaying; returns the destination side of the socket too
+        async fn start(options: RelayOptions) -> (Self, TcpStream) {
+            let (client, server, client_conn, server_conn) = connected_pair().await;
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let socket = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut dest, _) = listener.accept().await.unwrap();
+
+            let (mut send, recv) = client_conn.open_bi().await.unwrap();
+            send_message(
+                &mut send,
+                &TunnelMessage::Data {
+                    data: b"hi".to_vec(),
+                },
+            )
+            .await
+            .unwrap();
+            let shutdown = Shutdown::new();
+            let relay_shutdown = shutdown.clone();
+            let stats = std::sync::Arc::new(ConnectionStats::new());
+            let relay_stats = stats.clone();
+            let logger = CapturingLogger::default();
+            let relay_logger = logger.clone();
+            let relay = tokio::spawn(async move {
+                let (mut send, mut recv) = server_conn.accept_bi().await.unwrap();
+                relay_bidirectional(
+                    &mut send,
+                    &mut recv,
+                    socket,
+                    &relay_stats,
+                    &relay_shutdown,
+                    (RequestId::next(), &relay_logger),
+                    &options,
+                )
+                .await;
+                send.finish().unwrap();
+                send.stopped().await.ok();
+            });
+
+            // The relay is running once the first message came through
+            let mut buf = [0u8; 2];
+            dest.read_exact(&mut buf).await.unwrap();
+            let relay = Self {
+                _endpoints: (client, server),
+                send,
+                recv,
+                shutdown,
+                stats,
+                logger,
+                relay,
+            };
+            (relay, dest)
+        }
+
+        async fn next_message(&mut self) -> TunnelMessage {
+            recv_message(&mut self.recv).await.unwrap()
+        }
+
+        /// Messages the relay sends until the stream closes, the close last
+        async fn messages(mut self) -> Vec<TunnelMessage> {
+            let mut messages = Vec::new();
+            loop {
+                let msg = self.next_message().await;
+                let done = matches!(
+                    msg,
+                    TunnelMessage::Close | TunnelMessage::CloseWithReason { .. }
+                );
+                messages.push(msg);
+                if done {
+                    break;
+                }
+            }
+            drop(self.send);
+            self.relay.await.unwrap();
+            messages
+        }
+    }
+
+    /// End a relayed stream the way `end` does and return what the other
+    /// side is told
+    async fn close_message_after(end: &str) -> TunnelMessage {
+        let (relay, dest) = RelayUnderTest::start(RelayOptions::default()).await;
+        match end {
+            "eof" => drop(dest),
+            "reset" => {
+                socket2::SockRef::from(&dest)
+                    .set_linger(Some(Duration::ZERO))
+                    .unwrap();
+                drop(dest);
+            }
+            "shutdown" => relay.shutdown.trigger(),
+            _ => unreachable!(),
+        }
+        relay.messages().await.pop().unwrap()
+    }
+
+    /// Write `chunks` to the destination side with a pause between each
+    /// (so each is its own read), then return the payloads relayed
+    async fn relayed_chunks(coalesce: Duration, chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let options = RelayOptions {
+            coalesce,
+            ..Default::default()
+        };
+        let (relay, mut dest) = RelayUnderTest::start(options).await;
+        for chunk in chunks {
+            dest.write_all(chunk).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        dest.shutdown().await.unwrap();
+        relay
+            .messages()
+            .await
+            .into_iter()
+            .filter_map(|msg| match msg {
+                TunnelMessage::Data { data } => Some(data),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_small_reads_coalesce() {
+        let chunks: [&[u8]; 5] = [b"first", b"a", b"b", b"c", &[0; COALESCE_MAX_READ]];
+
+        // First read alone, then the small ones together with the large read
+        // that flushed them
+        let mut expected = b"abc".to_vec();
+        expected.extend_from_slice(&[0; COALESCE_MAX_READ]);
+        assert_eq!(
+            relayed_chunks(Duration::from_secs(5), &chunks).await,
+            vec![b"first".to_vec(), expected]
+        );
+
+        // Disabled: one message per read
+        assert_eq!(relayed_chunks(Duration::ZERO, &chunks[..4]).await.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_large_payload_split_into_chunks() {
+        let options = RelayOptions {
+            chunk_size: MIN_CHUNK_SIZE,
+            ..Default::default()
+        };
+        let (relay, mut dest) = RelayUnderTest::start(options).await;
+        let payload: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        dest.write_all(&payload).await.unwrap();
+        dest.shutdown().await.unwrap();
+
+        let chunks: Vec<Vec<u8>> = relay
+            .messages()
+            .await
+            .into_iter()
+            .filter_map(|msg| match msg {
+                TunnelMessage::Data { data } => Some(data),
+                _ => None,
+            })
+            .collect();
+        assert!(
+            chunks.len() >= payload.len() / MIN_CHUNK_SIZE,
+            "{}",
+            chunks.len()
+        );
+        assert!(chunks.iter().all(|chunk| chunk.len() <= MIN_CHUNK_SIZE));
+        assert_eq!(chunks.concat(), payload);
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_reads_flush_after_window() {
+        let options = RelayOptions {
+            coalesce: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let (mut relay, mut dest) = RelayUnderTest::start(options).await;
+        for chunk in [&b"first"[..], b"a", b"b"] {
+            dest.write_all(chunk).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // Sent without the socket closing
+        for expected in [&b"first"[..], b"ab"] {
+            match relay.next_message().await {
+                TunnelMessage::Data { data } => assert_eq!(data, expected),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        drop(dest);
+        assert!(matches!(relay.messages().await[..], [TunnelMessage::Close]));
+    }
+
+    #[tokio::test]
+    async fn test_clean_and_abnormal_close_differ() {
+        assert!(matches!(
+            close_message_after("eof").await,
+            TunnelMessage::Close
+        ));
+        assert!(matches!(
+            close_message_after("reset").await,
+            TunnelMessage::CloseWithReason {
+                code: CLOSE_SOCKET_ERROR,
+                ..
+            }
+        ));
+        assert!(matches!(
+            close_message_after("shutdown").await,
+            TunnelMessage::CloseWithReason {
+                code: CLOSE_SHUTTING_DOWN,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_options_negotiated_with_peer() {
+        let options = RelayOptions {
+            send_stats: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            options.features(),
+            Features::supported().with(Features::STATS)
+        );
+        assert_eq!(RelayOptions::default().features(), Features::supported());
+
+        // Stats only when both ends advertise them
+        let both = options.negotiated(options.features());
+        assert!(both.send_stats && both.close_reasons);
+        let peer = options.negotiated(Features::supported());
+        assert!(!peer.send_stats && peer.close_reasons);
+        let local = RelayOptions::default().negotiated(options.features());
+        assert!(!local.send_stats);
+
+        // A peer without CLOSE_REASON only ever gets a bare Close
+        let old = options.negotiated(Features::NONE);
+        assert!(!old.send_stats && !old.close_reasons);
+        assert!(matches!(
+            old.close_message(CLOSE_SOCKET_ERROR, "reset"),
+            TunnelMessage::Close
+        ));
+        assert!(matches!(
+            both.close_message(CLOSE_SOCKET_ERROR, "reset"),
+            TunnelMessage::CloseWithReason {
+                code: CLOSE_SOCKET_ERROR,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_max_lifetime_closes_busy_stream() {
+        let options = RelayOptions {
+            max_lifetime: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let (relay, mut dest) = RelayUnderTest::start(options).await;
+        let logger = relay.logger.clone();
+        let started = Instant::now();
+        // Keep data flowing the whole time, so only the lifetime can end it
+        let writer = tokio::spawn(async move {
+            while dest.write_all(b"still busy").await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+
+        let messages = relay.messages().await;
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(
+            messages
+                .iter()
+                .any(|msg| matches!(msg, TunnelMessage::Data { .. }))
+        );
+        assert!(matches!(
+            messages.last(),
+            Some(TunnelMessage::CloseWithReason {
+                code: CLOSE_LIFETIME_EXCEEDED,
+                ..
+            })
+        ));
+        assert!(
+            logger.lines().iter().any(|line| line.starts_with("info ")
+                && line.contains("⏰ Open for the maximum lifetime"))
+        );
+        tokio::time::timeout(Duration::from_secs(5), writer)
+            .await
+            .expect("destination socket stayed open")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_reused_frame_buffer_stops_allocating() {
+        let chunk = vec![7u8; DEFAULT_CHUNK_SIZE];
+
+        // Previous hot path: copy the payload, then encode into a fresh Vec
+        let fresh = allocations_during(|| {
+            for _ in 0..100 {
+                let msg = TunnelMessage::Data {
+                    data: chunk.to_vec(),
+                };
+                encode_message(&msg).unwrap();
+            }
+        });
+        assert!(fresh >= 200, "{} allocations", fresh);
+
+        // Relay loop: payload moved in and out, frame buffer reused
+        let mut payload = chunk.clone();
+        let mut frame = Vec::new();
+        encode_frame(&TunnelMessage::Data { data: chunk }, &mut frame).unwrap();
+        let reused = allocations_during(|| {
+            for _ in 0..100 {
+                let msg = TunnelMessage::Data {
+                    data: std::mem::take(&mut payload),
+                };
+                encode_frame(&msg, &mut frame).unwrap();
+                if let TunnelMessage::Data { data } = msg {
+                    payload = data;
+                }
+            }
+        });
+        assert_eq!(reused, 0);
+        assert_eq!(frame, frame_of(&TunnelMessage::Data { data: payload }));
+    }
+
+    #[tokio::test]
+    async fn test_recv_into_reuses_buffer() {
+        let (_client, _server, client_conn, server_conn) = connected_pair().await;
+        let (mut send, _recv) = client_conn.open_bi().await.unwrap();
+        let messages = [
+            TunnelMessage::Data {
+                data: vec![1; 20_000],
+            },
+            TunnelMessage::Connect {
+                host: "a.io".to_string(),
+                port: 443,
+            },
+            TunnelMessage::Data { data: vec![2; 300] },
+            TunnelMessage::Close,
+        ];
+        let mut frame = Vec::new();
+        for msg in &messages {
+            send_message_with(&mut send, msg, &mut frame).await.unwrap();
+        }
+        send.finish().unwrap();
+
+        let (_send, mut recv) = server_conn.accept_bi().await.unwrap();
+        let mut buf = Vec::new();
+        for expected in &messages {
+            let msg = recv_into(&mut recv, &mut buf).await.unwrap();
+            assert_eq!(format!("{:?}", msg), format!("{:?}", expected));
+        }
+        // Sized for the largest frame and kept
+        assert!(buf.capacity() >= 20_000);
+        assert!(recv_into(&mut recv, &mut buf).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stats_exchanged_before_close() {
+        let options = RelayOptions {
+            send_stats: true,
+            ..Default::default()
+        };
+        let (mut relay, mut dest) = RelayUnderTest::start(options).await;
+        dest.write_all(&[5; 300]).await.unwrap();
+        let mut relayed = 0;
+        while relayed < 300 {
+            match relay.next_message().await {
+                TunnelMessage::Data { data } => relayed += data.len(),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+
+        // Our side reports first and closes; the relay records it and
+        // answers with its own totals
+        let ours = TunnelMessage::Stats {
+            bytes_sent: 2,
+            bytes_received: 300,
+            duration_ms: 5,
+        };
+        send_message(&mut relay.send, &ours).await.unwrap();
+        send_message(&mut relay.send, &TunnelMessage::Close)
+            .await
+            .unwrap();
+        let stats = relay.stats.clone();
+        let logger = relay.logger.clone();
+        match &relay.messages().await[..] {
+            [
+                TunnelMessage::Stats {
+                    bytes_sent,
+                    bytes_received,
+                    ..
+                },
+                TunnelMessage::Close,
+            ] => {
+                assert_eq!(*bytes_sent, 300);
+                assert_eq!(*bytes_received, 2);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.peer_bytes_sent, 2);
+        assert_eq!(snapshot.peer_bytes_received, 300);
+        assert!(logger.lines().iter().any(|line| line.starts_with("info ")
+            && line.ends_with("📊 Peer stats: ↑ 2 bytes sent, ↓ 300 bytes received in 5 ms")));
+    }
 }
@@ -0,0 +1,114 @@
+// Per-destination routing to different upstream peers
+use anyhow::{Context, Result};
+use iroh::PublicKey;
+use std::str::FromStr;
+
+use crate::tunnel::filter::HostRule;
+
+/// Send requests whose host matches `pattern` through `peer`
+///
+/// Parsed from `PATTERN=NODE_ID`, e.g. `*.corp.example.com=<node id>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub pattern: HostRule,
+    pub peer: PublicKey,
+}
+
+impl FromStr for Route {
+    type Err = anyhow::Error;
+
+    fn from_str(route: &str) -> Result<Self> {
+        let (pattern, peer) = route
+            .split_once('=')
+            .with_context(|| format!("Route must be PATTERN=NODE_ID: {}", route))?;
+        Ok(Self {
+            pattern: pattern.parse()?,
+            peer: peer
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid node ID in route: {}", route))?,
+        })
+    }
+}
+
+/// Ordered routes; the first matching pattern picks the peer
+///
+/// Requests matching no route use the default peer connection. Routing is by
+/// the requested host, so CIDR patterns only match IP-literal requests.
+#[derive(Clone, Debug, Default)]
+pub struct RouteTable {
+    routes: Vec<Route>,
+}
+
+impl RouteTable {
+    pub fn new(routes: Vec<Route>) -> Self {
+        Self { routes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Peer a request for `host` should be sent through, if a route matches
+    pub fn route(&self, host: &str) -> Option<PublicKey> {
+        self.routes
+            .iter()
+            .find(|route| route.pattern.matches_host(host))
+            .map(|route| route.peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> PublicKey {
+        iroh::SecretKey::generate(&mut rand::rng()).public()
+    }
+
+    #[test]
+    fn test_route_by_wildcard_and_exact_host() {
+        let (corp, lab) = (peer(), peer());
+        let table = RouteTable::new(vec![
+            format!("*.corp.example.com={}", corp).parse().unwrap(),
+            format!("lab.example.org={}", lab).parse().unwrap(),
+        ]);
+
+        assert_eq!(table.route("git.corp.example.com"), Some(corp));
+        assert_eq!(table.route("GIT.Corp.Example.com."), Some(corp));
+        assert_eq!(table.route("lab.example.org"), Some(lab));
+        // The wildcard doesn't cover the bare domain
+        assert_eq!(table.route("corp.example.com"), None);
+        assert_eq!(table.route("example.com"), None);
+    }
+
+    #[test]
+    fn test_route_first_match_wins() {
+        let (specific, general) = (peer(), peer());
+        let table = RouteTable::new(vec![
+            format!("build.corp.example.com={}", specific)
+                .parse()
+                .unwrap(),
+            format!("*.corp.example.com={}", general).parse().unwrap(),
+        ]);
+        assert_eq!(table.route("build.corp.example.com"), Some(specific));
+        assert_eq!(table.route("wiki.corp.example.com"), Some(general));
+    }
+
+    #[test]
+    fn test_route_cidr_matches_ip_literals() {
+        let office = peer();
+        let table = RouteTable::new(vec![format!("10.20.0.0/16={}", office).parse().unwrap()]);
+        assert_eq!(table.route("10.20.3.4"), Some(office));
+        assert_eq!(table.route("10.21.3.4"), None);
+        assert_eq!(table.route("intranet.example"), None);
+    }
+
+    #[test]
+    fn test_parse_route_errors() {
+        assert!("*.corp.example.com".parse::<Route>().is_err());
+        assert!("*.corp.example.com=not-a-node".parse::<Route>().is_err());
+        assert!(format!("*={}", peer()).parse::<Route>().is_err());
+        assert!(RouteTable::default().is_empty());
+    }
+}
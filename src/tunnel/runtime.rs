@@ -0,0 +1,1955 @@
+// Embeddable entry points: start a tunnel node and control it through a handle
+use anyhow::{Context, Result};
+use iroh::endpoint::{Builder, Endpoint, RelayMode};
+use iroh::{EndpointAddr, PublicKey, RelayMap, RelayUrl, SecretKey};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, broadcast};
+use tokio::task::JoinHandle;
+
+use crate::connection::logger::{
+    has_direct_path, path_type_label, wait_for_direct_path, watch_endpoint_addrs,
+};
+use crate::http::pac::{PacOptions, render_pac, serve_pac};
+use crate::metrics::prometheus::serve_metrics;
+#[cfg(unix)]
+use crate::metrics::status::serve_status;
+use crate::tls::server::SocksTls;
+use crate::tunnel::access_log::AccessLog;
+use crate::tunnel::auth::{AuthToken, is_peer_allowed, reject_busy_peer, reject_peer};
+use crate::tunnel::connection::{
+    close_tunnel, connect_peer, generate_ticket, handle_peer_connection_with_monitoring,
+    monitor_connection_health, watch_network_changes,
+};
+#[cfg(unix)]
+use crate::tunnel::control::{ControlContext, serve_control};
+use crate::tunnel::dns::{DEFAULT_DNS_CACHE_SIZE, DnsCache};
+use crate::tunnel::events::{EventBus, TunnelEvent};
+use crate::tunnel::filter::HostFilter;
+use crate::tunnel::flow::{MIN_STREAM_WINDOW, flow_control};
+use crate::tunnel::forward::{PortForward, serve_forward};
+use crate::tunnel::handler::{PeerForwarder, SocksHandler, SocksLayer};
+use crate::tunnel::logger::{Logger, SharedLogger};
+use crate::tunnel::outbound::{EgressBind, Outbound};
+use crate::tunnel::persistence::{
+    Profile, clear_remote_peer_id, get_or_create_secret_key, load_remote_peer_id, read_secret_key,
+    save_remote_peer_id,
+};
+use crate::tunnel::quota::{DEFAULT_QUOTA_WINDOW, PeerQuotas};
+use crate::tunnel::relay::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE, RelayOptions};
+use crate::tunnel::routing::RouteTable;
+use crate::tunnel::shutdown::Shutdown;
+use crate::tunnel::socks::{serve_socks_tls, serve_socks_with};
+use crate::tunnel::state::{ConnectionState, TUNNEL_ALPN, TunnelState};
+use crate::tunnel::stats::StatsSnapshot;
+use crate::tunnel::tcp::{MAX_DSCP, TcpOptions};
+use crate::tunnel::transport::{
+    DEFAULT_IDLE_TIMEOUT, DEFAULT_QUIC_KEEPALIVE, describe, quic_transport,
+};
+use crate::tunnel::upstream::UpstreamProxy;
+use crate::utils::logging::{set_privacy, target};
+use crate::utils::qr::render_qr;
+
+pub const DEFAULT_SOCKS_PORT: u16 = 1080;
+pub const DEFAULT_MAX_STREAMS: usize = 256;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+pub const DEFAULT_MAX_QUEUED: usize = 256;
+pub const DEFAULT_RECONNECT_WAIT: Duration = Duration::from_secs(30);
+pub const DEFAULT_CONNECT_RETRIES: u32 = 2;
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which network paths iroh may use to reach peers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PathMode {
+    /// Relay first, upgrading to a direct path when hole-punching succeeds
+    #[default]
+    Auto,
+    /// Keep traffic on the relay: the endpoint only binds loopback sockets, so
+    /// there is no local address a remote peer could reach directly
+    ///
+    /// This approximates relay-only rather than enforcing it: iroh's own
+    /// relay-only path selection is only built with its `test-utils` feature,
+    /// and a peer on the same host can still reach the loopback sockets.
+    RelayOnly,
+    /// Disable the relay; peers must be reachable directly
+    DirectOnly,
+}
+
+impl PathMode {
+    /// Relay mode to bind with, given the configured one
+    pub fn relay_mode(self, configured: &RelayMode) -> RelayMode {
+        match self {
+            PathMode::DirectOnly => RelayMode::Disabled,
+            PathMode::Auto | PathMode::RelayOnly => configured.clone(),
+        }
+    }
+
+    /// Restrict the endpoint's sockets and relay according to the mode
+    pub fn configure(self, builder: Builder, configured: &RelayMode) -> Builder {
+        let builder = builder.relay_mode(self.relay_mode(configured));
+        match self {
+            PathMode::RelayOnly => builder
+                .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+                .bind_addr_v6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 0, 0, 0)),
+            PathMode::Auto | PathMode::DirectOnly => builder,
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            PathMode::Auto => "relay + direct (hole-punching)",
+            PathMode::RelayOnly => "relay only (loopback sockets, no remote direct paths)",
+            PathMode::DirectOnly => "direct only (relay disabled)",
+        }
+    }
+}
+
+/// Parse a relay server URL, e.g. `https://relay.example.com`
+///
+/// Only http(s) URLs with a host are accepted, so a typo fails at startup
+/// rather than leaving the node silently unreachable.
+pub fn parse_relay_url(url: &str) -> Result<RelayUrl> {
+    let relay: RelayUrl = url
+        .parse()
+        .with_context(|| format!("Invalid relay URL: {}", url))?;
+    if !matches!(relay.scheme(), "http" | "https") {
+        anyhow::bail!("Relay URL must use http or https: {}", url);
+    }
+    if relay.host_str().is_none_or(str::is_empty) {
+        anyhow::bail!("Relay URL has no host: {}", url);
+    }
+    Ok(relay)
+}
+
+/// Settings for `run_server` / `run_client`
+///
+/// Start from `TunnelConfig::default()` and override what you need:
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use iroh_socks5_proxy::tunnel::runtime::{TunnelConfig, run_server};
+///
+/// let handle = run_server(TunnelConfig::default().socks_port(1081).max_streams(64)).await?;
+/// println!("ticket: {}", handle.ticket());
+/// handle.shutdown().await
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct TunnelConfig {
+    /// Local SOCKS5 listener; port 0 picks a free port
+    pub socks_addr: SocketAddr,
+    /// Peers to connect to, first is primary (client mode only)
+    pub peers: Vec<EndpointAddr>,
+    /// Node identity; `None` loads `.tunnel_key` (server) or generates one (client)
+    pub secret_key: Option<SecretKey>,
+    /// Which persisted identity and peer files to use (`--profile`)
+    pub profile: Profile,
+    /// Back up and replace a corrupt `.tunnel_key` instead of failing
+    pub regenerate_corrupt_key: bool,
+    pub relay_mode: RelayMode,
+    /// Restrict path selection (`--relay-only` / `--no-relay`)
+    pub path_mode: PathMode,
+    /// Wait up to this long for a direct path before serving (client mode only)
+    pub prefer_direct: Option<Duration>,
+    pub log_file: Option<String>,
+    /// Append one line per completed local request to this file
+    pub access_log: Option<PathBuf>,
+    /// Hide destination hosts, SNI and HTTP paths in log output (process-wide)
+    pub privacy: bool,
+    /// Also print the ticket as a terminal QR code (server mode only)
+    pub show_qr: bool,
+    /// Give up after this many failed reconnection attempts (0 = retry forever)
+    pub max_reconnect_attempts: u32,
+    /// How often the peer connection is checked (must be non-zero)
+    pub health_check_interval: Duration,
+    /// Treat the peer connection as dead after this long without a Pong (zero = never)
+    pub heartbeat_timeout: Duration,
+    /// QUIC keep-alive interval of the peer connection (zero = off)
+    pub keepalive_interval: Duration,
+    /// Drop a peer connection after this long without any QUIC traffic
+    pub idle_timeout: Duration,
+    /// Serve Prometheus metrics on localhost at this port
+    pub metrics_port: Option<u16>,
+    /// Serve a PAC file for the SOCKS listener on localhost
+    pub pac: Option<PacOptions>,
+    /// Node IDs allowed to connect (empty allows any peer)
+    pub allow_peers: Vec<PublicKey>,
+    /// Refuse other peers while one is connected
+    pub exclusive: bool,
+    /// Persist the first peer to connect and accept only it from then on
+    pub sticky_peer: bool,
+    /// Announce this node and find peers on the LAN over mDNS
+    ///
+    /// Everyone on the network sees the node ID and LAN addresses, so leave
+    /// it off on shared or public networks.
+    pub local_discovery: bool,
+    pub host_filter: HostFilter,
+    pub upstream_proxy: Option<UpstreamProxy>,
+    /// Extra attempts at a destination lookup or dial that failed transiently
+    pub connect_retries: u32,
+    /// Local addresses the server connects to destinations from
+    pub egress_bind: EgressBind,
+    /// Exit-side DNS cache TTL (zero disables the cache)
+    pub dns_cache_ttl: Duration,
+    /// Most hosts the exit-side DNS cache holds (0 disables the cache)
+    pub dns_cache_size: usize,
+    /// Concurrent tunnel requests per peer connection (0 = unlimited)
+    pub max_streams: usize,
+    /// Un-read bytes a peer may send per stream (0 = iroh's default window)
+    pub stream_window: u64,
+    /// Simultaneous local clients across all listeners (0 = unlimited)
+    pub max_connections: usize,
+    /// How long SOCKS requests wait for a peer connection (`--connect-wait`)
+    pub reconnect_wait: Duration,
+    /// SOCKS requests waiting for a reconnection at once (0 = unlimited)
+    pub max_queued: usize,
+    /// How long in-flight clients may finish after shutdown is triggered
+    pub drain_timeout: Duration,
+    /// Also accept iptables-REDIRECTed connections here (Linux only)
+    pub transparent_addr: Option<SocketAddr>,
+    /// Local ports tunnelled to fixed destinations, without SOCKS
+    pub forwards: Vec<PortForward>,
+    /// Serve a JSON status document on this unix socket (Unix only)
+    pub status_socket: Option<PathBuf>,
+    /// Take line-based commands on this unix socket (Unix only)
+    pub control_socket: Option<PathBuf>,
+    /// Also accept SOCKS5 clients on this unix socket (Unix only)
+    pub socks_unix: Option<PathBuf>,
+    /// Require TLS on the SOCKS5 TCP listener, with this certificate and key
+    pub socks_tls: Option<SocksTls>,
+    /// Destinations to send through peers other than the default one
+    pub routes: RouteTable,
+    /// TCP_NODELAY / keepalive for local clients and destination sockets
+    pub tcp_options: TcpOptions,
+    /// Read coalescing and `Stats` reporting for relayed streams
+    pub relay_options: RelayOptions,
+    /// Middleware around the handler of SOCKS requests
+    pub socks_layer: Option<SocksLayer>,
+    /// Receives local request and connection events (stdout by default)
+    pub logger: SharedLogger,
+    /// Shared secret sent with every request and required from peers
+    pub auth_token: Option<AuthToken>,
+    /// Bytes each peer may relay per `quota_window` (0 = unlimited)
+    pub peer_quota: u64,
+    pub quota_window: Duration,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        Self {
+            socks_addr: (Ipv4Addr::LOCALHOST, DEFAULT_SOCKS_PORT).into(),
+            peers: Vec::new(),
+            secret_key: None,
+            profile: Profile::default(),
+            regenerate_corrupt_key: false,
+            relay_mode: RelayMode::Default,
+            path_mode: PathMode::Auto,
+            prefer_direct: None,
+            log_file: None,
+            access_log: None,
+            privacy: false,
+            show_qr: false,
+            max_reconnect_attempts: 0,
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            keepalive_interval: DEFAULT_QUIC_KEEPALIVE,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            metrics_port: None,
+            pac: None,
+            allow_peers: Vec::new(),
+            exclusive: false,
+            sticky_peer: false,
+            local_discovery: false,
+            host_filter: HostFilter::default(),
+            upstream_proxy: None,
+            connect_retries: DEFAULT_CONNECT_RETRIES,
+            egress_bind: EgressBind::default(),
+            dns_cache_ttl: Duration::ZERO,
+            dns_cache_size: DEFAULT_DNS_CACHE_SIZE,
+            max_streams: DEFAULT_MAX_STREAMS,
+            stream_window: 0,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            reconnect_wait: DEFAULT_RECONNECT_WAIT,
+            max_queued: DEFAULT_MAX_QUEUED,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            transparent_addr: None,
+            forwards: Vec::new(),
+            status_socket: None,
+            control_socket: None,
+            socks_unix: None,
+            socks_tls: None,
+            routes: RouteTable::default(),
+            tcp_options: TcpOptions::default(),
+            relay_options: RelayOptions::default(),
+            socks_layer: None,
+            logger: SharedLogger::default(),
+            auth_token: None,
+            peer_quota: 0,
+            quota_window: DEFAULT_QUOTA_WINDOW,
+        }
+    }
+}
+
+impl TunnelConfig {
+    /// Reject settings that can't work together, before anything is started
+    pub fn validate(&self) -> Result<()> {
+        if self.prefer_direct.is_some() && self.path_mode == PathMode::RelayOnly {
+            anyhow::bail!("Preferring a direct path contradicts relay-only mode");
+        }
+        if self.local_discovery && self.path_mode == PathMode::RelayOnly {
+            anyhow::bail!("Local discovery contradicts relay-only mode");
+        }
+        if self.local_discovery && !cfg!(feature = "local-discovery") {
+            anyhow::bail!(
+                "Local discovery isn't built in; rebuild with the local-discovery feature"
+            );
+        }
+        if self.transparent_addr.is_some() && !cfg!(target_os = "linux") {
+            anyhow::bail!("Transparent mode is only supported on Linux");
+        }
+        if self.health_check_interval.is_zero() {
+            anyhow::bail!("Health check interval must be non-zero");
+        }
+        if !self.heartbeat_timeout.is_zero() && self.heartbeat_timeout <= self.health_check_interval
+        {
+            anyhow::bail!("Heartbeat timeout must be longer than the health check interval");
+        }
+        quic_transport(self.keepalive_interval, self.idle_timeout)?;
+        if self.peer_quota > 0 && self.quota_window.is_zero() {
+            anyhow::bail!("Quota window must be non-zero");
+        }
+        if self.stream_window > 0 && self.stream_window < MIN_STREAM_WINDOW {
+            anyhow::bail!("Stream window must be at least {} bytes", MIN_STREAM_WINDOW);
+        }
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&self.relay_options.chunk_size) {
+            anyhow::bail!(
+                "Relay chunk size must be between {} and {} bytes",
+                MIN_CHUNK_SIZE,
+                MAX_CHUNK_SIZE
+            );
+        }
+        if let Some(dscp) = self.tcp_options.dscp
+            && dscp > MAX_DSCP
+        {
+            anyhow::bail!("DSCP must be between 0 and {}, got {}", MAX_DSCP, dscp);
+        }
+        let mut ports = vec![
+            ("SOCKS5 proxy", Some(self.socks_addr.port())),
+            ("metrics", self.metrics_port),
+            ("PAC file", self.pac.as_ref().map(|pac| pac.port)),
+            (
+                "transparent proxy",
+                self.transparent_addr.map(|addr| addr.port()),
+            ),
+        ];
+        ports.extend(
+            self.forwards
+                .iter()
+                .map(|forward| ("port forward", Some(forward.listen.port()))),
+        );
+        // Port 0 picks a free port, so only fixed ones can collide
+        let fixed: Vec<_> = ports
+            .iter()
+            .filter_map(|(name, port)| port.filter(|&port| port > 0).map(|port| (name, port)))
+            .collect();
+        for (i, (name, port)) in fixed.iter().enumerate() {
+            if let Some((other, _)) = fixed[..i].iter().find(|(_, p)| p == port) {
+                anyhow::bail!(
+                    "The {} and {} listeners can't share port {}",
+                    other,
+                    name,
+                    port
+                );
+            }
+        }
+        if self.relay_options.debug_parse && self.privacy {
+            anyhow::bail!("Dumping stream contents contradicts privacy mode");
+        }
+        if self.status_socket.is_some() && !cfg!(unix) {
+            anyhow::bail!("The status socket is only supported on Unix");
+        }
+        if self.control_socket.is_some() && !cfg!(unix) {
+            anyhow::bail!("The control socket is only supported on Unix");
+        }
+        if self.socks_unix.is_some() && !cfg!(unix) {
+            anyhow::bail!("SOCKS over a unix socket is only supported on Unix");
+        }
+        if self.socks_tls.is_some() && self.pac.is_some() {
+            anyhow::bail!(
+                "Browsers can't use SOCKS over TLS, so a PAC file would point them nowhere"
+            );
+        }
+        self.egress_bind.check_local()?;
+        Ok(())
+    }
+
+    pub fn socks_addr(mut self, addr: SocketAddr) -> Self {
+        self.socks_addr = addr;
+        self
+    }
+
+    /// Listen for SOCKS5 clients on localhost at `port`
+    pub fn socks_port(mut self, port: u16) -> Self {
+        self.socks_addr = (Ipv4Addr::LOCALHOST, port).into();
+        self
+    }
+
+    pub fn peers(mut self, peers: Vec<EndpointAddr>) -> Self {
+        self.peers = peers;
+        self
+    }
+
+    pub fn secret_key(mut self, key: SecretKey) -> Self {
+        self.secret_key = Some(key);
+        self
+    }
+
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn relay_mode(mut self, mode: RelayMode) -> Self {
+        self.relay_mode = mode;
+        self
+    }
+
+    /// Use only the relay server at `url` instead of the default relays
+    pub fn relay_url(mut self, url: RelayUrl) -> Self {
+        self.relay_mode = RelayMode::Custom(RelayMap::from(url));
+        self
+    }
+
+    pub fn path_mode(mut self, mode: PathMode) -> Self {
+        self.path_mode = mode;
+        self
+    }
+
+    /// Before serving, wait up to `limit` for hole-punching to give a direct path
+    pub fn prefer_direct(mut self, limit: Duration) -> Self {
+        self.prefer_direct = Some(limit);
+        self
+    }
+
+    pub fn log_file(mut self, path: impl Into<String>) -> Self {
+        self.log_file = Some(path.into());
+        self
+    }
+
+    pub fn access_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.access_log = Some(path.into());
+        self
+    }
+
+    /// Hide destination hosts, SNI and HTTP paths in log output
+    pub fn privacy(mut self, privacy: bool) -> Self {
+        self.privacy = privacy;
+        self
+    }
+
+    pub fn show_qr(mut self, show: bool) -> Self {
+        self.show_qr = show;
+        self
+    }
+
+    pub fn regenerate_corrupt_key(mut self, regenerate: bool) -> Self {
+        self.regenerate_corrupt_key = regenerate;
+        self
+    }
+
+    pub fn max_reconnect_attempts(mut self, attempts: u32) -> Self {
+        self.max_reconnect_attempts = attempts;
+        self
+    }
+
+    pub fn health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    pub fn heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.heartbeat_timeout = timeout;
+        self
+    }
+
+    pub fn keepalive_interval(mut self, interval: Duration) -> Self {
+        self.keepalive_interval = interval;
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn metrics_port(mut self, port: u16) -> Self {
+        self.metrics_port = Some(port);
+        self
+    }
+
+    pub fn pac(mut self, options: PacOptions) -> Self {
+        self.pac = Some(options);
+        self
+    }
+
+    pub fn allow_peers(mut self, peers: Vec<PublicKey>) -> Self {
+        self.allow_peers = peers;
+        self
+    }
+
+    /// Serve a single peer at a time, refusing the others until it disconnects
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// Pin the first peer to connect, saving it to the profile's peer file
+    ///
+    /// A server then accepts only that peer, across restarts too. A client
+    /// keeps a persistent identity instead of an ephemeral one, so a sticky
+    /// server keeps recognising it.
+    pub fn sticky_peer(mut self, sticky: bool) -> Self {
+        self.sticky_peer = sticky;
+        self
+    }
+
+    /// Find peers on the local network by node ID alone, announcing this node
+    /// there over mDNS (needs the `local-discovery` feature)
+    pub fn local_discovery(mut self, enabled: bool) -> Self {
+        self.local_discovery = enabled;
+        self
+    }
+
+    pub fn host_filter(mut self, filter: HostFilter) -> Self {
+        self.host_filter = filter;
+        self
+    }
+
+    pub fn upstream_proxy(mut self, proxy: UpstreamProxy) -> Self {
+        self.upstream_proxy = Some(proxy);
+        self
+    }
+
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    pub fn egress_bind(mut self, egress: EgressBind) -> Self {
+        self.egress_bind = egress;
+        self
+    }
+
+    pub fn dns_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.dns_cache_ttl = ttl;
+        self
+    }
+
+    pub fn dns_cache_size(mut self, size: usize) -> Self {
+        self.dns_cache_size = size;
+        self
+    }
+
+    pub fn max_streams(mut self, max: usize) -> Self {
+        self.max_streams = max;
+        self
+    }
+
+    pub fn stream_window(mut self, bytes: u64) -> Self {
+        self.stream_window = bytes;
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    pub fn reconnect_wait(mut self, wait: Duration) -> Self {
+        self.reconnect_wait = wait;
+        self
+    }
+
+    pub fn max_queued(mut self, max: usize) -> Self {
+        self.max_queued = max;
+        self
+    }
+
+    /// Grace period for in-flight clients on shutdown (zero closes them at once)
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    pub fn transparent_addr(mut self, addr: SocketAddr) -> Self {
+        self.transparent_addr = Some(addr);
+        self
+    }
+
+    /// Add a local listener tunnelled to a fixed destination (repeatable)
+    pub fn forward(mut self, forward: PortForward) -> Self {
+        self.forwards.push(forward);
+        self
+    }
+
+    pub fn status_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.status_socket = Some(path.into());
+        self
+    }
+
+    /// Take `status`, `peers`, `reconnect`, ... commands on the unix socket at `path`
+    pub fn control_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.control_socket = Some(path.into());
+        self
+    }
+
+    /// Also accept SOCKS5 clients on the unix socket at `path`
+    pub fn socks_unix(mut self, path: impl Into<PathBuf>) -> Self {
+        self.socks_unix = Some(path.into());
+        self
+    }
+
+    /// Require TLS on the SOCKS5 TCP listener
+    pub fn socks_tls(mut self, tls: SocksTls) -> Self {
+        self.socks_tls = Some(tls);
+        self
+    }
+
+    pub fn routes(mut self, routes: RouteTable) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    pub fn tcp_options(mut self, options: TcpOptions) -> Self {
+        self.tcp_options = options;
+        self
+    }
+
+    pub fn relay_options(mut self, options: RelayOptions) -> Self {
+        self.relay_options = options;
+        self
+    }
+
+    /// Wrap SOCKS request handling, e.g. with rate limiting or auth
+    pub fn socks_layer(mut self, layer: SocksLayer) -> Self {
+        self.socks_layer = Some(layer);
+        self
+    }
+
+    /// Report local requests, connection changes and request errors to `logger`
+    /// instead of stdout
+    pub fn logger(mut self, logger: Box<dyn Logger>) -> Self {
+        self.logger = SharedLogger::new(logger);
+        self
+    }
+
+    pub fn auth_token(mut self, token: AuthToken) -> Self {
+        self.auth_token = Some(token);
+        self
+    }
+
+    /// Refuse requests from a peer once it relayed `bytes` within `window`
+    pub fn peer_quota(mut self, bytes: u64, window: Duration) -> Self {
+        self.peer_quota = bytes;
+        self.quota_window = window;
+        self
+    }
+}
+
+/// A running tunnel node
+///
+/// Dropping the handle leaves the node running in the background; call
+/// `shutdown` (or trigger `shutdown_signal` and `wait`) to stop it.
+pub struct TunnelHandle {
+    endpoint: Endpoint,
+    state: Arc<Mutex<TunnelState>>,
+    socks_addr: SocketAddr,
+    /// Relays the endpoint may use, listed in tickets as fallbacks
+    relays: RelayMap,
+    shutdown: Shutdown,
+    events: EventBus,
+    task: JoinHandle<()>,
+}
+
+pub type ServerHandle = TunnelHandle;
+pub type ClientHandle = TunnelHandle;
+
+impl TunnelHandle {
+    pub fn node_id(&self) -> PublicKey {
+        self.endpoint.id()
+    }
+
+    /// Ticket a peer passes to `run_client` / `tunnel connect`
+    pub fn ticket(&self) -> String {
+        generate_ticket(&self.endpoint, &self.relays)
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Address the SOCKS5 listener is bound to
+    pub fn socks_addr(&self) -> SocketAddr {
+        self.socks_addr
+    }
+
+    /// Shared tunnel state, for inspection or e.g. `reset_reconnect`
+    pub fn state(&self) -> Arc<Mutex<TunnelState>> {
+        Arc::clone(&self.state)
+    }
+
+    pub async fn connection_state(&self) -> ConnectionState {
+        self.state.lock().await.connection_state.clone()
+    }
+
+    /// Traffic counters of the current peer connection
+    pub async fn stats(&self) -> StatsSnapshot {
+        self.state.lock().await.stats_snapshot()
+    }
+
+    /// Connection lifecycle events from now on
+    ///
+    /// Subscribers that don't keep up lose the oldest events; `recv` then
+    /// reports `RecvError::Lagged` with how many were skipped.
+    pub fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.events.subscribe()
+    }
+
+    /// Signal that stops the node when triggered, e.g. from a Ctrl-C handler
+    ///
+    /// The listeners stop at once; in-flight clients get the drain timeout to
+    /// finish. Trigger the state's `shutdown` to close them immediately.
+    pub fn shutdown_signal(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Wait until the node has stopped and the endpoint is closed
+    pub async fn wait(self) -> Result<()> {
+        self.task.await.context("Tunnel task panicked")
+    }
+
+    /// Stop the node and wait for it to close
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.trigger();
+        self.wait().await
+    }
+}
+
+/// Start a server node: persistent identity, accepts peers, prints its ticket
+pub async fn run_server(config: TunnelConfig) -> Result<ServerHandle> {
+    start(TunnelConfig {
+        peers: Vec::new(),
+        ..config
+    })
+    .await
+}
+
+/// Start a client node connected to `config.peers` (first reachable one wins)
+pub async fn run_client(config: TunnelConfig) -> Result<ClientHandle> {
+    if config.peers.is_empty() {
+        anyhow::bail!("run_client needs at least one peer");
+    }
+    start(config).await
+}
+
+/// Validate `config` without starting anything (`--check`)
+///
+/// Checks the settings, reads the persisted identity without creating it and
+/// makes sure the local listen addresses are free (each is bound, then
+/// released before returning). Returns what a real start would do, one line
+/// per item.
+pub async fn check_config(config: &TunnelConfig) -> Result<Vec<String>> {
+    config.validate()?;
+    let mut plan = Vec::new();
+
+    let server = config.peers.is_empty();
+    let identity = match &config.secret_key {
+        Some(key) => format!("Node ID {}", key.public()),
+        None if !server && !config.sticky_peer => "an ephemeral identity".to_string(),
+        None => match read_secret_key(&config.profile).await {
+            Ok(Some(key)) => format!(
+                "Node ID {} from {}",
+                key.public(),
+                config.profile.key_file().display()
+            ),
+            Ok(None) => format!(
+                "a new identity saved to {}",
+                config.profile.key_file().display()
+            ),
+            Err(e) if config.regenerate_corrupt_key => {
+                format!("a new identity, backing up the old key: {}", e)
+            }
+            Err(e) => return Err(e),
+        },
+    };
+    plan.push(format!(
+        "Run as {} with {}",
+        if server { "server" } else { "client" },
+        identity
+    ));
+    for (i, peer) in config.peers.iter().enumerate() {
+        let role = if i == 0 { "Connect to" } else { "Fail over to" };
+        plan.push(format!("{} peer {}", role, peer.id));
+    }
+
+    let local = |port: u16| SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let mut listeners = vec![
+        ("SOCKS5 proxy", Some(config.socks_addr)),
+        ("metrics", config.metrics_port.map(local)),
+        ("PAC file", config.pac.as_ref().map(|pac| local(pac.port))),
+        ("transparent proxy", config.transparent_addr),
+    ];
+    listeners.extend(
+        config
+            .forwards
+            .iter()
+            .map(|forward| ("port forward", Some(forward.listen))),
+    );
+    // Held until the end, so two listeners on one address are caught too
+    let mut bound = Vec::new();
+    for (name, addr) in listeners {
+        let Some(addr) = addr else { continue };
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Cannot listen for {} on {}", name, addr))?;
+        bound.push(listener);
+        plan.push(format!("Listen for {} on {}", name, addr));
+    }
+    if let Some(tls) = &config.socks_tls {
+        tls.acceptor()?;
+        plan.push(format!(
+            "Require TLS on the SOCKS5 proxy with {}",
+            tls.cert.display()
+        ));
+    }
+    if let Some(path) = &config.socks_unix {
+        plan.push(format!("Listen for SOCKS5 proxy on {}", path.display()));
+    }
+    if let Some(path) = &config.status_socket {
+        plan.push(format!("Serve status on {}", path.display()));
+    }
+    if let Some(path) = &config.control_socket {
+        plan.push(format!("Take control commands on {}", path.display()));
+    }
+    if let Some(path) = &config.access_log {
+        plan.push(format!("Append access log to {}", path.display()));
+    }
+    plan.push(format!(
+        "Use QUIC {}",
+        describe(config.keepalive_interval, config.idle_timeout)
+    ));
+    if !config.egress_bind.is_empty() {
+        plan.push(format!(
+            "Connect to destinations from {}",
+            config.egress_bind
+        ));
+    }
+    if let Some(dscp) = config.tcp_options.dscp {
+        plan.push(format!("Mark relayed TCP traffic with DSCP {}", dscp));
+    }
+    if config.stream_window > 0 {
+        plan.push(format!(
+            "Limit un-read data to {} bytes per stream",
+            config.stream_window
+        ));
+    }
+    if !config.allow_peers.is_empty() {
+        plan.push(format!(
+            "Accept {} allowed peer(s)",
+            config.allow_peers.len()
+        ));
+    }
+    if config.exclusive {
+        plan.push("Serve one peer at a time".to_string());
+    }
+    if config.sticky_peer && server {
+        plan.push(
+            match load_remote_peer_id(&config.profile, &*config.logger).await {
+                Some(peer) => format!("Accept only pinned peer {}", peer),
+                None => "Pin the first peer to connect and accept only it".to_string(),
+            },
+        );
+    }
+    if config.local_discovery {
+        plan.push("Announce this node and find peers on the LAN over mDNS".to_string());
+    }
+    Ok(plan)
+}
+
+async fn start(config: TunnelConfig) -> Result<TunnelHandle> {
+    config.validate()?;
+    let started_at = std::time::Instant::now();
+    let logger = config.logger.clone();
+
+    logger.info("🚇 Starting Iroh Tunnel...");
+
+    // Server mode persists its key for a stable Node ID, client mode is ephemeral
+    let secret_key = match config.secret_key.clone() {
+        Some(key) => key,
+        None => {
+            get_or_create_secret_key(
+                &config.profile,
+                config.peers.is_empty() || config.sticky_peer,
+                config.regenerate_corrupt_key,
+                &*logger,
+            )
+            .await?
+        }
+    };
+
+    // Setup Iroh Endpoint
+    let mut transport = quic_transport(config.keepalive_interval, config.idle_timeout)?;
+    if config.stream_window > 0 {
+        transport = flow_control(transport, config.stream_window, config.max_streams);
+    }
+    #[allow(unused_mut)]
+    let mut builder = Endpoint::builder()
+        .secret_key(secret_key)
+        .alpns(vec![TUNNEL_ALPN.to_vec()])
+        .transport_config(transport);
+    #[cfg(feature = "local-discovery")]
+    if config.local_discovery {
+        builder = builder.discovery(iroh::discovery::mdns::MdnsDiscovery::builder());
+    }
+    let endpoint = config
+        .path_mode
+        .configure(builder, &config.relay_mode)
+        .bind()
+        .await
+        .context("Failed to bind Iroh endpoint")?;
+
+    let relays = config.path_mode.relay_mode(&config.relay_mode).relay_map();
+
+    logger.info(&format!("📡 Node ID: {}", endpoint.id()));
+    if let Some(name) = config.profile.name() {
+        logger.info(&format!("🗂️  Profile: {}", name));
+    }
+    logger.info(&format!("🛣️  Path mode: {}", config.path_mode.describe()));
+    logger.info(&format!(
+        "⏱️  QUIC {}",
+        describe(config.keepalive_interval, config.idle_timeout)
+    ));
+    if !config.egress_bind.is_empty() {
+        logger.info(&format!(
+            "🚪 Connecting to destinations from {}",
+            config.egress_bind
+        ));
+    }
+    if let Some(dscp) = config.tcp_options.dscp {
+        logger.info(&format!(
+            "🏷️  Marking relayed TCP traffic with DSCP {}",
+            dscp
+        ));
+        if !cfg!(target_os = "linux") {
+            logger.warn("⚠️  IPv6 sockets can't be marked on this platform, only IPv4 ones");
+        }
+    }
+
+    // Print reachable addresses once discovery settles
+    tokio::spawn(watch_endpoint_addrs(endpoint.watch_addr(), logger.clone()));
+    if config.local_discovery {
+        logger.info("🏠 Local discovery: announcing this node on the LAN over mDNS");
+    }
+
+    // A sticky server keeps the peer it pinned; otherwise start fresh
+    let sticky = config.sticky_peer && config.peers.is_empty();
+    let pinned_peer = match sticky {
+        true => load_remote_peer_id(&config.profile, &*logger).await,
+        false => {
+            clear_remote_peer_id(&config.profile, &*logger).await.ok();
+            None
+        }
+    };
+    if sticky {
+        match pinned_peer {
+            Some(peer) => logger.info(&format!("📌 Sticky peer: only accepting {}", peer)),
+            None => logger.info("📌 Sticky peer: the first peer to connect will be pinned"),
+        }
+    }
+
+    if config.privacy {
+        set_privacy(true);
+        logger.info("🕶️  Privacy mode: destinations are hidden in logs");
+    }
+    let access_log = match &config.access_log {
+        Some(path) => Some(AccessLog::open(path, logger.clone()).await?),
+        None => None,
+    };
+
+    let tls_acceptor = config
+        .socks_tls
+        .as_ref()
+        .map(SocksTls::acceptor)
+        .transpose()?;
+
+    // Bound up front so requests leading back into our own listeners are refused
+    let listener = TcpListener::bind(config.socks_addr)
+        .await
+        .context("Failed to bind SOCKS5 server")?;
+    let socks_addr = listener.local_addr()?;
+    let mut forward_listeners = Vec::new();
+    for forward in &config.forwards {
+        let listener = TcpListener::bind(forward.listen)
+            .await
+            .with_context(|| format!("Failed to bind port forward {}", forward))?;
+        forward_listeners.push((listener.local_addr()?, listener, forward.clone()));
+    }
+    let local_listeners = std::iter::once(socks_addr)
+        .chain(config.transparent_addr)
+        .chain(forward_listeners.iter().map(|(addr, _, _)| *addr))
+        .collect();
+
+    let peers_to_connect = config.peers.clone();
+    let peer_ids: Vec<PublicKey> = peers_to_connect.iter().map(|addr| addr.id).collect();
+    let state = Arc::new(Mutex::new(TunnelState {
+        remote_peer_id: peer_ids.first().copied(),
+        candidate_peers: peer_ids.clone(),
+        max_reconnect_attempts: config.max_reconnect_attempts,
+        heartbeat_timeout: config.heartbeat_timeout,
+        max_streams_per_peer: config.max_streams,
+        exclusive: config.exclusive,
+        pinned_peer,
+        max_socks_clients: config.max_connections,
+        reconnect_wait: config.reconnect_wait,
+        max_queued_requests: config.max_queued,
+        drain_timeout: config.drain_timeout,
+        outbound: Arc::new(Outbound {
+            host_filter: config.host_filter.clone(),
+            upstream_proxy: config.upstream_proxy.clone(),
+            dns: DnsCache::new(config.dns_cache_ttl, config.dns_cache_size),
+            tcp: config.tcp_options.clone(),
+            relay: config.relay_options.clone(),
+            auth_token: config.auth_token.clone(),
+            quotas: (config.peer_quota > 0)
+                .then(|| PeerQuotas::new(config.peer_quota, config.quota_window)),
+            local_listeners,
+            node_id: Some(endpoint.id()),
+            connect_retries: config.connect_retries,
+            egress: config.egress_bind,
+            logger: logger.clone(),
+        }),
+        tcp_options: config.tcp_options.clone(),
+        relay_options: config.relay_options.clone(),
+        auth_token: config.auth_token.clone(),
+        routes: config.routes.clone(),
+        _log_file: config.log_file.clone(),
+        access_log,
+        logger: config.logger.clone(),
+        ..Default::default()
+    }));
+
+    // If we have peers to connect to, connect to the first reachable one (client mode)
+    if let Some(&primary) = peer_ids.first() {
+        // Persist the primary peer to disk
+        save_remote_peer_id(&config.profile, primary).await.ok();
+
+        let mut connected = None;
+        for peer_addr in peers_to_connect {
+            let peer_id = peer_addr.id;
+            logger.info(&format!("🔌 Connecting to peer {}...", peer_id));
+            match connect_peer(&endpoint, peer_addr, &*logger).await {
+                Ok(conn) => {
+                    logger.info(&format!("✅ Connected to peer: {}", conn.remote_id()));
+
+                    // Update state
+                    let epoch = {
+                        let mut state_guard = state.lock().await;
+                        state_guard.remote_peer_id = Some(peer_id);
+                        state_guard.install_connection(conn.clone())
+                    };
+
+                    // Spawn handler with monitoring
+                    let endpoint_clone = endpoint.clone();
+                    let state_clone = Arc::clone(&state);
+                    tokio::spawn(async move {
+                        handle_peer_connection_with_monitoring(
+                            conn,
+                            endpoint_clone,
+                            state_clone,
+                            epoch,
+                        )
+                        .await;
+                    });
+                    connected = Some(peer_id);
+                    break;
+                }
+                Err(e) => logger.error(
+                    None,
+                    &format!("Failed to connect to peer {}: {}", peer_id, e),
+                ),
+            }
+        }
+
+        match connected {
+            Some(peer_id) => {
+                if let Some(limit) = config.prefer_direct {
+                    await_direct_path(&endpoint, peer_id, limit, &*logger).await;
+                }
+            }
+            None => {
+                logger.warn("💡 Will keep retrying in background...");
+                let mut state_guard = state.lock().await;
+                state_guard.set_connection_state(ConnectionState::Disconnected);
+            }
+        }
+    } else {
+        logger.info("📋 Connection ticket (share this with peer):");
+        let ticket = generate_ticket(&endpoint, &relays);
+        logger.info(&format!("   {}", ticket));
+        if config.show_qr {
+            match render_qr(&ticket) {
+                Ok(qr) => logger.info(&format!("\n{}", qr)),
+                Err(e) => logger.warn(&format!("⚠️  Could not render ticket as QR code: {}", e)),
+            }
+        }
+        logger.info("\n💡 Waiting for peer to connect...");
+        if config.allow_peers.is_empty() && !sticky {
+            logger.warn("⚠️  No --allow-peer given: any peer with the ticket can use this node");
+        }
+    }
+
+    // Start connection health monitor for BOTH client and server modes
+    {
+        let state_clone = Arc::clone(&state);
+        let endpoint_clone = endpoint.clone();
+        let interval = config.health_check_interval;
+        tokio::spawn(async move {
+            monitor_connection_health(state_clone, endpoint_clone, interval).await;
+        });
+    }
+    tokio::spawn(watch_network_changes(
+        Arc::clone(&state),
+        endpoint.clone(),
+        endpoint.watch_addr(),
+    ));
+
+    // Start Prometheus metrics endpoint
+    if let Some(metrics_port) = config.metrics_port {
+        let metrics_addr = format!("127.0.0.1:{}", metrics_port);
+        let listener = TcpListener::bind(&metrics_addr)
+            .await
+            .context("Failed to bind metrics server")?;
+        logger.info(&format!(
+            "📈 Metrics available at http://{}/metrics",
+            metrics_addr
+        ));
+
+        let state_clone = Arc::clone(&state);
+        let shutdown = state.lock().await.shutdown.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                result = serve_metrics(listener, state_clone) => {
+                    if let Err(e) = result {
+                        logger.error(None, &format!("Metrics server stopped: {}", e));
+                    }
+                }
+                _ = shutdown.wait() => {}
+            }
+        });
+    }
+
+    // Start PAC file endpoint
+    if let Some(options) = &config.pac {
+        let pac_addr = format!("127.0.0.1:{}", options.port);
+        let listener = TcpListener::bind(&pac_addr)
+            .await
+            .context("Failed to bind PAC server")?;
+        logger.info(&format!(
+            "🧭 Proxy auto-config at http://{}/proxy.pac",
+            pac_addr
+        ));
+
+        let pac = Arc::new(render_pac(socks_addr, options));
+        let shutdown = state.lock().await.shutdown.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                result = serve_pac(listener, pac, logger.clone()) => {
+                    if let Err(e) = result {
+                        logger.error(None, &format!("PAC server stopped: {}", e));
+                    }
+                }
+                _ = shutdown.wait() => {}
+            }
+        });
+    }
+
+    // Start JSON status socket
+    #[cfg(unix)]
+    if let Some(path) = config.status_socket.clone() {
+        let node_id = endpoint.id();
+        let state_clone = Arc::clone(&state);
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_status(path, node_id, started_at, state_clone).await {
+                logger.error(None, &format!("Status socket stopped: {}", e));
+            }
+        });
+    }
+
+    // Start control socket
+    #[cfg(unix)]
+    if let Some(path) = config.control_socket.clone() {
+        let ctx = ControlContext {
+            node_id: endpoint.id(),
+            started_at,
+            state: Arc::clone(&state),
+            sticky: sticky.then(|| config.profile.clone()),
+        };
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_control(path, ctx).await {
+                logger.error(None, &format!("Control socket stopped: {}", e));
+            }
+        });
+    }
+
+    // Start SOCKS5 proxy server
+    if tls_acceptor.is_some() {
+        logger.info(&format!(
+            "🔒 SOCKS5 over TLS proxy listening on {}",
+            socks_addr
+        ));
+        logger.info(&format!(
+            "📝 Configure your client to use SOCKS5 over TLS: localhost:{}\n",
+            socks_addr.port()
+        ));
+    } else {
+        logger.info(&format!("🌐 SOCKS5 proxy listening on {}", socks_addr));
+        logger.info(&format!(
+            "📝 Configure your browser/app to use SOCKS5 proxy: localhost:{}\n",
+            socks_addr.port()
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(transparent_addr) = config.transparent_addr {
+        let listener = TcpListener::bind(transparent_addr)
+            .await
+            .context("Failed to bind transparent proxy listener")?;
+        logger.info(&format!(
+            "🪄 Transparent proxy listening on {} (redirect with iptables REDIRECT)",
+            listener.local_addr()?
+        ));
+        tokio::spawn(crate::tunnel::transparent::serve_transparent(
+            listener,
+            Arc::clone(&state),
+            endpoint.clone(),
+        ));
+    }
+
+    for (addr, listener, forward) in forward_listeners {
+        logger.info(&format!(
+            "🔀 Forwarding {} to {}:{} through the peer",
+            addr,
+            target(&forward.host),
+            forward.port
+        ));
+        tokio::spawn(serve_forward(
+            listener,
+            forward,
+            Arc::clone(&state),
+            endpoint.clone(),
+        ));
+    }
+
+    let draining = state.lock().await.draining.clone();
+    tokio::spawn(accept_peers(
+        endpoint.clone(),
+        Arc::clone(&state),
+        Arc::new(config.allow_peers.clone()),
+        sticky.then(|| config.profile.clone()),
+    ));
+
+    let mut handler: Arc<dyn SocksHandler> =
+        Arc::new(PeerForwarder::new(Arc::clone(&state), endpoint.clone()));
+    if let Some(layer) = &config.socks_layer {
+        handler = layer.layer(handler);
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = config.socks_unix.clone() {
+        let listener = crate::tunnel::unix_socket::bind_unix(&path, "SOCKS5")?;
+        logger.info(&format!(
+            "🌐 SOCKS5 proxy listening on unix socket {}",
+            path.display()
+        ));
+        let state = Arc::clone(&state);
+        let handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            crate::tunnel::socks::serve_socks_unix(listener, state, handler).await;
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    // Serve SOCKS5 until shutdown, let in-flight clients finish, then close the tunnel
+    let task = {
+        let state = Arc::clone(&state);
+        let endpoint = endpoint.clone();
+        tokio::spawn(async move {
+            match tls_acceptor {
+                Some(acceptor) => {
+                    serve_socks_tls(listener, Arc::clone(&state), handler, acceptor).await
+                }
+                None => serve_socks_with(listener, Arc::clone(&state), handler).await,
+            }
+            drain_clients(&state).await;
+            close_tunnel(&state, &endpoint).await;
+        })
+    };
+
+    let events = state.lock().await.events.clone();
+    Ok(TunnelHandle {
+        endpoint,
+        state,
+        socks_addr,
+        relays,
+        shutdown: draining,
+        events,
+        task,
+    })
+}
+
+/// Give in-flight clients up to the drain timeout to finish
+///
+/// The listeners have stopped accepting by now; whatever is still running
+/// afterwards is closed by the shutdown that follows.
+async fn drain_clients(state: &Arc<Mutex<TunnelState>>) {
+    let (in_flight, timeout, logger) = {
+        let state = state.lock().await;
+        (
+            state.in_flight.clone(),
+            state.drain_timeout,
+            state.logger.clone(),
+        )
+    };
+    let active = in_flight.count();
+    if active == 0 {
+        return;
+    }
+    logger.info(&format!(
+        "⏳ Draining {} in-flight request(s) for up to {:?}...",
+        active, timeout
+    ));
+    let (drained, force_closed) = in_flight.drain(timeout).await;
+    logger.info(&format!(
+        "✅ Drained {} request(s), force-closed {}",
+        drained, force_closed
+    ));
+}
+
+/// Hold off serving until the path to `peer` is direct or `limit` has passed
+///
+/// Path changes while waiting are logged by the connection's type tracker.
+async fn await_direct_path(
+    endpoint: &Endpoint,
+    peer: PublicKey,
+    limit: Duration,
+    logger: &dyn Logger,
+) {
+    let Some(mut watcher) = endpoint.conn_type(peer) else {
+        return;
+    };
+    logger.info(&format!(
+        "⏳ Waiting up to {:?} for a direct path to {}...",
+        limit,
+        peer.fmt_short()
+    ));
+    let found = wait_for_direct_path(&mut watcher, limit).await;
+    if has_direct_path(&found) {
+        logger.info(&format!(
+            "🎯 Direct path to {} ready ({})",
+            peer.fmt_short(),
+            found
+        ));
+    } else {
+        logger.info(&format!(
+            "⚠️  No direct path after {:?}, serving over {} for now",
+            limit,
+            path_type_label(&found)
+        ));
+    }
+}
+
+/// Accept incoming Iroh connections until shutdown or draining begins
+///
+/// With `sticky` set, the first peer accepted is pinned and saved to that
+/// profile's peer file; other peers are refused from then on.
+async fn accept_peers(
+    endpoint: Endpoint,
+    state: Arc<Mutex<TunnelState>>,
+    allow_peers: Arc<Vec<PublicKey>>,
+    sticky: Option<Profile>,
+) {
+    let (draining, shutdown, logger) = {
+        let state = state.lock().await;
+        (
+            state.draining.clone(),
+            state.shutdown.clone(),
+            state.logger.clone(),
+        )
+    };
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => incoming,
+            _ = draining.wait() => break,
+            _ = shutdown.wait() => break,
+        };
+        let Some(incoming) = incoming else {
+            break;
+        };
+        let state = Arc::clone(&state);
+        let endpoint = endpoint.clone();
+        let allow_peers = Arc::clone(&allow_peers);
+        let sticky = sticky.clone();
+        let logger = logger.clone();
+        match incoming.accept() {
+            Ok(connecting) => {
+                tokio::spawn(async move {
+                    match connecting.await {
+                        Ok(connection) => {
+                            let remote_id = connection.remote_id();
+                            if !is_peer_allowed(&allow_peers, &remote_id) {
+                                logger.warn(&format!(
+                                    "🚫 Rejected peer not in allowlist: {}",
+                                    remote_id
+                                ));
+                                reject_peer(&connection);
+                                return;
+                            }
+
+                            // Only a sticky server persists the peer, see below
+                            let (epoch, pinned) = {
+                                let mut state_guard = state.lock().await;
+                                if sticky.is_some()
+                                    && let Some(pinned) = state_guard.pinned_peer
+                                    && pinned != remote_id
+                                {
+                                    logger.warn(&format!(
+                                        "🚫 Rejected peer {}: this node is pinned to {}",
+                                        remote_id,
+                                        pinned.fmt_short()
+                                    ));
+                                    reject_peer(&connection);
+                                    return;
+                                }
+                                // Checked under the lock, so two peers can't both get the slot
+                                if let Some(holder) = state_guard.exclusive_holder(&remote_id) {
+                                    logger.warn(&format!(
+                                        "🚫 Rejected peer {}: {} holds the exclusive slot",
+                                        remote_id,
+                                        holder.fmt_short()
+                                    ));
+                                    reject_busy_peer(&connection);
+                                    return;
+                                }
+                                logger.info(&format!("✅ Peer connected: {}", remote_id));
+                                // Pinned under the lock, persisted after releasing it
+                                let pinned = sticky.is_some() && state_guard.pinned_peer.is_none();
+                                if pinned {
+                                    state_guard.pinned_peer = Some(remote_id);
+                                }
+                                state_guard.remote_peer_id = Some(remote_id);
+                                (state_guard.install_connection(connection.clone()), pinned)
+                            };
+                            if pinned && let Some(profile) = &sticky {
+                                match save_remote_peer_id(profile, remote_id).await {
+                                    Ok(()) => logger.info(&format!("📌 Pinned peer {}", remote_id)),
+                                    Err(e) => logger.warn(&format!(
+                                        "⚠️  Pinned peer {} for this run only: {}",
+                                        remote_id, e
+                                    )),
+                                }
+                            }
+
+                            handle_peer_connection_with_monitoring(
+                                connection, endpoint, state, epoch,
+                            )
+                            .await;
+                        }
+                        Err(e) => logger.error(None, &format!("Connection error: {}", e)),
+                    }
+                });
+            }
+            Err(e) => logger.error(None, &format!("Failed to accept connection: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::connection::parse_peer;
+    use crate::tunnel::test_support::CapturingLogger;
+    use tempfile::TempDir;
+
+    /// Loopback-only config whose key and peer files live in `dir`
+    fn local_config(dir: &TempDir) -> TunnelConfig {
+        TunnelConfig::default()
+            .profile(Profile::default().in_dir(dir.path()))
+            .socks_port(0)
+            .relay_mode(RelayMode::Disabled)
+            .secret_key(SecretKey::generate(&mut rand::rng()))
+    }
+
+    #[test]
+    fn test_config_builder_defaults() {
+        let config = TunnelConfig::default().socks_port(1081).max_streams(8);
+        assert_eq!(config.socks_addr, "127.0.0.1:1081".parse().unwrap());
+        assert_eq!(config.max_streams, 8);
+        assert_eq!(config.max_connections, DEFAULT_MAX_CONNECTIONS);
+        assert_eq!(config.reconnect_wait, DEFAULT_RECONNECT_WAIT);
+    }
+
+    #[tokio::test]
+    async fn test_check_config_reports_plan() {
+        let dir = TempDir::new().unwrap();
+        let key = SecretKey::generate(&mut rand::rng());
+        let config = local_config(&dir).secret_key(key.clone()).metrics_port(0);
+        let plan = check_config(&config).await.unwrap();
+        assert_eq!(
+            plan[0],
+            format!("Run as server with Node ID {}", key.public())
+        );
+        assert!(plan.contains(&"Listen for SOCKS5 proxy on 127.0.0.1:0".to_string()));
+        assert!(plan.contains(&"Listen for metrics on 127.0.0.1:0".to_string()));
+        assert!(plan.contains(&"Use QUIC keep-alive every 1s, idle timeout 30s".to_string()));
+
+        let peer = SecretKey::generate(&mut rand::rng()).public();
+        let mut config = local_config(&dir).peers(vec![EndpointAddr::new(peer)]);
+        config.secret_key = None;
+        let plan = check_config(&config).await.unwrap();
+        assert_eq!(plan[0], "Run as client with an ephemeral identity");
+        assert_eq!(plan[1], format!("Connect to peer {}", peer));
+    }
+
+    #[tokio::test]
+    async fn test_check_config_rejects_bad_config() {
+        let dir = TempDir::new().unwrap();
+        let config = local_config(&dir).health_check_interval(Duration::ZERO);
+        let err = check_config(&config).await.unwrap_err();
+        assert!(err.to_string().contains("non-zero"));
+
+        let config = local_config(&dir).stream_window(1024);
+        let err = check_config(&config).await.unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Stream window must be at least")
+        );
+
+        let mut config = local_config(&dir);
+        config.relay_options.chunk_size = 0;
+        let err = check_config(&config).await.unwrap_err();
+        assert!(err.to_string().starts_with("Relay chunk size"));
+
+        let mut config = local_config(&dir);
+        config.tcp_options.dscp = Some(64);
+        let err = check_config(&config).await.unwrap_err();
+        assert_eq!(err.to_string(), "DSCP must be between 0 and 63, got 64");
+
+        let config = local_config(&dir)
+            .pac(PacOptions::default())
+            .socks_tls(SocksTls {
+                cert: "cert.pem".into(),
+                key: "key.pem".into(),
+            });
+        let err = check_config(&config).await.unwrap_err();
+        assert!(err.to_string().contains("SOCKS over TLS"));
+
+        let config = local_config(&dir).socks_port(1080).metrics_port(1080);
+        let err = check_config(&config).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The SOCKS5 proxy and metrics listeners can't share port 1080"
+        );
+        let config = local_config(&dir)
+            .socks_port(1080)
+            .forward("1080:db.internal:5432".parse().unwrap());
+        let err = check_config(&config).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The SOCKS5 proxy and port forward listeners can't share port 1080"
+        );
+
+        let config = local_config(&dir)
+            .keepalive_interval(Duration::from_secs(60))
+            .idle_timeout(Duration::from_secs(30));
+        let err = check_config(&config).await.unwrap_err();
+        assert!(err.to_string().starts_with("Keep-alive interval"));
+
+        let config = local_config(&dir)
+            .egress_bind(EgressBind::new(&["192.0.2.10".parse().unwrap()]).unwrap());
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().starts_with("Egress address 192.0.2.10"));
+
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = local_config(&dir).socks_addr(taken.local_addr().unwrap());
+        let err = check_config(&config).await.unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Cannot listen for SOCKS5 proxy")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zero_health_check_interval_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let config = local_config(&dir).health_check_interval(Duration::ZERO);
+        let err = run_server(config).await.err().unwrap();
+        assert!(err.to_string().contains("non-zero"));
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_timeout_must_exceed_interval() {
+        let dir = TempDir::new().unwrap();
+        let config = local_config(&dir)
+            .health_check_interval(Duration::from_secs(5))
+            .heartbeat_timeout(Duration::from_secs(5));
+        let err = run_server(config).await.err().unwrap();
+        assert!(err.to_string().contains("Heartbeat timeout"));
+    }
+
+    #[tokio::test]
+    async fn test_prefer_direct_conflicts_with_relay_only() {
+        let dir = TempDir::new().unwrap();
+        let config = local_config(&dir)
+            .path_mode(PathMode::RelayOnly)
+            .prefer_direct(Duration::from_secs(5));
+        let err = run_server(config).await.err().unwrap();
+        assert!(err.to_string().contains("relay-only"));
+
+        let config = local_config(&dir)
+            .path_mode(PathMode::RelayOnly)
+            .local_discovery(true);
+        let err = run_server(config).await.err().unwrap();
+        assert!(err.to_string().contains("Local discovery"), "{}", err);
+    }
+
+    #[cfg(not(feature = "local-discovery"))]
+    #[test]
+    fn test_local_discovery_needs_feature() {
+        let err = TunnelConfig::default()
+            .local_discovery(true)
+            .validate()
+            .unwrap_err();
+        assert!(err.to_string().contains("local-discovery feature"));
+    }
+
+    #[test]
+    fn test_path_mode_relay_selection() {
+        assert_eq!(
+            PathMode::DirectOnly.relay_mode(&RelayMode::Default),
+            RelayMode::Disabled
+        );
+        assert_eq!(
+            PathMode::RelayOnly.relay_mode(&RelayMode::Staging),
+            RelayMode::Staging
+        );
+        assert_eq!(
+            PathMode::Auto.relay_mode(&RelayMode::Default),
+            RelayMode::Default
+        );
+    }
+
+    #[test]
+    fn test_parse_relay_url() {
+        // RelayUrl makes the host fully qualified
+        let url = parse_relay_url("https://relay.example.com").unwrap();
+        assert_eq!(url.host_str(), Some("relay.example.com."));
+        assert!(parse_relay_url("http://10.0.0.5:3340").is_ok());
+
+        assert!(parse_relay_url("relay.example.com").is_err());
+        assert!(parse_relay_url("ftp://relay.example.com").is_err());
+        assert!(parse_relay_url("https://").is_err());
+
+        let config = TunnelConfig::default().relay_url(url.clone());
+        assert_eq!(config.relay_mode, RelayMode::Custom(RelayMap::from(url)));
+    }
+
+    #[tokio::test]
+    async fn test_relay_only_binds_loopback() {
+        let dir = TempDir::new().unwrap();
+        let handle = run_server(local_config(&dir).path_mode(PathMode::RelayOnly))
+            .await
+            .unwrap();
+        let sockets = handle.endpoint().bound_sockets();
+        assert!(!sockets.is_empty());
+        assert!(
+            sockets.iter().all(|addr| addr.ip().is_loopback()),
+            "{:?}",
+            sockets
+        );
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_server_handle_and_shutdown() {
+        let dir = TempDir::new().unwrap();
+        let key = SecretKey::generate(&mut rand::rng());
+        let handle = run_server(local_config(&dir).secret_key(key.clone()))
+            .await
+            .unwrap();
+
+        assert_eq!(handle.node_id(), key.public());
+        assert_eq!(parse_peer(&handle.ticket()).unwrap().id, key.public());
+        assert_ne!(handle.socks_addr().port(), 0);
+        assert_eq!(
+            handle.connection_state().await,
+            ConnectionState::Disconnected
+        );
+        assert_eq!(handle.stats().await.requests, 0);
+
+        tokio::time::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .expect("tunnel did not shut down")
+            .unwrap();
+    }
+
+    /// `endpoint`'s address on this host, without relays or discovery
+    fn loopback_addr(endpoint: &Endpoint) -> EndpointAddr {
+        let mut addr = EndpointAddr::new(endpoint.id());
+        for socket in endpoint.bound_sockets() {
+            addr = addr.with_ip_addr((Ipv4Addr::LOCALHOST, socket.port()).into());
+        }
+        addr
+    }
+
+    /// A bare endpoint to dial the tunnel from
+    async fn test_peer() -> Endpoint {
+        Endpoint::empty_builder(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap()
+    }
+
+    /// Waits until `handle` has a peer connected
+    async fn wait_connected(handle: &ServerHandle) {
+        let connected = async {
+            while handle.connection_state().await != ConnectionState::Connected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), connected)
+            .await
+            .expect("peer did not connect");
+    }
+
+    #[tokio::test]
+    async fn test_exclusive_rejects_second_peer() {
+        use crate::tunnel::auth::PEER_BUSY_CODE;
+        use iroh::endpoint::{ConnectionError, VarInt};
+
+        let dir = TempDir::new().unwrap();
+        let handle = run_server(local_config(&dir).exclusive(true))
+            .await
+            .unwrap();
+        let addr = loopback_addr(handle.endpoint());
+        let (first, second) = (test_peer().await, test_peer().await);
+
+        let held = first.connect(addr.clone(), TUNNEL_ALPN).await.unwrap();
+        wait_connected(&handle).await;
+
+        let refused = second.connect(addr.clone(), TUNNEL_ALPN).await.unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(5), refused.closed())
+            .await
+            .expect("second peer was not refused");
+        assert!(matches!(
+            err,
+            ConnectionError::ApplicationClosed(close) if close.error_code == VarInt::from_u32(PEER_BUSY_CODE)
+        ));
+        assert_eq!(handle.state().lock().await.remote_peer_id, Some(first.id()));
+
+        // Once the first peer leaves, the slot is free again
+        held.close(VarInt::from_u32(0), b"bye");
+        let retry = async {
+            loop {
+                let conn = second.connect(addr.clone(), TUNNEL_ALPN).await.unwrap();
+                let closed = tokio::time::timeout(Duration::from_millis(300), conn.closed());
+                if closed.await.is_err() {
+                    break;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), retry)
+            .await
+            .expect("slot was not freed");
+        assert_eq!(
+            handle.state().lock().await.remote_peer_id,
+            Some(second.id())
+        );
+
+        first.close().await;
+        second.close().await;
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_refuses_unknown_peer() {
+        use crate::tunnel::auth::PEER_NOT_ALLOWED_CODE;
+        use iroh::endpoint::{ConnectionError, VarInt};
+
+        let dir = TempDir::new().unwrap();
+        let (allowed, stranger) = (test_peer().await, test_peer().await);
+        let handle = run_server(local_config(&dir).allow_peers(vec![allowed.id()]))
+            .await
+            .unwrap();
+        let addr = loopback_addr(handle.endpoint());
+
+        let refused = stranger.connect(addr.clone(), TUNNEL_ALPN).await.unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(5), refused.closed())
+            .await
+            .expect("peer not on the allowlist was not refused");
+        assert!(matches!(
+            err,
+            ConnectionError::ApplicationClosed(close) if close.error_code == VarInt::from_u32(PEER_NOT_ALLOWED_CODE)
+        ));
+        assert_eq!(handle.state().lock().await.remote_peer_id, None);
+
+        let _held = allowed.connect(addr, TUNNEL_ALPN).await.unwrap();
+        wait_connected(&handle).await;
+        assert_eq!(
+            handle.state().lock().await.remote_peer_id,
+            Some(allowed.id())
+        );
+
+        allowed.close().await;
+        stranger.close().await;
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sticky_peer_pins_first_peer() {
+        use crate::tunnel::auth::PEER_NOT_ALLOWED_CODE;
+        use iroh::endpoint::{ConnectionError, VarInt};
+
+        let dir = TempDir::new().unwrap();
+        let profile = Profile::named("sticky").unwrap().in_dir(dir.path());
+        let config = || {
+            local_config(&dir)
+                .profile(profile.clone())
+                .sticky_peer(true)
+        };
+        let (first, second) = (test_peer().await, test_peer().await);
+        let refused = |handle: &ServerHandle| {
+            let (second, addr) = (second.clone(), loopback_addr(handle.endpoint()));
+            async move {
+                let conn = second.connect(addr, TUNNEL_ALPN).await.unwrap();
+                let err = tokio::time::timeout(Duration::from_secs(5), conn.closed())
+                    .await
+                    .expect("second peer was not refused");
+                matches!(
+                    err,
+                    ConnectionError::ApplicationClosed(close) if close.error_code == VarInt::from_u32(PEER_NOT_ALLOWED_CODE)
+                )
+            }
+        };
+
+        let handle = run_server(config()).await.unwrap();
+        let held = first
+            .connect(loopback_addr(handle.endpoint()), TUNNEL_ALPN)
+            .await
+            .unwrap();
+        wait_connected(&handle).await;
+        assert_eq!(
+            load_remote_peer_id(&profile, &CapturingLogger::default()).await,
+            Some(first.id())
+        );
+
+        // Unlike --exclusive, the pin holds after the first peer leaves
+        held.close(VarInt::from_u32(0), b"bye");
+        assert!(refused(&handle).await);
+        handle.shutdown().await.unwrap();
+
+        // and across restarts
+        let handle = run_server(config()).await.unwrap();
+        assert_eq!(handle.state().lock().await.pinned_peer, Some(first.id()));
+        assert!(refused(&handle).await);
+        assert_eq!(
+            load_remote_peer_id(&profile, &CapturingLogger::default()).await,
+            Some(first.id())
+        );
+
+        handle.shutdown().await.unwrap();
+        first.close().await;
+        second.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_transfer_completes_during_drain() {
+        use crate::tunnel::test_support::echo_server;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpStream;
+
+        let dir = TempDir::new().unwrap();
+        let server = run_server(local_config(&dir)).await.unwrap();
+        let client = run_client(
+            local_config(&dir)
+                .peers(vec![loopback_addr(server.endpoint())])
+                .drain_timeout(Duration::from_secs(5)),
+        )
+        .await
+        .unwrap();
+        let socks_addr = client.socks_addr();
+
+        let destination = echo_server().await;
+        let std::net::IpAddr::V4(ip) = destination.ip() else {
+            unreachable!()
+        };
+        let mut stream = TcpStream::connect(socks_addr).await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        let mut request = vec![5, 1, 0, 1];
+        request.extend_from_slice(&ip.octets());
+        request.extend_from_slice(&destination.port().to_be_bytes());
+        stream.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0, "SOCKS CONNECT failed");
+
+        stream.write_all(b"before").await.unwrap();
+        let mut echoed = [0u8; 6];
+        stream.read_exact(&mut echoed).await.unwrap();
+
+        let state = client.state();
+        client.shutdown_signal().trigger();
+        let closed = client.wait();
+        tokio::pin!(closed);
+
+        // The listener goes away, the transfer in progress keeps flowing
+        let refused = async {
+            while TcpStream::connect(socks_addr).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), refused)
+            .await
+            .expect("listener still accepting while draining");
+        stream.write_all(b"during drain").await.unwrap();
+        let mut echoed = [0u8; 12];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"during drain");
+        assert_eq!(state.lock().await.in_flight.count(), 1);
+
+        // Finishing the transfer ends the drain well before its timeout
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(3), &mut closed)
+            .await
+            .expect("drain did not end when the transfer finished")
+            .unwrap();
+        assert_eq!(state.lock().await.in_flight.count(), 0);
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_client_requires_peers() {
+        let dir = TempDir::new().unwrap();
+        assert!(run_client(local_config(&dir)).await.is_err());
+    }
+}
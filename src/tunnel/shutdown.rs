@@ -0,0 +1,150 @@
+// Process-wide shutdown signal and graceful draining
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Notify, broadcast};
+
+/// Broadcast shutdown notifier shared by the accept loops and relay tasks
+///
+/// Cloning is cheap; every clone observes the same trigger. Tasks that start
+/// waiting after the trigger still see it, so there is no subscribe race.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    sender: broadcast::Sender<()>,
+    triggered: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(1);
+        Self {
+            sender,
+            triggered: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Signal every waiting task to stop
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        let _ = self.sender.send(());
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once shutdown has been triggered (immediately if it already was)
+    pub async fn wait(&self) {
+        // Subscribe before checking the flag so a concurrent trigger isn't missed
+        let mut receiver = self.sender.subscribe();
+        if self.is_triggered() {
+            return;
+        }
+        let _ = receiver.recv().await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Local clients still being relayed, which a graceful shutdown waits for
+///
+/// Each accepted client holds an `InFlightGuard` for as long as it is served.
+#[derive(Clone, Debug, Default)]
+pub struct InFlight {
+    active: Arc<AtomicUsize>,
+    idle: Arc<Notify>,
+}
+
+impl InFlight {
+    pub fn enter(&self) -> InFlightGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Wait up to `grace` for every client to finish
+    ///
+    /// Returns how many finished in time and how many were still running.
+    pub async fn drain(&self, grace: Duration) -> (usize, usize) {
+        let started = self.count();
+        let idle = async {
+            loop {
+                // Register before checking the count so a last exit isn't missed
+                let notified = self.idle.notified();
+                if self.count() == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(grace, idle).await;
+        let remaining = self.count();
+        (started.saturating_sub(remaining), remaining)
+    }
+}
+
+pub struct InFlightGuard(InFlight);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_wakes_waiting_tasks() {
+        let shutdown = Shutdown::new();
+        let tasks: Vec<_> = (0..3)
+            .map(|_| {
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move { shutdown.wait().await })
+            })
+            .collect();
+
+        tokio::task::yield_now().await;
+        shutdown.trigger();
+
+        for task in tasks {
+            tokio::time::timeout(Duration::from_secs(1), task)
+                .await
+                .expect("task did not exit on shutdown")
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_wait_after_trigger_returns_immediately() {
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        tokio::time::timeout(Duration::from_millis(100), shutdown.wait())
+            .await
+            .expect("late waiter missed the shutdown");
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_in_flight_clients() {
+        let in_flight = InFlight::default();
+        assert_eq!(in_flight.drain(Duration::from_secs(1)).await, (0, 0));
+
+        let quick = in_flight.enter();
+        let _stuck = in_flight.enter();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(quick);
+        });
+        assert_eq!(in_flight.drain(Duration::from_millis(300)).await, (1, 1));
+    }
+}
@@ -1,30 +1,355 @@
-use anyhow::Result;
-use iroh::endpoint::Endpoint;
-use std::net::SocketAddr;
+use anyhow::{Context, Result};
+use iroh::endpoint::{Connection, Endpoint};
+use std::net::{Ipv6Addr, SocketAddr};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
 
-use crate::connection::logger::log_connection_details;
+use crate::connection::logger::{
+    current_connection_type, format_connection_details, path_type_label,
+};
+use crate::http::parser::starts_with_http_method;
 use crate::socks5::protocol::*;
-use crate::tunnel::protocol::TunnelMessage;
-use crate::tunnel::relay::{recv_message, send_message};
-use crate::tunnel::state::{ConnectionState, TunnelState};
-use crate::utils::logging::format_log;
+use crate::tls::server::accept_tls;
+use crate::tunnel::access_log::{
+    AccessRecord, STATUS_NO_PEER, STATUS_NOT_ALLOWED, STATUS_OK, STATUS_TIMEOUT,
+};
+use crate::tunnel::auth::AuthToken;
+use crate::tunnel::connection::send_preamble;
+use crate::tunnel::dns::normalize_host;
+use crate::tunnel::events::TunnelEvent;
+use crate::tunnel::handler::{PeerForwarder, SocksHandler, SocksRequest};
+use crate::tunnel::logger::SharedLogger;
+use crate::tunnel::protocol::{
+    AUTH_FAILED_MESSAGE, CLOSE_SOCKET_ERROR, NOT_ALLOWED_MESSAGE, TunnelMessage,
+};
+use crate::tunnel::quota::QUOTA_EXCEEDED_MESSAGE;
+use crate::tunnel::relay::{ProtocolKind, RelayOptions, recv_after_hello, send_message};
+use crate::tunnel::shutdown::Shutdown;
+use crate::tunnel::state::{TUNNEL_ALPN, TunnelState};
+use crate::tunnel::stats::ConnectionStats;
+use crate::tunnel::streams::TunnelTransport;
+use crate::utils::logging::{RequestId, detail, format_log, target};
 
-pub async fn handle_socks_client(
-    mut socket: TcpStream,
-    _addr: SocketAddr,
+/// Deadline for a client to finish the SOCKS negotiation
+pub const SOCKS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Clients over `--max-connections` being turned away at once, per listener;
+/// past this they are closed without a reply
+pub const MAX_PENDING_REFUSALS: usize = 16;
+
+/// Deadline for a client over the limit to send the request it is refused
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often a parked request re-checks the connection state
+const QUEUE_RECHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Client address logged for connections on the unix socket, which have none
+#[cfg(unix)]
+pub const UNIX_CLIENT_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+/// A local client connection: a TCP socket, or a unix socket with `--socks-unix`
+pub trait LocalStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> LocalStream for T {}
+
+/// Holds one unit of a shared counter until dropped
+struct CountGuard {
+    active: Arc<AtomicU64>,
+}
+
+impl CountGuard {
+    fn new(active: &Arc<AtomicU64>) -> Self {
+        active.fetch_add(1, Ordering::Relaxed);
+        Self {
+            active: Arc::clone(active),
+        }
+    }
+}
+
+impl Drop for CountGuard {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Accept SOCKS5 clients on `listener` until shutdown is triggered
+///
+/// At most `max_socks_clients` clients are served at once (0 = unlimited);
+/// further clients get a general-failure reply once they send their request,
+/// or are closed when `MAX_PENDING_REFUSALS` are already waiting for one.
+pub async fn serve_socks(
+    listener: TcpListener,
     state: Arc<Mutex<TunnelState>>,
     endpoint: Endpoint,
-) -> Result<()> {
+) {
+    let handler = Arc::new(PeerForwarder::new(Arc::clone(&state), endpoint));
+    serve_socks_with(listener, state, handler).await;
+}
+
+/// `serve_socks` passing negotiated requests to `handler`
+pub async fn serve_socks_with(
+    listener: TcpListener,
+    state: Arc<Mutex<TunnelState>>,
+    handler: Arc<dyn SocksHandler>,
+) {
+    let (tcp_options, logger) = {
+        let state = state.lock().await;
+        (state.tcp_options.clone(), state.logger.clone())
+    };
+    let (listener, tcp_options, logger) = (&listener, &tcp_options, &logger);
+    let accept = move || async move {
+        let (socket, addr) = listener.accept().await?;
+        tcp_options.apply_or_warn(&socket, &**logger);
+        Ok((socket, addr))
+    };
+    serve_clients(accept, state, handler).await;
+}
+
+/// `serve_socks_with` for clients speaking SOCKS5 inside TLS (`--socks-tls-cert`)
+///
+/// The TLS handshake runs in the client's own task, after it got one of the
+/// `--max-connections` slots, and must finish within the SOCKS handshake
+/// timeout. Clients over the limit are closed without a handshake.
+pub async fn serve_socks_tls(
+    listener: TcpListener,
+    state: Arc<Mutex<TunnelState>>,
+    handler: Arc<dyn SocksHandler>,
+    acceptor: TlsAcceptor,
+) {
+    let (tcp_options, logger) = {
+        let state = state.lock().await;
+        (state.tcp_options.clone(), state.logger.clone())
+    };
+    let accept_logger = logger.clone();
+    let (listener, tcp_options, accept_logger) = (&listener, &tcp_options, &accept_logger);
+    let accept = move || async move {
+        let (socket, addr) = listener.accept().await?;
+        tcp_options.apply_or_warn(&socket, &**accept_logger);
+        Ok((socket, addr))
+    };
+    let serve = move |socket: TcpStream, addr: SocketAddr, admitted: bool| {
+        let (handler, acceptor) = (Arc::clone(&handler), acceptor.clone());
+        let logger = logger.clone();
+        async move {
+            if !admitted {
+                return;
+            }
+            let result = async {
+                let stream = accept_tls(&acceptor, socket, SOCKS_HANDSHAKE_TIMEOUT).await?;
+                handle_socks_client(stream, addr, handler.as_ref()).await
+            };
+            if let Err(e) = result.await {
+                logger.error(None, &format!("SOCKS error from {}: {:#}", addr, e));
+            }
+        }
+    };
+    accept_clients("SOCKS", accept, &state, serve).await;
+}
+
+/// `serve_socks_with` for clients connecting to a unix socket (`--socks-unix`)
+///
+/// Unix clients have no address; they show up as `UNIX_CLIENT_ADDR` in the
+/// log and the access log.
+#[cfg(unix)]
+pub async fn serve_socks_unix(
+    listener: tokio::net::UnixListener,
+    state: Arc<Mutex<TunnelState>>,
+    handler: Arc<dyn SocksHandler>,
+) {
+    let listener = &listener;
+    let accept = move || async move {
+        let (socket, _) = listener.accept().await?;
+        Ok((socket, UNIX_CLIENT_ADDR))
+    };
+    serve_clients(accept, state, handler).await;
+}
+
+/// Accept loop shared by the SOCKS listeners
+async fn serve_clients<S, F, Fut>(
+    accept: F,
+    state: Arc<Mutex<TunnelState>>,
+    handler: Arc<dyn SocksHandler>,
+) where
+    S: LocalStream + 'static,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::io::Result<(S, SocketAddr)>>,
+{
+    let logger = state.lock().await.logger.clone();
+    let serve = move |mut socket: S, addr: SocketAddr, admitted: bool| {
+        let handler = Arc::clone(&handler);
+        let logger = logger.clone();
+        async move {
+            if !admitted {
+                refuse_socks_client(&mut socket).await;
+            } else if let Err(e) = handle_socks_client(socket, addr, handler.as_ref()).await {
+                logger.error(None, &format!("SOCKS error from {}: {}", addr, e));
+            }
+        }
+    };
+    accept_clients("SOCKS", accept, &state, serve).await;
+}
+
+/// Answer a client over the connection limit with a general failure
+async fn refuse_socks_client(socket: &mut impl LocalStream) {
+    if let Ok((host, _)) = negotiate(socket, REFUSAL_TIMEOUT).await {
+        let reply = socks_reply(1, reply_addr_type(&host));
+        let _ = socket.write_all(&reply).await;
+    }
+}
+
+/// Accept loop shared by every local listener: SOCKS5, transparent and forwards
+///
+/// Each accepted client is handed to `serve` in its own task, until draining
+/// or shutdown begins. All listeners draw from the same `max_socks_clients`
+/// slots (`--max-connections`); a client arriving when they are all taken is
+/// handed to `serve` with `admitted` false, to be turned away. At most
+/// `MAX_PENDING_REFUSALS` of those run at once, so a flood of clients can't
+/// pile up tasks; the rest are closed straight away.
+pub(crate) async fn accept_clients<S, A, AFut, F, Fut>(
+    kind: &str,
+    mut accept: A,
+    state: &Arc<Mutex<TunnelState>>,
+    mut serve: F,
+) where
+    A: FnMut() -> AFut,
+    AFut: Future<Output = std::io::Result<(S, SocketAddr)>>,
+    F: FnMut(S, SocketAddr, bool) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (draining, shutdown, in_flight, active, slots, max_clients, logger) = {
+        let mut state = state.lock().await;
+        (
+            state.draining.clone(),
+            state.shutdown.clone(),
+            state.in_flight.clone(),
+            Arc::clone(&state.active_socks_clients),
+            state.client_slots(),
+            state.max_socks_clients,
+            state.logger.clone(),
+        )
+    };
+    let refusals = Arc::new(Semaphore::new(MAX_PENDING_REFUSALS));
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = accept() => accepted,
+            _ = draining.wait() => break,
+            _ = shutdown.wait() => break,
+        };
+        let (socket, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                logger.error(
+                    None,
+                    &format!("Failed to accept {} connection: {}", kind, e),
+                );
+                continue;
+            }
+        };
+        let permit = match &slots {
+            Some(slots) => match Arc::clone(slots).try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    logger.warn(&format!(
+                        "⚠️  Connection limit ({}) reached, rejecting {} client {}",
+                        max_clients, kind, addr
+                    ));
+                    if let Ok(permit) = Arc::clone(&refusals).try_acquire_owned() {
+                        let refused = serve(socket, addr, false);
+                        tokio::spawn(async move {
+                            let _permit = permit;
+                            refused.await;
+                        });
+                    }
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        let guard = CountGuard::new(&active);
+        let in_flight = in_flight.enter();
+        let served = serve(socket, addr, true);
+        tokio::spawn(async move {
+            let _permit = permit;
+            let _guard = guard;
+            let _in_flight = in_flight;
+            served.await;
+        });
+    }
+}
+
+/// Run `socks_handshake`, giving up if it doesn't complete within `limit`
+///
+/// Bounds the whole negotiation, so a client dribbling bytes can't hold a
+/// task (and a connection slot) forever.
+async fn negotiate(socket: &mut impl LocalStream, limit: Duration) -> Result<(String, u16)> {
+    tokio::time::timeout(limit, socks_handshake(socket))
+        .await
+        .map_err(|_| anyhow::anyhow!("SOCKS handshake timed out after {:?}", limit))?
+}
+
+/// Bytes of a non-SOCKS client's first data shown in the log
+const FOREIGN_PEEK_LEN: usize = 16;
+
+/// How long to wait for the rest of those bytes
+const FOREIGN_PEEK_WAIT: Duration = Duration::from_millis(100);
+
+/// Answer to an HTTP request on the SOCKS port, usually a browser set up to
+/// use the tunnel as an HTTP proxy
+const NOT_SOCKS_BODY: &str = "This is a SOCKS5 proxy port, not an HTTP proxy.\n\
+    Configure your client to use it as a SOCKS5 proxy (e.g. socks5h://host:port).\n";
+
+/// Explain a client that doesn't speak SOCKS5, whose first two bytes were `head`
+///
+/// HTTP clients get a `400 Bad Request` saying what the port is for; for
+/// anything else the error carries the first bytes in hex.
+async fn reject_foreign_protocol(socket: &mut impl LocalStream, head: [u8; 2]) -> anyhow::Error {
+    let mut first = head.to_vec();
+    let mut more = [0u8; FOREIGN_PEEK_LEN - 2];
+    if let Ok(Ok(n)) = tokio::time::timeout(FOREIGN_PEEK_WAIT, socket.read(&mut more)).await {
+        first.extend_from_slice(&more[..n]);
+    }
+    if starts_with_http_method(&first) {
+        let response = format!(
+            "HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            NOT_SOCKS_BODY.len(),
+            NOT_SOCKS_BODY
+        );
+        if socket.write_all(response.as_bytes()).await.is_ok() {
+            socket.shutdown().await.ok();
+        }
+        return anyhow::anyhow!(
+            "Got an HTTP request on the SOCKS5 port; configure the client for SOCKS5, not HTTP"
+        );
+    }
+    let hex: Vec<String> = first.iter().map(|byte| format!("{:02x}", byte)).collect();
+    anyhow::anyhow!(
+        "Not a SOCKS5 client (version byte {}), first bytes: {}",
+        head[0],
+        hex.join(" ")
+    )
+}
+
+/// Negotiate SOCKS5 up to and including the CONNECT request
+///
+/// Returns the requested destination; unsupported commands and address types
+/// as well as empty or non-UTF-8 domain names are answered with the matching
+/// SOCKS error before bailing.
+async fn socks_handshake(socket: &mut impl LocalStream) -> Result<(String, u16)> {
     // SOCKS5 handshake
     let mut buf = [0u8; 2];
     socket.read_exact(&mut buf).await?;
 
     if buf[0] != SOCKS_VERSION {
-        anyhow::bail!("Unsupported SOCKS version: {}", buf[0]);
+        return Err(reject_foreign_protocol(socket, buf).await);
     }
 
     let nmethods = buf[1] as usize;
@@ -44,14 +369,12 @@ pub async fn handle_socks_client(
 
     if buf[1] != SOCKS_CMD_CONNECT {
         // Send "command not supported"
-        socket
-            .write_all(&[SOCKS_VERSION, 7, 0, 1, 0, 0, 0, 0, 0, 0])
-            .await?;
+        socket.write_all(&socks_reply(7, buf[3])).await?;
         anyhow::bail!("Only CONNECT command is supported");
     }
 
     // Parse destination address
-    let (host, port) = match buf[3] {
+    let destination = match buf[3] {
         SOCKS_ADDR_TYPE_IPV4 => {
             let mut addr = [0u8; 4];
             socket.read_exact(&mut addr).await?;
@@ -71,7 +394,17 @@ pub async fn handle_socks_client(
             let mut port_buf = [0u8; 2];
             socket.read_exact(&mut port_buf).await?;
             let port = u16::from_be_bytes(port_buf);
-            (String::from_utf8(domain)?, port)
+            if domain.is_empty() {
+                socket.write_all(&socks_reply(1, buf[3])).await?;
+                anyhow::bail!("Empty domain name in SOCKS request");
+            }
+            match String::from_utf8(domain) {
+                Ok(host) => (host, port),
+                Err(_) => {
+                    socket.write_all(&socks_reply(1, buf[3])).await?;
+                    anyhow::bail!("Domain name in SOCKS request is not valid UTF-8");
+                }
+            }
         }
         SOCKS_ADDR_TYPE_IPV6 => {
             let mut addr = [0u8; 16];
@@ -79,121 +412,1500 @@ pub async fn handle_socks_client(
             let mut port_buf = [0u8; 2];
             socket.read_exact(&mut port_buf).await?;
             let port = u16::from_be_bytes(port_buf);
-            // Format IPv6 address
-            let ipv6_str = format!(
-                "{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}",
-                addr[0],
-                addr[1],
-                addr[2],
-                addr[3],
-                addr[4],
-                addr[5],
-                addr[6],
-                addr[7],
-                addr[8],
-                addr[9],
-                addr[10],
-                addr[11],
-                addr[12],
-                addr[13],
-                addr[14],
-                addr[15]
-            );
-            (format!("[{}]", ipv6_str), port)
+            (format!("[{}]", Ipv6Addr::from(addr)), port)
         }
         _ => {
-            socket
-                .write_all(&[SOCKS_VERSION, 8, 0, 1, 0, 0, 0, 0, 0, 0])
-                .await?;
+            socket.write_all(&socks_reply(8, buf[3])).await?;
             anyhow::bail!("Unsupported address type");
         }
     };
 
-    println!("\n📥 {}", format_log("PROXY REQUEST", &host, port));
+    Ok(destination)
+}
 
-    // Get peer connection with wait-for-reconnection logic
-    let peer_conn = {
-        const MAX_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
-        const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
-        let start = std::time::Instant::now();
+/// A request gave up waiting for a peer connection after `reconnect_wait`
+#[derive(Debug)]
+struct PeerWaitTimeout(Duration);
 
-        loop {
-            let (conn, conn_state) = {
-                let state_guard = state.lock().await;
-                (
-                    state_guard.peer_connection.clone(),
-                    state_guard.connection_state.clone(),
-                )
-            };
+impl std::fmt::Display for PeerWaitTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No peer connection within {:?}", self.0)
+    }
+}
 
-            if let Some(conn) = conn {
-                break conn;
+impl std::error::Error for PeerWaitTimeout {}
+
+/// Return the peer connection, waiting for a reconnection if one is expected
+///
+/// Waiting requests are capped at `max_queued_requests` and give up after
+/// `reconnect_wait` with `PeerWaitTimeout`; all of them are woken as soon as
+/// a connection is installed.
+async fn wait_for_peer(
+    state: &Arc<Mutex<TunnelState>>,
+) -> Result<(Connection, Arc<ConnectionStats>, Shutdown)> {
+    let (peer_connected, queued, max_queued, wait, logger) = {
+        let state = state.lock().await;
+        (
+            Arc::clone(&state.peer_connected),
+            Arc::clone(&state.queued_requests),
+            state.max_queued_requests,
+            state.reconnect_wait,
+            state.logger.clone(),
+        )
+    };
+    let deadline = Instant::now() + wait;
+    let mut queued_guard = None;
+
+    loop {
+        // Register for the wakeup before looking at the state so it can't be missed
+        let notified = peer_connected.notified();
+        let (shutdown, conn_state) = {
+            let state = state.lock().await;
+            if let Some(conn) = state.peer_connection.clone() {
+                return Ok((conn, Arc::clone(&state.stats), state.shutdown.clone()));
             }
+            if !state.reconnect_expected() {
+                anyhow::bail!(
+                    "No peer connection available (state: {:?})",
+                    state.connection_state
+                );
+            }
+            (state.shutdown.clone(), state.connection_state.clone())
+        };
 
-            if conn_state == ConnectionState::Connecting {
-                if start.elapsed() < MAX_WAIT {
-                    tokio::time::sleep(CHECK_INTERVAL).await;
-                    continue; // Wait for reconnection
-                }
+        if queued_guard.is_none() {
+            let guard = CountGuard::new(&queued);
+            let waiting = queued.load(Ordering::Relaxed);
+            if max_queued > 0 && waiting > max_queued as u64 {
+                anyhow::bail!("Reconnect queue full ({} requests waiting)", max_queued);
             }
+            logger.info(&format!(
+                "⏳ Waiting for peer reconnection (state: {:?}, {} queued)",
+                conn_state, waiting
+            ));
+            queued_guard = Some(guard);
+        }
 
-            // No connection and not reconnecting, or timeout
-            eprintln!("❌ No peer connection available (state: {:?})", conn_state);
-            socket
-                .write_all(&[SOCKS_VERSION, 4, 0, 1, 0, 0, 0, 0, 0, 0])
-                .await?;
-            anyhow::bail!("No peer connection");
+        tokio::select! {
+            _ = notified => {}
+            // Re-check periodically in case reconnection gave up meanwhile
+            _ = tokio::time::sleep(QUEUE_RECHECK_INTERVAL) => {}
+            _ = tokio::time::sleep_until(deadline) => {
+                return Err(PeerWaitTimeout(wait).into());
+            }
+            _ = shutdown.wait() => anyhow::bail!("Shutting down"),
+        }
+    }
+}
+
+/// Connection a request for `host` should use: a routed peer or the default one
+async fn peer_for_host(
+    state: &Arc<Mutex<TunnelState>>,
+    endpoint: &Endpoint,
+    host: &str,
+) -> Result<(Connection, Arc<ConnectionStats>, Shutdown)> {
+    let routed = {
+        let mut state = state.lock().await;
+        match state.routes.route(host) {
+            // A route to the default peer is just the default connection
+            Some(peer) if Some(peer) != state.remote_peer_id => {
+                let dial = Arc::clone(state.routed_dials.entry(peer).or_default());
+                Some((
+                    peer,
+                    dial,
+                    Arc::clone(&state.stats),
+                    state.shutdown.clone(),
+                    state.logger.clone(),
+                ))
+            }
+            _ => None,
         }
     };
 
-    log_connection_details(&endpoint, peer_conn.remote_id(), "   ℹ️  Connection Info:");
+    let Some((peer, dial, stats, shutdown, logger)) = routed else {
+        return wait_for_peer(state).await;
+    };
+    // One dial per peer at a time; whoever waited on it finds its connection
+    let _dialing = dial.lock().await;
+    let open = state
+        .lock()
+        .await
+        .routed_connections
+        .get(&peer)
+        .filter(|conn| conn.close_reason().is_none())
+        .cloned();
+    if let Some(conn) = open {
+        return Ok((conn, stats, shutdown));
+    }
+
+    logger.info(&format!(
+        "🧭 Routing {} via peer {}, connecting...",
+        target(host),
+        peer.fmt_short()
+    ));
+    let conn = endpoint
+        .connect(peer, TUNNEL_ALPN)
+        .await
+        .with_context(|| format!("Failed to connect to routed peer {}", peer.fmt_short()))?;
+    state
+        .lock()
+        .await
+        .routed_connections
+        .insert(peer, conn.clone());
+    Ok((conn, stats, shutdown))
+}
 
-    // Open tunnel stream
-    let (mut send, mut recv) = peer_conn.open_bi().await?;
+/// Negotiate SOCKS5 with a client and hand its request to `handler`
+pub async fn handle_socks_client(
+    mut socket: impl LocalStream + 'static,
+    addr: SocketAddr,
+    handler: &dyn SocksHandler,
+) -> Result<()> {
+    let (host, port) = negotiate(&mut socket, SOCKS_HANDSHAKE_TIMEOUT).await?;
+
+    handler
+        .handle(SocksRequest {
+            socket: Box::new(socket),
+            client: addr,
+            host,
+            port,
+        })
+        .await
+}
+
+/// How the local client learns whether its tunnel was established
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LocalProtocol {
+    /// Client negotiated SOCKS5 and expects a reply
+    Socks5,
+    /// Redirected connection; failures just close the socket
+    Transparent,
+    /// Connection to a `--forward` listener; failures just close the socket
+    Forward,
+}
+
+impl LocalProtocol {
+    /// Label of the request line in the log
+    pub fn log_label(self) -> &'static str {
+        match self {
+            LocalProtocol::Socks5 => "PROXY REQUEST",
+            LocalProtocol::Transparent => "TRANSPARENT",
+            LocalProtocol::Forward => "PORT FORWARD",
+        }
+    }
 
-    // Send connect request
+    /// Request method recorded in the access log
+    pub fn access_method(self) -> &'static str {
+        match self {
+            LocalProtocol::Socks5 => "CONNECT",
+            LocalProtocol::Transparent => "TRANSPARENT",
+            LocalProtocol::Forward => "FORWARD",
+        }
+    }
+}
+
+/// Answer a SOCKS client, using the address family of its requested `host`
+async fn send_reply(
+    socket: &mut impl LocalStream,
+    protocol: LocalProtocol,
+    host: &str,
+    code: u8,
+) -> Result<()> {
+    if protocol == LocalProtocol::Socks5 {
+        socket
+            .write_all(&socks_reply(code, reply_addr_type(host)))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Open a tunnel stream to `host:port` through the peer and relay `socket` over it
+pub async fn forward_to_peer(
+    mut socket: impl LocalStream,
+    client: SocketAddr,
+    host: String,
+    port: u16,
+    state: Arc<Mutex<TunnelState>>,
+    endpoint: Endpoint,
+    protocol: LocalProtocol,
+) -> Result<()> {
+    let id = RequestId::next();
+    let (access_log, relay_options, auth_token, logger) = {
+        let state = state.lock().await;
+        state.events.publish(TunnelEvent::Request {
+            host: host.clone(),
+            port,
+        });
+        (
+            state.access_log.clone(),
+            state.relay_options.clone(),
+            state.auth_token.clone(),
+            state.logger.clone(),
+        )
+    };
+    logger.request_start(id, protocol.log_label(), &host, port);
+    let mut record = AccessRecord::start(access_log, client, protocol.access_method(), &host, port);
+
+    // Route and connect by the canonical name; the lines above keep the original
+    let host = match normalize_host(&host) {
+        Ok(normalized) => {
+            if normalized != host {
+                logger.info(&format!(
+                    "   {} 🔤 Normalized to {}",
+                    id,
+                    target(&normalized)
+                ));
+            }
+            normalized
+        }
+        Err(e) => {
+            logger.error(Some(id), detail(&e.to_string()));
+            send_reply(&mut socket, protocol, &host, 1).await?;
+            return Err(e);
+        }
+    };
+
+    // Get the peer connection for this destination, parking the request while
+    // a reconnection of the default peer is under way
+    let mut retried = false;
+    let (peer_conn, stats, shutdown, tunnel) = loop {
+        let (peer_conn, stats, shutdown) = match peer_for_host(&state, &endpoint, &host).await {
+            Ok(peer) => peer,
+            Err(e) if e.is::<PeerWaitTimeout>() => {
+                logger.warn(&format!("⏱️  {} {}", id, e));
+                record.status = STATUS_TIMEOUT;
+                send_reply(&mut socket, protocol, &host, SOCKS_REPLY_TTL_EXPIRED).await?;
+                return Err(e);
+            }
+            Err(e) => {
+                logger.error(Some(id), detail(&e.to_string()));
+                record.status = STATUS_NO_PEER;
+                send_reply(&mut socket, protocol, &host, 4).await?;
+                return Err(e);
+            }
+        };
+
+        logger.info(&format_connection_details(
+            &endpoint,
+            peer_conn.remote_id(),
+            &format!("   {} ℹ️  Connection Info:", id),
+            stats.rtt(),
+        ));
+
+        let established =
+            establish_tunnel(&peer_conn, &relay_options, auth_token.as_ref(), &host, port).await;
+        let e = match established {
+            Ok(tunnel) => break (peer_conn, stats, shutdown, tunnel),
+            Err(e) => e,
+        };
+
+        // Only a dead connection is worth another try, and only on a new one
+        let Some(reason) = peer_conn.close_reason() else {
+            logger.error(
+                Some(id),
+                &format!("Tunnel stream failed: {}", detail(&e.to_string())),
+            );
+            send_reply(&mut socket, protocol, &host, 1).await?;
+            return Err(e.context("Tunnel stream failed before the tunnel was established"));
+        };
+        logger.error(
+            Some(id),
+            &format!(
+                "Peer connection lost before the tunnel was established: {}",
+                reason
+            ),
+        );
+        if !retried && peer_replaced(&state, &endpoint, &host, &peer_conn).await {
+            logger.info(&format!("   {} 🔄 Retrying on the new peer connection", id));
+            retried = true;
+            continue;
+        }
+        record.status = STATUS_NO_PEER;
+        send_reply(&mut socket, protocol, &host, 4).await?;
+        anyhow::bail!("Peer connection lost: {}", reason);
+    };
+    let _request = stats.start_request();
+    let _stream = stats.open_stream();
+
+    let request = TunnelRequest {
+        id,
+        host: &host,
+        port,
+        protocol,
+        logger: &logger,
+        record: &mut record,
+    };
+    let (sent, received, protocol, sni) =
+        complete_tunnel(socket, tunnel, request, &stats, &shutdown).await?;
+    logger.request_end(
+        id,
+        sent,
+        received,
+        path_type_label(&current_connection_type(&endpoint, peer_conn.remote_id())),
+        protocol,
+        sni.as_deref(),
+    );
+    record.bytes_sent = sent;
+    record.bytes_received = received;
+    record.sni = sni;
+    Ok(())
+}
+
+/// One local request on its way through the tunnel
+struct TunnelRequest<'a> {
+    id: RequestId,
+    host: &'a str,
+    port: u16,
+    protocol: LocalProtocol,
+    logger: &'a SharedLogger,
+    record: &'a mut AccessRecord,
+}
+
+/// Answer the local client from the exit node's response and, once
+/// connected, relay it over the stream
+///
+/// Returns the bytes sent and received, the protocol and the SNI seen.
+async fn complete_tunnel(
+    mut socket: impl LocalStream,
+    (mut send, mut recv, relay_options, response): (
+        impl AsyncWrite + Unpin,
+        impl AsyncRead + Unpin,
+        RelayOptions,
+        TunnelMessage,
+    ),
+    request: TunnelRequest<'_>,
+    stats: &ConnectionStats,
+    shutdown: &Shutdown,
+) -> Result<(u64, u64, ProtocolKind, Option<String>)> {
+    let TunnelRequest {
+        id,
+        host,
+        port,
+        protocol,
+        logger,
+        record,
+    } = request;
+    match response {
+        TunnelMessage::Connected => {
+            logger.info(&format!(
+                "✅ {}",
+                format_log(id, "TUNNEL ESTABLISHED", host, port)
+            ));
+            record.status = STATUS_OK;
+            send_reply(&mut socket, protocol, host, 0).await?;
+
+            // Relay data bidirectionally
+            Ok(crate::tunnel::relay::relay_bidirectional(
+                &mut send,
+                &mut recv,
+                socket,
+                stats,
+                shutdown,
+                (id, &**logger),
+                &relay_options,
+            )
+            .await)
+        }
+        TunnelMessage::Error { message } => {
+            logger.error(Some(id), &format!("Tunnel error: {}", detail(&message)));
+            // Policy denials get "not allowed by ruleset", anything else "refused"
+            let reply = if message.starts_with(NOT_ALLOWED_MESSAGE)
+                || message.starts_with(QUOTA_EXCEEDED_MESSAGE)
+                || message == AUTH_FAILED_MESSAGE
+            {
+                record.status = STATUS_NOT_ALLOWED;
+                SOCKS_REPLY_NOT_ALLOWED
+            } else {
+                5
+            };
+            send_reply(&mut socket, protocol, host, reply).await?;
+            anyhow::bail!("Tunnel connection failed: {}", detail(&message));
+        }
+        TunnelMessage::CloseWithReason { code, message } => {
+            logger.error(
+                Some(id),
+                &format!(
+                    "Exit node closed the stream: {} (code {})",
+                    detail(&message),
+                    code
+                ),
+            );
+            send_reply(&mut socket, protocol, host, close_reply(code)).await?;
+            anyhow::bail!("Tunnel closed: {}", detail(&message));
+        }
+        other => {
+            logger.error(
+                Some(id),
+                &format!("Unexpected response from the exit node: {:?}", other),
+            );
+            send_reply(&mut socket, protocol, host, 1).await?;
+            anyhow::bail!("Unexpected response");
+        }
+    }
+}
+
+/// Open a tunnel stream, send the connect request and wait for the response
+///
+/// Also returns `relay_options` narrowed to the features in the exit node's
+/// `Hello`.
+async fn establish_tunnel<T: TunnelTransport>(
+    transport: &T,
+    relay_options: &RelayOptions,
+    auth_token: Option<&AuthToken>,
+    host: &str,
+    port: u16,
+) -> Result<(T::Send, T::Recv, RelayOptions, TunnelMessage)> {
+    let (mut send, mut recv) = transport.open_bi().await?;
+    send_preamble(&mut send, relay_options.features(), auth_token).await?;
     send_message(
         &mut send,
         &TunnelMessage::Connect {
-            host: host.clone(),
+            host: host.to_string(),
             port,
         },
     )
     .await?;
+    let (features, response) = recv_after_hello(&mut recv).await?;
+    let relay_options = relay_options.negotiated(features.unwrap_or_default());
+    Ok((send, recv, relay_options, response))
+}
 
-    // Wait for response
-    match recv_message(&mut recv).await? {
-        TunnelMessage::Connected => {
-            println!("✅ {}", format_log("TUNNEL ESTABLISHED", &host, port));
-            // Send success reply
-            socket
-                .write_all(&[SOCKS_VERSION, 0, 0, 1, 0, 0, 0, 0, 0, 0])
-                .await?;
+/// Whether a live peer connection for `host` has replaced the `lost` one
+///
+/// Routed peers are redialled; the default peer is only checked, as a retry
+/// doesn't wait out a reconnection.
+async fn peer_replaced(
+    state: &Arc<Mutex<TunnelState>>,
+    endpoint: &Endpoint,
+    host: &str,
+    lost: &Connection,
+) -> bool {
+    let current = {
+        let state = state.lock().await;
+        match state.routes.route(host) {
+            Some(peer) if Some(peer) != state.remote_peer_id => None,
+            _ => Some(state.peer_connection.clone()),
+        }
+    };
+    let conn = match current {
+        Some(conn) => conn,
+        None => peer_for_host(state, endpoint, host)
+            .await
+            .ok()
+            .map(|(conn, _, _)| conn),
+    };
+    conn.is_some_and(|conn| conn.stable_id() != lost.stable_id() && conn.close_reason().is_none())
+}
 
-            // Relay data bidirectionally
-            let (sent, received, sni) =
-                crate::tunnel::relay::relay_bidirectional(&mut send, &mut recv, socket).await;
-            println!(
-                "   📊 Stats: ↑ {} bytes sent, ↓ {} bytes received{}",
-                sent,
-                received,
-                sni.map(|s| format!(" (SNI: {})", s)).unwrap_or_default()
-            );
+/// SOCKS reply for a stream the exit node closed before `Connected`
+fn close_reply(code: u16) -> u8 {
+    match code {
+        // Destination refused or reset us
+        CLOSE_SOCKET_ERROR => 5,
+        // Shutting down, protocol trouble, or a code we don't know yet
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::connection::handle_peer_connection;
+    use crate::tunnel::filter::HostFilter;
+    use crate::tunnel::logger::SharedLogger;
+    use crate::tunnel::outbound::Outbound;
+    use crate::tunnel::relay::recv_message;
+    use crate::tunnel::routing::{Route, RouteTable};
+    use crate::tunnel::state::ConnectionState;
+    use crate::tunnel::test_support::{
+        CapturingLogger, TunnelHarness, connected_pair, echo_server, self_signed_tls,
+    };
+    use iroh::endpoint::RelayMode;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    #[test]
+    fn test_close_reason_maps_to_socks_reply() {
+        assert_eq!(close_reply(CLOSE_SOCKET_ERROR), 5);
+        assert_eq!(close_reply(crate::tunnel::protocol::CLOSE_SHUTTING_DOWN), 1);
+        assert_eq!(close_reply(999), 1);
+    }
+
+    #[tokio::test]
+    async fn test_serve_socks_exits_on_shutdown() {
+        let endpoint = Endpoint::empty_builder(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let state = Arc::new(Mutex::new(TunnelState::default()));
+        let shutdown = state.lock().await.shutdown.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let server = tokio::spawn(serve_socks(listener, state, endpoint.clone()));
+        tokio::task::yield_now().await;
+        shutdown.trigger();
+
+        tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("SOCKS listener did not exit on shutdown")
+            .unwrap();
+        endpoint.close().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socks_over_unix_socket() {
+        let harness = TunnelHarness::start().await;
+        let destination = echo_server().await;
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("socks.sock");
+        // A stale socket from an earlier run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = crate::tunnel::unix_socket::bind_unix(&path, "SOCKS5").unwrap();
+        tokio::spawn(serve_socks_unix(
+            listener,
+            Arc::clone(&harness.client_state),
+            harness.forwarder(),
+        ));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        let [hi, lo] = destination.port().to_be_bytes();
+        stream
+            .write_all(&[SOCKS_VERSION, SOCKS_CMD_CONNECT, 0, 1, 127, 0, 0, 1, hi, lo])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+
+        stream.write_all(b"over a unix socket").await.unwrap();
+        let mut echoed = [0u8; 18];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"over a unix socket");
+        assert_eq!(harness.server_stats.snapshot().requests, 1);
+
+        harness.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_socks_over_tls() {
+        let harness = TunnelHarness::start().await;
+        let destination = echo_server().await;
+        let dir = std::env::temp_dir().join(format!("tunnel-socks-tls-{}", std::process::id()));
+        let (tls, connector) = self_signed_tls(&dir);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_socks_tls(
+            listener,
+            Arc::clone(&harness.client_state),
+            harness.forwarder(),
+            tls.acceptor().unwrap(),
+        ));
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(name, tcp).await.unwrap();
+        stream.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [SOCKS_VERSION, 0]);
+        let [hi, lo] = destination.port().to_be_bytes();
+        stream
+            .write_all(&[SOCKS_VERSION, SOCKS_CMD_CONNECT, 0, 1, 127, 0, 0, 1, hi, lo])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+
+        stream.write_all(b"inside TLS").await.unwrap();
+        let mut echoed = [0u8; 10];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"inside TLS");
+        assert_eq!(harness.server_stats.snapshot().requests, 1);
+
+        // Plaintext SOCKS is refused before any request is read
+        let mut plain = TcpStream::connect(addr).await.unwrap();
+        plain.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        plain.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHODS]);
+        assert_eq!(plain.read(&mut method).await.unwrap(), 0);
+        assert_eq!(harness.server_stats.snapshot().requests, 1);
+
+        harness.close().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshakes_take_connection_slots() {
+        let harness = TunnelHarness::start().await;
+        harness.client_state.lock().await.max_socks_clients = 1;
+        let dir = tempfile::TempDir::new().unwrap();
+        let (tls, connector) = self_signed_tls(dir.path());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_socks_tls(
+            listener,
+            Arc::clone(&harness.client_state),
+            harness.forwarder(),
+            tls.acceptor().unwrap(),
+        ));
+
+        // A client stalling before its TLS handshake holds the only slot
+        let stalled = TcpStream::connect(addr).await.unwrap();
+        let active = Arc::clone(&harness.client_state.lock().await.active_socks_clients);
+        while active.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
-        TunnelMessage::Error { message } => {
-            eprintln!("❌ Tunnel error: {}", message);
-            socket
-                .write_all(&[SOCKS_VERSION, 5, 0, 1, 0, 0, 0, 0, 0, 0])
-                .await?;
-            anyhow::bail!("Tunnel connection failed: {}", message);
+        let name = ServerName::try_from("localhost").unwrap();
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let refused =
+            tokio::time::timeout(Duration::from_secs(5), connector.connect(name.clone(), tcp))
+                .await
+                .expect("handshake over the limit was not refused");
+        assert!(refused.is_err());
+        assert_eq!(active.load(Ordering::Relaxed), 1);
+
+        drop(stalled);
+        while active.load(Ordering::Relaxed) != 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
-        _ => {
-            socket
-                .write_all(&[SOCKS_VERSION, 1, 0, 1, 0, 0, 0, 0, 0, 0])
-                .await?;
-            anyhow::bail!("Unexpected response");
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        connector.connect(name, tcp).await.unwrap();
+        harness.close().await;
+    }
+
+    /// SOCKS CONNECT to 127.0.0.1:`port`, returning the reply code
+    async fn socks_connect(addr: SocketAddr, port: u16) -> u8 {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        let [hi, lo] = port.to_be_bytes();
+        client
+            .write_all(&[SOCKS_VERSION, SOCKS_CMD_CONNECT, 0, 1, 127, 0, 0, 1, hi, lo])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        reply[1]
+    }
+
+    #[tokio::test]
+    async fn test_end_to_end_echo_through_tunnel() {
+        let harness = TunnelHarness::start().await;
+        let destination = echo_server().await;
+
+        let mut stream = harness.socks_connect(destination).await;
+        for payload in [&b"hello through the tunnel"[..], &[0u8; 70_000][..]] {
+            stream.write_all(payload).await.unwrap();
+            let mut echoed = vec![0u8; payload.len()];
+            stream.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(echoed, payload);
         }
+        drop(stream);
+
+        let client_stats = harness.client_state.lock().await.stats_snapshot();
+        assert_eq!(client_stats.requests, 1);
+        assert_eq!(client_stats.bytes_sent, 70_024);
+        assert_eq!(harness.server_stats.snapshot().requests, 1);
+        harness.close().await;
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_filtered_destination_gets_not_allowed_reply() {
+        let destination = echo_server().await;
+        let outbound = Outbound {
+            host_filter: HostFilter::new(vec![], vec!["127.0.0.1".parse().unwrap()]),
+            ..Default::default()
+        };
+        let harness = TunnelHarness::start_with_outbound(outbound).await;
+        let reply = socks_connect(harness.socks_addr, destination.port()).await;
+        assert_eq!(reply, SOCKS_REPLY_NOT_ALLOWED);
+        harness.close().await;
+
+        // `--block-private` refusals are policy denials too
+        let outbound = Outbound {
+            host_filter: HostFilter::default().block_private(true),
+            ..Default::default()
+        };
+        let harness = TunnelHarness::start_with_outbound(outbound).await;
+        let reply = socks_connect(harness.socks_addr, destination.port()).await;
+        assert_eq!(reply, SOCKS_REPLY_NOT_ALLOWED);
+        assert_eq!(harness.server_stats.snapshot().requests, 1);
+        harness.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_custom_logger_records_events() {
+        let harness = TunnelHarness::start().await;
+        let logger = CapturingLogger::default();
+        harness.client_state.lock().await.logger = SharedLogger::new(Box::new(logger.clone()));
+        let destination = echo_server().await;
+
+        let mut stream = harness.socks_connect(destination).await;
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !logger.lines().iter().any(|line| line.starts_with("end ")) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("request end not reported");
+
+        // Nothing listens on a just-closed port, so the exit node reports an error
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        assert_eq!(socks_connect(harness.socks_addr, closed_port).await, 5);
+        harness
+            .client_state
+            .lock()
+            .await
+            .set_connection_state(ConnectionState::Connecting);
+
+        let lines = logger.events();
+        let start = format!("PROXY REQUEST 127.0.0.1:{}", destination.port());
+        assert!(lines[0].starts_with("start #") && lines[0].ends_with(&start));
+        assert!(lines[1].starts_with("end #") && lines[1].ends_with(" 4/4"));
+        assert!(lines[2].ends_with(&format!("127.0.0.1:{}", closed_port)));
+        assert!(lines[3].contains("Tunnel error"), "{:?}", lines);
+        assert!(lines[4].contains("SOCKS error"), "{:?}", lines);
+        assert_eq!(lines[5], "state connecting");
+        assert_eq!(lines.len(), 6, "{:?}", lines);
+
+        // The exit node reports its side of both requests too
+        let lines = harness.server_logger.events();
+        let outgoing = format!("OUTGOING 127.0.0.1:{}", destination.port());
+        assert!(lines[0].starts_with("start #") && lines[0].ends_with(&outgoing));
+        assert!(lines[1].starts_with("end #") && lines[1].ends_with(" 4/4"));
+        let outgoing = format!("OUTGOING 127.0.0.1:{}", closed_port);
+        assert!(lines[2].ends_with(&outgoing), "{:?}", lines);
+        assert!(lines[3].contains("Failed to connect"), "{:?}", lines);
+        assert_eq!(lines.len(), 4, "{:?}", lines);
+        assert!(
+            harness
+                .server_logger
+                .lines()
+                .iter()
+                .any(|line| line.contains("CONNECTED"))
+        );
+        harness.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_are_isolated() {
+        const REQUESTS: usize = 50;
+        let harness = TunnelHarness::start().await;
+        let destination = echo_server().await;
+
+        // A destination that accepts but never answers must not stall the rest
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = silent.accept().await {
+                held.push(socket);
+            }
+        });
+        let mut stalled = harness.socks_connect(silent_addr).await;
+        stalled.write_all(b"anyone there?").await.unwrap();
+
+        let mut streams = Vec::new();
+        for _ in 0..REQUESTS {
+            streams.push(harness.socks_connect(destination).await);
+        }
+        let mut tasks = tokio::task::JoinSet::new();
+        for (i, mut stream) in streams.into_iter().enumerate() {
+            tasks.spawn(async move {
+                let payload = format!("request {}", i).repeat(100);
+                stream.write_all(payload.as_bytes()).await.unwrap();
+                let mut echoed = vec![0u8; payload.len()];
+                stream.read_exact(&mut echoed).await.unwrap();
+                assert_eq!(echoed, payload.as_bytes());
+            });
+        }
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while let Some(result) = tasks.join_next().await {
+                result.unwrap();
+            }
+        })
+        .await
+        .expect("concurrent requests stalled");
+
+        let server_stats = harness.server_stats.snapshot();
+        assert_eq!(server_stats.requests, REQUESTS as u64 + 1);
+        assert_eq!(server_stats.peak_active_requests, REQUESTS as u64 + 1);
+        drop(stalled);
+        harness.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_open_streams_gauge_follows_requests() {
+        let harness = TunnelHarness::start().await;
+        let destination = echo_server().await;
+        let client_stats = Arc::clone(&harness.client_state.lock().await.stats);
+        let open_streams = |expected: u64| {
+            let server_stats = Arc::clone(&harness.server_stats);
+            let client_stats = Arc::clone(&client_stats);
+            async move {
+                while client_stats.snapshot().open_streams != expected
+                    || server_stats.snapshot().open_streams != expected
+                {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+
+        let first = harness.socks_connect(destination).await;
+        let second = harness.socks_connect(destination).await;
+        tokio::time::timeout(Duration::from_secs(5), open_streams(2))
+            .await
+            .expect("streams not counted as open");
+
+        drop((first, second));
+        tokio::time::timeout(Duration::from_secs(5), open_streams(0))
+            .await
+            .expect("finished streams still counted as open");
+        assert_eq!(harness.server_stats.snapshot().requests, 2);
+        harness.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_request_waits_for_reconnection() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = destination.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = destination.accept().await {
+                held.push(socket);
+            }
+        });
+
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        let state = Arc::new(Mutex::new(TunnelState {
+            connection_state: ConnectionState::Connecting,
+            reconnect_wait: Duration::from_secs(10),
+            ..Default::default()
+        }));
+        let queued = Arc::clone(&state.lock().await.queued_requests);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_socks(listener, Arc::clone(&state), client.clone()));
+
+        let request = tokio::spawn(socks_connect(addr, port));
+        while queued.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The reconnection completes while the request is parked
+        tokio::spawn(handle_peer_connection(
+            server_conn,
+            server.clone(),
+            Arc::new(ConnectionStats::new()),
+            Shutdown::new(),
+            Default::default(),
+            0,
+            SharedLogger::default(),
+        ));
+        state.lock().await.install_connection(client_conn);
+
+        let reply = tokio::time::timeout(Duration::from_secs(5), request)
+            .await
+            .expect("parked request was not woken")
+            .unwrap();
+        assert_eq!(reply, 0);
+        assert_eq!(queued.load(Ordering::Relaxed), 0);
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_routed_host_uses_routed_peer() {
+        let destination = echo_server().await;
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        tokio::spawn(handle_peer_connection(
+            server_conn,
+            server.clone(),
+            Arc::new(ConnectionStats::new()),
+            Shutdown::new(),
+            Default::default(),
+            0,
+            SharedLogger::default(),
+        ));
+
+        // No default peer at all: only the route can carry the request
+        let mut state = TunnelState {
+            routes: RouteTable::new(vec![Route {
+                pattern: "127.0.0.0/8".parse().unwrap(),
+                peer: server.id(),
+            }]),
+            ..Default::default()
+        };
+        state.routed_connections.insert(server.id(), client_conn);
+        let state = Arc::new(Mutex::new(state));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_socks(listener, state, client.clone()));
+
+        let reply = tokio::time::timeout(
+            Duration::from_secs(5),
+            socks_connect(addr, destination.port()),
+        )
+        .await
+        .expect("routed request did not complete");
+        assert_eq!(reply, 0);
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_share_one_routed_dial() {
+        use iroh::EndpointAddr;
+        use iroh::discovery::static_provider::StaticProvider;
+
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TUNNEL_ALPN.to_vec()])
+            .bind()
+            .await
+            .unwrap();
+        let mut server_addr = EndpointAddr::new(server.id());
+        for socket in server.bound_sockets() {
+            server_addr =
+                server_addr.with_ip_addr((std::net::Ipv4Addr::LOCALHOST, socket.port()).into());
+        }
+        let known = StaticProvider::new();
+        known.add_endpoint_info(server_addr);
+        let client = Endpoint::empty_builder(RelayMode::Disabled)
+            .discovery(known)
+            .bind()
+            .await
+            .unwrap();
+        let accepted = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&accepted);
+        let acceptor = server.clone();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Some(incoming) = acceptor.accept().await {
+                if let Ok(conn) = incoming.await {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    held.push(conn);
+                }
+            }
+        });
+
+        let state = Arc::new(Mutex::new(TunnelState {
+            routes: RouteTable::new(vec![Route {
+                pattern: "127.0.0.0/8".parse().unwrap(),
+                peer: server.id(),
+            }]),
+            ..Default::default()
+        }));
+        let requests: Vec<_> = (0..4)
+            .map(|_| {
+                let (state, client) = (Arc::clone(&state), client.clone());
+                tokio::spawn(async move { peer_for_host(&state, &client, "127.0.0.1").await })
+            })
+            .collect();
+        let mut ids = Vec::new();
+        for request in requests {
+            let (conn, _, _) = tokio::time::timeout(Duration::from_secs(10), request)
+                .await
+                .expect("routed dial did not complete")
+                .unwrap()
+                .unwrap();
+            ids.push(conn.stable_id());
+        }
+
+        // Every request got the connection of the single dial
+        ids.dedup();
+        assert_eq!(ids.len(), 1);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+        assert_eq!(state.lock().await.routed_connections.len(), 1);
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_wait_times_out_without_peer() {
+        let endpoint = Endpoint::empty_builder(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        // A peer connection is on its way but never arrives
+        let state = Arc::new(Mutex::new(TunnelState {
+            connection_state: ConnectionState::Connecting,
+            reconnect_wait: Duration::from_millis(200),
+            ..Default::default()
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_socks(listener, state, endpoint.clone()));
+
+        let reply = tokio::time::timeout(Duration::from_secs(2), socks_connect(addr, 80))
+            .await
+            .expect("request outlived the connect wait");
+        assert_eq!(reply, SOCKS_REPLY_TTL_EXPIRED);
+        endpoint.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_peer_lost_after_connect_replies_host_unreachable() {
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+        // An exit node that dies as soon as the connect request arrives
+        tokio::spawn(async move {
+            let (_send, mut recv) = server_conn.accept_bi().await.unwrap();
+            let mut buf = [0u8; 64];
+            let _ = recv.read(&mut buf).await;
+            server_conn.close(0u32.into(), b"gone");
+        });
+
+        let state = Arc::new(Mutex::new(TunnelState {
+            peer_connection: Some(client_conn),
+            ..Default::default()
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_socks(listener, state, client.clone()));
+
+        let reply = tokio::time::timeout(Duration::from_secs(2), socks_connect(addr, 80))
+            .await
+            .expect("request outlived the peer connection");
+        assert_eq!(reply, 4);
+
+        client.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_request_fails_without_expected_reconnection() {
+        let endpoint = Endpoint::empty_builder(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let state = Arc::new(Mutex::new(TunnelState {
+            reconnect_wait: Duration::from_secs(10),
+            ..Default::default()
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_socks(listener, state, endpoint.clone()));
+
+        let reply = tokio::time::timeout(Duration::from_secs(1), socks_connect(addr, 80))
+            .await
+            .expect("request without a peer should fail immediately");
+        assert_eq!(reply, 4);
+        endpoint.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_destination_is_canonical() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        let ip: Ipv6Addr = "2001:db8::ff00:42:8329".parse().unwrap();
+        let mut request = vec![SOCKS_VERSION, 1, 0];
+        request.extend_from_slice(&[SOCKS_VERSION, SOCKS_CMD_CONNECT, 0, SOCKS_ADDR_TYPE_IPV6]);
+        request.extend_from_slice(&ip.octets());
+        request.extend_from_slice(&443u16.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let (host, port) = socks_handshake(&mut socket).await.unwrap();
+        assert_eq!(host, "[2001:db8::ff00:42:8329]");
+        assert_eq!(port, 443);
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_request_gets_ipv6_reply() {
+        let endpoint = Endpoint::empty_builder(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let state = Arc::new(Mutex::new(TunnelState::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_socks(listener, state, endpoint.clone()));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut request = vec![SOCKS_VERSION, 1, 0];
+        request.extend_from_slice(&[SOCKS_VERSION, SOCKS_CMD_CONNECT, 0, SOCKS_ADDR_TYPE_IPV6]);
+        request.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        request.extend_from_slice(&80u16.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply.len(), 22);
+        assert_eq!(reply[1], 4);
+        assert_eq!(reply[3], SOCKS_ADDR_TYPE_IPV6);
+        endpoint.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_handle_socks_client_over_duplex() {
+        let harness = TunnelHarness::start().await;
+        let destination = echo_server().await;
+        let (mut client, socket) = tokio::io::duplex(64 * 1024);
+        let handler = harness.forwarder();
+        let served = tokio::spawn(async move {
+            handle_socks_client(socket, "127.0.0.1:0".parse().unwrap(), handler.as_ref()).await
+        });
+
+        client.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [SOCKS_VERSION, 0]);
+        let [hi, lo] = destination.port().to_be_bytes();
+        client
+            .write_all(&[SOCKS_VERSION, SOCKS_CMD_CONNECT, 0, 1, 127, 0, 0, 1, hi, lo])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+
+        client.write_all(b"in memory").await.unwrap();
+        let mut echoed = [0u8; 9];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"in memory");
+
+        // Closing the client ends the relay and the request
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), served)
+            .await
+            .expect("request did not finish")
+            .unwrap()
+            .unwrap();
+        assert_eq!(harness.server_stats.snapshot().requests, 1);
+        harness.close().await;
+    }
+
+    /// In-memory stand-in for a peer connection; the far end of each opened
+    /// stream goes to the test, which plays the exit node
+    struct MockTransport {
+        streams: tokio::sync::mpsc::UnboundedSender<tokio::io::DuplexStream>,
+    }
+
+    impl TunnelTransport for MockTransport {
+        type Send = tokio::io::WriteHalf<tokio::io::DuplexStream>;
+        type Recv = tokio::io::ReadHalf<tokio::io::DuplexStream>;
+
+        async fn open_bi(&self) -> Result<(Self::Send, Self::Recv)> {
+            let (local, remote) = tokio::io::duplex(64 * 1024);
+            self.streams
+                .send(remote)
+                .map_err(|_| anyhow::anyhow!("Exit node gone"))?;
+            let (recv, send) = tokio::io::split(local);
+            Ok((send, recv))
+        }
+    }
+
+    /// Tunnel a request for example.com:443 over a mock exit node answering
+    /// `response`, returning the outcome, the SOCKS reply and the access status
+    async fn request_over_mock(
+        response: TunnelMessage,
+    ) -> (Result<(u64, u64, ProtocolKind, Option<String>)>, u8, u16) {
+        let (streams, mut opened) = tokio::sync::mpsc::unbounded_channel();
+        let transport = MockTransport { streams };
+        let exit = tokio::spawn(async move {
+            let mut stream = opened.recv().await.unwrap();
+            // Answered without a Hello, so the client sends no close reasons
+            let hello = recv_message(&mut stream).await.unwrap();
+            assert!(matches!(hello, TunnelMessage::Hello { .. }), "{:?}", hello);
+            let request = recv_message(&mut stream).await.unwrap();
+            assert!(matches!(
+                request,
+                TunnelMessage::Connect { ref host, port: 443 } if host == "example.com"
+            ));
+            send_message(&mut stream, &response).await.unwrap();
+            // Whatever the client relays, up to its Close
+            let mut relayed = Vec::new();
+            while let Ok(msg) = recv_message(&mut stream).await {
+                let done = matches!(msg, TunnelMessage::Close);
+                relayed.push(msg);
+                if done {
+                    break;
+                }
+            }
+            relayed
+        });
+
+        let options = RelayOptions::default();
+        let tunnel = establish_tunnel(&transport, &options, None, "example.com", 443)
+            .await
+            .unwrap();
+        let (mut client, socket) = tokio::io::duplex(1024);
+        let mut record = AccessRecord::start(
+            None,
+            "127.0.0.1:1".parse().unwrap(),
+            "CONNECT",
+            "example.com",
+            443,
+        );
+        let logger = SharedLogger::default();
+        let request = TunnelRequest {
+            id: RequestId::next(),
+            host: "example.com",
+            port: 443,
+            protocol: LocalProtocol::Socks5,
+            logger: &logger,
+            record: &mut record,
+        };
+        let stats = ConnectionStats::new();
+        let shutdown = Shutdown::new();
+        let completed = complete_tunnel(socket, tunnel, request, &stats, &shutdown);
+        let local_client = async {
+            let mut reply = [0u8; 10];
+            client.read_exact(&mut reply).await.unwrap();
+            if reply[1] == 0 {
+                client.write_all(b"hello").await.unwrap();
+            }
+            drop(client);
+            reply[1]
+        };
+        let (outcome, reply) = tokio::join!(completed, local_client);
+
+        let relayed = exit.await.unwrap();
+        if reply == 0 {
+            assert!(
+                matches!(
+                    relayed.as_slice(),
+                    [TunnelMessage::Data { data }, TunnelMessage::Close] if data == b"hello"
+                ),
+                "{:?}",
+                relayed
+            );
+        } else {
+            assert!(relayed.is_empty(), "{:?}", relayed);
+        }
+        (outcome, reply, record.status)
+    }
+
+    #[tokio::test]
+    async fn test_mock_transport_connected() {
+        let (outcome, reply, status) = request_over_mock(TunnelMessage::Connected).await;
+        assert_eq!(reply, 0);
+        assert_eq!(status, STATUS_OK);
+        let (sent, received, protocol, _) = outcome.unwrap();
+        assert_eq!((sent, received), (5, 0));
+        assert_eq!(protocol, ProtocolKind::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_mock_transport_error() {
+        let denied = TunnelMessage::Error {
+            message: format!("{}: example.com", NOT_ALLOWED_MESSAGE),
+        };
+        let (outcome, reply, status) = request_over_mock(denied).await;
+        assert_eq!(reply, SOCKS_REPLY_NOT_ALLOWED);
+        assert_eq!(status, STATUS_NOT_ALLOWED);
+        assert!(outcome.is_err());
+
+        let refused = TunnelMessage::Error {
+            message: "Connection failed: connection refused".to_string(),
+        };
+        let (outcome, reply, _) = request_over_mock(refused).await;
+        assert_eq!(reply, 5);
+        assert!(
+            outcome
+                .unwrap_err()
+                .to_string()
+                .contains("connection refused"),
+            "unexpected error"
+        );
+    }
+
+    /// Feed `request` to `handle_socks_client` as a client on the SOCKS port
+    async fn serve_foreign_client(request: &[u8]) -> (Result<()>, Vec<u8>) {
+        let (mut client, socket) = tokio::io::duplex(1024);
+        client.write_all(request).await.unwrap();
+        let handler = |_: SocksRequest| async { panic!("not a SOCKS request") };
+        let result = handle_socks_client(socket, "127.0.0.1:0".parse().unwrap(), &handler).await;
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        (result, reply)
+    }
+
+    #[tokio::test]
+    async fn test_http_request_on_socks_port_gets_hint() {
+        let (result, reply) =
+            serve_foreign_client(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await;
+        let reply = String::from_utf8(reply).unwrap();
+        assert!(
+            reply.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            reply
+        );
+        assert!(reply.ends_with(NOT_SOCKS_BODY), "{}", reply);
+        assert!(reply.contains(&format!("Content-Length: {}\r\n", NOT_SOCKS_BODY.len())));
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("HTTP request on the SOCKS5 port")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_protocol_on_socks_port_is_logged_as_hex() {
+        let (result, reply) = serve_foreign_client(b"SSH-2.0-OpenSSH_9.6\r\n").await;
+        assert!(reply.is_empty());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Not a SOCKS5 client (version byte 83), first bytes: \
+             53 53 48 2d 32 2e 30 2d 4f 70 65 6e 53 53 48 5f"
+        );
+
+        // SOCKS4 is turned away the same way
+        let (result, _) = serve_foreign_client(&[4, 1, 0, 80, 127, 0, 0, 1, 0]).await;
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .starts_with("Not a SOCKS5 client (version byte 4), first bytes: 04 01 00 50")
+        );
+    }
+
+    /// Run the handshake for a CONNECT to `domain`, returning the result and the reply
+    async fn handshake_with_domain(domain: &[u8]) -> (Result<(String, u16)>, Vec<u8>) {
+        let (mut client, mut socket) = tokio::io::duplex(1024);
+
+        let mut request = vec![SOCKS_VERSION, 1, 0];
+        request.extend_from_slice(&[SOCKS_VERSION, SOCKS_CMD_CONNECT, 0, SOCKS_ADDR_TYPE_DOMAIN]);
+        request.push(domain.len() as u8);
+        request.extend_from_slice(domain);
+        request.extend_from_slice(&80u16.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let result = socks_handshake(&mut socket).await;
+        drop(socket);
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        (result, reply.split_off(2))
+    }
+
+    #[tokio::test]
+    async fn test_empty_domain_gets_general_failure() {
+        let (result, reply) = handshake_with_domain(b"").await;
+        assert!(result.unwrap_err().to_string().contains("Empty domain"));
+        assert_eq!(reply, socks_reply(1, SOCKS_ADDR_TYPE_DOMAIN));
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_domain_gets_general_failure() {
+        let (result, reply) = handshake_with_domain(b"bad\xff.example").await;
+        assert!(result.unwrap_err().to_string().contains("not valid UTF-8"));
+        assert_eq!(reply, socks_reply(1, SOCKS_ADDR_TYPE_DOMAIN));
+
+        let (result, reply) = handshake_with_domain(b"example.com").await;
+        assert_eq!(result.unwrap(), ("example.com".to_string(), 80));
+        assert!(reply.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_handshake_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        // Greeting arrives, then the client stalls mid-request
+        client.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+        client.write_all(&[SOCKS_VERSION]).await.unwrap();
+
+        let started = std::time::Instant::now();
+        let err = negotiate(&mut socket, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_rejects_extra_client() {
+        let endpoint = Endpoint::empty_builder(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let state = Arc::new(Mutex::new(TunnelState {
+            max_socks_clients: 1,
+            ..Default::default()
+        }));
+        let active = Arc::clone(&state.lock().await.active_socks_clients);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_socks(listener, state, endpoint.clone()));
+
+        // The first client holds its slot by never finishing the handshake
+        let _first = TcpStream::connect(addr).await.unwrap();
+        while active.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(socks_connect(addr, 80).await, 1, "expected general failure");
+        assert_eq!(active.load(Ordering::Relaxed), 1);
+
+        endpoint.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_refusals_over_the_limit_are_capped() {
+        let endpoint = Endpoint::empty_builder(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let state = Arc::new(Mutex::new(TunnelState {
+            max_socks_clients: 1,
+            ..Default::default()
+        }));
+        let active = Arc::clone(&state.lock().await.active_socks_clients);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_socks(listener, state, endpoint.clone()));
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        while active.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Silent clients over the limit each hold a refusal until it times out
+        let mut waiting = Vec::new();
+        for _ in 0..MAX_PENDING_REFUSALS {
+            waiting.push(TcpStream::connect(addr).await.unwrap());
+        }
+        let started = Instant::now();
+        let mut closed = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(REFUSAL_TIMEOUT, closed.read(&mut buf))
+            .await
+            .expect("client past the refusal cap was not closed");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert!(started.elapsed() < REFUSAL_TIMEOUT);
+
+        // The waiting ones give up after the short refusal deadline
+        let read = tokio::time::timeout(REFUSAL_TIMEOUT * 2, waiting[0].read(&mut buf))
+            .await
+            .expect("refused client held past the refusal deadline");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        endpoint.close().await;
+    }
 }
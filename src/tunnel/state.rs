@@ -1,20 +1,484 @@
-use iroh::endpoint::Connection;
+use iroh::endpoint::{Connection, ConnectionType};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, Semaphore};
 
-pub const TUNNEL_ALPN: &[u8] = b"iroh-tunnel/1";
+use crate::tunnel::access_log::AccessLog;
+use crate::tunnel::auth::AuthToken;
+use crate::tunnel::events::{EventBus, TunnelEvent};
+use crate::tunnel::logger::SharedLogger;
+use crate::tunnel::outbound::Outbound;
+use crate::tunnel::relay::RelayOptions;
+use crate::tunnel::routing::RouteTable;
+use crate::tunnel::shutdown::{InFlight, Shutdown};
+use crate::tunnel::stats::{ConnectionStats, StatsSnapshot};
+use crate::tunnel::tcp::TcpOptions;
 
-#[derive(Clone, Debug, PartialEq)]
+/// Tunnel protocol version; bumped on incompatible wire changes
+///
+/// Version 2 switched message encoding from bincode to postcard, version 3
+/// added the `Hello` feature exchange. Peers on different versions fail the
+/// QUIC handshake instead of misparsing frames.
+pub const TUNNEL_PROTOCOL: &str = "iroh-tunnel/3";
+
+/// ALPN naming the tunnel protocol version
+pub const TUNNEL_ALPN: &[u8] = TUNNEL_PROTOCOL.as_bytes();
+
+/// Minimum time between the start of two connection attempts
+pub const MIN_RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How recent a network change must be for a reconnection to skip the backoff
+///
+/// A connection broken by e.g. a Wi-Fi to cellular switch is only noticed once
+/// it idles out or misses heartbeats, well within this with the defaults.
+pub const FAST_RETRY_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum ConnectionState {
+    #[default]
     Disconnected,
     Connecting,
     Connected,
+    /// Reconnection gave up after `max_reconnect_attempts`; terminal until reset
     Failed,
 }
 
+impl ConnectionState {
+    /// Lowercase name used in metrics and the status document
+    pub fn label(&self) -> &'static str {
+        match self {
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct TunnelState {
     pub peer_connection: Option<Connection>,
     pub connection_state: ConnectionState,
     pub remote_peer_id: Option<iroh::PublicKey>,
+    pub candidate_peers: Vec<iroh::PublicKey>,
     pub reconnect_attempts: u32,
-    pub last_connection_attempt: Option<std::time::Instant>,
+    /// Give up after this many failed reconnection attempts (0 = retry forever)
+    pub max_reconnect_attempts: u32,
+    pub last_connection_attempt: Option<Instant>,
+    /// Drop a connection whose peer hasn't answered a Ping for this long (zero = never)
+    pub heartbeat_timeout: Duration,
+    /// Last Pong from the current peer connection (or when it was installed)
+    pub last_heartbeat: Option<Instant>,
+    /// Bumped every time a new peer connection is installed
+    pub connection_epoch: u64,
+    /// Set while attempt_reconnection is running
+    pub reconnect_in_progress: bool,
+    /// Last change of this node's addresses not yet answered by a fast reconnect
+    pub network_changed: Option<Instant>,
+    /// Traffic counters for the current peer connection
+    pub stats: Arc<ConnectionStats>,
+    /// Current iroh path to the peer (Direct/Relay/Mixed)
+    pub conn_type: ConnectionType,
+    /// Peer connections established since start
+    pub total_connections: u64,
+    /// Successful reconnections since start
+    pub total_reconnects: u64,
+    /// Concurrent tunnel requests relayed per peer connection (0 = unlimited)
+    pub max_streams_per_peer: usize,
+    /// Woken whenever a new peer connection is installed
+    pub peer_connected: Arc<Notify>,
+    /// How long a SOCKS request waits for a peer connection before failing
+    pub reconnect_wait: Duration,
+    /// SOCKS requests allowed to wait for a reconnection at once (0 = unlimited)
+    pub max_queued_requests: usize,
+    /// SOCKS requests currently waiting for a reconnection
+    pub queued_requests: Arc<AtomicU64>,
+    /// Concurrent clients served by the local listeners (0 = unlimited)
+    pub max_socks_clients: usize,
+    /// Local clients (SOCKS, transparent, forwards) currently being served
+    pub active_socks_clients: Arc<AtomicU64>,
+    /// Slots shared by every local listener, see `client_slots`
+    pub client_slots: Option<Arc<Semaphore>>,
+    /// Socket options for accepted SOCKS / transparent clients
+    pub tcp_options: TcpOptions,
+    /// Relay behaviour for streams opened by local clients
+    pub relay_options: RelayOptions,
+    /// Sent to peers ahead of every Connect (`--auth-token`)
+    pub auth_token: Option<AuthToken>,
+    /// Destination rules and upstream proxy for incoming tunnel requests
+    pub outbound: Arc<Outbound>,
+    /// Serve one peer at a time, refusing others while it is connected (`--exclusive`)
+    pub exclusive: bool,
+    /// The only peer accepted under `--sticky-peer`, once the first has connected
+    pub pinned_peer: Option<iroh::PublicKey>,
+    /// Destinations sent through peers other than the default one
+    pub routes: RouteTable,
+    /// Open connections to routed peers, dialled on first use
+    pub routed_connections: HashMap<iroh::PublicKey, Connection>,
+    /// Held while a routed peer is dialled, so concurrent requests for it
+    /// wait for that dial instead of starting their own
+    pub routed_dials: HashMap<iroh::PublicKey, Arc<tokio::sync::Mutex<()>>>,
     pub _log_file: Option<String>,
+    /// Audit trail of local requests (`--access-log`)
+    pub access_log: Option<AccessLog>,
+    /// Triggered on Ctrl-C; the listeners stop accepting while relays finish
+    pub draining: Shutdown,
+    /// Local clients a drain waits for
+    pub in_flight: InFlight,
+    /// How long a drain waits before in-flight clients are closed (`--drain-timeout`)
+    pub drain_timeout: Duration,
+    /// Triggered once draining is over; accept loops and relays stop when it fires
+    pub shutdown: Shutdown,
+    /// Lifecycle events for subscribers such as UIs
+    pub events: EventBus,
+    /// Where request and connection events are reported
+    pub logger: SharedLogger,
+}
+
+impl TunnelState {
+    /// The `max_socks_clients` slots all local listeners draw from, `None` if
+    /// unlimited
+    pub fn client_slots(&mut self) -> Option<Arc<Semaphore>> {
+        let max = self.max_socks_clients;
+        if max == 0 {
+            return None;
+        }
+        let slots = self
+            .client_slots
+            .get_or_insert_with(|| Arc::new(Semaphore::new(max)));
+        Some(Arc::clone(slots))
+    }
+
+    /// Make `conn` the active peer connection
+    ///
+    /// Returns the new connection epoch; the connection's handler passes it back
+    /// to `release_connection` so a stale handler can't clear a newer connection.
+    pub fn install_connection(&mut self, conn: Connection) -> u64 {
+        self.peer_connection = Some(conn);
+        self.set_connection_state(ConnectionState::Connected);
+        self.stats = Arc::new(ConnectionStats::new());
+        self.conn_type = ConnectionType::None;
+        self.last_heartbeat = Some(Instant::now());
+        self.total_connections += 1;
+        let epoch = self.bump_epoch();
+        self.peer_connected.notify_waiters();
+        epoch
+    }
+
+    /// The peer whose open connection keeps `peer` out under `--exclusive`
+    ///
+    /// The active peer itself may connect again, e.g. after a network change
+    /// left its old connection to time out.
+    pub fn exclusive_holder(&self, peer: &iroh::PublicKey) -> Option<iroh::PublicKey> {
+        if !self.exclusive {
+            return None;
+        }
+        let conn = self.peer_connection.as_ref()?;
+        let holder = conn.remote_id();
+        (conn.close_reason().is_none() && holder != *peer).then_some(holder)
+    }
+
+    /// Whether a missing peer connection is expected to come back
+    ///
+    /// True while connecting, or while disconnected from a known peer that the
+    /// health monitor will retry; false once reconnection has given up.
+    pub fn reconnect_expected(&self) -> bool {
+        match self.connection_state {
+            ConnectionState::Connecting => true,
+            ConnectionState::Disconnected => self.remote_peer_id.is_some(),
+            ConnectionState::Connected | ConnectionState::Failed => false,
+        }
+    }
+
+    /// Snapshot of the current connection's traffic counters
+    pub fn stats_snapshot(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Clear the active connection if it is still the one from `epoch`
+    pub fn release_connection(&mut self, epoch: u64) -> bool {
+        if epoch != self.connection_epoch {
+            return false;
+        }
+        self.peer_connection = None;
+        self.set_connection_state(ConnectionState::Disconnected);
+        true
+    }
+
+    /// Change the connection state, publishing an event if it differs
+    pub fn set_connection_state(&mut self, new: ConnectionState) {
+        if self.connection_state == new {
+            return;
+        }
+        let event = match &new {
+            ConnectionState::Connecting => Some(TunnelEvent::Connecting),
+            ConnectionState::Connected => {
+                self.peer_connection
+                    .as_ref()
+                    .map(|conn| TunnelEvent::Connected {
+                        peer: conn.remote_id(),
+                    })
+            }
+            ConnectionState::Disconnected => Some(TunnelEvent::Disconnected),
+            ConnectionState::Failed => Some(TunnelEvent::Failed),
+        };
+        let peer = match (&new, &self.peer_connection) {
+            (ConnectionState::Connected, Some(conn)) => Some(conn.remote_id()),
+            _ => None,
+        };
+        self.logger.connection_state(&new, peer);
+        self.connection_state = new;
+        if let Some(event) = event {
+            self.events.publish(event);
+        }
+    }
+
+    /// Record the path type for the connection from `epoch`
+    ///
+    /// Returns false if that connection has since been replaced.
+    pub fn set_conn_type(&mut self, epoch: u64, conn_type: ConnectionType) -> bool {
+        if epoch != self.connection_epoch {
+            return false;
+        }
+        self.conn_type = conn_type;
+        true
+    }
+
+    /// Record a Pong received on the connection from `epoch`
+    pub fn record_heartbeat(&mut self, epoch: u64, now: Instant) -> bool {
+        if epoch != self.connection_epoch {
+            return false;
+        }
+        self.last_heartbeat = Some(now);
+        true
+    }
+
+    /// Whether the peer has been silent for longer than `heartbeat_timeout`
+    pub fn heartbeat_stale(&self, now: Instant) -> bool {
+        !self.heartbeat_timeout.is_zero()
+            && self
+                .last_heartbeat
+                .is_some_and(|last| now.saturating_duration_since(last) > self.heartbeat_timeout)
+    }
+
+    fn bump_epoch(&mut self) -> u64 {
+        self.connection_epoch += 1;
+        self.connection_epoch
+    }
+
+    /// Claim the right to run a reconnection attempt
+    ///
+    /// Fails if another attempt is already running, a connection is already up,
+    /// or the previous attempt was too recent. Pair with `finish_reconnect`.
+    pub fn begin_reconnect(&mut self, now: Instant) -> bool {
+        if self.reconnect_in_progress || self.peer_connection.is_some() {
+            return false;
+        }
+        if !self.try_begin_attempt(now) {
+            return false;
+        }
+        self.reconnect_in_progress = true;
+        true
+    }
+
+    /// Release the claim taken by `begin_reconnect`
+    pub fn finish_reconnect(&mut self) {
+        self.reconnect_in_progress = false;
+    }
+
+    /// Record a failed reconnection attempt
+    ///
+    /// Returns true once the attempt cap is reached and the state has become
+    /// terminal `Failed`; the health monitor stops retrying from then on.
+    pub fn record_failed_attempt(&mut self) -> bool {
+        self.reconnect_attempts += 1;
+
+        if self.max_reconnect_attempts != 0
+            && self.reconnect_attempts >= self.max_reconnect_attempts
+        {
+            self.set_connection_state(ConnectionState::Failed);
+            true
+        } else {
+            self.set_connection_state(ConnectionState::Disconnected);
+            false
+        }
+    }
+
+    /// Whether enough time has passed since the last attempt to start a new one
+    pub fn reconnect_allowed(&self, now: Instant) -> bool {
+        self.last_connection_attempt
+            .is_none_or(|last| now.saturating_duration_since(last) >= MIN_RECONNECT_INTERVAL)
+    }
+
+    /// Record the start of a connection attempt
+    ///
+    /// Returns false (and records nothing) if the previous attempt started less
+    /// than `MIN_RECONNECT_INTERVAL` ago, so a flapping connection can't spin.
+    pub fn try_begin_attempt(&mut self, now: Instant) -> bool {
+        if !self.reconnect_allowed(now) {
+            return false;
+        }
+        self.last_connection_attempt = Some(now);
+        true
+    }
+
+    /// Note that this node's network addresses changed at `now`
+    pub fn record_network_change(&mut self, now: Instant) {
+        self.network_changed = Some(now);
+    }
+
+    /// Whether the next reconnection attempt should skip the backoff
+    ///
+    /// True once per network change within `FAST_RETRY_WINDOW`; later attempts
+    /// back off as usual.
+    pub fn take_fast_retry(&mut self, now: Instant) -> bool {
+        self.network_changed
+            .take()
+            .is_some_and(|at| now.saturating_duration_since(at) <= FAST_RETRY_WINDOW)
+    }
+
+    /// Clear the attempt counter and leave a terminal `Failed` state so retries resume
+    pub fn reset_reconnect(&mut self) {
+        self.reconnect_attempts = 0;
+        if self.connection_state == ConnectionState::Failed {
+            self.set_connection_state(ConnectionState::Disconnected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_failed_attempt_gives_up_at_cap() {
+        let mut state = TunnelState {
+            max_reconnect_attempts: 3,
+            ..Default::default()
+        };
+
+        assert!(!state.record_failed_attempt());
+        assert!(!state.record_failed_attempt());
+        assert_eq!(state.connection_state, ConnectionState::Disconnected);
+
+        assert!(state.record_failed_attempt());
+        assert_eq!(state.connection_state, ConnectionState::Failed);
+        assert_eq!(state.reconnect_attempts, 3);
+    }
+
+    #[test]
+    fn test_record_failed_attempt_unlimited() {
+        let mut state = TunnelState::default();
+        for _ in 0..100 {
+            assert!(!state.record_failed_attempt());
+        }
+        assert_eq!(state.connection_state, ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn test_reconnect_expected() {
+        let mut state = TunnelState::default();
+        assert!(!state.reconnect_expected(), "no peer to reconnect to");
+
+        state.remote_peer_id = Some(iroh::SecretKey::generate(&mut rand::rng()).public());
+        assert!(state.reconnect_expected());
+
+        state.connection_state = ConnectionState::Failed;
+        assert!(!state.reconnect_expected());
+
+        state.connection_state = ConnectionState::Connecting;
+        assert!(state.reconnect_expected());
+    }
+
+    #[test]
+    fn test_try_begin_attempt_rate_limited() {
+        let mut state = TunnelState::default();
+        let now = Instant::now();
+
+        assert!(state.try_begin_attempt(now));
+        assert!(!state.try_begin_attempt(now + Duration::from_millis(100)));
+        assert_eq!(state.last_connection_attempt, Some(now));
+
+        assert!(state.try_begin_attempt(now + MIN_RECONNECT_INTERVAL));
+    }
+
+    #[test]
+    fn test_begin_reconnect_rejects_overlapping_triggers() {
+        let mut state = TunnelState::default();
+        let now = Instant::now();
+
+        assert!(state.begin_reconnect(now));
+        // Second trigger while the first attempt is still running
+        assert!(!state.begin_reconnect(now + MIN_RECONNECT_INTERVAL));
+
+        state.finish_reconnect();
+        assert!(state.begin_reconnect(now + MIN_RECONNECT_INTERVAL));
+    }
+
+    #[test]
+    fn test_release_connection_ignores_stale_epoch() {
+        let mut state = TunnelState::default();
+        let old_epoch = state.bump_epoch();
+        let new_epoch = state.bump_epoch();
+        state.connection_state = ConnectionState::Connected;
+
+        // The old handler winding down must not clear the new connection
+        assert!(!state.release_connection(old_epoch));
+        assert_eq!(state.connection_state, ConnectionState::Connected);
+
+        assert!(state.release_connection(new_epoch));
+        assert_eq!(state.connection_state, ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn test_reset_reconnect_leaves_failed_state() {
+        let mut state = TunnelState {
+            max_reconnect_attempts: 1,
+            ..Default::default()
+        };
+        assert!(state.record_failed_attempt());
+
+        state.reset_reconnect();
+        assert_eq!(state.reconnect_attempts, 0);
+        assert_eq!(state.connection_state, ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn test_fast_retry_once_per_recent_network_change() {
+        let mut state = TunnelState::default();
+        let now = Instant::now();
+        assert!(!state.take_fast_retry(now));
+
+        state.record_network_change(now);
+        assert!(state.take_fast_retry(now + FAST_RETRY_WINDOW));
+        assert!(!state.take_fast_retry(now + FAST_RETRY_WINDOW), "only once");
+
+        // A change long before the connection was lost says nothing about it
+        state.record_network_change(now);
+        assert!(!state.take_fast_retry(now + FAST_RETRY_WINDOW + Duration::from_secs(1)));
+        assert_eq!(state.network_changed, None);
+    }
+
+    #[test]
+    fn test_heartbeat_stale_after_timeout() {
+        let now = Instant::now();
+        let mut state = TunnelState {
+            heartbeat_timeout: Duration::from_secs(30),
+            last_heartbeat: Some(now),
+            ..Default::default()
+        };
+        let epoch = state.bump_epoch();
+        assert!(!state.heartbeat_stale(now + Duration::from_secs(30)));
+        assert!(state.heartbeat_stale(now + Duration::from_secs(31)));
+
+        // A Pong from a replaced connection doesn't count
+        assert!(!state.record_heartbeat(epoch - 1, now + Duration::from_secs(31)));
+        assert!(state.record_heartbeat(epoch, now + Duration::from_secs(31)));
+        assert!(!state.heartbeat_stale(now + Duration::from_secs(31)));
+
+        state.heartbeat_timeout = Duration::ZERO;
+        assert!(!state.heartbeat_stale(now + Duration::from_secs(3600)));
+    }
 }
@@ -0,0 +1,308 @@
+// Per-connection traffic statistics
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::tunnel::quota::PeerUsage;
+
+/// Traffic counters for one peer connection
+///
+/// Shared between the relay tasks of a connection via `Arc`; all updates are
+/// lock-free atomics so the relay hot path stays cheap.
+#[derive(Debug)]
+pub struct ConnectionStats {
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub established_at: Instant,
+    /// Milliseconds after `established_at` of the last relayed data
+    pub last_activity: AtomicU64,
+    pub requests: AtomicU64,
+    /// Tunnel requests currently being relayed
+    pub active_requests: AtomicU64,
+    /// Most tunnel requests relayed at once on this connection
+    pub peak_active_requests: AtomicU64,
+    /// Tunnel streams currently open on this connection, whatever they carry
+    pub open_streams: AtomicU64,
+    /// Tunnel requests refused because they would loop back into our own proxy
+    pub loops_rejected: AtomicU64,
+    /// Smoothed round-trip time to the peer in microseconds (0 = not measured yet)
+    pub rtt_micros: AtomicU64,
+    /// Totals the peer reported in `Stats` messages, from its point of view
+    pub peer_bytes_sent: AtomicU64,
+    pub peer_bytes_received: AtomicU64,
+    /// Quota usage of the peer this connection belongs to (`--peer-quota`)
+    usage: OnceLock<Arc<PeerUsage>>,
+}
+
+/// Marks a tunnel request as active until dropped
+pub struct ActiveRequest<'a> {
+    stats: &'a ConnectionStats,
+}
+
+impl Drop for ActiveRequest<'_> {
+    fn drop(&mut self) {
+        self.stats.active_requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a tunnel stream as open until dropped
+pub struct OpenStream<'a> {
+    stats: &'a ConnectionStats,
+}
+
+impl Drop for OpenStream<'_> {
+    fn drop(&mut self) {
+        self.stats.open_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Point-in-time copy of `ConnectionStats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsSnapshot {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub established_at: Instant,
+    pub last_activity: Instant,
+    pub requests: u64,
+    pub active_requests: u64,
+    pub peak_active_requests: u64,
+    pub open_streams: u64,
+    pub loops_rejected: u64,
+    pub rtt: Option<Duration>,
+    pub peer_bytes_sent: u64,
+    pub peer_bytes_received: u64,
+}
+
+/// Weight of a new sample in the smoothed RTT, as in TCP's SRTT (1/8)
+const RTT_SMOOTHING: u32 = 8;
+
+/// Exponentially weighted moving average of RTT samples
+///
+/// The first sample is taken as-is; later ones move the average by 1/8 of
+/// the difference, so single outliers don't make the reading jump around.
+pub fn ewma_rtt(previous: Option<Duration>, sample: Duration) -> Duration {
+    match previous {
+        None => sample,
+        Some(previous) => (previous * (RTT_SMOOTHING - 1) + sample) / RTT_SMOOTHING,
+    }
+}
+
+impl StatsSnapshot {
+    /// How long the connection has been up
+    pub fn uptime(&self) -> Duration {
+        self.established_at.elapsed()
+    }
+}
+
+impl ConnectionStats {
+    pub fn new() -> Self {
+        Self {
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            established_at: Instant::now(),
+            last_activity: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            active_requests: AtomicU64::new(0),
+            peak_active_requests: AtomicU64::new(0),
+            open_streams: AtomicU64::new(0),
+            loops_rejected: AtomicU64::new(0),
+            rtt_micros: AtomicU64::new(0),
+            peer_bytes_sent: AtomicU64::new(0),
+            peer_bytes_received: AtomicU64::new(0),
+            usage: OnceLock::new(),
+        }
+    }
+
+    /// Also count this connection's traffic towards `usage`
+    pub fn count_towards(&self, usage: Arc<PeerUsage>) {
+        let _ = self.usage.set(usage);
+    }
+
+    /// Count bytes sent from the local socket into the tunnel
+    pub fn record_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        self.count_usage(bytes);
+        self.touch();
+    }
+
+    /// Count bytes received from the tunnel and written to the local socket
+    pub fn record_received(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+        self.count_usage(bytes);
+        self.touch();
+    }
+
+    /// Add a stream's totals as reported by the peer in a `Stats` message
+    pub fn record_peer_report(&self, bytes_sent: u64, bytes_received: u64) {
+        self.peer_bytes_sent
+            .fetch_add(bytes_sent, Ordering::Relaxed);
+        self.peer_bytes_received
+            .fetch_add(bytes_received, Ordering::Relaxed);
+    }
+
+    /// Count a tunnel request (one SOCKS CONNECT or one incoming Connect)
+    ///
+    /// The request stays in `active_requests` until the returned guard is dropped.
+    pub fn start_request(&self) -> ActiveRequest<'_> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let active = self.active_requests.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_active_requests
+            .fetch_max(active, Ordering::Relaxed);
+        self.touch();
+        ActiveRequest { stats: self }
+    }
+
+    /// Count a tunnel stream opened or accepted on this connection
+    ///
+    /// Unlike `start_request` this covers every stream (pings, lookups and
+    /// rejected requests too) and is a gauge only; it stays in `open_streams`
+    /// until the returned guard is dropped.
+    pub fn open_stream(&self) -> OpenStream<'_> {
+        self.open_streams.fetch_add(1, Ordering::Relaxed);
+        OpenStream { stats: self }
+    }
+
+    /// Count a tunnel request refused by loop prevention
+    pub fn record_loop_rejected(&self) {
+        self.loops_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Fold a round-trip time measurement into the smoothed RTT
+    pub fn record_rtt(&self, sample: Duration) {
+        let smoothed = ewma_rtt(self.rtt(), sample);
+        // Keep 0 reserved for "not measured yet"
+        let micros = (smoothed.as_micros() as u64).max(1);
+        self.rtt_micros.store(micros, Ordering::Relaxed);
+    }
+
+    /// Smoothed round-trip time to the peer, once measured
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    fn count_usage(&self, bytes: u64) {
+        if let Some(usage) = self.usage.get() {
+            usage.record(bytes);
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.established_at.elapsed().as_millis() as u64;
+        self.last_activity.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            established_at: self.established_at,
+            last_activity: self.established_at
+                + Duration::from_millis(self.last_activity.load(Ordering::Relaxed)),
+            requests: self.requests.load(Ordering::Relaxed),
+            active_requests: self.active_requests.load(Ordering::Relaxed),
+            peak_active_requests: self.peak_active_requests.load(Ordering::Relaxed),
+            open_streams: self.open_streams.load(Ordering::Relaxed),
+            loops_rejected: self.loops_rejected.load(Ordering::Relaxed),
+            rtt: self.rtt(),
+            peer_bytes_sent: self.peer_bytes_sent.load(Ordering::Relaxed),
+            peer_bytes_received: self.peer_bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_count_relayed_payload() {
+        let stats = ConnectionStats::new();
+        let payload = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+        let request = stats.start_request();
+        stats.record_sent(payload.len() as u64);
+        stats.record_received(1024);
+        stats.record_received(512);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests, 1);
+        assert_eq!(snapshot.active_requests, 1);
+        assert_eq!(snapshot.bytes_sent, payload.len() as u64);
+        assert_eq!(snapshot.bytes_received, 1536);
+        assert!(snapshot.last_activity >= snapshot.established_at);
+
+        drop(request);
+        assert_eq!(stats.snapshot().active_requests, 0);
+        assert_eq!(stats.snapshot().peak_active_requests, 1);
+    }
+
+    #[test]
+    fn test_peak_active_requests_is_high_water_mark() {
+        let stats = ConnectionStats::new();
+        let first = stats.start_request();
+        let second = stats.start_request();
+        drop(first);
+        let third = stats.start_request();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.active_requests, 2);
+        assert_eq!(snapshot.peak_active_requests, 2);
+        drop((second, third));
+        assert_eq!(stats.snapshot().peak_active_requests, 2);
+    }
+
+    #[test]
+    fn test_open_streams_gauge() {
+        let stats = ConnectionStats::new();
+        let ping = stats.open_stream();
+        let connect = stats.open_stream();
+        let _request = stats.start_request();
+        assert_eq!(stats.snapshot().open_streams, 2);
+
+        drop(ping);
+        assert_eq!(stats.snapshot().open_streams, 1);
+        drop(connect);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.open_streams, 0);
+        assert_eq!(snapshot.requests, 1);
+    }
+
+    #[test]
+    fn test_stats_start_empty() {
+        let snapshot = ConnectionStats::new().snapshot();
+        assert_eq!(snapshot.bytes_sent, 0);
+        assert_eq!(snapshot.bytes_received, 0);
+        assert_eq!(snapshot.requests, 0);
+        assert_eq!(snapshot.last_activity, snapshot.established_at);
+        assert_eq!(snapshot.rtt, None);
+    }
+
+    #[test]
+    fn test_ewma_rtt_update() {
+        let ms = Duration::from_millis;
+        assert_eq!(ewma_rtt(None, ms(40)), ms(40));
+        assert_eq!(ewma_rtt(Some(ms(40)), ms(40)), ms(40));
+        // A single spike only moves the average by 1/8 of the difference
+        assert_eq!(ewma_rtt(Some(ms(40)), ms(120)), ms(50));
+        assert_eq!(ewma_rtt(Some(ms(40)), ms(0)), ms(35));
+    }
+
+    #[test]
+    fn test_record_rtt_smooths_samples() {
+        let stats = ConnectionStats::new();
+        assert_eq!(stats.rtt(), None);
+        stats.record_rtt(Duration::from_millis(80));
+        assert_eq!(stats.rtt(), Some(Duration::from_millis(80)));
+        stats.record_rtt(Duration::from_millis(160));
+        assert_eq!(stats.snapshot().rtt, Some(Duration::from_millis(90)));
+    }
+}
@@ -0,0 +1,26 @@
+// Where tunnel streams come from
+//
+// The client side only needs a way to open a bidirectional stream to the exit
+// node. `TunnelTransport` names that, so the SOCKS → tunnel handshake can run
+// over an in-memory mock in tests instead of a live iroh connection.
+use anyhow::Result;
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Opens bidirectional streams to the exit node
+pub trait TunnelTransport {
+    type Send: AsyncWrite + Unpin + Send;
+    type Recv: AsyncRead + Unpin + Send;
+
+    fn open_bi(&self) -> impl Future<Output = Result<(Self::Send, Self::Recv)>> + Send;
+}
+
+impl TunnelTransport for Connection {
+    type Send = SendStream;
+    type Recv = RecvStream;
+
+    async fn open_bi(&self) -> Result<(SendStream, RecvStream)> {
+        Ok(Connection::open_bi(self).await?)
+    }
+}
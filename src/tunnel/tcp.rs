@@ -0,0 +1,270 @@
+// TCP socket options for relayed connections
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+use crate::tunnel::logger::Logger;
+
+pub const DEFAULT_KEEPALIVE_IDLE: Duration = Duration::from_secs(60);
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Largest DSCP code point; it is the top 6 bits of the TOS / traffic class byte
+pub const MAX_DSCP: u8 = 63;
+
+/// Options applied to local SOCKS sockets and outgoing destination sockets
+///
+/// `TCP_NODELAY` is on by default so interactive protocols (SSH, terminals)
+/// aren't delayed by Nagle's algorithm; keepalive probes let half-dead
+/// connections be noticed and closed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TcpOptions {
+    pub nodelay: bool,
+    /// Idle time before the first keepalive probe (`None` disables keepalive)
+    pub keepalive_idle: Option<Duration>,
+    pub keepalive_interval: Duration,
+    /// DSCP class marked on outgoing packets (`--dscp`), at most `MAX_DSCP`
+    ///
+    /// IPv4 sockets are marked on all major platforms, IPv6 ones on Linux only.
+    pub dscp: Option<u8>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive_idle: Some(DEFAULT_KEEPALIVE_IDLE),
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            dscp: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(stream);
+        if let Some(dscp) = self.dscp {
+            set_dscp(&socket, stream.local_addr()?.is_ipv6(), dscp)?;
+        }
+        match self.keepalive_idle {
+            Some(idle) => {
+                let keepalive = TcpKeepalive::new().with_time(idle);
+                #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+                let keepalive = keepalive.with_interval(self.keepalive_interval);
+                socket.set_tcp_keepalive(&keepalive)
+            }
+            None => socket.set_keepalive(false),
+        }
+    }
+
+    /// Apply the options, logging instead of failing the connection
+    pub fn apply_or_warn(&self, stream: &TcpStream, logger: &dyn Logger) {
+        if let Err(e) = self.apply(stream) {
+            logger.warn(&format!("⚠️  Failed to set TCP socket options: {}", e));
+        }
+    }
+}
+
+/// Parse a `--dscp` value: a code point from 0 to 63, or a class name such
+/// as `EF`, `AF41` or `CS1`
+pub fn parse_dscp(value: &str) -> Result<u8, String> {
+    let invalid = || {
+        format!(
+            "expected a DSCP value from 0 to {} or a class like EF, AF41, CS1, got {:?}",
+            MAX_DSCP, value
+        )
+    };
+    let class = value.to_ascii_uppercase();
+    let digit = |c: u8, range: std::ops::RangeInclusive<u8>| {
+        let n = c.wrapping_sub(b'0');
+        range.contains(&n).then_some(n)
+    };
+    let dscp = match class.as_bytes() {
+        b"EF" => Some(46),
+        [b'C', b'S', c] => digit(*c, 0..=7).map(|n| n * 8),
+        [b'A', b'F', class, drop] => digit(*class, 1..=4)
+            .zip(digit(*drop, 1..=3))
+            .map(|(class, drop)| class * 8 + drop * 2),
+        _ => value.parse().ok().filter(|&dscp| dscp <= MAX_DSCP),
+    };
+    dscp.ok_or_else(invalid)
+}
+
+/// Mark the socket's packets with `dscp` in the IPv4 TOS or IPv6 traffic class
+fn set_dscp(socket: &SockRef<'_>, ipv6: bool, dscp: u8) -> io::Result<()> {
+    if dscp > MAX_DSCP {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("DSCP {} is out of range (0-{})", dscp, MAX_DSCP),
+        ));
+    }
+    // The low two bits of the byte are ECN, which the kernel owns
+    let tos = u32::from(dscp) << 2;
+    match ipv6 {
+        true => set_tclass_v6(socket, tos),
+        false => set_tos_v4(socket, tos),
+    }
+}
+
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+)))]
+fn set_tos_v4(socket: &SockRef<'_>, tos: u32) -> io::Result<()> {
+    socket.set_tos_v4(tos)
+}
+
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+))]
+fn set_tos_v4(_socket: &SockRef<'_>, _tos: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_tclass_v6(socket: &SockRef<'_>, tclass: u32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let value = tclass as libc::c_int;
+    // SAFETY: value is a live c_int and its size is passed along
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// socket2 has no traffic class setter, and libc is only a Linux dependency
+#[cfg(not(target_os = "linux"))]
+fn set_tclass_v6(_socket: &SockRef<'_>, _tclass: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn stream() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_default_options_are_applied() {
+        let stream = stream().await;
+        TcpOptions::default().apply(&stream).unwrap();
+
+        assert!(stream.nodelay().unwrap());
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.tcp_keepalive_time().unwrap(), DEFAULT_KEEPALIVE_IDLE);
+            assert_eq!(
+                socket.tcp_keepalive_interval().unwrap(),
+                DEFAULT_KEEPALIVE_INTERVAL
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_options_can_be_disabled() {
+        let stream = stream().await;
+        let options = TcpOptions {
+            nodelay: false,
+            keepalive_idle: None,
+            ..Default::default()
+        };
+        options.apply(&stream).unwrap();
+
+        assert!(!stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[test]
+    fn test_parse_dscp() {
+        assert_eq!(parse_dscp("0"), Ok(0));
+        assert_eq!(parse_dscp("46"), Ok(46));
+        assert_eq!(parse_dscp("63"), Ok(63));
+        assert_eq!(parse_dscp("ef"), Ok(46));
+        assert_eq!(parse_dscp("AF41"), Ok(34));
+        assert_eq!(parse_dscp("af13"), Ok(14));
+        assert_eq!(parse_dscp("CS1"), Ok(8));
+        assert_eq!(parse_dscp("cs7"), Ok(56));
+        for bad in ["64", "255", "-1", "", "AF51", "AF14", "CS8", "EF1", "46.0"] {
+            assert!(parse_dscp(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dscp_is_marked() {
+        let stream = stream().await;
+        let options = TcpOptions {
+            dscp: Some(46),
+            ..Default::default()
+        };
+        options.apply(&stream).unwrap();
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        assert_eq!(SockRef::from(&stream).tos_v4().unwrap(), 46 << 2);
+
+        let options = TcpOptions {
+            dscp: Some(MAX_DSCP + 1),
+            ..Default::default()
+        };
+        let err = options.apply(&stream).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_dscp_is_marked_on_ipv6() {
+        use std::os::fd::AsRawFd;
+
+        // Hosts without IPv6 loopback have nothing to check
+        let Ok(listener) = TcpListener::bind("[::1]:0").await else {
+            return;
+        };
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let options = TcpOptions {
+            dscp: Some(10),
+            ..Default::default()
+        };
+        options.apply(&stream).unwrap();
+
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: value and len describe a valid, writable buffer
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_TCLASS,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(value, 10 << 2);
+    }
+}
@@ -0,0 +1,274 @@
+// Shared helpers for tests that need a live iroh connection
+use iroh::EndpointAddr;
+use iroh::endpoint::{Connection, Endpoint, RelayMode, TransportConfig};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+use crate::socks5::protocol::{SOCKS_ADDR_TYPE_IPV4, SOCKS_CMD_CONNECT, SOCKS_VERSION};
+use crate::tls::server::SocksTls;
+use crate::tunnel::connection::handle_peer_connection;
+use crate::tunnel::handler::{PeerForwarder, SocksHandler, SocksLayer};
+use crate::tunnel::logger::{Logger, SharedLogger};
+use crate::tunnel::outbound::Outbound;
+use crate::tunnel::relay::ProtocolKind;
+use crate::tunnel::shutdown::Shutdown;
+use crate::tunnel::socks::serve_socks_with;
+use crate::tunnel::state::{ConnectionState, TUNNEL_ALPN, TunnelState};
+use crate::tunnel::stats::ConnectionStats;
+use crate::utils::logging::RequestId;
+
+async fn local_endpoint(transport: Option<fn() -> TransportConfig>) -> Endpoint {
+    let mut builder =
+        Endpoint::empty_builder(RelayMode::Disabled).alpns(vec![TUNNEL_ALPN.to_vec()]);
+    if let Some(transport) = transport {
+        builder = builder.transport_config(transport());
+    }
+    builder.bind().await.unwrap()
+}
+
+/// Connect two local endpoints: (client, server, client conn, server conn)
+pub async fn connected_pair() -> (Endpoint, Endpoint, Connection, Connection) {
+    connected_pair_with(None).await
+}
+
+/// `connected_pair` with both endpoints using the given transport config
+pub async fn connected_pair_with(
+    transport: Option<fn() -> TransportConfig>,
+) -> (Endpoint, Endpoint, Connection, Connection) {
+    let server = local_endpoint(transport).await;
+    let client = local_endpoint(transport).await;
+    let mut addr = EndpointAddr::new(server.id());
+    for socket in server.bound_sockets() {
+        addr = addr.with_ip_addr((std::net::Ipv4Addr::LOCALHOST, socket.port()).into());
+    }
+    let (client_conn, server_conn) = tokio::join!(
+        async { client.connect(addr, TUNNEL_ALPN).await.unwrap() },
+        async {
+            server
+                .accept()
+                .await
+                .unwrap()
+                .accept()
+                .unwrap()
+                .await
+                .unwrap()
+        }
+    );
+    (client, server, client_conn, server_conn)
+}
+
+/// Self-signed `localhost` certificate and key written to `dir`, with a
+/// connector that trusts exactly that certificate
+pub fn self_signed_tls(dir: &std::path::Path) -> (SocksTls, TlsConnector) {
+    let issued = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::create_dir_all(dir).unwrap();
+    let tls = SocksTls {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+    };
+    std::fs::write(&tls.cert, issued.cert.pem()).unwrap();
+    std::fs::write(&tls.key, issued.signing_key.serialize_pem()).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(issued.cert.der().clone()).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    (tls, TlsConnector::from(Arc::new(config)))
+}
+
+/// TCP echo server standing in for a destination
+pub async fn echo_server() -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = socket.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    addr
+}
+
+/// Records what the tunnel reports instead of printing it
+#[derive(Clone, Default)]
+pub struct CapturingLogger(Arc<std::sync::Mutex<Vec<String>>>);
+
+impl CapturingLogger {
+    /// Everything reported so far, one line per call
+    pub fn lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// `lines` without the `info` and `warn` messages
+    pub fn events(&self) -> Vec<String> {
+        self.lines()
+            .into_iter()
+            .filter(|line| !line.starts_with("info ") && !line.starts_with("warn "))
+            .collect()
+    }
+
+    fn push(&self, line: String) {
+        self.0.lock().unwrap().push(line);
+    }
+}
+
+impl Logger for CapturingLogger {
+    fn request_start(&self, id: RequestId, kind: &str, host: &str, port: u16) {
+        self.push(format!("start {} {} {}:{}", id, kind, host, port));
+    }
+
+    fn request_end(
+        &self,
+        id: RequestId,
+        bytes_sent: u64,
+        bytes_received: u64,
+        _path: &str,
+        _protocol: ProtocolKind,
+        _sni: Option<&str>,
+    ) {
+        self.push(format!("end {} {}/{}", id, bytes_sent, bytes_received));
+    }
+
+    fn connection_state(&self, state: &ConnectionState, _peer: Option<iroh::PublicKey>) {
+        self.push(format!("state {}", state.label()));
+    }
+
+    fn error(&self, id: Option<RequestId>, message: &str) {
+        self.push(format!(
+            "error {:?} {}",
+            id.map(|id| id.to_string()),
+            message
+        ));
+    }
+
+    fn info(&self, message: &str) {
+        self.push(format!("info {}", message));
+    }
+
+    fn warn(&self, message: &str) {
+        self.push(format!("warn {}", message));
+    }
+}
+
+/// Two connected in-process tunnel nodes with a SOCKS listener on the client
+///
+/// Requests sent to `socks_addr` go through the client's SOCKS listener, over
+/// the iroh connection, and out of the server's `handle_peer_connection`.
+pub struct TunnelHarness {
+    pub socks_addr: std::net::SocketAddr,
+    pub client_state: Arc<Mutex<TunnelState>>,
+    pub server_stats: Arc<ConnectionStats>,
+    /// What the server reports while serving requests
+    pub server_logger: CapturingLogger,
+    client: Endpoint,
+    server: Endpoint,
+}
+
+impl TunnelHarness {
+    pub async fn start() -> Self {
+        Self::start_with(None).await
+    }
+
+    /// Start with SOCKS requests going through `layer` around the default handler
+    pub async fn start_with(layer: Option<SocksLayer>) -> Self {
+        Self::start_full(layer, Outbound::default()).await
+    }
+
+    /// Start with the server reaching destinations through `outbound`
+    pub async fn start_with_outbound(outbound: Outbound) -> Self {
+        Self::start_full(None, outbound).await
+    }
+
+    async fn start_full(layer: Option<SocksLayer>, outbound: Outbound) -> Self {
+        let (client, server, client_conn, server_conn) = connected_pair().await;
+
+        let server_stats = Arc::new(ConnectionStats::new());
+        let server_logger = CapturingLogger::default();
+        tokio::spawn(handle_peer_connection(
+            server_conn,
+            server.clone(),
+            Arc::clone(&server_stats),
+            Shutdown::new(),
+            Arc::new(outbound),
+            0,
+            SharedLogger::new(Box::new(server_logger.clone())),
+        ));
+
+        let client_state = Arc::new(Mutex::new(TunnelState::default()));
+        client_state.lock().await.install_connection(client_conn);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_addr = listener.local_addr().unwrap();
+        let mut handler: Arc<dyn SocksHandler> = Arc::new(PeerForwarder::new(
+            Arc::clone(&client_state),
+            client.clone(),
+        ));
+        if let Some(layer) = layer {
+            handler = layer.layer(handler);
+        }
+        tokio::spawn(serve_socks_with(
+            listener,
+            Arc::clone(&client_state),
+            handler,
+        ));
+
+        Self {
+            socks_addr,
+            client_state,
+            server_stats,
+            server_logger,
+            client,
+            server,
+        }
+    }
+
+    /// Open a SOCKS5 CONNECT to `dest` and return the stream after a success reply
+    pub async fn socks_connect(&self, dest: std::net::SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(self.socks_addr).await.unwrap();
+        stream.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [SOCKS_VERSION, 0]);
+
+        let std::net::IpAddr::V4(ip) = dest.ip() else {
+            panic!("harness only dials IPv4 destinations");
+        };
+        let mut request = vec![SOCKS_VERSION, SOCKS_CMD_CONNECT, 0, SOCKS_ADDR_TYPE_IPV4];
+        request.extend_from_slice(&ip.octets());
+        request.extend_from_slice(&dest.port().to_be_bytes());
+        stream.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0, "SOCKS CONNECT failed");
+        stream
+    }
+
+    /// The client's default handler, for tests serving SOCKS on another listener
+    pub fn forwarder(&self) -> Arc<dyn SocksHandler> {
+        Arc::new(PeerForwarder::new(
+            Arc::clone(&self.client_state),
+            self.client.clone(),
+        ))
+    }
+
+    /// The client's endpoint, for tests serving other local listeners
+    pub fn client_endpoint(&self) -> Endpoint {
+        self.client.clone()
+    }
+
+    pub async fn close(self) {
+        self.client_state.lock().await.shutdown.trigger();
+        self.client.close().await;
+        self.server.close().await;
+    }
+}
@@ -0,0 +1,174 @@
+// Transparent proxy listener for iptables REDIRECT (Linux only)
+use anyhow::Result;
+use iroh::endpoint::Endpoint;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use crate::tunnel::socks::{LocalProtocol, accept_clients, forward_to_peer};
+use crate::tunnel::state::TunnelState;
+
+/// `SO_ORIGINAL_DST` from linux/netfilter_ipv4.h (same value for IPv6)
+const SO_ORIGINAL_DST: libc::c_int = 80;
+
+/// Decode a socket address returned by the kernel
+pub fn parse_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: ss_family says the storage holds a sockaddr_in
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)).into())
+        }
+        libc::AF_INET6 => {
+            // SAFETY: ss_family says the storage holds a sockaddr_in6
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Some(
+                SocketAddrV6::new(
+                    ip,
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )
+                .into(),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Destination a connection had before netfilter redirected it to us
+pub fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let level = match stream.local_addr()? {
+        SocketAddr::V4(_) => libc::SOL_IP,
+        SocketAddr::V6(_) => libc::SOL_IPV6,
+    };
+    // SAFETY: sockaddr_storage is plain old data, zeroed is a valid value
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: storage and len describe a valid, writable buffer
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            SO_ORIGINAL_DST,
+            &mut storage as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    parse_sockaddr(&storage)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown address family"))
+}
+
+/// Accept redirected connections and tunnel each to its original destination
+pub async fn serve_transparent(
+    listener: TcpListener,
+    state: Arc<Mutex<TunnelState>>,
+    endpoint: Endpoint,
+) {
+    let (tcp_options, logger) = {
+        let state = state.lock().await;
+        (state.tcp_options.clone(), state.logger.clone())
+    };
+    let (listener, tcp_options, accept_logger) = (&listener, &tcp_options, &logger);
+    let accept = move || async move {
+        let (socket, addr) = listener.accept().await?;
+        tcp_options.apply_or_warn(&socket, &**accept_logger);
+        Ok((socket, addr))
+    };
+    let serve = |socket: TcpStream, addr: SocketAddr, admitted: bool| {
+        let state = Arc::clone(&state);
+        let endpoint = endpoint.clone();
+        let logger = logger.clone();
+        async move {
+            // Over the limit the connection is just closed
+            if !admitted {
+                return;
+            }
+            if let Err(e) = handle_transparent_client(socket, addr, state, endpoint).await {
+                logger.error(
+                    None,
+                    &format!("Transparent proxy error from {}: {}", addr, e),
+                );
+            }
+        }
+    };
+    accept_clients("transparent", accept, &state, serve).await;
+}
+
+async fn handle_transparent_client(
+    socket: TcpStream,
+    client: SocketAddr,
+    state: Arc<Mutex<TunnelState>>,
+    endpoint: Endpoint,
+) -> Result<()> {
+    let dest = original_dst(&socket)?;
+    // Without a REDIRECT rule the "original" destination is the listener itself
+    if dest == socket.local_addr()? {
+        anyhow::bail!("Connection was not redirected (destination is this listener)");
+    }
+
+    let host = dest.ip().to_string();
+    let host = if dest.is_ipv6() {
+        format!("[{}]", host)
+    } else {
+        host
+    };
+    forward_to_peer(
+        socket,
+        client,
+        host,
+        dest.port(),
+        state,
+        endpoint,
+        LocalProtocol::Transparent,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sockaddr_ipv4() {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_port = 8080u16.to_be();
+        addr.sin_addr.s_addr = u32::from(Ipv4Addr::new(10, 1, 2, 3)).to_be();
+
+        assert_eq!(
+            parse_sockaddr(&storage),
+            Some("10.1.2.3:8080".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_sockaddr_ipv6() {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+        addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        addr.sin6_port = 443u16.to_be();
+        addr.sin6_addr.s6_addr = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets();
+
+        assert_eq!(
+            parse_sockaddr(&storage),
+            Some("[2001:db8::1]:443".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_sockaddr_unknown_family() {
+        let storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        assert_eq!(parse_sockaddr(&storage), None);
+    }
+}
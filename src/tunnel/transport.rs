@@ -0,0 +1,82 @@
+// QUIC transport of the iroh endpoint (`--keepalive-interval`, `--idle-timeout`)
+//
+// QUIC drops a connection that has been silent for the idle timeout; each end
+// announces one and the shorter applies. Keep-alive PINGs stop a quiet but
+// healthy connection from idling out, so the interval must be well below the
+// timeout. The app-level Ping/Pong heartbeat sits on top of this and catches
+// paths that stay open at the QUIC layer but no longer deliver anything.
+use anyhow::Result;
+use iroh::endpoint::{TransportConfig, VarInt};
+use std::time::Duration;
+
+/// Keep-alive of iroh's default transport config
+pub const DEFAULT_QUIC_KEEPALIVE: Duration = Duration::from_secs(1);
+
+/// QUIC's default max idle timeout
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Transport config sending keep-alives every `keepalive_interval` (zero = never)
+/// and dropping connections idle for `idle_timeout`
+pub fn quic_transport(
+    keepalive_interval: Duration,
+    idle_timeout: Duration,
+) -> Result<TransportConfig> {
+    if idle_timeout.is_zero() {
+        anyhow::bail!("Idle timeout must be non-zero");
+    }
+    if !keepalive_interval.is_zero() && keepalive_interval >= idle_timeout {
+        anyhow::bail!("Keep-alive interval must be shorter than the idle timeout");
+    }
+    let idle_ms = u64::try_from(idle_timeout.as_millis())
+        .ok()
+        .and_then(|ms| VarInt::from_u64(ms).ok())
+        .ok_or_else(|| anyhow::anyhow!("Idle timeout of {:?} is too long", idle_timeout))?;
+
+    let mut config = TransportConfig::default();
+    config.keep_alive_interval((!keepalive_interval.is_zero()).then_some(keepalive_interval));
+    config.max_idle_timeout(Some(idle_ms.into()));
+    Ok(config)
+}
+
+/// One-line summary of the effective settings for the startup log
+pub fn describe(keepalive_interval: Duration, idle_timeout: Duration) -> String {
+    let keepalive = if keepalive_interval.is_zero() {
+        "off".to_string()
+    } else {
+        format!("every {:?}", keepalive_interval)
+    };
+    format!("keep-alive {}, idle timeout {:?}", keepalive, idle_timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quic_transport_settings() {
+        // Only observable through Debug, which lists the fields
+        let debug = format!(
+            "{:?}",
+            quic_transport(Duration::from_secs(5), Duration::from_secs(120)).unwrap()
+        );
+        assert!(
+            debug.contains("max_idle_timeout: Some(120000)"),
+            "{}",
+            debug
+        );
+        assert!(debug.contains("keep_alive_interval: Some(5s)"), "{}", debug);
+
+        let debug = format!(
+            "{:?}",
+            quic_transport(Duration::ZERO, DEFAULT_IDLE_TIMEOUT).unwrap()
+        );
+        assert!(debug.contains("keep_alive_interval: None"), "{}", debug);
+    }
+
+    #[test]
+    fn test_quic_transport_rejects_bad_values() {
+        assert!(quic_transport(DEFAULT_QUIC_KEEPALIVE, Duration::ZERO).is_err());
+        assert!(quic_transport(Duration::from_secs(30), Duration::from_secs(30)).is_err());
+        assert!(quic_transport(Duration::from_secs(1), Duration::MAX).is_err());
+    }
+}
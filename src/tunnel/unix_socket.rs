@@ -0,0 +1,133 @@
+// Binding the unix socket listeners (status, control, SOCKS5)
+use anyhow::{Context, Result, bail};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::UnixListener;
+
+/// Bind a unix listener at `path` for the `what` socket
+///
+/// A socket file left behind by a previous run is replaced. Anything else at
+/// `path` is an error: a file that isn't a socket, or a socket another process
+/// is still listening on.
+pub fn bind_unix(path: &Path, what: &str) -> Result<UnixListener> {
+    remove_stale_socket(path, what)?;
+    let listener = std::os::unix::net::UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind {} socket {:?}", what, path))?;
+    from_std(listener)
+}
+
+/// Like `bind_unix`, but only the current user may connect
+///
+/// The socket is bound inside a fresh `0700` directory next to `path`,
+/// restricted to `0600`, and only then renamed into place, so there is no
+/// moment when other users could connect to it.
+pub fn bind_private_unix(path: &Path, what: &str) -> Result<UnixListener> {
+    remove_stale_socket(path, what)?;
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{} socket path {:?} has no file name", what, path))?;
+    let staging = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_context(|| format!("Failed to create {:?}", staging))?;
+
+    let bound = (|| {
+        let staged = staging.join(file_name);
+        let listener = std::os::unix::net::UnixListener::bind(&staged)
+            .with_context(|| format!("Failed to bind {} socket {:?}", what, path))?;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to restrict {} socket {:?}", what, path))?;
+        std::fs::rename(&staged, path)
+            .with_context(|| format!("Failed to move {} socket to {:?}", what, path))?;
+        anyhow::Ok(listener)
+    })();
+    let _ = std::fs::remove_dir_all(&staging);
+    from_std(bound?)
+}
+
+/// Remove the socket file at `path` if nothing is listening on it any more
+fn remove_stale_socket(path: &Path, what: &str) -> Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to inspect {:?}", path)),
+    };
+    if !metadata.file_type().is_socket() {
+        bail!(
+            "Refusing to replace {:?} with the {} socket: it is not a socket",
+            path,
+            what
+        );
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        bail!(
+            "Another process is already listening on {} socket {:?}",
+            what,
+            path
+        );
+    }
+    std::fs::remove_file(path)
+        .with_context(|| format!("Failed to remove stale {} socket {:?}", what, path))
+}
+
+fn from_std(listener: std::os::unix::net::UnixListener) -> Result<UnixListener> {
+    listener.set_nonblocking(true)?;
+    Ok(UnixListener::from_std(listener)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_stale_socket_is_replaced() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("stale.sock");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = bind_unix(&path, "test").unwrap();
+        let (connected, accepted) =
+            tokio::join!(tokio::net::UnixStream::connect(&path), listener.accept());
+        connected.unwrap();
+        accepted.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_live_socket_and_other_files_are_kept() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("live.sock");
+        let _live = bind_unix(&path, "test").unwrap();
+        let err = bind_unix(&path, "test").unwrap_err();
+        assert!(err.to_string().contains("already listening"), "{}", err);
+        assert!(path.exists());
+
+        let file = dir.path().join("notes.txt");
+        std::fs::write(&file, "keep me").unwrap();
+        let err = bind_unix(&file, "test").unwrap_err();
+        assert!(err.to_string().contains("not a socket"), "{}", err);
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+    }
+
+    #[tokio::test]
+    async fn test_private_socket_is_owner_only() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("control.sock");
+        let listener = bind_private_unix(&path, "test").unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // The staging directory is gone
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        let (connected, accepted) =
+            tokio::join!(tokio::net::UnixStream::connect(&path), listener.accept());
+        connected.unwrap();
+        accepted.unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+// Chaining outgoing connections through an upstream SOCKS5 / HTTP proxy
+use anyhow::{Context, Result};
+use std::net::IpAddr;
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::socks5::protocol::{
+    SOCKS_ADDR_TYPE_DOMAIN, SOCKS_ADDR_TYPE_IPV4, SOCKS_ADDR_TYPE_IPV6, SOCKS_CMD_CONNECT,
+    SOCKS_VERSION,
+};
+
+const MAX_HTTP_RESPONSE_HEADER: usize = 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpstreamKind {
+    Socks5,
+    Http,
+}
+
+/// Upstream proxy the exit node dials instead of the destination
+///
+/// Parsed from `socks5://host:port` or `http://host:port`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamProxy {
+    pub kind: UpstreamKind,
+    /// `host:port` of the proxy itself
+    pub addr: String,
+}
+
+impl FromStr for UpstreamProxy {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .with_context(|| format!("Upstream proxy must be a URL: {}", url))?;
+        let kind = match scheme.to_ascii_lowercase().as_str() {
+            "socks5" | "socks5h" => UpstreamKind::Socks5,
+            "http" => UpstreamKind::Http,
+            _ => anyhow::bail!("Unsupported upstream proxy scheme: {}", scheme),
+        };
+        let addr = rest.trim_end_matches('/');
+        let has_port = addr
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !has_port || addr.contains('/') || addr.contains('@') {
+            anyhow::bail!("Upstream proxy must be scheme://host:port: {}", url);
+        }
+        Ok(Self {
+            kind,
+            addr: addr.to_string(),
+        })
+    }
+}
+
+impl UpstreamProxy {
+    /// Open a connection to `host:port` through the proxy
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.addr)
+            .await
+            .with_context(|| format!("Failed to reach upstream proxy {}", self.addr))?;
+        match self.kind {
+            UpstreamKind::Socks5 => socks5_connect(&mut stream, host, port).await?,
+            UpstreamKind::Http => http_connect(&mut stream, host, port).await?,
+        }
+        Ok(stream)
+    }
+}
+
+/// Issue a SOCKS5 CONNECT (no authentication) on an open proxy connection
+pub async fn socks5_connect(stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+    stream.write_all(&[SOCKS_VERSION, 1, 0]).await?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [SOCKS_VERSION, 0] {
+        anyhow::bail!("Upstream proxy refused unauthenticated access");
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CMD_CONNECT, 0];
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(SOCKS_ADDR_TYPE_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(SOCKS_ADDR_TYPE_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len()).context("Host name too long for SOCKS5")?;
+            request.push(SOCKS_ADDR_TYPE_DOMAIN);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        anyhow::bail!("Upstream proxy CONNECT failed (SOCKS reply {})", reply[1]);
+    }
+
+    // Skip the bound address and port
+    let addr_len = match reply[3] {
+        SOCKS_ADDR_TYPE_IPV4 => 4,
+        SOCKS_ADDR_TYPE_IPV6 => 16,
+        SOCKS_ADDR_TYPE_DOMAIN => stream.read_u8().await? as usize,
+        other => anyhow::bail!("Upstream proxy sent unknown address type {}", other),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Issue an HTTP CONNECT on an open proxy connection
+pub async fn http_connect(stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+    let authority = format!("{}:{}", host, port);
+    let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority);
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte so nothing past the header is consumed
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HTTP_RESPONSE_HEADER {
+            anyhow::bail!("Upstream proxy response header too large");
+        }
+        header.push(stream.read_u8().await?);
+    }
+
+    let header = String::from_utf8_lossy(&header);
+    let status = header.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        let line = header.lines().next().unwrap_or("");
+        anyhow::bail!("Upstream proxy CONNECT failed: {}", line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::test_support::echo_server;
+    use tokio::net::TcpListener;
+
+    /// Minimal proxy that checks the CONNECT target, then splices to `target`
+    async fn stub_proxy(kind: UpstreamKind, target: std::net::SocketAddr) -> UpstreamProxy {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            match kind {
+                UpstreamKind::Socks5 => {
+                    let mut greeting = [0u8; 3];
+                    client.read_exact(&mut greeting).await.unwrap();
+                    client.write_all(&[SOCKS_VERSION, 0]).await.unwrap();
+                    let mut head = [0u8; 5];
+                    client.read_exact(&mut head).await.unwrap();
+                    assert_eq!(head[3], SOCKS_ADDR_TYPE_DOMAIN);
+                    let mut rest = vec![0u8; head[4] as usize + 2];
+                    client.read_exact(&mut rest).await.unwrap();
+                    assert_eq!(&rest[..head[4] as usize], b"dest.example");
+                    client
+                        .write_all(&[SOCKS_VERSION, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                        .await
+                        .unwrap();
+                }
+                UpstreamKind::Http => {
+                    let mut header = Vec::new();
+                    while !header.ends_with(b"\r\n\r\n") {
+                        header.push(client.read_u8().await.unwrap());
+                    }
+                    assert!(header.starts_with(b"CONNECT dest.example:443 HTTP/1.1\r\n"));
+                    client
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await
+                        .unwrap();
+                }
+            }
+            let mut upstream = TcpStream::connect(target).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+        });
+        UpstreamProxy {
+            kind,
+            addr: addr.to_string(),
+        }
+    }
+
+    async fn assert_echo_through(proxy: UpstreamProxy) {
+        let mut stream = proxy.connect("dest.example", 443).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_socks5_upstream() {
+        let proxy = stub_proxy(UpstreamKind::Socks5, echo_server().await).await;
+        assert_echo_through(proxy).await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_http_upstream() {
+        let proxy = stub_proxy(UpstreamKind::Http, echo_server().await).await;
+        assert_echo_through(proxy).await;
+    }
+
+    #[tokio::test]
+    async fn test_http_upstream_refusal() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 256];
+            let _ = client.read(&mut buf).await;
+            let _ = client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await;
+        });
+        let proxy: UpstreamProxy = format!("http://{}", addr).parse().unwrap();
+        let err = proxy.connect("dest.example", 443).await.unwrap_err();
+        assert!(err.to_string().contains("403"));
+    }
+
+    #[test]
+    fn test_parse_upstream_url() {
+        assert_eq!(
+            "socks5://proxy.corp:1080".parse::<UpstreamProxy>().unwrap(),
+            UpstreamProxy {
+                kind: UpstreamKind::Socks5,
+                addr: "proxy.corp:1080".to_string()
+            }
+        );
+        assert_eq!(
+            "http://10.0.0.1:3128/"
+                .parse::<UpstreamProxy>()
+                .unwrap()
+                .kind,
+            UpstreamKind::Http
+        );
+        assert!("ftp://proxy:21".parse::<UpstreamProxy>().is_err());
+        assert!("socks5://proxy".parse::<UpstreamProxy>().is_err());
+        assert!("proxy:1080".parse::<UpstreamProxy>().is_err());
+    }
+}
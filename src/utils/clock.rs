@@ -0,0 +1,124 @@
+// Wall-clock timestamps for log lines
+//
+// With the `chrono` feature (on by default) times are shown in the local time
+// zone. Without it they are formatted from `SystemTime` in UTC, so minimal
+// builds don't need the dependency.
+use std::time::SystemTime;
+
+/// A point in time as written to the request and access logs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timestamp(SystemTime);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self(SystemTime::now())
+    }
+
+    pub fn from_system_time(time: SystemTime) -> Self {
+        Self(time)
+    }
+
+    /// `HH:MM:SS`
+    pub fn time_of_day(&self) -> String {
+        #[cfg(feature = "chrono")]
+        return self.local().format("%H:%M:%S").to_string();
+        #[cfg(not(feature = "chrono"))]
+        return self.utc().3;
+    }
+
+    /// `DD/Mon/YYYY:HH:MM:SS +ZZZZ`, as in the Common Log Format
+    pub fn log_date(&self) -> String {
+        #[cfg(feature = "chrono")]
+        return self.local().format("%d/%b/%Y:%H:%M:%S %z").to_string();
+        #[cfg(not(feature = "chrono"))]
+        {
+            let (year, month, day, clock) = self.utc();
+            format!("{:02}/{}/{}:{} +0000", day, month, year, clock)
+        }
+    }
+
+    #[cfg(feature = "chrono")]
+    fn local(&self) -> chrono::DateTime<chrono::Local> {
+        self.0.into()
+    }
+
+    /// Year, month name, day and `HH:MM:SS` in UTC
+    #[cfg(not(feature = "chrono"))]
+    fn utc(&self) -> (i64, &'static str, u32, String) {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        let secs = match self.0.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        };
+        let (days, time) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+        let (year, month, day) = civil_from_days(days);
+        let clock = format!("{:02}:{:02}:{:02}", time / 3600, time / 60 % 60, time % 60);
+        (year, MONTHS[month as usize - 1], day, clock)
+    }
+}
+
+/// (year, month, day) of the proleptic Gregorian calendar, `days` after 1970-01-01
+#[cfg(not(feature = "chrono"))]
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's algorithm, counting in 400-year eras from 0000-03-01
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// 2026-10-17 09:05:03 UTC
+    fn sample() -> Timestamp {
+        Timestamp::from_system_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_792_227_903))
+    }
+
+    #[test]
+    fn test_time_of_day_shape() {
+        let time = Timestamp::now().time_of_day();
+        let bytes = time.as_bytes();
+        assert_eq!(bytes.len(), 8, "{}", time);
+        assert!(bytes[2] == b':' && bytes[5] == b':', "{}", time);
+        assert!(time.replace(':', "").chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_local_time_with_chrono() {
+        // Any time zone is within a day of UTC, and this is mid-month
+        let date = sample().log_date();
+        assert!(date.contains("/Oct/2026:"), "{}", date);
+        assert!(date.contains(&sample().time_of_day()), "{}", date);
+        let offset = date.rsplit(' ').next().unwrap();
+        assert!(
+            offset.starts_with(['+', '-']) && offset.len() == 5,
+            "{}",
+            date
+        );
+    }
+
+    #[cfg(not(feature = "chrono"))]
+    #[test]
+    fn test_utc_without_chrono() {
+        assert_eq!(sample().time_of_day(), "09:05:03");
+        assert_eq!(sample().log_date(), "17/Oct/2026:09:05:03 +0000");
+        // Leap day and the day before the epoch
+        let at =
+            |secs| Timestamp::from_system_time(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(951_782_400).log_date(), "29/Feb/2000:00:00:00 +0000");
+        let before = Timestamp::from_system_time(SystemTime::UNIX_EPOCH - Duration::from_secs(1));
+        assert_eq!(before.log_date(), "31/Dec/1969:23:59:59 +0000");
+    }
+}
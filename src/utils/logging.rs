@@ -1,20 +1,177 @@
-use chrono::Local;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-/// Format log messages with timestamp
-pub fn format_log(direction: &str, host: &str, port: u16) -> String {
-    let timestamp = Local::now().format("%H:%M:%S");
-    format!("[{}] {} {}:{}", timestamp, direction, host, port)
+use crate::tunnel::relay::ProtocolKind;
+use crate::utils::clock::Timestamp;
+
+/// Whether destinations are hidden from log output (`--privacy`)
+static PRIVACY: AtomicBool = AtomicBool::new(false);
+
+/// Hide destination hosts, SNI and HTTP paths in all log output from now on
+///
+/// Process-wide, since logging is; byte counts and outcomes are still logged.
+pub fn set_privacy(enabled: bool) {
+    PRIVACY.store(enabled, Ordering::Relaxed);
+}
+
+pub fn privacy_enabled() -> bool {
+    PRIVACY.load(Ordering::Relaxed)
+}
+
+/// Stable pseudonym for a destination, so repeat visits can be correlated
+/// without naming the host
+pub fn pseudonym(value: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    value.to_ascii_lowercase().hash(&mut hasher);
+    format!("host-{:08x}", hasher.finish() as u32)
+}
+
+/// A host, SNI or path as it may appear in logs
+pub fn target(value: &str) -> Cow<'_, str> {
+    target_with(privacy_enabled(), value)
+}
+
+fn target_with(privacy: bool, value: &str) -> Cow<'_, str> {
+    if privacy {
+        Cow::Owned(pseudonym(value))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Free-form detail (error text, filter reason) that may name the destination
+pub fn detail(text: &str) -> &str {
+    if privacy_enabled() {
+        "details hidden by --privacy"
+    } else {
+        text
+    }
+}
+
+/// `value` cut to at most `max_chars` characters, marking the cut with `…`
+pub fn truncate(value: &str, max_chars: usize) -> Cow<'_, str> {
+    match value.char_indices().nth(max_chars) {
+        Some((end, _)) => Cow::Owned(format!("{}…", &value[..end])),
+        None => Cow::Borrowed(value),
+    }
+}
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Short ID tying together the log lines of one request, shown as `#42`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestId(u64);
+
+impl RequestId {
+    /// Next ID from a process-wide counter
+    pub fn next() -> Self {
+        Self(NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Format log messages with timestamp and request ID
+pub fn format_log(id: RequestId, direction: &str, host: &str, port: u16) -> String {
+    format_log_with(privacy_enabled(), id, direction, host, port)
+}
+
+fn format_log_with(privacy: bool, id: RequestId, direction: &str, host: &str, port: u16) -> String {
+    format!(
+        "[{}] {} {} {}:{}",
+        Timestamp::now().time_of_day(),
+        id,
+        direction,
+        target_with(privacy, host),
+        port
+    )
+}
+
+/// Summary printed when a relayed request ends
+pub fn format_stats(
+    id: RequestId,
+    sent: u64,
+    received: u64,
+    path: &str,
+    protocol: ProtocolKind,
+    sni: Option<&str>,
+) -> String {
+    let detail = match (protocol, sni) {
+        (ProtocolKind::Tls, Some(sni)) => format!(" (SNI: {})", target(sni)),
+        (_, Some(request)) => format!(" ({})", target(request)),
+        (_, None) => String::new(),
+    };
+    format!(
+        "   {} 📊 Stats: ↑ {} bytes sent, ↓ {} bytes received via {}, {}{}",
+        id,
+        sent,
+        received,
+        path,
+        protocol.label(),
+        detail
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("curl/8.5.0", 20), "curl/8.5.0");
+        assert_eq!(truncate("Mozilla/5.0 (X11)", 7), "Mozilla…");
+        assert_eq!(truncate("äöü", 2), "äö…");
+    }
+
     #[test]
     fn test_format_log() {
-        let log = format_log("TEST", "example.com", 443);
-        assert!(log.contains("TEST"));
+        let log = format_log_with(false, RequestId(7), "TEST", "example.com", 443);
+        assert!(log.contains("#7 TEST"));
         assert!(log.contains("example.com:443"));
         assert!(log.contains("[")); // Has timestamp
     }
+
+    #[test]
+    fn test_privacy_hashes_host() {
+        let log = format_log_with(true, RequestId(1), "OUTGOING", "secret.example.com", 443);
+        assert!(!log.contains("secret"));
+        assert!(log.contains(&format!("{}:443", pseudonym("secret.example.com"))));
+
+        assert_eq!(pseudonym("Example.com"), pseudonym("example.com"));
+        assert_ne!(pseudonym("a.example"), pseudonym("b.example"));
+        assert_eq!(target_with(false, "example.com"), "example.com");
+    }
+
+    #[test]
+    fn test_request_and_stats_lines_share_id() {
+        let id = RequestId::next();
+        assert_ne!(RequestId::next(), id);
+
+        let request = format_log_with(false, id, "PROXY REQUEST", "example.com", 443);
+        let stats = format_stats(id, 517, 4096, "direct", ProtocolKind::Unknown, None);
+        let tag = format!("{} ", id);
+        assert!(request.contains(&tag), "{}", request);
+        assert!(stats.trim_start().starts_with(&tag), "{}", stats);
+        assert!(
+            stats.ends_with("↑ 517 bytes sent, ↓ 4096 bytes received via direct, unknown protocol")
+        );
+    }
+
+    #[test]
+    fn test_stats_line_names_protocol() {
+        let id = RequestId::next();
+        let tls = format_stats(id, 1, 2, "relay", ProtocolKind::Tls, Some("github.com"));
+        assert!(tls.ends_with("via relay, TLS (SNI: github.com)"), "{}", tls);
+        let http = format_stats(id, 1, 2, "direct", ProtocolKind::Http, Some("GET / → 404"));
+        assert!(http.ends_with("via direct, HTTP (GET / → 404)"), "{}", http);
+        let tls = format_stats(id, 1, 2, "direct", ProtocolKind::Tls, None);
+        assert!(tls.ends_with("via direct, TLS"), "{}", tls);
+    }
 }
@@ -1,2 +1,6 @@
 // Utility functions
+pub mod clock;
 pub mod logging;
+pub mod parse_debug;
+pub mod qr;
+pub mod version;
@@ -0,0 +1,133 @@
+// `--debug-parse`: hex dump of the first chunk of a stream and what the TLS and
+// HTTP parsers made of it, for finding out why SNI/Host extraction failed
+use std::fmt::Write as _;
+
+use crate::http::parser::diagnose_http_request;
+use crate::tls::sni::diagnose_client_hello;
+
+/// Bytes of a chunk shown in the hex dump; the rest is only counted
+pub const MAX_DUMP_BYTES: usize = 256;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// `xxd`-style dump of the first `max` bytes of `data`
+pub fn hex_dump(data: &[u8], max: usize) -> String {
+    let shown = &data[..data.len().min(max)];
+    let mut out = String::new();
+    for (i, line) in shown.chunks(BYTES_PER_LINE).enumerate() {
+        let _ = write!(out, "{:04x} ", i * BYTES_PER_LINE);
+        for byte in line {
+            let _ = write!(out, " {:02x}", byte);
+        }
+        let padding = 3 * (BYTES_PER_LINE - line.len());
+        let text: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(out, "{:padding$}  |{}|", "", text, padding = padding);
+    }
+    if data.len() > shown.len() {
+        let _ = writeln!(out, "… {} more bytes", data.len() - shown.len());
+    }
+    out
+}
+
+/// Hex dump of `data` followed by the TLS and HTTP parse results, or where
+/// each parser gave up
+pub fn parse_dump(data: &[u8]) -> String {
+    let mut out = format!("{} bytes\n", data.len());
+    out.push_str(&hex_dump(data, MAX_DUMP_BYTES));
+    let _ = match diagnose_client_hello(data) {
+        Ok(server_name) => writeln!(out, "TLS:  ClientHello, {:?}", server_name),
+        Err(reason) => writeln!(out, "TLS:  no ClientHello: {}", reason),
+    };
+    let _ = match diagnose_http_request(data) {
+        Ok(request) => writeln!(out, "HTTP: {:?}", request),
+        Err(reason) => writeln!(out, "HTTP: no request: {}", reason),
+    };
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_dump_is_bounded() {
+        let dump = hex_dump(b"GET / HTTP/1.1\r\nHost: a\r\n", 32);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(
+            lines[0],
+            "0000  47 45 54 20 2f 20 48 54 54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|"
+        );
+        assert_eq!(
+            lines[1],
+            "0010  48 6f 73 74 3a 20 61 0d 0a                       |Host: a..|"
+        );
+
+        let dump = hex_dump(&[0u8; 1000], MAX_DUMP_BYTES);
+        assert_eq!(dump.lines().count(), MAX_DUMP_BYTES / BYTES_PER_LINE + 1);
+        assert!(dump.ends_with("… 744 more bytes\n"), "{}", dump);
+    }
+
+    #[test]
+    fn test_parse_dump_names_failure_points() {
+        // A TLS record cut off before the ClientHello is complete
+        let mut truncated = vec![0u8; 44];
+        truncated[..6].copy_from_slice(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01]);
+        let dump = parse_dump(&truncated);
+        assert!(
+            dump.contains("TLS:  no ClientHello: cipher suites length at offset 44"),
+            "{}",
+            dump
+        );
+        assert!(
+            dump.contains("HTTP: no request: request line has 1 of 3"),
+            "{}",
+            dump
+        );
+
+        let dump = parse_dump(b"GET /caf\xe9 HTTP/1.1\r\n\r\n");
+        assert!(
+            dump.contains("HTTP: no request: not UTF-8 at offset 8"),
+            "{}",
+            dump
+        );
+
+        // Application data, not a handshake
+        let dump = parse_dump(&[0x17; 64]);
+        assert!(
+            dump.contains("record type 0x17 is not a handshake"),
+            "{}",
+            dump
+        );
+
+        let dump = parse_dump(b"FETCH /index.html HTTP/1.1\r\n\r\n");
+        assert!(
+            dump.contains("HTTP: no request: \"FETCH\" is not an HTTP method"),
+            "{}",
+            dump
+        );
+        let dump = parse_dump(b"GET /index.html HTTP/2.0\r\n\r\n");
+        assert!(dump.contains("\"HTTP/2.0\" is not HTTP/1.x"), "{}", dump);
+    }
+
+    #[test]
+    fn test_parse_dump_shows_full_result() {
+        let dump = parse_dump(b"GET /a HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert!(dump.starts_with("38 bytes\n"), "{}", dump);
+        assert!(
+            dump.contains(
+                "HTTP: HttpRequestInfo { method: \"GET\", path: \"/a\", host: Some(\"example.com\")"
+            ),
+            "{}",
+            dump
+        );
+    }
+}
@@ -0,0 +1,37 @@
+// Terminal QR codes for sharing tickets
+use anyhow::{Context, Result};
+use qrcode::QrCode;
+use qrcode::render::unicode::Dense1x2;
+
+/// Render `data` as a QR code of Unicode half blocks, two rows per line
+///
+/// Colors are inverted for the usual light-on-dark terminal, so light
+/// modules print as blocks; the quiet zone is included for scanners.
+pub fn render_qr(data: &str) -> Result<String> {
+    let code = QrCode::new(data.as_bytes()).context("Failed to encode QR code")?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .quiet_zone(true)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    #[test]
+    fn test_render_qr_for_ticket() {
+        let ticket = SecretKey::generate(&mut rand::rng()).public().to_string();
+        let qr = render_qr(&ticket).unwrap();
+        let lines: Vec<&str> = qr.lines().collect();
+        assert!(lines.len() > 10, "{}", qr);
+        // Square code: two module rows per line, every line the same width
+        let width = lines[0].chars().count();
+        assert!(lines.iter().all(|line| line.chars().count() == width));
+        assert!(width >= 2 * lines.len() - 2);
+        assert!(qr.contains('█'));
+    }
+}
@@ -0,0 +1,40 @@
+// Build details for `tunnel --version` and bug reports (captured by build.rs)
+use crate::tunnel::state::TUNNEL_PROTOCOL;
+
+/// Short commit hash the binary was built from, or `unknown` outside a git checkout
+pub const GIT_COMMIT: &str = env!("TUNNEL_GIT_COMMIT");
+
+/// Output of `rustc --version` for the compiler used
+pub const RUSTC_VERSION: &str = env!("TUNNEL_RUSTC_VERSION");
+
+/// iroh version pinned in Cargo.lock
+pub const IROH_VERSION: &str = env!("TUNNEL_IROH_VERSION");
+
+/// Multi-line version report: crate, commit, protocol, iroh and rustc versions
+pub fn version_info() -> String {
+    format!(
+        "{} ({})\nprotocol: {}\niroh: {}\nrustc: {}",
+        env!("CARGO_PKG_VERSION"),
+        GIT_COMMIT,
+        TUNNEL_PROTOCOL,
+        IROH_VERSION,
+        RUSTC_VERSION
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_info_names_protocol() {
+        let info = version_info();
+        assert!(info.starts_with(env!("CARGO_PKG_VERSION")), "{}", info);
+        assert!(
+            info.contains(&format!("protocol: {}", TUNNEL_PROTOCOL)),
+            "{}",
+            info
+        );
+        assert!(info.contains("iroh: "), "{}", info);
+    }
+}